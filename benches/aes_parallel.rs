@@ -0,0 +1,25 @@
+//! Compares sequential and parallel ECB encryption of large payloads
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use secure_communication::encryption::aes::{AESManager, KeySize};
+
+fn parallel(c: &mut Criterion) {
+    let manager = AESManager::new(KeySize::K256);
+
+    let mut group = c.benchmark_group("aes_parallel");
+    group.sample_size(10);
+    for size in [1024 * 1024, 10 * 1024 * 1024] {
+        let message = vec![0xA5u8; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("sequential", size), &message, |b, message| {
+            b.iter(|| manager.encrypt_blocks(message).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("parallel", size), &message, |b, message| {
+            b.iter(|| manager.encrypt_parallel(message).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parallel);
+criterion_main!(benches);
@@ -0,0 +1,43 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use secure_communication::encryption::rsa::RSAKeysGenerator;
+
+fn key_generation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("generate_keys_512");
+    group.sample_size(10);
+    group.bench_function("sieve", |b| {
+        let generator = RSAKeysGenerator::new(512).with_sieve(true);
+        b.iter(|| generator.generate_keys())
+    });
+    group.bench_function("no_sieve", |b| {
+        let generator = RSAKeysGenerator::new(512).with_sieve(false);
+        b.iter(|| generator.generate_keys())
+    });
+    group.finish();
+}
+
+/// Compares the exponent check of `generate_keys` with no check at all, and with the encrypt and
+/// decrypt round trip of `RSAKeys::valid` that it replaced
+fn key_validation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("validate_keys_1024");
+    group.sample_size(10);
+    let generator = RSAKeysGenerator::new(1024);
+    group.bench_function("unchecked", |b| {
+        b.iter(|| unsafe { generator.generate_keys_unchecked() })
+    });
+    group.bench_function("exponent_check", |b| {
+        b.iter(|| generator.generate_keys())
+    });
+    group.bench_function("round_trip_check", |b| {
+        b.iter(|| loop {
+            let keys = unsafe { generator.generate_keys_unchecked() };
+            if keys.valid() {
+                break keys;
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, key_generation, key_validation);
+criterion_main!(benches);
@@ -0,0 +1,24 @@
+//! Compares searching for the two primes one after the other and at the same time
+//!
+//! Each prime is found independently, so on a machine with at least two cores the parallel
+//! version should take a little over half as long.
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use secure_communication::encryption::rsa::RSAKeysGenerator;
+
+fn key_generation(c: &mut Criterion) {
+    let generator = RSAKeysGenerator::new(1024);
+
+    let mut group = c.benchmark_group("generate_keys_1024");
+    group.sample_size(10);
+    group.bench_function("sequential", |b| {
+        b.iter(|| generator.generate_keys())
+    });
+    group.bench_function("parallel", |b| {
+        b.iter(|| generator.generate_keys_parallel())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, key_generation);
+criterion_main!(benches);
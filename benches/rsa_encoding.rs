@@ -0,0 +1,30 @@
+//! Compares the decimal and base64 line encodings of `RSAWriter`
+//!
+//! The throughput is of plaintext bytes. Base64 also puts roughly 44% fewer bytes on the wire,
+//! which `encodings_round_trip` in `rsa_stream` checks.
+use std::io::Write;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use secure_communication::encryption::rsa::{RSAKeysGenerator, RSAStreamEncoding, RSAWriter};
+
+fn encoding(c: &mut Criterion) {
+    let keys = RSAKeysGenerator::new(512).generate_keys();
+    let message = vec![0xA5u8; 4096];
+
+    let mut group = c.benchmark_group("rsa_encoding");
+    group.throughput(Throughput::Bytes(message.len() as u64));
+    for (name, encoding) in [("decimal", RSAStreamEncoding::Decimal), ("base64", RSAStreamEncoding::Base64)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut output = Vec::new();
+                RSAWriter::with_encoding(keys.public_key(), &mut output, encoding).write_all(&message).unwrap();
+                output
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encoding);
+criterion_main!(benches);
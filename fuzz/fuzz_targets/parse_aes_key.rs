@@ -0,0 +1,18 @@
+#![no_main]
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+
+use secure_communication::encryption::rsa::{RSAKeys, RSAKeysGenerator, RSAReader};
+use secure_communication::encryption::secure::get_aes_key;
+
+/// A small key keeps each decryption cheap so more inputs can be tried
+fn keys() -> &'static RSAKeys {
+    static KEYS: OnceLock<RSAKeys> = OnceLock::new();
+    KEYS.get_or_init(|| RSAKeysGenerator::new(128).generate_keys())
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = RSAReader::new(keys().private_key(), data);
+    let _ = get_aes_key(&mut reader);
+});
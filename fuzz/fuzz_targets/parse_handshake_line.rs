@@ -0,0 +1,28 @@
+#![no_main]
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+
+use secure_communication::encryption::rsa::{RSAKeys, RSAKeysGenerator, RSAReader, RSAWriter};
+use secure_communication::encryption::nonce::Nonce;
+use secure_communication::encryption::{secure, unsecure};
+
+/// A small key keeps each decryption cheap so more inputs can be tried
+fn keys() -> &'static RSAKeys {
+    static KEYS: OnceLock<RSAKeys> = OnceLock::new();
+    KEYS.get_or_init(|| RSAKeysGenerator::new(128).generate_keys())
+}
+
+fuzz_target!(|data: &[u8]| {
+    let nonce = Nonce::from([0x12; 16]);
+
+    let _ = unsecure::server_ack(&mut std::io::sink(), &mut &*data);
+    let _ = unsecure::receive_ack(&nonce, &mut &*data);
+
+    let keys = keys();
+    let mut writer = RSAWriter::new(keys.public_key(), std::io::sink());
+    let _ = secure::server_ack(&nonce, &mut writer, &mut RSAReader::new(keys.private_key(), data));
+    let _ = secure::receive_and_repeat(&nonce, &mut writer, &mut RSAReader::new(keys.private_key(), data));
+    let _ = secure::client_repeat_correct(&nonce, &mut writer, &mut RSAReader::new(keys.private_key(), data));
+    let _ = secure::encryption_successful(&mut RSAReader::new(keys.private_key(), data));
+});
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use secure_communication::encryption::unsecure::receive_public_key;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = receive_public_key(&mut &*data);
+});
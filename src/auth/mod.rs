@@ -0,0 +1,2 @@
+//! Additional authentication steps that can be performed once the encrypted connection is set up
+pub mod totp;
@@ -0,0 +1,159 @@
+//! Time-based one-time passwords (RFC 6238) used as a second factor after the handshake
+use std::io::{ErrorKind, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+static TOTP_PHRASE: &str = "TOTP";
+
+/// The number of seconds each token is valid for
+pub const TIME_STEP: u64 = 30;
+/// The number of decimal digits in a token
+pub const DIGITS: u32 = 6;
+
+/// Checks tokens generated from a shared secret
+pub struct TotpVerifier {
+    secret: [u8; 20]
+}
+
+impl TotpVerifier {
+    pub fn new(secret: &[u8; 20]) -> Self {
+        TotpVerifier { secret: *secret }
+    }
+
+    /// Creates the token for the time step containing `timestamp`, in seconds since the epoch
+    pub fn token_at(&self, timestamp: u64) -> u32 {
+        hotp(&self.secret, timestamp / TIME_STEP)
+    }
+
+    /// Creates the token for the current time
+    pub fn current_token(&self) -> std::io::Result<u32> {
+        Ok(self.token_at(now()?))
+    }
+
+    /// Checks the token against the current time
+    ///
+    /// Tokens from the previous and the next time step are also accepted to account for clock drift.
+    pub fn verify(&self, token: u32) -> std::io::Result<bool> {
+        Ok(self.verify_at(token, now()?))
+    }
+
+    /// Checks the token against the time step containing `timestamp`, and its neighbours
+    ///
+    /// All three tokens are compared in constant time, so the time taken doesn't reveal which
+    /// digits or which time step matched.
+    pub fn verify_at(&self, token: u32, timestamp: u64) -> bool {
+        let counter = timestamp / TIME_STEP;
+        let first = counter.saturating_sub(1);
+        (first..=counter + 1).fold(false, |matched, counter| matched | constant_time_eq(hotp(&self.secret, counter), token))
+    }
+}
+
+/// HMAC-based one-time password (RFC 4226)
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let binary = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]])
+        & 0x7fff_ffff;
+    binary % 10u32.pow(DIGITS)
+}
+
+fn constant_time_eq(a: u32, b: u32) -> bool {
+    a.to_be_bytes().iter().zip(b.to_be_bytes().iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn now() -> std::io::Result<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .map_err(|_| std::io::Error::other("system clock is before the unix epoch"))
+}
+
+/// Reads up to and including the next `\n` one byte at a time, so that nothing after it is taken
+/// from `reader`
+fn read_line<R: Read>(reader: &mut R) -> std::io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    while reader.read(&mut byte)? == 1 {
+        line.push(byte[0]);
+        if byte[0] == b'\n' {
+            break;
+        }
+    }
+    String::from_utf8(line).map_err(|_| std::io::Error::new(ErrorKind::InvalidData, "TOTP line is not UTF-8"))
+}
+
+/// Client sends its token
+pub fn client_send_totp<W: Write>(token: u32, writer: &mut W) -> std::io::Result<()> {
+    writeln!(writer, "{}:{:0width$}", TOTP_PHRASE, token, width = DIGITS as usize)
+}
+
+/// Server checks the token sent by the client
+pub fn server_verify_totp<R: Read>(verifier: &TotpVerifier, reader: &mut R) -> Result<bool, std::io::Error> {
+    let line = read_line(reader)?;
+    let mut split = line.trim().splitn(2, ':');
+    if split.next() != Some(TOTP_PHRASE) {
+        return Err(std::io::Error::new(ErrorKind::InvalidData, "expected a TOTP token"));
+    }
+    let token: u32 = split.next()
+        .and_then(|token| token.parse().ok())
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "TOTP token is not a number"))?;
+    verifier.verify(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8; 20] = b"12345678901234567890";
+
+    #[test]
+    fn rfc_6238_vectors() {
+        let verifier = TotpVerifier::new(SECRET);
+        assert_eq!(verifier.token_at(59), 287082);
+        assert_eq!(verifier.token_at(1111111109), 81804);
+        assert_eq!(verifier.token_at(1111111111), 50471);
+        assert_eq!(verifier.token_at(1234567890), 5924);
+        assert_eq!(verifier.token_at(2000000000), 279037);
+    }
+
+    #[test]
+    fn adjacent_windows_accepted() {
+        let verifier = TotpVerifier::new(SECRET);
+        let token = verifier.token_at(1234567890);
+        assert!(verifier.verify_at(token, 1234567890));
+        assert!(verifier.verify_at(token, 1234567890 + TIME_STEP));
+        assert!(verifier.verify_at(token, 1234567890 - TIME_STEP));
+        assert!(!verifier.verify_at(token, 1234567890 + 3 * TIME_STEP));
+    }
+
+    #[test]
+    fn send_and_verify() {
+        let verifier = TotpVerifier::new(SECRET);
+        let mut inner: Vec<u8> = Vec::new();
+        client_send_totp(verifier.current_token().unwrap(), &mut inner).unwrap();
+        assert!(server_verify_totp(&verifier, &mut &*inner).unwrap());
+
+        let mut inner: Vec<u8> = Vec::new();
+        client_send_totp((verifier.current_token().unwrap() + 1) % 1_000_000, &mut inner).unwrap();
+        assert!(!server_verify_totp(&verifier, &mut &*inner).unwrap());
+
+        let error = server_verify_totp(&verifier, &mut &b"COM_BEGIN 1234\n"[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn bytes_after_the_token_are_left_in_the_reader() {
+        let verifier = TotpVerifier::new(SECRET);
+        let mut inner: Vec<u8> = Vec::new();
+        client_send_totp(verifier.current_token().unwrap(), &mut inner).unwrap();
+        inner.extend_from_slice(b"first channel message");
+
+        let mut reader = &*inner;
+        assert!(server_verify_totp(&verifier, &mut reader).unwrap());
+        assert_eq!(reader, b"first channel message");
+    }
+}
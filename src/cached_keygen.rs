@@ -0,0 +1,180 @@
+//! Keeps a server's RSA keys on disk so they survive restarts
+//!
+//! Clients that pin the server's public key would otherwise be broken every time the server
+//! generates a fresh key pair.
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use num_bigint::BigUint;
+use regex::Regex;
+use zeroize::Zeroize;
+
+use crate::encryption::rsa::{RSAKeys, RSAKeysGenerator};
+
+/// Loads RSA keys from a cache file, generating and saving new ones if the file doesn't exist
+pub struct CachedRSAKeysGenerator {
+    path: PathBuf,
+    keys: RSAKeys
+}
+
+impl CachedRSAKeysGenerator {
+
+    /// Loads the keys stored at `path`, or generates keys of `key_size` bits and stores them there
+    ///
+    /// An existing cache file that can not be parsed, or that holds an invalid key pair, is an
+    /// error rather than being silently replaced.
+    pub fn new<P: AsRef<Path>>(path: P, key_size: u16) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let keys = match fs::read_to_string(&path) {
+            Ok(contents) => parse_keys(&contents)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let keys = RSAKeysGenerator::new(key_size).generate_keys();
+                let mut contents = format_keys(&keys);
+                let written = write_private(&path, contents.as_bytes());
+                contents.zeroize();
+                written?;
+                keys
+            }
+            Err(e) => return Err(e)
+        };
+        Ok(CachedRSAKeysGenerator { path, keys })
+    }
+
+    pub fn keys(&self) -> &RSAKeys {
+        &self.keys
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Deletes the cache file, so the next instance generates fresh keys
+    pub fn invalidate(&mut self) -> std::io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            other => other
+        }
+    }
+}
+
+/// Writes `contents` to a temporary file that only the owner can read, then renames it to `path`
+///
+/// The cache holds the private exponent, so it must not be readable by other users, and a crash
+/// part way through must not leave a truncated file that [`CachedRSAKeysGenerator::new`] refuses
+/// to load.
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    // a file left by an earlier crash may have been created with other permissions
+    match fs::remove_file(&temp_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let written = options.open(&temp_path).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+    match written.and_then(|_| fs::rename(&temp_path, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            Err(e)
+        }
+    }
+}
+
+/// Stores the keys as a JSON object of decimal strings
+fn format_keys(keys: &RSAKeys) -> String {
+    let public_key = keys.public_key();
+    let private_key = keys.private_key();
+    format!(
+        "{{\"e\":\"{}\",\"d\":\"{}\",\"n\":\"{}\"}}\n",
+        public_key.key(),
+        private_key.key(),
+        public_key.n_value()
+    )
+}
+
+fn parse_keys(contents: &str) -> std::io::Result<RSAKeys> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new("\"([edn])\"\\s*:\\s*\"(\\d+)\"").unwrap();
+    }
+
+    let invalid = |message: &str| std::io::Error::new(ErrorKind::InvalidData, message.to_string());
+
+    let mut e: Option<BigUint> = None;
+    let mut d: Option<BigUint> = None;
+    let mut n: Option<BigUint> = None;
+    for captures in RE.captures_iter(contents) {
+        let value: BigUint = captures[2].parse().map_err(|_| invalid("key cache value is not an integer"))?;
+        match &captures[1] {
+            "e" => e = Some(value),
+            "d" => d = Some(value),
+            _ => n = Some(value)
+        }
+    }
+    match (e, d, n) {
+        (Some(e), Some(d), Some(n)) => {
+            RSAKeys::new(e, d, n).map_err(|_| invalid("key cache does not hold a valid key pair"))
+        }
+        _ => Err(invalid("key cache is missing a value"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_keys_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.json");
+
+        let first = CachedRSAKeysGenerator::new(&path, 128).unwrap();
+        let second = CachedRSAKeysGenerator::new(&path, 128).unwrap();
+        assert_eq!(first.keys().public_key().to_string(), second.keys().public_key().to_string());
+        assert_eq!(first.keys().private_key().key(), second.keys().private_key().key());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cache_is_only_readable_by_its_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.json");
+        CachedRSAKeysGenerator::new(&path, 128).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn invalidate_regenerates() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.json");
+
+        let mut first = CachedRSAKeysGenerator::new(&path, 128).unwrap();
+        first.invalidate().unwrap();
+        assert!(!path.exists());
+        let second = CachedRSAKeysGenerator::new(&path, 128).unwrap();
+        assert_ne!(first.keys().public_key().to_string(), second.keys().public_key().to_string());
+    }
+
+    #[test]
+    fn corrupt_cache_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.json");
+        fs::write(&path, "{\"e\":\"3\"}").unwrap();
+        let error = CachedRSAKeysGenerator::new(&path, 128).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,209 @@
+//! Certificates that bind an RSA public key to an identity such as a host name
+//!
+//! Sending a bare public key lets anyone on the path swap it for their own. A certificate states
+//! which identity the key belongs to, and is signed so that the binding can be checked.
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use crate::encryption::rsa::{PrivateKey, PublicKey, RSAKeys, Signature};
+
+const PEM_BEGIN: &str = "-----BEGIN SECURE_COM CERTIFICATE-----";
+const PEM_END: &str = "-----END SECURE_COM CERTIFICATE-----";
+/// The number of base64 characters on each line of a PEM body
+const PEM_LINE_LENGTH: usize = 64;
+
+/// An issuer's signature over a certificate, made with [`PrivateKey::sign`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RSASignature(Signature);
+
+impl RSASignature {
+
+    /// Signs `message` with PKCS#1 v1.5 padding over its SHA-256 hash
+    pub fn sign(message: &[u8], private_key: &PrivateKey) -> Self {
+        RSASignature(private_key.sign(message))
+    }
+
+    pub fn verify(&self, message: &[u8], public_key: &PublicKey) -> bool {
+        public_key.verify_signature(message, &self.0)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CertError {
+    /// The text is not surrounded by the certificate's PEM markers
+    MissingPemMarkers,
+    /// The PEM body is not valid base64
+    InvalidBase64,
+    /// The decoded body does not have the certificate's layout
+    Malformed
+}
+
+impl Display for CertError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for CertError { }
+
+#[derive(Debug, Clone)]
+pub struct Certificate {
+    identity: String,
+    public_key: PublicKey,
+    issuer_signature: Option<RSASignature>
+}
+
+impl Certificate {
+
+    /// Creates a certificate for the keys' public key, signed by their own private key
+    pub fn self_signed(identity: &str, keys: &RSAKeys) -> Certificate {
+        let public_key = keys.public_key();
+        let signature = RSASignature::sign(&signed_bytes(identity, &public_key), &keys.private_key());
+        Certificate {
+            identity: identity.to_string(),
+            public_key,
+            issuer_signature: Some(signature)
+        }
+    }
+
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    pub fn issuer_signature(&self) -> Option<&RSASignature> {
+        self.issuer_signature.as_ref()
+    }
+
+    /// Checks that the certificate is signed by the private half of its own public key
+    pub fn verify_self_signed(&self) -> bool {
+        match &self.issuer_signature {
+            Some(signature) => signature.verify(&signed_bytes(&self.identity, &self.public_key), &self.public_key),
+            None => false
+        }
+    }
+
+    /// Encodes the certificate as base64 between PEM markers
+    pub fn to_pem(&self) -> String {
+        let body = BASE64.encode(self.to_bytes());
+        let mut pem = format!("{}\n", PEM_BEGIN);
+        for line in body.as_bytes().chunks(PEM_LINE_LENGTH) {
+            pem.push_str(std::str::from_utf8(line).unwrap());
+            pem.push('\n');
+        }
+        pem.push_str(PEM_END);
+        pem.push('\n');
+        pem
+    }
+
+    pub fn from_pem(s: &str) -> Result<Self, CertError> {
+        let s = s.trim();
+        let body = s.strip_prefix(PEM_BEGIN)
+            .and_then(|rest| rest.strip_suffix(PEM_END))
+            .ok_or(CertError::MissingPemMarkers)?;
+        let body: String = body.split_whitespace().collect();
+        let bytes = BASE64.decode(body).map_err(|_| CertError::InvalidBase64)?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// The identity, public key, and signature, each as a big endian `u32` length followed by the
+    /// field. A missing signature has a length of zero.
+    fn to_bytes(&self) -> Vec<u8> {
+        let signature = self.issuer_signature.as_ref().map(|s| s.0.as_bytes().to_vec()).unwrap_or_default();
+        let mut bytes = Vec::new();
+        for field in [self.identity.as_bytes(), self.public_key.to_string().as_bytes(), &signature] {
+            bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(field);
+        }
+        bytes
+    }
+
+    fn from_bytes(mut bytes: &[u8]) -> Result<Self, CertError> {
+        let mut fields = Vec::with_capacity(3);
+        for _ in 0..3 {
+            if bytes.len() < 4 {
+                return Err(CertError::Malformed);
+            }
+            let (length, rest) = bytes.split_at(4);
+            let length = u32::from_be_bytes(<[u8; 4]>::try_from(length).unwrap()) as usize;
+            if rest.len() < length {
+                return Err(CertError::Malformed);
+            }
+            let (field, rest) = rest.split_at(length);
+            fields.push(field);
+            bytes = rest;
+        }
+        if !bytes.is_empty() {
+            return Err(CertError::Malformed);
+        }
+
+        let identity = String::from_utf8(fields[0].to_vec()).map_err(|_| CertError::Malformed)?;
+        let public_key = std::str::from_utf8(fields[1]).ok()
+            .and_then(|key| PublicKey::from_str(key).ok())
+            .ok_or(CertError::Malformed)?;
+        let issuer_signature = if fields[2].is_empty() {
+            None
+        } else {
+            Some(RSASignature(Signature::from(fields[2].to_vec())))
+        };
+        Ok(Certificate { identity, public_key, issuer_signature })
+    }
+}
+
+/// `identity || public_key_bytes`, with a separator so that the boundary between them is fixed
+fn signed_bytes(identity: &str, public_key: &PublicKey) -> Vec<u8> {
+    let mut bytes = identity.as_bytes().to_vec();
+    bytes.push(0);
+    bytes.extend_from_slice(public_key.to_string().as_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encryption::rsa::RSAKeysGenerator;
+
+    use super::*;
+
+    #[test]
+    fn self_signed_round_trip() {
+        let keys = RSAKeysGenerator::new(512).generate_keys();
+        let certificate = Certificate::self_signed("server.example.com", &keys);
+        assert!(certificate.verify_self_signed());
+
+        let pem = certificate.to_pem();
+        assert!(pem.starts_with(PEM_BEGIN));
+        let parsed = Certificate::from_pem(&pem).unwrap();
+        assert_eq!(parsed.identity(), "server.example.com");
+        assert_eq!(parsed.public_key().to_string(), keys.public_key().to_string());
+        assert!(parsed.verify_self_signed());
+    }
+
+    #[test]
+    fn substituted_identity_fails() {
+        let keys = RSAKeysGenerator::new(512).generate_keys();
+        let mut certificate = Certificate::self_signed("server.example.com", &keys);
+        certificate.identity = "attacker.example.com".to_string();
+        assert!(!certificate.verify_self_signed());
+
+        let other = RSAKeysGenerator::new(512).generate_keys();
+        let mut certificate = Certificate::self_signed("server.example.com", &keys);
+        certificate.public_key = other.public_key();
+        assert!(!certificate.verify_self_signed());
+    }
+
+    #[test]
+    fn invalid_pem() {
+        assert_eq!(Certificate::from_pem("hello").err(), Some(CertError::MissingPemMarkers));
+        let not_base64 = format!("{}\n!!!!\n{}", PEM_BEGIN, PEM_END);
+        assert_eq!(Certificate::from_pem(&not_base64).err(), Some(CertError::InvalidBase64));
+        let truncated = format!("{}\n{}\n{}", PEM_BEGIN, BASE64.encode([0, 0, 0, 9, b'a']), PEM_END);
+        assert_eq!(Certificate::from_pem(&truncated).err(), Some(CertError::Malformed));
+    }
+}
@@ -0,0 +1,1541 @@
+//! The encrypted connection left once a handshake has agreed on an AES key
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+use crate::encryption::aes::AESManager;
+use crate::encryption::aes::aes_stream::AESStream;
+use crate::encryption::cipher_stream::{aes_stream, suite_of, CipherSuite, CipherSuiteMismatch};
+use crate::error::SecureComError;
+use crate::protocol::{EOF_FRAME_PHRASE, KEEPALIVE_PHRASE, REKEY_ACK_PHRASE, REKEY_CONFIRM_PHRASE, REKEY_PHRASE, REKEY_REQUEST_PHRASE};
+
+/// A stream whose reads can be switched between blocking and failing with `WouldBlock`, for
+/// [`SecureChannel::set_nonblocking`]
+pub trait NonblockingStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()>;
+}
+
+impl NonblockingStream for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl NonblockingStream for &TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl NonblockingStream for std::os::unix::net::UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        std::os::unix::net::UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// A stream that can hand out a second handle for writing to it, for
+/// [`SecureChannel::enable_keepalive`]
+pub trait CloneWriter {
+    type Writer : Write + Send + 'static;
+
+    fn clone_writer(&self) -> std::io::Result<Self::Writer>;
+}
+
+impl CloneWriter for TcpStream {
+    type Writer = TcpStream;
+
+    fn clone_writer(&self) -> std::io::Result<TcpStream> {
+        self.try_clone()
+    }
+}
+
+impl CloneWriter for &TcpStream {
+    type Writer = TcpStream;
+
+    fn clone_writer(&self) -> std::io::Result<TcpStream> {
+        self.try_clone()
+    }
+}
+
+#[cfg(unix)]
+impl CloneWriter for std::os::unix::net::UnixStream {
+    type Writer = Self;
+
+    fn clone_writer(&self) -> std::io::Result<Self> {
+        self.try_clone()
+    }
+}
+
+/// The number of keepalive messages a [`SecureChannel`] has sent and received
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeepaliveStats {
+    pub sent: u64,
+    pub received: u64
+}
+
+/// The thread started by [`SecureChannel::enable_keepalive`], which stops when this is dropped
+struct Keepalive {
+    /// The thread's own handle to the stream. It is locked whenever either the thread or the
+    /// channel writes, so that their messages don't interleave.
+    stream: Arc<Mutex<AESStream<Box<dyn Write + Send>>>>,
+    /// Dropped with the channel, which wakes the thread and stops it
+    _stop: Sender<()>
+}
+
+impl Keepalive {
+    fn lock(&self) -> MutexGuard<'_, AESStream<Box<dyn Write + Send>>> {
+        self.stream.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The first byte of a message sent by [`SecureChannel::send`]
+const DATA_MESSAGE: u8 = 0;
+/// The first byte of the channel's own messages, such as those of [`SecureChannel::rekey`]
+const CONTROL_MESSAGE: u8 = 1;
+/// The first byte of a message sent by [`SecureChannel::send_authenticated`]
+const AUTHENTICATED_MESSAGE: u8 = 2;
+/// The first byte of each chunk of a stream sent by [`SecureChannel::send_stream`]
+const STREAM_MESSAGE: u8 = 3;
+/// The most bytes [`SecureChannel::send_stream`] puts in one chunk
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Sends and receives whole messages over a stream, encrypted with the key from the handshake
+///
+/// Each message is framed with its length, so [`recv`](SecureChannel::recv) returns exactly what
+/// one call to [`send`](SecureChannel::send) on the other end sent.
+///
+/// The key can be replaced with [`rekey`](SecureChannel::rekey) or
+/// [`initiate_rekey`](SecureChannel::initiate_rekey) while the channel is open, which the other
+/// end handles inside `recv`.
+pub struct SecureChannel<S> {
+    stream: AESStream<S>,
+    /// The bytes sent and received, if the channel was made with [`with_metrics`](Self::with_metrics)
+    stats: Option<(u64, u64)>,
+    keepalive: Option<Keepalive>,
+    keepalives_sent: Arc<AtomicU64>,
+    keepalives_received: u64,
+    /// The sequence number of the last message sent with [`send_authenticated`](Self::send_authenticated)
+    authenticated_seq: u64,
+    /// The sequence number of the last message accepted by [`recv_authenticated`](Self::recv_authenticated)
+    last_seen_seq: u64,
+    /// Messages that arrived while [`rekey`](Self::rekey) waited for the other end's
+    /// acknowledgement, to be returned before any more are read
+    pending: VecDeque<Received>,
+    /// The bytes of the messages sent, whether or not the channel counts [`stats`](Self::stats)
+    bytes_sent: u64,
+    /// The bytes sent since the last [`initiate_rekey`](Self::initiate_rekey)
+    sent_since_rekey: u64,
+    /// From [`auto_rekey_after`](Self::auto_rekey_after)
+    auto_rekey_after: Option<u64>,
+    /// The number of keys derived with [`initiate_rekey`](Self::initiate_rekey) by either end
+    rekey_sequence: u64
+}
+
+impl<S> SecureChannel<S> {
+    pub fn new(manager: AESManager, stream: S) -> Self {
+        Self::from_stream(AESStream::new(manager, stream))
+    }
+
+    fn from_stream(stream: AESStream<S>) -> Self {
+        SecureChannel {
+            stream,
+            stats: None,
+            keepalive: None,
+            keepalives_sent: Arc::new(AtomicU64::new(0)),
+            keepalives_received: 0,
+            authenticated_seq: 0,
+            last_seen_seq: 0,
+            pending: VecDeque::new(),
+            bytes_sent: 0,
+            sent_since_rekey: 0,
+            auto_rekey_after: None,
+            rekey_sequence: 0
+        }
+    }
+
+    /// Creates a channel that encrypts with `suite`, so that AES keys can be used with AES-GCM or CTR,
+    /// failing if `manager`'s key is for another cipher
+    ///
+    /// [`new`](Self::new) is the same as [`CipherSuite::Aes`] for an AES key, and
+    /// [`CipherSuite::ChaCha20Poly1305`] for a ChaCha20-Poly1305 key. Both ends must use the same suite.
+    pub fn with_cipher_suite(suite: CipherSuite, manager: AESManager, stream: S) -> Result<Self, CipherSuiteMismatch> {
+        Ok(Self::from_stream(aes_stream(suite, manager, stream)?))
+    }
+
+    /// Creates a channel that counts the bytes of the messages it sends and receives
+    ///
+    /// It counts the messages given to [`send`](Self::send) and returned by [`recv`](Self::recv),
+    /// without the framing or the channel's own messages, which are the same plaintext bytes a
+    /// [`MeteredAesManager`](crate::encryption::aes::MeteredAesManager) counts for its streams.
+    /// The channel keeps the counts itself rather than wrapping its key in one, since its stream
+    /// holds a plain [`AESManager`], which is replaced when the channel is rekeyed. The counts
+    /// carry on across a [`rekey`](Self::rekey).
+    pub fn with_metrics(manager: AESManager, stream: S) -> Self {
+        SecureChannel { stats: Some((0, 0)), ..Self::new(manager, stream) }
+    }
+
+    /// Starts the channel with `raw`, encrypted bytes that were already read from the stream, such
+    /// as those the handshake read past its last message
+    pub(crate) fn with_buffered(mut self, raw: Vec<u8>) -> Self {
+        self.stream = self.stream.with_buffered(raw);
+        self
+    }
+
+    /// Calls [`initiate_rekey`](Self::initiate_rekey) from the send that brings the bytes sent
+    /// since the last one to at least `bytes`
+    ///
+    /// That send doesn't return until the other end has confirmed the new key, so the other end
+    /// must be receiving. Only one end of a channel should rekey automatically, since requests
+    /// from both ends at once fail.
+    pub fn auto_rekey_after(mut self, bytes: u64) -> Self {
+        self.auto_rekey_after = Some(bytes);
+        self
+    }
+
+    /// The bytes of the messages sent so far, without the framing or the channel's own messages
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// The number of bytes sent and received so far, or `None` if the channel isn't counting them
+    pub fn stats(&self) -> Option<(u64, u64)> {
+        self.stats
+    }
+
+    pub fn manager(&self) -> &AESManager {
+        self.stream.manager()
+    }
+
+    pub fn cipher_suite(&self) -> CipherSuite {
+        suite_of(&self.stream)
+    }
+
+    /// Returns the key and the raw stream, for callers that continue without the channel
+    pub fn into_parts(self) -> (AESManager, S) {
+        self.stream.into_parts()
+    }
+
+    /// Starts a thread that sends a keepalive message every `interval`, so that middleboxes don't
+    /// close an idle connection, replacing any earlier one
+    ///
+    /// The other end discards keepalives inside [`recv`](Self::recv). The thread is paused while
+    /// either end changes the key with [`rekey`](Self::rekey), so that every keepalive is sent
+    /// with the key the other end expects. The thread stops when the channel is dropped or a
+    /// keepalive can't be written.
+    pub fn enable_keepalive(&mut self, interval: Duration) -> std::io::Result<()> where S : CloneWriter {
+        let writer: Box<dyn Write + Send> = Box::new(self.stream.inner().clone_writer()?);
+        let stream = Arc::new(Mutex::new(self.stream.with_copied_key(writer)));
+        let (stop, stopped) = channel::<()>();
+        let (thread_stream, sent) = (Arc::clone(&stream), Arc::clone(&self.keepalives_sent));
+        std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let message = format!("{}:{}", KEEPALIVE_PHRASE, unix_time());
+                let mut stream = thread_stream.lock().unwrap_or_else(PoisonError::into_inner);
+                if send_kind(&mut *stream, CONTROL_MESSAGE, message.as_bytes()).is_err() {
+                    break;
+                }
+                sent.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        self.keepalive = Some(Keepalive { stream, _stop: stop });
+        Ok(())
+    }
+
+    pub fn keepalive_stats(&self) -> KeepaliveStats {
+        KeepaliveStats { sent: self.keepalives_sent.load(Ordering::Relaxed), received: self.keepalives_received }
+    }
+
+    /// Makes [`try_recv`](SecureChannel::try_recv) return straight away when nothing has arrived
+    pub fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> where S : NonblockingStream {
+        self.stream.inner().set_nonblocking(nonblocking)
+    }
+
+    fn count_sent(&mut self, bytes: usize) {
+        self.bytes_sent += bytes as u64;
+        self.sent_since_rekey += bytes as u64;
+        if let Some((sent, _)) = &mut self.stats {
+            *sent += bytes as u64;
+        }
+    }
+
+    fn count_received(&mut self, bytes: usize) {
+        if let Some((_, received)) = &mut self.stats {
+            *received += bytes as u64;
+        }
+    }
+}
+
+impl<S : Read + Write> SecureChannel<S> {
+    pub fn send(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.send_kind(DATA_MESSAGE, data)?;
+        self.count_sent(data.len());
+        self.rekey_if_due()
+    }
+
+    pub fn recv(&mut self) -> std::io::Result<Vec<u8>> {
+        loop {
+            let received = self.next_received()?;
+            if let Some(data) = self.handle(received)? {
+                return Ok(data);
+            }
+        }
+    }
+
+    /// Like [`recv`](Self::recv), but returns `Ok(None)` instead of waiting if no whole message has
+    /// arrived
+    ///
+    /// The stream must be non-blocking, see [`set_nonblocking`](Self::set_nonblocking), or this
+    /// blocks like `recv`. Part of a message that has arrived is kept for the next call.
+    pub fn try_recv(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        if let Some(received) = self.pending.pop_front() {
+            return self.handle(received);
+        }
+        while let Some(message) = self.stream.try_read_message()? {
+            if let Some(data) = self.handle(Received::parse(message)?)? {
+                return Ok(Some(data));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Sends `message` encoded as MessagePack, after a [`MessageHeader`] with the
+    /// [`UNTAGGED`](MessageHeader::UNTAGGED) type tag
+    #[cfg(feature = "serde")]
+    pub fn send_typed<T : Serialize>(&mut self, message: &T) -> std::io::Result<()> {
+        self.send_message(MessageHeader::UNTAGGED, message)
+    }
+
+    /// Sends `body` encoded as MessagePack, after a [`MessageHeader`] with `type_tag`, so that the
+    /// other end can tell what to decode it as with [`recv_message`](Self::recv_message)
+    #[cfg(feature = "serde")]
+    pub fn send_message<T : Serialize>(&mut self, type_tag: u16, body: &T) -> std::io::Result<()> {
+        let payload = rmp_serde::to_vec(body)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let payload_len: u32 = payload.len().try_into().map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "a payload is limited to u32::MAX bytes")
+        })?;
+        let mut data = Vec::with_capacity(MessageHeader::SIZE + payload.len());
+        data.extend_from_slice(&MessageHeader { type_tag, payload_len }.to_bytes());
+        data.extend_from_slice(&payload);
+        self.send(&data)
+    }
+
+    /// Receives a message sent with [`send_typed`](Self::send_typed), whatever its type tag
+    ///
+    /// Fails with `InvalidData` if the message's length does not match its header, or its payload
+    /// is not the MessagePack encoding of a `T`.
+    #[cfg(feature = "serde")]
+    pub fn recv_typed<T : DeserializeOwned>(&mut self) -> std::io::Result<T> {
+        Ok(self.recv_message()?.body)
+    }
+
+    /// Receives a message sent with [`send_message`](Self::send_message) along with its type tag
+    ///
+    /// Fails like [`recv_typed`](Self::recv_typed), so the tag can only choose between types when
+    /// they are variants of one enum, or each tag is followed by a known type.
+    #[cfg(feature = "serde")]
+    pub fn recv_message<T : DeserializeOwned>(&mut self) -> std::io::Result<Message<T>> {
+        let data = self.recv()?;
+        let header = MessageHeader::from_bytes(&data).ok_or_else(|| invalid_data("message is shorter than its header"))?;
+        let payload = &data[MessageHeader::SIZE..];
+        if payload.len() != header.payload_len as usize {
+            return Err(invalid_data("payload length does not match the header"));
+        }
+        let body = rmp_serde::from_slice(payload).map_err(invalid_data)?;
+        Ok(Message { type_tag: header.type_tag, body })
+    }
+
+    /// The first message that arrived during a rekey, or else the next one from the stream
+    fn next_received(&mut self) -> std::io::Result<Received> {
+        match self.pending.pop_front() {
+            Some(received) => Ok(received),
+            None => Received::parse(self.stream.read_message()?)
+        }
+    }
+
+    /// Sends `data` as an [`AuthenticatedMessage`] with the next sequence number and the current time
+    pub fn send_authenticated(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let seq = self.authenticated_seq + 1;
+        let timestamp_secs = unix_time();
+        let ciphertext = self.manager().seal(data);
+        let hmac = authentication_tag(self.manager(), seq, timestamp_secs, &ciphertext);
+        let message = AuthenticatedMessage { seq, timestamp_secs, ciphertext, hmac };
+        self.send_kind(AUTHENTICATED_MESSAGE, &message.to_bytes())?;
+        self.authenticated_seq = seq;
+        self.count_sent(data.len());
+        self.rekey_if_due()
+    }
+
+    /// Receives a message sent with [`send_authenticated`](Self::send_authenticated)
+    ///
+    /// Fails with `InvalidData` if the message's HMAC is wrong, it was sent more than `max_age` ago,
+    /// to the second, or its sequence number is not after the last one accepted, as happens when a
+    /// recorded message is replayed. A rejected message doesn't change the last sequence number.
+    pub fn recv_authenticated(&mut self, max_age: Duration) -> std::io::Result<Vec<u8>> {
+        let message = loop {
+            let message = self.next_received()?;
+            match self.handle_control(message)? {
+                Some(Received::Authenticated(message)) => break message,
+                Some(_) => return Err(invalid_data("expected an authenticated message")),
+                None => {}
+            }
+        };
+        let message = AuthenticatedMessage::from_bytes(&message).ok_or_else(|| invalid_data("authenticated message is too short"))?;
+        let mac = message_mac(self.manager(), message.seq, message.timestamp_secs, &message.ciphertext);
+        mac.verify_slice(&message.hmac).map_err(|_| invalid_data("authenticated message has the wrong HMAC"))?;
+        if message.seq <= self.last_seen_seq {
+            return Err(invalid_data(format!("authenticated message {} was already received", message.seq)));
+        }
+        if unix_time().saturating_sub(message.timestamp_secs) > max_age.as_secs() {
+            return Err(invalid_data(format!("authenticated message {} is too old", message.seq)));
+        }
+        let data = self.manager().open(&message.ciphertext).map_err(|_| invalid_data("authenticated message failed to decrypt"))?;
+        self.last_seen_seq = message.seq;
+        self.count_received(data.len());
+        Ok(data)
+    }
+
+    /// Sends exactly `content_length` bytes read from `source`, in chunks of up to 64 KiB, followed
+    /// by an end of stream frame
+    ///
+    /// Fails with `UnexpectedEof` if `source` ends early, in which case the other end won't see the
+    /// end of the stream and the channel can't be used any more.
+    pub fn send_stream<R : Read>(&mut self, mut source: R, content_length: u64) -> std::io::Result<()> {
+        let mut chunk = vec![0u8; STREAM_CHUNK_SIZE];
+        let mut remaining = content_length;
+        while remaining > 0 {
+            let length = remaining.min(STREAM_CHUNK_SIZE as u64) as usize;
+            let read = source.read_exact(&mut chunk[..length]);
+            let sent = read.and_then(|_| self.send_kind(STREAM_MESSAGE, &chunk[..length]));
+            if sent.is_err() {
+                chunk.zeroize();
+            }
+            sent?;
+            self.count_sent(length);
+            remaining -= length as u64;
+        }
+        chunk.zeroize();
+        self.send_kind(CONTROL_MESSAGE, format!("{}:{}", EOF_FRAME_PHRASE, content_length).as_bytes())?;
+        self.rekey_if_due()
+    }
+
+    /// Receives a stream sent with [`send_stream`](Self::send_stream) into `destination`, returning
+    /// its length
+    ///
+    /// Fails with `InvalidData` if any other message arrives before the end of the stream, or the
+    /// stream's length doesn't match its end frame.
+    pub fn recv_stream<W : Write>(&mut self, destination: W) -> std::io::Result<u64> {
+        self.recv_stream_with_progress(destination, |_| {})
+    }
+
+    /// Like [`recv_stream`](Self::recv_stream), calling `progress` with the number of bytes
+    /// received so far after each chunk
+    pub fn recv_stream_with_progress<W : Write, F : FnMut(u64)>(&mut self, mut destination: W, mut progress: F) -> std::io::Result<u64> {
+        let mut received = 0u64;
+        loop {
+            let message = self.next_received()?;
+            match self.handle_control(message)? {
+                Some(Received::StreamChunk(mut chunk)) => {
+                    let (length, written) = (chunk.len(), destination.write_all(&chunk));
+                    chunk.zeroize();
+                    written?;
+                    self.count_received(length);
+                    received += length as u64;
+                    progress(received);
+                }
+                Some(Received::StreamEnd(length)) if length == received => break,
+                Some(Received::StreamEnd(length)) => {
+                    return Err(invalid_data(format!("stream of {} bytes ended after {}", length, received)));
+                }
+                Some(_) => return Err(invalid_data("expected the rest of a stream")),
+                None => {}
+            }
+        }
+        destination.flush()?;
+        Ok(received)
+    }
+
+    /// Returns the data of a message, or handles one of the channel's own messages and returns `None`
+    fn handle(&mut self, received: Received) -> std::io::Result<Option<Vec<u8>>> {
+        match self.handle_control(received)? {
+            Some(Received::Data(data)) => {
+                self.count_received(data.len());
+                Ok(Some(data))
+            }
+            Some(Received::Authenticated(_)) => Err(unexpected_authenticated()),
+            Some(_) => Err(unexpected_stream()),
+            None => Ok(None)
+        }
+    }
+
+    /// Handles one of the channel's own messages and returns `None`, or returns any other message
+    fn handle_control(&mut self, received: Received) -> std::io::Result<Option<Received>> {
+        match received {
+            Received::Rekey(manager) => {
+                // the acknowledgement is the last message with the old key, so no keepalive may
+                // be sent between it and the switch
+                let keepalive = self.keepalive.as_ref().map(Keepalive::lock);
+                send_kind(&mut self.stream, CONTROL_MESSAGE, REKEY_ACK_PHRASE.as_bytes())?;
+                switch_key(&mut self.stream, keepalive, *manager);
+                Ok(None)
+            }
+            Received::RekeyRequest => {
+                let keepalive = self.keepalive.as_ref().map(Keepalive::lock);
+                send_kind(&mut self.stream, CONTROL_MESSAGE, REKEY_CONFIRM_PHRASE.as_bytes())?;
+                self.rekey_sequence += 1;
+                let manager = derived_key(self.stream.manager(), self.rekey_sequence);
+                switch_key(&mut self.stream, keepalive, manager);
+                Ok(None)
+            }
+            Received::RekeyAck => Err(unexpected_reply(REKEY_ACK_PHRASE)),
+            Received::RekeyConfirm => Err(unexpected_reply(REKEY_CONFIRM_PHRASE)),
+            Received::Keepalive => {
+                self.keepalives_received += 1;
+                Ok(None)
+            }
+            received => Ok(Some(received))
+        }
+    }
+
+    /// Replaces the key with a new random one of the same cipher, sent to the other end with the
+    /// current key
+    ///
+    /// The other end switches to the new key inside [`recv`](Self::recv), after acknowledging the
+    /// request with the old key. Until the acknowledgement arrives this end keeps the old key, and
+    /// messages the other end sent before it saw the request are kept for the next `recv`. Both
+    /// ends must not rekey at the same time.
+    pub fn rekey(&mut self) -> Result<(), SecureComError> {
+        let manager = AESManager::new(self.manager().cipher_choice());
+        let mut request = rekey_request(&manager);
+        // no keepalive may be sent between the request and the switch to the new key
+        let keepalive = self.keepalive.as_ref().map(Keepalive::lock);
+        let sent = send_kind(&mut self.stream, CONTROL_MESSAGE, request.as_bytes());
+        request.zeroize();
+        sent?;
+        await_rekey_reply(&mut self.stream, &mut self.pending, &mut self.keepalives_received, REKEY_ACK_PHRASE)?;
+        switch_key(&mut self.stream, keepalive, manager);
+        Ok(())
+    }
+
+    /// Replaces the key with one derived from it, without sending the new key
+    ///
+    /// Both ends derive the new key with HKDF-SHA256 from the old key followed by the number of
+    /// keys derived so far, so a recorded request reveals nothing about it. This end sends
+    /// [`REKEY_REQUEST_PHRASE`], and the other end answers inside [`recv`](Self::recv) with
+    /// [`REKEY_CONFIRM_PHRASE`] as its last message with the old key, like [`rekey`](Self::rekey).
+    pub fn initiate_rekey(&mut self) -> Result<(), SecureComError> {
+        // no keepalive may be sent between the request and the switch to the new key
+        let keepalive = self.keepalive.as_ref().map(Keepalive::lock);
+        send_kind(&mut self.stream, CONTROL_MESSAGE, REKEY_REQUEST_PHRASE.as_bytes())?;
+        await_rekey_reply(&mut self.stream, &mut self.pending, &mut self.keepalives_received, REKEY_CONFIRM_PHRASE)?;
+        self.rekey_sequence += 1;
+        let manager = derived_key(self.stream.manager(), self.rekey_sequence);
+        switch_key(&mut self.stream, keepalive, manager);
+        self.sent_since_rekey = 0;
+        Ok(())
+    }
+
+    /// Calls [`initiate_rekey`](Self::initiate_rekey) if enough has been sent since the last one,
+    /// see [`auto_rekey_after`](Self::auto_rekey_after)
+    fn rekey_if_due(&mut self) -> std::io::Result<()> {
+        match self.auto_rekey_after {
+            Some(limit) if self.sent_since_rekey >= limit => self.initiate_rekey().map_err(|e| match e {
+                SecureComError::IoError(e) => e,
+                e => invalid_data(e.to_string())
+            }),
+            _ => Ok(())
+        }
+    }
+
+    fn send_kind(&mut self, kind: u8, data: &[u8]) -> std::io::Result<()> {
+        let _keepalive = self.keepalive.as_ref().map(Keepalive::lock);
+        send_kind(&mut self.stream, kind, data)
+    }
+}
+
+/// Reads with the old key until `reply` to a rekey request, keeping the messages the other end
+/// sent before it saw the request for the next `recv`
+fn await_rekey_reply<S : Read>(stream: &mut AESStream<S>, pending: &mut VecDeque<Received>, keepalives_received: &mut u64, reply: &str)
+    -> Result<(), SecureComError> {
+    loop {
+        match Received::parse(stream.read_message()?)? {
+            Received::RekeyAck if reply == REKEY_ACK_PHRASE => return Ok(()),
+            Received::RekeyConfirm if reply == REKEY_CONFIRM_PHRASE => return Ok(()),
+            Received::Keepalive => *keepalives_received += 1,
+            received @ (Received::Data(_) | Received::Authenticated(_) | Received::StreamChunk(_) | Received::StreamEnd(_)) => {
+                pending.push_back(received)
+            }
+            _ => return Err(SecureComError::HandshakePhaseError(format!("expected {}", reply)))
+        }
+    }
+}
+
+/// The key that replaces `manager`'s on the `sequence`th [`SecureChannel::initiate_rekey`],
+/// derived with HKDF-SHA256 from the old key followed by the sequence number
+fn derived_key(manager: &AESManager, sequence: u64) -> AESManager {
+    let mut input = Vec::with_capacity(manager.key_bytes().len() + 8);
+    input.extend_from_slice(manager.key_bytes());
+    input.extend_from_slice(&sequence.to_be_bytes());
+    let hkdf = Hkdf::<Sha256>::new(None, &input);
+    input.zeroize();
+    let mut key = vec![0u8; manager.key_bytes().len()];
+    hkdf.expand(b"channel-rekey", &mut key).expect("a key is a valid HKDF output length");
+    AESManager::from_cipher_bytes(manager.cipher_choice(), key).expect("the derived key is as long as the old one")
+}
+
+/// Switches `stream`, and the keepalive thread's stream if there is one, to `manager`
+fn switch_key<S>(stream: &mut AESStream<S>, keepalive: Option<MutexGuard<'_, AESStream<Box<dyn Write + Send>>>>, manager: AESManager) {
+    if let Some(mut keepalive) = keepalive {
+        keepalive.replace_manager(manager.clone());
+    }
+    stream.replace_manager(manager);
+}
+
+impl<S : SplitStream> SecureChannel<S> {
+    /// Splits the channel into a half that receives and a half that sends, so that each can be
+    /// moved to its own thread
+    ///
+    /// The halves can't change the key together, so neither can [`rekey`](SecureChannel::rekey),
+    /// and a rekey request from the other end fails the reader's `recv`. Keepalives stop. Messages
+    /// that arrived during the last rekey and have not been received yet go to the reader.
+    pub fn into_split(self) -> std::io::Result<SplitChannel<S>> {
+        let (reader, writer) = self.stream.split(SplitStream::split)?;
+        Ok((SecureChannelReader { stream: reader, pending: self.pending }, SecureChannelWriter { stream: writer }))
+    }
+}
+
+/// The halves of a [`SecureChannel`] over `S`, from [`SecureChannel::into_split`]
+pub type SplitChannel<S> = (SecureChannelReader<<S as SplitStream>::Reader>, SecureChannelWriter<<S as SplitStream>::Writer>);
+
+/// A stream that can be divided into a half that reads and a half that writes, for
+/// [`SecureChannel::into_split`]
+pub trait SplitStream {
+    type Reader : Read;
+    type Writer : Write;
+
+    fn split(self) -> std::io::Result<(Self::Reader, Self::Writer)>;
+}
+
+impl SplitStream for TcpStream {
+    type Reader = TcpStream;
+    type Writer = TcpStream;
+
+    fn split(self) -> std::io::Result<(TcpStream, TcpStream)> {
+        let writer = self.try_clone()?;
+        Ok((self, writer))
+    }
+}
+
+#[cfg(unix)]
+impl SplitStream for std::os::unix::net::UnixStream {
+    type Reader = Self;
+    type Writer = Self;
+
+    fn split(self) -> std::io::Result<(Self, Self)> {
+        let writer = self.try_clone()?;
+        Ok((self, writer))
+    }
+}
+
+/// The receiving half of a [`SecureChannel`], from [`into_split`](SecureChannel::into_split)
+pub struct SecureChannelReader<R> {
+    stream: AESStream<R>,
+    /// The channel's messages from before the split that have not been received yet
+    pending: VecDeque<Received>
+}
+
+impl<R : Read> SecureChannelReader<R> {
+    /// Receives one message, see [`SecureChannel::recv`]
+    ///
+    /// Fails with `InvalidData` if the other end asks to rekey, since the halves can't change the
+    /// key together.
+    pub fn recv(&mut self) -> std::io::Result<Vec<u8>> {
+        loop {
+            let received = match self.pending.pop_front() {
+                Some(received) => received,
+                None => Received::parse(self.stream.read_message()?)?
+            };
+            match received {
+                Received::Data(data) => return Ok(data),
+                Received::Rekey(_) | Received::RekeyRequest => return Err(invalid_data("rekey request on a split channel")),
+                Received::RekeyAck => return Err(unexpected_reply(REKEY_ACK_PHRASE)),
+                Received::RekeyConfirm => return Err(unexpected_reply(REKEY_CONFIRM_PHRASE)),
+                Received::Authenticated(_) => return Err(unexpected_authenticated()),
+                Received::StreamChunk(_) | Received::StreamEnd(_) => return Err(unexpected_stream()),
+                Received::Keepalive => {}
+            }
+        }
+    }
+}
+
+/// The sending half of a [`SecureChannel`], from [`into_split`](SecureChannel::into_split)
+pub struct SecureChannelWriter<W> {
+    stream: AESStream<W>
+}
+
+impl<W : Write> SecureChannelWriter<W> {
+    /// Sends one message, see [`SecureChannel::send`]
+    pub fn send(&mut self, data: &[u8]) -> std::io::Result<()> {
+        send_kind(&mut self.stream, DATA_MESSAGE, data)
+    }
+}
+
+/// Sends `data` as one message of the given kind
+fn send_kind<W : Write>(stream: &mut AESStream<W>, kind: u8, data: &[u8]) -> std::io::Result<()> {
+    let mut message = Vec::with_capacity(1 + data.len());
+    message.push(kind);
+    message.extend_from_slice(data);
+    let written = stream.write_message(&message);
+    message.zeroize();
+    written?;
+    stream.flush()
+}
+
+/// A message received with [`SecureChannel::recv_message`], with the type tag it was sent with
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message<T> {
+    pub type_tag: u16,
+    pub body: T
+}
+
+/// Sent before the payload of every typed message
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageHeader {
+    pub type_tag: u16,
+    pub payload_len: u32
+}
+
+#[cfg(feature = "serde")]
+impl MessageHeader {
+    /// The size of the header on the wire
+    pub const SIZE: usize = 6;
+
+    /// The type tag of messages sent with [`SecureChannel::send_typed`]
+    pub const UNTAGGED: u16 = 0;
+
+    /// The type tag then the payload length, both big endian
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[..2].copy_from_slice(&self.type_tag.to_be_bytes());
+        bytes[2..].copy_from_slice(&self.payload_len.to_be_bytes());
+        bytes
+    }
+
+    /// Reads the header at the start of `bytes`, or `None` if there are fewer than
+    /// [`SIZE`](Self::SIZE) bytes
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::SIZE {
+            return None;
+        }
+        Some(MessageHeader {
+            type_tag: u16::from_be_bytes([bytes[0], bytes[1]]),
+            payload_len: u32::from_be_bytes([bytes[2], bytes[3], bytes[4], bytes[5]])
+        })
+    }
+}
+
+/// A message sent with [`SecureChannel::send_authenticated`]
+///
+/// The data is sealed with the channel's key, and the HMAC, keyed with a key derived from it, covers
+/// `seq || timestamp_secs || ciphertext` so that neither can be changed to replay the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthenticatedMessage {
+    pub seq: u64,
+    pub timestamp_secs: u64,
+    pub ciphertext: Vec<u8>,
+    pub hmac: [u8; 32]
+}
+
+impl AuthenticatedMessage {
+    /// The size of the sequence number, timestamp and HMAC before the ciphertext
+    const HEADER_SIZE: usize = 48;
+
+    /// The sequence number and timestamp, both big endian, then the HMAC and the ciphertext
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::HEADER_SIZE + self.ciphertext.len());
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp_secs.to_be_bytes());
+        bytes.extend_from_slice(&self.hmac);
+        bytes.extend_from_slice(&self.ciphertext);
+        bytes
+    }
+
+    /// Reads a message from [`to_bytes`](Self::to_bytes), or `None` if it is too short
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::HEADER_SIZE {
+            return None;
+        }
+        let (header, ciphertext) = bytes.split_at(Self::HEADER_SIZE);
+        Some(AuthenticatedMessage {
+            seq: u64::from_be_bytes(header[..8].try_into().ok()?),
+            timestamp_secs: u64::from_be_bytes(header[8..16].try_into().ok()?),
+            ciphertext: ciphertext.to_vec(),
+            hmac: header[16..].try_into().ok()?
+        })
+    }
+}
+
+/// The HMAC of an [`AuthenticatedMessage`], before it is finalized
+fn message_mac(manager: &AESManager, seq: u64, timestamp_secs: u64, ciphertext: &[u8]) -> Hmac<Sha256> {
+    let hkdf = Hkdf::<Sha256>::new(None, manager.key_bytes());
+    let mut key = [0u8; 32];
+    hkdf.expand(b"channel-message-hmac", &mut key).expect("32 bytes is a valid HKDF output length");
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("HMAC accepts keys of any length");
+    key.zeroize();
+    mac.update(&seq.to_be_bytes());
+    mac.update(&timestamp_secs.to_be_bytes());
+    mac.update(ciphertext);
+    mac
+}
+
+fn authentication_tag(manager: &AESManager, seq: u64, timestamp_secs: u64, ciphertext: &[u8]) -> [u8; 32] {
+    message_mac(manager, seq, timestamp_secs, ciphertext).finalize().into_bytes().into()
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// A message read from the stream, after the channel's own messages have been parsed
+enum Received {
+    Data(Vec<u8>),
+    /// The bytes of an [`AuthenticatedMessage`]
+    Authenticated(Vec<u8>),
+    StreamChunk(Vec<u8>),
+    /// The end of a stream, with its length
+    StreamEnd(u64),
+    Rekey(Box<AESManager>),
+    RekeyAck,
+    RekeyRequest,
+    RekeyConfirm,
+    Keepalive
+}
+
+impl Received {
+    fn parse(mut message: Vec<u8>) -> std::io::Result<Self> {
+        let received = match message.split_first() {
+            Some((&DATA_MESSAGE, _)) => {
+                message.remove(0);
+                return Ok(Received::Data(message));
+            }
+            Some((&CONTROL_MESSAGE, control)) => Self::parse_control(control),
+            Some((&AUTHENTICATED_MESSAGE, _)) => {
+                message.remove(0);
+                return Ok(Received::Authenticated(message));
+            }
+            Some((&STREAM_MESSAGE, _)) => {
+                message.remove(0);
+                return Ok(Received::StreamChunk(message));
+            }
+            _ => Err(invalid_data("message is missing its kind"))
+        };
+        // a rekey request holds the new key
+        message.zeroize();
+        received
+    }
+
+    fn parse_control(control: &[u8]) -> std::io::Result<Self> {
+        let text = std::str::from_utf8(control).map_err(|_| invalid_data("control message is not UTF-8"))?;
+        match text {
+            REKEY_ACK_PHRASE => return Ok(Received::RekeyAck),
+            REKEY_REQUEST_PHRASE => return Ok(Received::RekeyRequest),
+            REKEY_CONFIRM_PHRASE => return Ok(Received::RekeyConfirm),
+            _ => {}
+        }
+        match text.split_once(':') {
+            Some((REKEY_PHRASE, key)) => {
+                let manager = AESManager::from_str(key).map_err(|e| invalid_data(e.to_string()))?;
+                Ok(Received::Rekey(Box::new(manager)))
+            }
+            Some((KEEPALIVE_PHRASE, _)) => Ok(Received::Keepalive),
+            Some((EOF_FRAME_PHRASE, length)) => {
+                length.parse().map(Received::StreamEnd).map_err(|_| invalid_data("stream length is not a number"))
+            }
+            _ => Err(invalid_data("unknown control message"))
+        }
+    }
+}
+
+fn rekey_request(manager: &AESManager) -> String {
+    format!("{}:{}", REKEY_PHRASE, manager.parsable_string())
+}
+
+fn invalid_data<E : Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+}
+
+fn unexpected_reply(reply: &str) -> std::io::Error {
+    invalid_data(format!("{} without a rekey request", reply))
+}
+
+fn unexpected_authenticated() -> std::io::Error {
+    invalid_data("authenticated message outside recv_authenticated")
+}
+
+fn unexpected_stream() -> std::io::Error {
+    invalid_data("stream outside recv_stream")
+}
+
+#[cfg(feature = "async")]
+impl<S : tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin> SecureChannel<S> {
+    /// The async version of [`send`](SecureChannel::send)
+    pub async fn send_async(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.send_kind_async(DATA_MESSAGE, data).await?;
+        self.count_sent(data.len());
+        self.rekey_if_due_async().await
+    }
+
+    /// The async version of [`recv`](SecureChannel::recv)
+    pub async fn recv_async(&mut self) -> std::io::Result<Vec<u8>> {
+        loop {
+            let received = match self.pending.pop_front() {
+                Some(received) => received,
+                None => Received::parse(self.stream.read_message_async().await?)?
+            };
+            match received {
+                Received::Data(data) => {
+                    self.count_received(data.len());
+                    return Ok(data);
+                }
+                Received::Rekey(manager) => {
+                    self.send_kind_async(CONTROL_MESSAGE, REKEY_ACK_PHRASE.as_bytes()).await?;
+                    self.stream.replace_manager(*manager);
+                }
+                Received::RekeyRequest => {
+                    self.send_kind_async(CONTROL_MESSAGE, REKEY_CONFIRM_PHRASE.as_bytes()).await?;
+                    self.rekey_sequence += 1;
+                    let manager = derived_key(self.stream.manager(), self.rekey_sequence);
+                    self.stream.replace_manager(manager);
+                }
+                Received::RekeyAck => return Err(unexpected_reply(REKEY_ACK_PHRASE)),
+                Received::RekeyConfirm => return Err(unexpected_reply(REKEY_CONFIRM_PHRASE)),
+                Received::Authenticated(_) => return Err(unexpected_authenticated()),
+                Received::StreamChunk(_) | Received::StreamEnd(_) => return Err(unexpected_stream()),
+                Received::Keepalive => self.keepalives_received += 1
+            }
+        }
+    }
+
+    /// The async version of [`rekey`](SecureChannel::rekey)
+    pub async fn rekey_async(&mut self) -> Result<(), SecureComError> {
+        let manager = AESManager::new(self.manager().cipher_choice());
+        let mut request = rekey_request(&manager);
+        let sent = self.send_kind_async(CONTROL_MESSAGE, request.as_bytes()).await;
+        request.zeroize();
+        sent?;
+        self.await_rekey_reply_async(REKEY_ACK_PHRASE).await?;
+        self.stream.replace_manager(manager);
+        Ok(())
+    }
+
+    /// The async version of [`initiate_rekey`](SecureChannel::initiate_rekey)
+    pub async fn initiate_rekey_async(&mut self) -> Result<(), SecureComError> {
+        self.send_kind_async(CONTROL_MESSAGE, REKEY_REQUEST_PHRASE.as_bytes()).await?;
+        self.await_rekey_reply_async(REKEY_CONFIRM_PHRASE).await?;
+        self.rekey_sequence += 1;
+        let manager = derived_key(self.stream.manager(), self.rekey_sequence);
+        self.stream.replace_manager(manager);
+        self.sent_since_rekey = 0;
+        Ok(())
+    }
+
+    /// The async version of [`rekey_if_due`](SecureChannel::rekey_if_due)
+    async fn rekey_if_due_async(&mut self) -> std::io::Result<()> {
+        match self.auto_rekey_after {
+            Some(limit) if self.sent_since_rekey >= limit => self.initiate_rekey_async().await.map_err(|e| match e {
+                SecureComError::IoError(e) => e,
+                e => invalid_data(e.to_string())
+            }),
+            _ => Ok(())
+        }
+    }
+
+    /// The async version of [`await_rekey_reply`]
+    async fn await_rekey_reply_async(&mut self, reply: &str) -> Result<(), SecureComError> {
+        loop {
+            match Received::parse(self.stream.read_message_async().await?)? {
+                Received::RekeyAck if reply == REKEY_ACK_PHRASE => return Ok(()),
+                Received::RekeyConfirm if reply == REKEY_CONFIRM_PHRASE => return Ok(()),
+                Received::Keepalive => self.keepalives_received += 1,
+                received @ (Received::Data(_) | Received::Authenticated(_) | Received::StreamChunk(_) | Received::StreamEnd(_)) => {
+                    self.pending.push_back(received)
+                }
+                _ => return Err(SecureComError::HandshakePhaseError(format!("expected {}", reply)))
+            }
+        }
+    }
+
+    async fn send_kind_async(&mut self, kind: u8, data: &[u8]) -> std::io::Result<()> {
+        let mut message = Vec::with_capacity(1 + data.len());
+        message.push(kind);
+        message.extend_from_slice(data);
+        let written = self.stream.write_message_async(&message).await;
+        message.zeroize();
+        written?;
+        self.stream.flush_async().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::mpsc::channel;
+
+    use crate::encryption::aes::{CipherChoice, KeySize};
+    use crate::testing::ChannelDuplex;
+
+    use super::*;
+
+    #[test]
+    fn exchange_messages() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let manager = AESManager::new(KeySize::K256);
+        let server_manager = manager.clone();
+
+        let server = std::thread::spawn(move || {
+            let mut channel = SecureChannel::new(server_manager, &server_end);
+            loop {
+                let message = channel.recv().unwrap();
+                if message.is_empty() {
+                    break;
+                }
+                channel.send(&message.to_ascii_uppercase()).unwrap();
+            }
+        });
+
+        let mut channel = SecureChannel::new(manager, &client_end);
+        for message in [&b"hello"[..], b"a message spanning more than one block", b"\0"] {
+            channel.send(message).unwrap();
+            assert_eq!(channel.recv().unwrap(), message.to_ascii_uppercase());
+        }
+        channel.send(b"").unwrap();
+        server.join().unwrap();
+
+        let (key, _stream) = channel.into_parts();
+        assert_eq!(key.parsable_string().len(), 64);
+    }
+
+    #[test]
+    fn try_recv() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let manager = AESManager::new(KeySize::K256);
+        let server_manager = manager.clone();
+
+        let mut channel = SecureChannel::new(manager, &client_end);
+        channel.set_nonblocking(true).unwrap();
+        assert_eq!(channel.try_recv().unwrap(), None);
+
+        let mut server = SecureChannel::new(server_manager, Cursor::new(Vec::new()));
+        server.send(b"a message spanning more than one block").unwrap();
+        let encrypted = server.into_parts().1.into_inner();
+        (&server_end).write_all(&encrypted[..20]).unwrap();
+        assert_eq!(channel.try_recv().unwrap(), None);
+        (&server_end).write_all(&encrypted[20..]).unwrap();
+        assert_eq!(channel.try_recv().unwrap().unwrap(), b"a message spanning more than one block");
+        assert_eq!(channel.try_recv().unwrap(), None);
+
+        drop(server_end);
+        assert_eq!(channel.try_recv().unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[cfg(feature = "serde")]
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    enum Command {
+        Ping,
+        Echo(String)
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn typed_messages() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let manager = AESManager::new(KeySize::K256);
+        let server_manager = manager.clone();
+
+        let server = std::thread::spawn(move || {
+            let mut channel = SecureChannel::new(server_manager, &server_end);
+            let received = [channel.recv_typed::<Command>().unwrap(), channel.recv_typed::<Command>().unwrap()];
+            let tagged = channel.recv_message::<Command>().unwrap();
+            let wrong_type = channel.recv_typed::<Command>().unwrap_err().kind();
+            let raw = channel.recv().unwrap();
+            (received, tagged, wrong_type, raw)
+        });
+
+        let mut channel = SecureChannel::new(manager, &client_end);
+        channel.send_typed(&Command::Ping).unwrap();
+        channel.send_typed(&Command::Echo("hello".to_string())).unwrap();
+        channel.send_message(7, &Command::Echo("tagged".to_string())).unwrap();
+        channel.send_typed(&(1u8, 2u8)).unwrap();
+        channel.send_typed(&Command::Echo("hi".to_string())).unwrap();
+
+        let (received, tagged, wrong_type, raw) = server.join().unwrap();
+        assert_eq!(received, [Command::Ping, Command::Echo("hello".to_string())]);
+        assert_eq!(tagged, Message { type_tag: 7, body: Command::Echo("tagged".to_string()) });
+        assert_eq!(wrong_type, std::io::ErrorKind::InvalidData);
+        let payload = rmp_serde::to_vec(&Command::Echo("hi".to_string())).unwrap();
+        assert_eq!(
+            MessageHeader::from_bytes(&raw),
+            Some(MessageHeader { type_tag: MessageHeader::UNTAGGED, payload_len: payload.len() as u32 })
+        );
+        assert_eq!(&raw[MessageHeader::SIZE..], payload);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn message_header() {
+        let header = MessageHeader { type_tag: 0x0102, payload_len: 0x03040506 };
+        assert_eq!(header.to_bytes(), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(MessageHeader::from_bytes(&[1, 2, 3, 4, 5, 6, 7]), Some(header));
+        assert_eq!(MessageHeader::from_bytes(&[1, 2, 3, 4, 5]), None);
+    }
+
+    #[test]
+    fn split_between_threads() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let manager = AESManager::new(KeySize::K256);
+        let server_manager = manager.clone();
+
+        let server = std::thread::spawn(move || {
+            let mut channel = SecureChannel::new(server_manager, &server_end);
+            for _ in 0..100 {
+                let message = channel.recv().unwrap();
+                channel.send(&message).unwrap();
+            }
+        });
+
+        let (mut reader, mut writer) = SecureChannel::new(manager, client_end).into_split().unwrap();
+        let producer = std::thread::spawn(move || {
+            for i in 0..100 {
+                writer.send(format!("message {}", i).as_bytes()).unwrap();
+            }
+        });
+        let consumer = std::thread::spawn(move || {
+            (0..100).map(|_| String::from_utf8(reader.recv().unwrap()).unwrap()).collect::<Vec<_>>()
+        });
+
+        producer.join().unwrap();
+        let received = consumer.join().unwrap();
+        server.join().unwrap();
+        assert_eq!(received, (0..100).map(|i| format!("message {}", i)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn metrics() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let manager = AESManager::new(KeySize::K256);
+        let server_manager = manager.clone();
+
+        let server = std::thread::spawn(move || {
+            let mut channel = SecureChannel::with_metrics(server_manager, &server_end);
+            channel.recv().unwrap();
+            channel.rekey().unwrap();
+            channel.send(&[0; 10]).unwrap();
+            channel.stats()
+        });
+
+        let mut channel = SecureChannel::with_metrics(manager, &client_end);
+        channel.send(&[0; 1000]).unwrap();
+        assert_eq!(channel.recv().unwrap().len(), 10);
+        assert_eq!(channel.stats(), Some((1000, 10)));
+        assert_eq!(server.join().unwrap(), Some((10, 1000)));
+
+        assert_eq!(SecureChannel::new(AESManager::new(KeySize::K256), Vec::<u8>::new()).stats(), None);
+    }
+
+    #[test]
+    fn rekey() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let manager = AESManager::new(KeySize::K128);
+        let server_manager = manager.clone();
+
+        let server = std::thread::spawn(move || {
+            let mut channel = SecureChannel::new(server_manager, &server_end);
+            for _ in 0..2 {
+                let message = channel.recv().unwrap();
+                channel.send(&message).unwrap();
+            }
+            channel.into_parts().0
+        });
+
+        let mut channel = SecureChannel::new(manager.clone(), &client_end);
+        channel.send(b"before").unwrap();
+        assert_eq!(channel.recv().unwrap(), b"before");
+        channel.rekey().unwrap();
+        assert_ne!(channel.manager(), &manager);
+        assert_eq!(channel.manager().cipher_choice(), manager.cipher_choice());
+        // "REKEY:" as data is not mistaken for a request
+        channel.send(b"REKEY:after").unwrap();
+        assert_eq!(channel.recv().unwrap(), b"REKEY:after");
+        assert_eq!(&server.join().unwrap(), channel.manager());
+    }
+
+    #[test]
+    fn rekey_with_message_in_flight() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let manager = AESManager::new(KeySize::K256);
+        let server_manager = manager.clone();
+        let (sent, wait_for_send) = channel::<()>();
+
+        let server = std::thread::spawn(move || {
+            let mut channel = SecureChannel::new(server_manager, &server_end);
+            channel.send(b"in flight").unwrap();
+            sent.send(()).unwrap();
+            // handles the rekey request, then gets the message sent with the new key
+            let message = channel.recv().unwrap();
+            channel.send(&message).unwrap();
+        });
+
+        let mut channel = SecureChannel::new(manager, &client_end);
+        wait_for_send.recv().unwrap();
+        channel.rekey().unwrap();
+        assert_eq!(channel.recv().unwrap(), b"in flight");
+        channel.send(b"after").unwrap();
+        assert_eq!(channel.recv().unwrap(), b"after");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn split_after_rekey_with_message_in_flight() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let manager = AESManager::new(KeySize::K256);
+        let server_manager = manager.clone();
+        let (sent, wait_for_send) = channel::<()>();
+
+        let server = std::thread::spawn(move || {
+            let mut channel = SecureChannel::new(server_manager, &server_end);
+            channel.send(b"in flight").unwrap();
+            sent.send(()).unwrap();
+            let message = channel.recv().unwrap();
+            channel.send(&message).unwrap();
+        });
+
+        let mut channel = SecureChannel::new(manager, client_end);
+        wait_for_send.recv().unwrap();
+        channel.rekey().unwrap();
+        let (mut reader, mut writer) = channel.into_split().unwrap();
+        assert_eq!(reader.recv().unwrap(), b"in flight");
+        writer.send(b"after").unwrap();
+        assert_eq!(reader.recv().unwrap(), b"after");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn authenticated_messages() {
+        let manager = AESManager::new(KeySize::K128);
+        let mut sender = SecureChannel::new(manager.clone(), Cursor::new(Vec::new()));
+        sender.send_authenticated(b"first").unwrap();
+        sender.send_authenticated(b"second").unwrap();
+        let sent = sender.into_parts().1.into_inner();
+
+        let mut receiver = SecureChannel::new(manager, Cursor::new(sent));
+        assert_eq!(receiver.recv_authenticated(Duration::from_secs(60)).unwrap(), b"first");
+        assert_eq!(receiver.recv_authenticated(Duration::from_secs(60)).unwrap(), b"second");
+    }
+
+    #[test]
+    fn replayed_authenticated_message_is_rejected() {
+        let manager = AESManager::new(KeySize::K128);
+        let mut sender = SecureChannel::new(manager.clone(), Cursor::new(Vec::new()));
+        sender.send_authenticated(b"pay 10").unwrap();
+        let sent = sender.into_parts().1.into_inner();
+
+        let mut receiver = SecureChannel::new(manager, Cursor::new(sent.repeat(2)));
+        assert_eq!(receiver.recv_authenticated(Duration::from_secs(60)).unwrap(), b"pay 10");
+        let error = receiver.recv_authenticated(Duration::from_secs(60)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn old_authenticated_message_is_rejected() {
+        let manager = AESManager::new(KeySize::K128);
+        let mut sender = SecureChannel::new(manager.clone(), Cursor::new(Vec::new()));
+        let (seq, timestamp_secs, ciphertext) = (1, unix_time() - 120, manager.seal(b"late"));
+        let hmac = authentication_tag(&manager, seq, timestamp_secs, &ciphertext);
+        let message = AuthenticatedMessage { seq, timestamp_secs, ciphertext, hmac };
+        sender.send_kind(AUTHENTICATED_MESSAGE, &message.to_bytes()).unwrap();
+        let sent = sender.into_parts().1.into_inner();
+
+        let mut receiver = SecureChannel::new(manager.clone(), Cursor::new(sent.clone()));
+        assert!(receiver.recv_authenticated(Duration::from_secs(60)).is_err());
+        let mut receiver = SecureChannel::new(manager, Cursor::new(sent));
+        assert_eq!(receiver.recv_authenticated(Duration::from_secs(300)).unwrap(), b"late");
+    }
+
+    #[test]
+    fn stream_round_trip() {
+        let manager = AESManager::new(KeySize::K128);
+        let content: Vec<u8> = (0..10 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let mut sender = SecureChannel::new(manager.clone(), Cursor::new(Vec::new()));
+        sender.send_stream(content.as_slice(), content.len() as u64).unwrap();
+        sender.send(b"after the stream").unwrap();
+        let sent = sender.into_parts().1.into_inner();
+
+        let mut receiver = SecureChannel::new(manager, Cursor::new(sent));
+        let mut received = Vec::new();
+        let mut progress = Vec::new();
+        let length = receiver.recv_stream_with_progress(&mut received, |bytes| progress.push(bytes)).unwrap();
+        assert_eq!(length, content.len() as u64);
+        assert!(received == content);
+        assert_eq!(progress.len(), 160);
+        assert_eq!(progress.last(), Some(&length));
+        assert_eq!(receiver.recv().unwrap(), b"after the stream");
+    }
+
+    #[test]
+    fn stream_shorter_than_its_length() {
+        let mut sender = SecureChannel::new(AESManager::new(KeySize::K128), Cursor::new(Vec::new()));
+        let error = sender.send_stream(&b"short"[..], 10).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn keepalive() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let manager = AESManager::new(KeySize::K128);
+        let server_manager = manager.clone();
+
+        let server = std::thread::spawn(move || {
+            let mut channel = SecureChannel::new(server_manager, server_end);
+            let messages = vec![channel.recv().unwrap(), channel.recv().unwrap()];
+            (messages, channel.keepalive_stats())
+        });
+
+        let interval = Duration::from_millis(10);
+        let mut channel = SecureChannel::new(manager, client_end);
+        channel.enable_keepalive(interval).unwrap();
+        std::thread::sleep(interval * 10);
+        assert!(channel.keepalive_stats().sent > 0);
+        channel.send(b"after keepalives").unwrap();
+        channel.rekey().unwrap();
+        std::thread::sleep(interval * 5);
+        channel.send(b"after rekey").unwrap();
+
+        let (messages, stats) = server.join().unwrap();
+        assert_eq!(messages, vec![b"after keepalives".to_vec(), b"after rekey".to_vec()]);
+        assert!(stats.received > 0);
+        assert_eq!(stats.sent, 0);
+    }
+
+    #[test]
+    fn rekey_with_keepalives_on_both_ends() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let manager = AESManager::new(KeySize::K128);
+        let server_manager = manager.clone();
+        let interval = Duration::from_millis(1);
+
+        let server = std::thread::spawn(move || {
+            let mut channel = SecureChannel::new(server_manager, server_end);
+            channel.enable_keepalive(interval).unwrap();
+            loop {
+                let message = channel.recv().unwrap();
+                if message.is_empty() {
+                    break;
+                }
+                channel.send(&message).unwrap();
+            }
+            channel.keepalive_stats()
+        });
+
+        let mut channel = SecureChannel::new(manager, client_end);
+        channel.enable_keepalive(interval).unwrap();
+        for round in 0..20u8 {
+            channel.rekey().unwrap();
+            channel.send(&[round + 1]).unwrap();
+            assert_eq!(channel.recv().unwrap(), [round + 1]);
+            std::thread::sleep(interval * 2);
+        }
+        channel.send(b"").unwrap();
+
+        let stats = server.join().unwrap();
+        assert!(stats.sent > 0 && stats.received > 0);
+        assert!(channel.keepalive_stats().received > 0);
+    }
+
+    #[test]
+    fn automatic_rekey() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let manager = AESManager::new(KeySize::K256);
+        let server_manager = manager.clone();
+
+        let server = std::thread::spawn(move || {
+            let mut channel = SecureChannel::new(server_manager, &server_end);
+            loop {
+                let message = channel.recv().unwrap();
+                if message.is_empty() {
+                    break;
+                }
+                channel.send(&message).unwrap();
+            }
+            channel.into_parts().0
+        });
+
+        let mut channel = SecureChannel::new(manager.clone(), &client_end)
+            .auto_rekey_after(100);
+        let mut keys = vec![manager];
+        for round in 0..5u8 {
+            let message = [round; 60];
+            channel.send(&message).unwrap();
+            assert_eq!(channel.recv().unwrap(), message);
+            if channel.manager() != keys.last().unwrap() {
+                keys.push(channel.manager().clone());
+            }
+        }
+        // the second and fourth sends reach 100 bytes since the last rekey
+        assert_eq!(keys.len(), 3);
+        assert_eq!(channel.bytes_sent(), 300);
+        assert_eq!(keys[1], derived_key(&keys[0], 1));
+        assert_eq!(keys[2], derived_key(&keys[1], 2));
+
+        channel.send(b"").unwrap();
+        assert_eq!(&server.join().unwrap(), channel.manager());
+    }
+
+    #[test]
+    fn initiate_rekey() {
+        for cipher in [CipherChoice::Aes(KeySize::K128), CipherChoice::ChaCha20Poly1305] {
+            let (client_end, server_end) = ChannelDuplex::pair();
+            let manager = AESManager::new(cipher);
+            let server_manager = manager.clone();
+
+            let server = std::thread::spawn(move || {
+                let mut channel = SecureChannel::new(server_manager, &server_end);
+                for _ in 0..2 {
+                    let message = channel.recv().unwrap();
+                    channel.send(&message).unwrap();
+                }
+            });
+
+            let mut channel = SecureChannel::new(manager, &client_end);
+            channel.send(b"before").unwrap();
+            assert_eq!(channel.recv().unwrap(), b"before");
+            channel.initiate_rekey().unwrap();
+            assert_eq!(channel.manager().cipher_choice(), cipher);
+            channel.send(b"after").unwrap();
+            assert_eq!(channel.recv().unwrap(), b"after");
+            server.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn chacha20_poly1305_rekey() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let manager = AESManager::new(CipherChoice::ChaCha20Poly1305);
+        let server_manager = manager.clone();
+
+        let server = std::thread::spawn(move || {
+            let mut channel = SecureChannel::new(server_manager, &server_end);
+            let message = channel.recv().unwrap();
+            channel.send(&message).unwrap();
+        });
+
+        let mut channel = SecureChannel::new(manager, &client_end);
+        channel.rekey().unwrap();
+        assert_eq!(channel.manager().cipher_choice(), CipherChoice::ChaCha20Poly1305);
+        channel.send(b"sealed").unwrap();
+        assert_eq!(channel.recv().unwrap(), b"sealed");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn cipher_suites_round_trip() {
+        for (suite, cipher) in [
+            (CipherSuite::AesGcm, CipherChoice::Aes(KeySize::K256)),
+            (CipherSuite::AesCtr, CipherChoice::Aes(KeySize::K128)),
+            (CipherSuite::ChaCha20Poly1305, CipherChoice::ChaCha20Poly1305)
+        ] {
+            let (client_end, server_end) = ChannelDuplex::pair();
+            let manager = AESManager::new(cipher);
+            let server_manager = manager.clone();
+
+            let server = std::thread::spawn(move || {
+                let mut channel = SecureChannel::with_cipher_suite(suite, server_manager, &server_end).unwrap();
+                loop {
+                    let message = channel.recv().unwrap();
+                    if message.is_empty() {
+                        break;
+                    }
+                    channel.send(&message.to_ascii_uppercase()).unwrap();
+                }
+            });
+
+            let mut channel = SecureChannel::with_cipher_suite(suite, manager, &client_end).unwrap();
+            assert_eq!(channel.cipher_suite(), suite);
+            channel.send(b"before").unwrap();
+            assert_eq!(channel.recv().unwrap(), b"BEFORE");
+            channel.rekey().unwrap();
+            assert_eq!(channel.cipher_suite(), suite);
+            channel.send(b"after").unwrap();
+            assert_eq!(channel.recv().unwrap(), b"AFTER");
+            channel.send(b"").unwrap();
+            server.join().unwrap();
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn rekey_async() {
+        let (client_end, server_end) = tokio::io::duplex(64);
+        let manager = AESManager::new(KeySize::K256);
+        let mut client = SecureChannel::new(manager.clone(), client_end);
+        let mut server = SecureChannel::new(manager.clone(), server_end);
+
+        let client_side = async {
+            client.send_async(b"first").await.unwrap();
+            client.rekey_async().await.unwrap();
+            client.send_async(b"second").await.unwrap();
+        };
+        let server_side = async {
+            let first = server.recv_async().await.unwrap();
+            let second = server.recv_async().await.unwrap();
+            (first, second)
+        };
+        let ((), (first, second)) = tokio::join!(client_side, server_side);
+        assert_eq!(first, b"first");
+        assert_eq!(second, b"second");
+        assert_ne!(client.manager(), &manager);
+        assert_eq!(client.manager(), server.manager());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn automatic_rekey_async() {
+        let (client_end, server_end) = tokio::io::duplex(64);
+        let manager = AESManager::new(KeySize::K256);
+        let mut client = SecureChannel::new(manager.clone(), client_end).auto_rekey_after(100);
+        let mut server = SecureChannel::new(manager.clone(), server_end);
+
+        let client_side = async {
+            for round in 0..5u8 {
+                client.send_async(&[round; 60]).await.unwrap();
+            }
+        };
+        let server_side = async {
+            let mut received = Vec::new();
+            for _ in 0..5 {
+                received.push(server.recv_async().await.unwrap());
+            }
+            received
+        };
+        let ((), received) = tokio::join!(client_side, server_side);
+        assert_eq!(received, (0..5u8).map(|round| vec![round; 60]).collect::<Vec<_>>());
+        // the second and fourth sends reach 100 bytes since the last rekey
+        assert_eq!(client.manager(), &derived_key(&derived_key(&manager, 1), 2));
+        assert_eq!(client.manager(), server.manager());
+    }
+}
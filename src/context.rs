@@ -0,0 +1,86 @@
+//! State a server shares between the connections it accepts
+//!
+//! Generating an RSA key pair is the slowest part of the handshake, so a server that accepts many
+//! connections generates one when it starts and uses it for all of them.
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use crate::channel::SecureChannel;
+use crate::encryption::rsa::{PublicKey, RSAKeys};
+use crate::error::SecureComError;
+use crate::handshake::{channel, server_handshake_with_keys, HandshakeConfig, HandshakeResult, SharedStream};
+
+/// The configuration and RSA key pair used for every connection a server accepts
+///
+/// Cloning a context shares its key pair, so it can be handed to one thread per connection.
+#[derive(Debug, Clone)]
+pub struct SecureComContext {
+    server_keys: Arc<RSAKeys>,
+    config: HandshakeConfig
+}
+
+impl SecureComContext {
+    /// Creates a context with a new key pair of `config.rsa_key_bits` bits
+    pub fn new(config: HandshakeConfig) -> Self {
+        let server_keys = RSAKeys::generate(config.rsa_key_bits);
+        Self::with_keys(config, server_keys)
+    }
+
+    /// Creates a context that uses `server_keys`, such as those kept on disk by a
+    /// `CachedRSAKeysGenerator`
+    ///
+    /// The keys must have `config.rsa_key_bits` bits.
+    pub fn with_keys(config: HandshakeConfig, server_keys: RSAKeys) -> Self {
+        SecureComContext { server_keys: Arc::new(server_keys), config }
+    }
+
+    pub fn config(&self) -> &HandshakeConfig {
+        &self.config
+    }
+
+    /// The public key every client sees during the handshake, for pinning it
+    pub fn server_public_key(&self) -> PublicKey {
+        self.server_keys.public_key()
+    }
+
+    /// Runs the server's side of the handshake with the stored key pair
+    pub fn accept<W: Write, R: Read>(&self, writer: W, reader: R) -> Result<HandshakeResult, SecureComError> {
+        server_handshake_with_keys(writer, reader, &self.config, &self.server_keys, None).map(|(result, _)| result)
+    }
+
+    /// Runs [`accept`](Self::accept) over a single stream, returning a channel for the encrypted
+    /// messages that follow
+    pub fn accept_connection<S: Read + Write>(&self, stream: S) -> Result<SecureChannel<S>, SecureComError> {
+        let stream = RefCell::new(stream);
+        let (result, leftover) = server_handshake_with_keys(SharedStream(&stream), SharedStream(&stream), &self.config, &self.server_keys, None)?;
+        Ok(channel(result.aes_manager, stream.into_inner(), &self.config, leftover))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::client_handshake_with_config;
+    use crate::testing::ChannelDuplex;
+
+    #[test]
+    fn connections_share_the_server_keys() {
+        let config = HandshakeConfig::builder().rsa_key_bits(1024).build();
+        let context = SecureComContext::new(config.clone());
+
+        for message in [&b"first"[..], b"second"] {
+            let (client_end, server_end) = ChannelDuplex::pair();
+            let server_context = context.clone();
+            let server = std::thread::spawn(move || {
+                let mut channel = server_context.accept_connection(server_end).unwrap();
+                channel.recv().unwrap()
+            });
+
+            let result = client_handshake_with_config(&client_end, &client_end, &config).unwrap();
+            assert_eq!(result.remote_public_key.fingerprint(), context.server_public_key().fingerprint());
+            SecureChannel::new(result.aes_manager, &client_end).send(message).unwrap();
+            assert_eq!(server.join().unwrap(), message);
+        }
+    }
+}
@@ -1,22 +1,63 @@
+use std::convert::{TryFrom, TryInto};
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
+use std::hint::black_box;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use aes::{Aes128, Aes192, Aes256, BlockCipher, NewBlockCipher};
 use aes::cipher::stream::generic_array::GenericArray;
-use num::Num;
 use num_bigint::{BigUint, ParseBigIntError};
 use rand::random;
-use aes::cipher::generic_array::functional::FunctionalSequence;
+use aes::cipher::block::Block;
+use aes::cipher::consts::U16;
+use aes_gcm::aead::Payload;
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::aead::Aead;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use zeroize::Zeroize;
 
 #[path="./aes_stream.rs"]
 pub mod aes_stream;
 
+#[path="./aes_hmac_stream.rs"]
+pub mod aes_hmac_stream;
+
+/// The size of the big endian length that [`AESManager::encrypt`] puts before the message
+const LENGTH_HEADER_SIZE: usize = 4;
+
+/// The size of the random nonce that [`AESManager::seal`] puts before the ciphertext
+const SEAL_NONCE_SIZE: usize = 12;
+
+/// Put before the hex key by [`AESManager::parsable_string`] for a ChaCha20-Poly1305 key. AES keys
+/// have no prefix, so their strings are the same as before ChaCha20-Poly1305 was supported.
+const CHACHA20_POLY1305_PREFIX: &str = "chacha20poly1305:";
+
 #[derive(Debug, Clone)]
 pub enum Key {
     Aes128(Aes128),
     Aes192(Aes192),
-    Aes256(Aes256)
+    Aes256(Aes256),
+    ChaCha20Poly1305(ChaCha20Poly1305Cipher)
+}
+
+/// The ChaCha20-Poly1305 cipher, which does not implement `Debug` itself
+#[derive(Clone)]
+pub struct ChaCha20Poly1305Cipher(ChaCha20Poly1305);
+
+impl ChaCha20Poly1305Cipher {
+    fn new(key: &[u8]) -> Self {
+        ChaCha20Poly1305Cipher(<ChaCha20Poly1305 as chacha20poly1305::KeyInit>::new_from_slice(key).expect("a ChaCha20-Poly1305 key is 32 bytes"))
+    }
+}
+
+impl Debug for ChaCha20Poly1305Cipher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ChaCha20Poly1305Cipher")
+    }
 }
 
 impl Key {
@@ -45,16 +86,119 @@ impl Key {
         }
     }
 
+    pub fn chacha20poly1305(&self) -> Option<&ChaCha20Poly1305Cipher> {
+        if let Key::ChaCha20Poly1305(ret) = self {
+            Some(ret)
+        } else {
+            None
+        }
+    }
+
+    /// The AES cipher, or [`UnsupportedCipher`] for ChaCha20-Poly1305, which has no blocks
+    fn block_key(&self) -> Result<BlockKey<'_>, UnsupportedCipher> {
+        match self {
+            Key::Aes128(k) => Ok(BlockKey::Aes128(k)),
+            Key::Aes192(k) => Ok(BlockKey::Aes192(k)),
+            Key::Aes256(k) => Ok(BlockKey::Aes256(k)),
+            Key::ChaCha20Poly1305(_) => Err(UnsupportedCipher)
+        }
+    }
+
+    /// The size of the key, which is always 256 bits for ChaCha20-Poly1305
     pub fn cipher_size(&self) -> KeySize {
         match self {
             Key::Aes128(_) => { KeySize::K128 }
             Key::Aes192(_) => { KeySize::K192 }
-            Key::Aes256(_) => { KeySize::K256 }
+            Key::Aes256(_) | Key::ChaCha20Poly1305(_) => { KeySize::K256 }
         }
     }
+
+    /// The encryption of an all zero block, which differs between keys
+    ///
+    /// ChaCha20-Poly1305 has no blocks, so the tag of an empty message with an all zero nonce is
+    /// used instead.
+    fn zero_block_encryption(&self) -> Block<Aes128> {
+        let mut block = GenericArray::default();
+        match self {
+            Key::Aes128(k) => k.encrypt_block(&mut block),
+            Key::Aes192(k) => k.encrypt_block(&mut block),
+            Key::Aes256(k) => k.encrypt_block(&mut block),
+            Key::ChaCha20Poly1305(k) => {
+                let tag = k.0.encrypt(&Default::default(), &[][..]).expect("an empty message can be encrypted");
+                block.copy_from_slice(&tag);
+            }
+        }
+        block
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
+/// One of the AES ciphers of a [`Key`], so that block operations can't be given a
+/// ChaCha20-Poly1305 key
+#[derive(Clone, Copy)]
+enum BlockKey<'a> {
+    Aes128(&'a Aes128),
+    Aes192(&'a Aes192),
+    Aes256(&'a Aes256)
+}
+
+impl BlockKey<'_> {
+    fn encrypt_block(self, block: &mut Block<Aes128>) {
+        match self {
+            BlockKey::Aes128(k) => k.encrypt_block(block),
+            BlockKey::Aes192(k) => k.encrypt_block(block),
+            BlockKey::Aes256(k) => k.encrypt_block(block)
+        }
+    }
+
+    fn decrypt_block(self, block: &mut Block<Aes128>) {
+        match self {
+            BlockKey::Aes128(k) => k.decrypt_block(block),
+            BlockKey::Aes192(k) => k.decrypt_block(block),
+            BlockKey::Aes256(k) => k.decrypt_block(block)
+        }
+    }
+
+    /// Encrypts the message one block at a time, padding the last block with zeros
+    fn encrypt_blocks(self, message: &[u8]) -> Vec<[u8; 16]> {
+        message.chunks(16)
+            .map(|chunk| {
+                let mut block = GenericArray::default();
+                block[..chunk.len()].copy_from_slice(chunk);
+                self.encrypt_block(&mut block);
+                block.into()
+            })
+            .collect()
+    }
+}
+
+/// Replaces the cipher's round keys with those of an all zero key of the same size
+impl Zeroize for Key {
+    fn zeroize(&mut self) {
+        let zeroed = match self {
+            Key::Aes128(_) => Key::Aes128(Aes128::new(&GenericArray::default())),
+            Key::Aes192(_) => Key::Aes192(Aes192::new(&GenericArray::default())),
+            Key::Aes256(_) => Key::Aes256(Aes256::new(&GenericArray::default())),
+            Key::ChaCha20Poly1305(_) => Key::ChaCha20Poly1305(ChaCha20Poly1305Cipher::new(&[0; 32]))
+        };
+        // the ciphers own no memory elsewhere, and reading the key back keeps the store from being
+        // removed as a dead store when the key is dropped straight after
+        *self = zeroed;
+        black_box(&*self);
+    }
+}
+
+/// The ciphers don't implement `PartialEq`, so keys of the same size are compared by how they
+/// encrypt a block
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+            && self.zero_block_encryption() == other.zero_block_encryption()
+    }
+}
+
+impl Eq for Key { }
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(u16)]
 pub enum KeySize {
     K128 = 128,
@@ -62,7 +206,66 @@ pub enum KeySize {
     K256 = 256
 }
 
-#[derive(Debug)]
+impl KeySize {
+    /// The key size with this many bits, if AES supports it
+    pub fn from_bits(bits: u16) -> Option<KeySize> {
+        match bits {
+            128 => Some(KeySize::K128),
+            192 => Some(KeySize::K192),
+            256 => Some(KeySize::K256),
+            _ => None
+        }
+    }
+
+    pub fn bits(self) -> u16 {
+        self as u16
+    }
+}
+
+impl TryFrom<u16> for KeySize {
+    type Error = UnsupportedKeySize;
+
+    fn try_from(bits: u16) -> Result<Self, Self::Error> {
+        KeySize::from_bits(bits).ok_or(UnsupportedKeySize { bits })
+    }
+}
+
+/// AES keys are 128, 192 or 256 bits
+#[derive(Debug, PartialEq)]
+pub struct UnsupportedKeySize {
+    pub bits: u16
+}
+
+impl Display for UnsupportedKeySize {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for UnsupportedKeySize { }
+
+/// The cipher used by a new [`AESManager`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CipherChoice {
+    Aes(KeySize),
+    /// ChaCha20-Poly1305 with a 256 bit key
+    ///
+    /// It is a stream cipher, so a manager using it encrypts with [`seal`](AESManager::seal) and
+    /// [`open`](AESManager::open). [`AESStream`](aes_stream::AESStream), and the channel built on
+    /// it, seal every frame. The block based methods, and the block streams which use them, fail
+    /// with [`UnsupportedCipher`].
+    ChaCha20Poly1305
+}
+
+impl From<KeySize> for CipherChoice {
+    fn from(key_size: KeySize) -> Self {
+        CipherChoice::Aes(key_size)
+    }
+}
+
+/// A cloned manager holds its own copy of the key, which is zeroized when it is dropped like the
+/// original's
+#[derive(Debug, Clone)]
 pub struct AESManager {
     key_value: Vec<u8>,
     key: Key
@@ -70,84 +273,630 @@ pub struct AESManager {
 
 impl PartialEq for AESManager {
     fn eq(&self, other: &Self) -> bool {
-        self.key_value == other.key_value
+        self.key_value == other.key_value && std::mem::discriminant(&self.key) == std::mem::discriminant(&other.key)
+    }
+}
+
+impl Eq for AESManager { }
+
+impl Zeroize for AESManager {
+    fn zeroize(&mut self) {
+        self.key_value.zeroize();
+        self.key.zeroize();
+    }
+}
+
+impl Drop for AESManager {
+    fn drop(&mut self) {
+        self.zeroize();
     }
 }
 
 impl AESManager {
 
-    pub fn new(key_size: KeySize) -> Self {
-        let (key, bytes) = generate_key(key_size);
+    /// Creates a manager with a random key. A [`KeySize`] can be passed to get an AES key.
+    pub fn new<C : Into<CipherChoice>>(cipher: C) -> Self {
+        let (key, bytes) = match cipher.into() {
+            CipherChoice::Aes(key_size) => generate_key(key_size),
+            CipherChoice::ChaCha20Poly1305 => {
+                let bytes = random_bytes(32);
+                (Key::ChaCha20Poly1305(ChaCha20Poly1305Cipher::new(&bytes)), bytes)
+            }
+        };
         Self {
             key_value: bytes,
             key
         }
     }
 
-    pub fn parsable_string(&self) -> String {
-        let big_uint =  BigUint::from_bytes_be(&* self.key_value);
-        format!("{:x}", big_uint)
-    }
-
-    pub fn encrypt<S : AsRef<[u8]>>(&self, message: S) -> Vec<[u8; 16]> {
-        let string = message.as_ref();
-        let bytes: Vec<u8> = string.to_vec();
-        let mut vector = vec![];
-        let total = bytes.len() / 16 + if bytes.len() % 16 > 0 { 1 } else { 0 };
-        for i in 0..total {
-            let mut array = [0u8; 16];
-            for j in 0..16 {
-                if let Some(byte) = bytes.get(i * 16 + j) {
-                    array[j] = *byte;
-                }
+    /// Creates a manager from key material made elsewhere, such as by a key derivation function
+    ///
+    /// `bytes` must be exactly as long as `size`, otherwise
+    /// [`WrongKeyLength`](AESManagerParseError::WrongKeyLength) is returned.
+    pub fn from_key_bytes(size: KeySize, bytes: &[u8]) -> Result<Self, AESManagerParseError> {
+        if bytes.len() * 8 != size.bits() as usize {
+            return Err(AESManagerParseError::WrongKeyLength { got: bytes.len() });
+        }
+        Self::from_key_value(bytes.to_vec())
+    }
+
+    /// Creates a manager from raw key bytes, which must be 16, 24, or 32 bytes long
+    pub(crate) fn from_key_value(bytes: Vec<u8>) -> Result<Self, AESManagerParseError> {
+        let key = match bytes.len() * 8 {
+            128 => {
+                Key::Aes128(Aes128::new_varkey(&bytes).unwrap())
+            },
+            192 => {
+                Key::Aes192(Aes192::new_varkey(&bytes).unwrap())
+            },
+            256 => {
+                Key::Aes256(Aes256::new_varkey(&bytes).unwrap())
+            },
+            _ => {
+                return Err(AESManagerParseError::WrongKeyLength { got: bytes.len() })
             }
+        };
+        Ok(Self {
+            key_value: bytes,
+            key
+        })
+    }
 
-            match &self.key {
-                Key::Aes128(k) => {
-                    let mut block = GenericArray::clone_from_slice(&array);
-                    k.encrypt_block(&mut block);
-                    array.clone_from_slice(block.as_slice());
-                }
-                Key::Aes192(k) => {
-                    let mut block = GenericArray::clone_from_slice(&array);
-                    k.encrypt_block(&mut block);
-                    array.clone_from_slice(block.as_slice());
-                }
-                Key::Aes256(k) => {
-                    let mut block = GenericArray::clone_from_slice(&array);
-                    k.encrypt_block(&mut block);
-                    array.clone_from_slice(block.as_slice());
-                }
+    /// Creates a manager for `cipher` from raw key bytes, which must be the right length for it
+    pub(crate) fn from_cipher_bytes(cipher: CipherChoice, key_value: Vec<u8>) -> Result<Self, AESManagerParseError> {
+        match cipher {
+            CipherChoice::ChaCha20Poly1305 if key_value.len() == 32 => {
+                let key = Key::ChaCha20Poly1305(ChaCha20Poly1305Cipher::new(&key_value));
+                Ok(AESManager { key_value, key })
             }
-            vector.push(array);
+            CipherChoice::Aes(size) if key_value.len() * 8 == size.bits() as usize => Self::from_key_value(key_value),
+            _ => Err(AESManagerParseError::WrongKeyLength { got: key_value.len() })
         }
-        vector
     }
 
-    pub fn decrypt<V : AsRef<[[u8; 16]]>>(&self, blocks: V) -> Vec<u8> {
+    /// The raw key, for deriving other keys from it without going through
+    /// [`parsable_string`](Self::parsable_string)
+    pub fn key_bytes(&self) -> &[u8] {
+        &self.key_value
+    }
+
+    pub fn key_size(&self) -> KeySize {
+        self.key.cipher_size()
+    }
+
+    pub fn cipher_choice(&self) -> CipherChoice {
+        match self.key {
+            Key::ChaCha20Poly1305(_) => CipherChoice::ChaCha20Poly1305,
+            _ => CipherChoice::Aes(self.key_size())
+        }
+    }
+
+    /// The key as a hex string, zero padded to two characters per key byte
+    ///
+    /// A ChaCha20-Poly1305 key starts with `chacha20poly1305:`, so that parsing it gives the same
+    /// cipher back.
+    pub fn parsable_string(&self) -> String {
+        let big_uint =  BigUint::from_bytes_be(&self.key_value);
+        let prefix = match self.key {
+            Key::ChaCha20Poly1305(_) => CHACHA20_POLY1305_PREFIX,
+            _ => ""
+        };
+        format!("{}{:0>width$x}", prefix, big_uint, width = self.key_value.len() * 2)
+    }
+
+    /// Encrypts and authenticates the message with AES-GCM, or with ChaCha20-Poly1305 for a
+    /// ChaCha20-Poly1305 key
+    ///
+    /// The result is a random 12 byte nonce, the ciphertext and a 16 byte tag. Unlike the other
+    /// methods this works with every [`CipherChoice`].
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; SEAL_NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let sealed = match &self.key {
+            Key::ChaCha20Poly1305(k) => {
+                k.0.encrypt(GenericArray::from_slice(&nonce), plaintext).expect("ChaCha20-Poly1305 can encrypt the message")
+            }
+            _ => aes_stream::GcmCipher::new(self).encrypt(&nonce, Payload { msg: plaintext, aad: &[] })
+        };
+        let mut output = Vec::with_capacity(SEAL_NONCE_SIZE + sealed.len());
+        output.extend_from_slice(&nonce);
+        output.extend_from_slice(&sealed);
+        output
+    }
+
+    /// Decrypts a message from [`seal`](Self::seal), failing if it was changed or sealed with
+    /// another key
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, AuthenticationError> {
+        if sealed.len() < SEAL_NONCE_SIZE {
+            return Err(AuthenticationError);
+        }
+        let (nonce, ciphertext) = sealed.split_at(SEAL_NONCE_SIZE);
+        match &self.key {
+            Key::ChaCha20Poly1305(k) => k.0.decrypt(GenericArray::from_slice(nonce), ciphertext).ok(),
+            _ => aes_stream::GcmCipher::new(self).decrypt(nonce, Payload { msg: ciphertext, aad: &[] })
+        }.ok_or(AuthenticationError)
+    }
+
+    /// Encrypts the message's length as a big endian `u32` followed by the message, so that
+    /// [`decrypt`](Self::decrypt) returns exactly the original bytes
+    ///
+    /// # Panics
+    /// If the message is longer than `u32::MAX` bytes
+    pub fn encrypt(&self, message: &[u8]) -> Result<Vec<u8>, UnsupportedCipher> {
+        let key = self.key.block_key()?;
+        let length = u32::try_from(message.len()).expect("a message is limited to u32::MAX bytes");
+        let mut plaintext = Vec::with_capacity(LENGTH_HEADER_SIZE + message.len());
+        plaintext.extend_from_slice(&length.to_be_bytes());
+        plaintext.extend_from_slice(message);
+        let encrypted = key.encrypt_blocks(&plaintext).concat();
+        plaintext.zeroize();
+        Ok(encrypted)
+    }
+
+    /// Decrypts a message from [`encrypt`](Self::encrypt), without the padding of its last block
+    pub fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, CiphertextError> {
+        if ciphertext.is_empty() || !ciphertext.len().is_multiple_of(16) {
+            return Err(CiphertextError::InvalidLength);
+        }
+        let blocks: Vec<[u8; 16]> = ciphertext.chunks_exact(16)
+            .map(|chunk| chunk.try_into().expect("chunks are 16 bytes"))
+            .collect();
+        let mut plaintext = self.decrypt_blocks(&blocks)?;
+        let length = u32::from_be_bytes([plaintext[0], plaintext[1], plaintext[2], plaintext[3]]) as usize;
+        let end = LENGTH_HEADER_SIZE + length;
+        // the padding is never a whole block, so a valid length always ends in the last block
+        if end > plaintext.len() || plaintext.len() - end >= 16 {
+            plaintext.zeroize();
+            return Err(CiphertextError::InvalidLength);
+        }
+        plaintext.truncate(end);
+        Ok(plaintext.split_off(LENGTH_HEADER_SIZE))
+    }
+
+    /// Encrypts the message with PKCS#7 padding, which adds `n` bytes of value `n` to reach a
+    /// whole number of blocks, and a whole block of 16s if the message already ends on one
+    pub fn encrypt_pkcs7(&self, plaintext: &[u8]) -> Result<Vec<u8>, UnsupportedCipher> {
+        let key = self.key.block_key()?;
+        let mut padded = pkcs7_pad(plaintext);
+        let encrypted = key.encrypt_blocks(&padded).concat();
+        padded.zeroize();
+        Ok(encrypted)
+    }
+
+    /// Decrypts a message from [`encrypt_pkcs7`](Self::encrypt_pkcs7) and removes its padding
+    pub fn decrypt_pkcs7(&self, ciphertext: &[u8]) -> Result<Vec<u8>, PaddingError> {
+        if ciphertext.is_empty() || !ciphertext.len().is_multiple_of(16) {
+            return Err(PaddingError::InvalidLength);
+        }
+        let blocks: Vec<[u8; 16]> = ciphertext.chunks_exact(16)
+            .map(|chunk| chunk.try_into().expect("chunks are 16 bytes"))
+            .collect();
+        pkcs7_unpad(self.decrypt_blocks(&blocks)?)
+    }
+
+    /// Encrypts the message with PKCS#7 padding in CBC mode, where each block is XORed with the
+    /// previous ciphertext block before it is encrypted, and the first with `iv`
+    ///
+    /// Unlike the blocks of [`encrypt_pkcs7`](Self::encrypt_pkcs7), equal plaintext blocks give
+    /// different ciphertext blocks. The IV should be random and never reused with the same key. It
+    /// is not secret, so it can be sent with the ciphertext.
+    ///
+    /// CBC on its own only hides the message, it does not protect it. Anyone who can change the
+    /// ciphertext can flip chosen bits of the plaintext, and anyone who can see whether
+    /// [`decrypt_cbc`](Self::decrypt_cbc) failed can use it as a padding oracle to decrypt the
+    /// message. Add a MAC over the IV and ciphertext after encrypting (encrypt-then-MAC, like the
+    /// HMAC-SHA256 tag of [`encrypt_authenticated`](Self::encrypt_authenticated)) and check it
+    /// before decrypting, or use [`seal`](Self::seal) instead.
+    pub fn encrypt_cbc(&self, iv: &[u8; 16], plaintext: &[u8]) -> Result<Vec<u8>, UnsupportedCipher> {
+        let key = self.key.block_key()?;
+        let mut padded = pkcs7_pad(plaintext);
+        let mut output = Vec::with_capacity(padded.len());
+        let mut previous = *iv;
+        for block in padded.chunks_exact_mut(16) {
+            block.iter_mut().zip(&previous).for_each(|(byte, chained)| *byte ^= chained);
+            previous = key.encrypt_blocks(block)[0];
+            output.extend_from_slice(&previous);
+        }
+        padded.zeroize();
+        Ok(output)
+    }
+
+    /// Decrypts a message from [`encrypt_cbc`](Self::encrypt_cbc) with the same IV
+    ///
+    /// Only call this once a MAC over the IV and ciphertext has been checked, see
+    /// [`encrypt_cbc`](Self::encrypt_cbc). The padding is checked in constant time, but whether
+    /// it was valid is still in the result.
+    pub fn decrypt_cbc(&self, iv: &[u8; 16], ciphertext: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        if ciphertext.is_empty() || !ciphertext.len().is_multiple_of(16) {
+            return Err(PaddingError::InvalidLength.into());
+        }
+        let blocks: Vec<[u8; 16]> = ciphertext.chunks_exact(16)
+            .map(|chunk| chunk.try_into().expect("chunks are 16 bytes"))
+            .collect();
+        let mut plaintext = self.decrypt_blocks(&blocks)?;
+        let chained = std::iter::once(iv).chain(&blocks);
+        for (block, previous) in plaintext.chunks_exact_mut(16).zip(chained) {
+            block.iter_mut().zip(previous).for_each(|(byte, chained)| *byte ^= chained);
+        }
+        Ok(pkcs7_unpad(plaintext)?)
+    }
+
+    /// Encrypts the message in CTR mode, XORing it with the encryptions of `counter`, `counter + 1`
+    /// and so on, as a big endian 128 bit number
+    ///
+    /// The ciphertext is as long as the message, with no padding. A counter must never be used
+    /// twice with the same key, so start each message at a random one and send it along. Like
+    /// [`encrypt_cbc`](Self::encrypt_cbc) this does not protect the message from changes.
+    pub fn encrypt_ctr(&self, counter: &[u8; 16], plaintext: &[u8]) -> Result<Vec<u8>, UnsupportedCipher> {
+        let key = self.key.block_key()?;
+        let start = u128::from_be_bytes(*counter);
+        let counters: Vec<u8> = (0..plaintext.len().div_ceil(16))
+            .flat_map(|i| start.wrapping_add(i as u128).to_be_bytes())
+            .collect();
+        let mut key_stream = key.encrypt_blocks(&counters).concat();
+        let output = plaintext.iter().zip(&key_stream).map(|(byte, stream)| byte ^ stream).collect();
+        key_stream.zeroize();
+        Ok(output)
+    }
+
+    /// Decrypts a message from [`encrypt_ctr`](Self::encrypt_ctr) with the same counter
+    pub fn decrypt_ctr(&self, counter: &[u8; 16], ciphertext: &[u8]) -> Result<Vec<u8>, UnsupportedCipher> {
+        self.encrypt_ctr(counter, ciphertext)
+    }
+
+    /// Encrypts the string with [`encrypt_pkcs7`](Self::encrypt_pkcs7)
+    pub fn encrypt_message(&self, message: &str) -> Result<Vec<u8>, UnsupportedCipher> {
+        self.encrypt_pkcs7(message.as_bytes())
+    }
+
+    /// Decrypts a string from [`encrypt_message`](Self::encrypt_message)
+    pub fn decrypt_message(&self, ciphertext: &[u8]) -> Result<String, DecryptionError> {
+        let plaintext = self.decrypt_pkcs7(ciphertext)?;
+        String::from_utf8(plaintext).map_err(|e| {
+            e.into_bytes().zeroize();
+            DecryptionError::InvalidUtf8
+        })
+    }
+
+    /// Fails if the key is for ChaCha20-Poly1305, for types that check once when they are created
+    /// rather than on every block
+    pub(crate) fn check_block_cipher(&self) -> Result<(), UnsupportedCipher> {
+        self.key.block_key().map(|_| ())
+    }
+
+    /// Encrypts the message one block at a time, padding the last block with zeros
+    ///
+    /// This and every method built on blocks fail with [`UnsupportedCipher`] if the key is for
+    /// ChaCha20-Poly1305.
+    pub fn encrypt_blocks<S : AsRef<[u8]>>(&self, message: S) -> Result<Vec<[u8; 16]>, UnsupportedCipher> {
+        Ok(self.key.block_key()?.encrypt_blocks(message.as_ref()))
+    }
+
+    /// Encrypts the message like [`encrypt_blocks`](Self::encrypt_blocks), spreading the work across threads
+    ///
+    /// The message is split into chunks of 1024 blocks which are encrypted independently. This is
+    /// only equivalent to `encrypt_blocks` because every block is encrypted on its own (ECB); a chaining
+    /// mode could not be split this way.
+    #[cfg(feature = "rayon")]
+    pub fn encrypt_parallel(&self, message: &[u8]) -> Result<Vec<[u8; 16]>, UnsupportedCipher> {
+        use rayon::prelude::*;
+
+        const CHUNK_BYTES: usize = 1024 * 16;
+        let key = self.key.block_key()?;
+        Ok(message.par_chunks(CHUNK_BYTES)
+            .flat_map_iter(|chunk| key.encrypt_blocks(chunk))
+            .collect())
+    }
+
+    pub fn decrypt_blocks<V : AsRef<[[u8; 16]]>>(&self, blocks: V) -> Result<Vec<u8>, UnsupportedCipher> {
+        let key = self.key.block_key()?;
         let blocks = blocks.as_ref();
-        let mut output = vec![];
+        let mut output = Vec::with_capacity(blocks.len() * 16);
         for block in blocks {
             let mut block = GenericArray::clone_from_slice(block);
-            match &self.key {
-                Key::Aes128(k) => {
-                    k.decrypt_block(&mut block);
-                }
-                Key::Aes192(k) => {
-                    k.decrypt_block(&mut block);
-                }
-                Key::Aes256(k) => {
-                    k.decrypt_block(&mut block);
-                }
-            }
-            block.map(|b| output.push(b));
+            key.decrypt_block(&mut block);
+            output.extend_from_slice(&block);
         }
-        output
+        Ok(output)
+    }
+
+    /// Encrypts the message like [`encrypt_blocks`](Self::encrypt_blocks), also returning an HMAC-SHA256 tag over
+    /// the ciphertext blocks, keyed with the AES key
+    pub fn encrypt_authenticated(&self, plaintext: &[u8]) -> Result<(Vec<[u8; 16]>, [u8; 32]), UnsupportedCipher> {
+        let blocks = self.encrypt_blocks(plaintext)?;
+        let tag = self.ciphertext_mac(&blocks).finalize().into_bytes().into();
+        Ok((blocks, tag))
+    }
+
+    /// Checks the tag from [`encrypt_authenticated`](Self::encrypt_authenticated), only
+    /// decrypting the blocks if it matches
+    ///
+    /// A ChaCha20-Poly1305 key can't have made the blocks, so it always fails.
+    pub fn decrypt_authenticated(&self, blocks: &[[u8; 16]], tag: &[u8; 32]) -> Result<Vec<u8>, AuthenticationError> {
+        self.ciphertext_mac(blocks)
+            .verify_slice(tag)
+            .map_err(|_| AuthenticationError)?;
+        self.decrypt_blocks(blocks).map_err(|_| AuthenticationError)
+    }
+
+    fn ciphertext_mac(&self, blocks: &[[u8; 16]]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key_value).expect("HMAC accepts keys of any length");
+        for block in blocks {
+            mac.update(block);
+        }
+        mac
+    }
+}
+
+/// Encrypts and decrypts whole blocks, so the AES streams can be used with wrappers around
+/// [`AESManager`] as well as the manager itself
+pub trait AESBlockCipher {
+    fn encrypt_blocks(&self, message: &[u8]) -> Result<Vec<[u8; 16]>, UnsupportedCipher>;
+    fn decrypt_blocks(&self, blocks: &[[u8; 16]]) -> Result<Vec<u8>, UnsupportedCipher>;
+
+    /// Called by the streams with the number of message bytes they encrypted, leaving out their
+    /// own length headers and padding
+    fn record_encrypted(&self, _bytes: usize) { }
+
+    /// Called by the streams with the number of message bytes they decrypted, leaving out their
+    /// own length headers and padding
+    fn record_decrypted(&self, _bytes: usize) { }
+}
+
+impl AESBlockCipher for AESManager {
+    fn encrypt_blocks(&self, message: &[u8]) -> Result<Vec<[u8; 16]>, UnsupportedCipher> {
+        self.encrypt_blocks(message)
+    }
+
+    fn decrypt_blocks(&self, blocks: &[[u8; 16]]) -> Result<Vec<u8>, UnsupportedCipher> {
+        self.decrypt_blocks(blocks)
     }
 }
 
+/// An [`AESManager`] that counts the plaintext bytes passing through it, for example to enforce a
+/// quota
+///
+/// [`encrypt`](Self::encrypt) and [`decrypt`](Self::decrypt) count the message, and the
+/// [`AESReader`](aes_stream::AESReader) and [`AESWriter`](aes_stream::AESWriter) count the bytes
+/// read and written through [`AESBlockCipher::record_encrypted`] and
+/// [`AESBlockCipher::record_decrypted`]. Blocks encrypted or decrypted on their own are not
+/// counted, since the manager can't tell the zero padding of the last block apart from the message.
+///
+/// A [`SecureChannel`](crate::channel::SecureChannel) does not use one, as its stream holds a plain
+/// [`AESManager`]. [`SecureChannel::with_metrics`](crate::channel::SecureChannel::with_metrics)
+/// counts the same plaintext bytes itself.
 #[derive(Debug)]
-pub struct AESManagerParseError;
+pub struct MeteredAesManager {
+    inner: AESManager,
+    bytes_encrypted: AtomicU64,
+    bytes_decrypted: AtomicU64
+}
+
+impl MeteredAesManager {
+    pub fn new(inner: AESManager) -> Self {
+        MeteredAesManager { inner, bytes_encrypted: AtomicU64::new(0), bytes_decrypted: AtomicU64::new(0) }
+    }
+
+    /// [`AESManager::encrypt`], counting the bytes of `message`
+    pub fn encrypt(&self, message: &[u8]) -> Result<Vec<u8>, UnsupportedCipher> {
+        let encrypted = self.inner.encrypt(message)?;
+        self.record_encrypted(message.len());
+        Ok(encrypted)
+    }
+
+    /// [`AESManager::decrypt`], counting the bytes of the decrypted message
+    pub fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, CiphertextError> {
+        let message = self.inner.decrypt(ciphertext)?;
+        self.record_decrypted(message.len());
+        Ok(message)
+    }
+
+    /// [`AESManager::encrypt_blocks`], without counting. See [`MeteredAesManager`].
+    pub fn encrypt_blocks<S : AsRef<[u8]>>(&self, message: S) -> Result<Vec<[u8; 16]>, UnsupportedCipher> {
+        AESBlockCipher::encrypt_blocks(self, message.as_ref())
+    }
+
+    /// [`AESManager::decrypt_blocks`], without counting. See [`MeteredAesManager`].
+    pub fn decrypt_blocks<V : AsRef<[[u8; 16]]>>(&self, blocks: V) -> Result<Vec<u8>, UnsupportedCipher> {
+        AESBlockCipher::decrypt_blocks(self, blocks.as_ref())
+    }
+
+    /// The number of bytes encrypted and decrypted so far
+    pub fn stats(&self) -> (u64, u64) {
+        (self.bytes_encrypted.load(Ordering::Relaxed), self.bytes_decrypted.load(Ordering::Relaxed))
+    }
+
+    pub fn inner(&self) -> &AESManager {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> AESManager {
+        self.inner
+    }
+}
+
+impl From<AESManager> for MeteredAesManager {
+    fn from(inner: AESManager) -> Self {
+        MeteredAesManager::new(inner)
+    }
+}
+
+/// The streams report the bytes of their messages themselves, so the blocks are not counted here
+impl AESBlockCipher for MeteredAesManager {
+    fn encrypt_blocks(&self, message: &[u8]) -> Result<Vec<[u8; 16]>, UnsupportedCipher> {
+        self.inner.encrypt_blocks(message)
+    }
+
+    fn decrypt_blocks(&self, blocks: &[[u8; 16]]) -> Result<Vec<u8>, UnsupportedCipher> {
+        self.inner.decrypt_blocks(blocks)
+    }
+
+    fn record_encrypted(&self, bytes: usize) {
+        self.bytes_encrypted.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn record_decrypted(&self, bytes: usize) {
+        self.bytes_decrypted.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// The key is for ChaCha20-Poly1305, which has no blocks, so it can only be used with
+/// [`AESManager::seal`] and [`AESManager::open`]
+#[derive(Debug, PartialEq)]
+pub struct UnsupportedCipher;
+
+impl Display for UnsupportedCipher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for UnsupportedCipher { }
+
+impl From<UnsupportedCipher> for std::io::Error {
+    fn from(e: UnsupportedCipher) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CiphertextError {
+    /// The ciphertext given to [`AESManager::decrypt`] is not whole blocks, or its length header
+    /// does not fit the blocks
+    InvalidLength,
+    /// The key is for ChaCha20-Poly1305, see [`UnsupportedCipher`]
+    UnsupportedCipher
+}
+
+impl Display for CiphertextError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for CiphertextError { }
+
+impl From<UnsupportedCipher> for CiphertextError {
+    fn from(_: UnsupportedCipher) -> Self {
+        CiphertextError::UnsupportedCipher
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PaddingError {
+    /// The ciphertext given to [`AESManager::decrypt_pkcs7`] is not whole blocks
+    InvalidLength,
+    /// The last byte is not a padding length from 1 to 16, or the bytes before it don't repeat it
+    InvalidPadding,
+    /// The key is for ChaCha20-Poly1305, see [`UnsupportedCipher`]
+    UnsupportedCipher
+}
+
+impl Display for PaddingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for PaddingError { }
+
+impl From<UnsupportedCipher> for PaddingError {
+    fn from(_: UnsupportedCipher) -> Self {
+        PaddingError::UnsupportedCipher
+    }
+}
+
+/// Adds `n` bytes of value `n` to reach a whole number of blocks
+fn pkcs7_pad(plaintext: &[u8]) -> Vec<u8> {
+    let padding = 16 - plaintext.len() % 16;
+    let mut padded = Vec::with_capacity(plaintext.len() + padding);
+    padded.extend_from_slice(plaintext);
+    padded.resize(plaintext.len() + padding, padding as u8);
+    padded
+}
+
+/// Removes the padding from whole blocks of `plaintext`
+///
+/// The last 16 bytes are all read whatever the padding length, and the checks are kept in a mask
+/// instead of branching on the plaintext, so the time taken doesn't say which byte was wrong.
+fn pkcs7_unpad(mut plaintext: Vec<u8>) -> Result<Vec<u8>, PaddingError> {
+    let last_block = &plaintext[plaintext.len() - 16..];
+    let padding = last_block[15];
+    // the padding length must be from 1 to 16
+    let mut invalid = !ct_lt(padding.wrapping_sub(1), 16);
+    for (distance, &byte) in last_block.iter().rev().enumerate() {
+        let is_padding = ct_lt(distance as u8, padding);
+        invalid |= is_padding & (byte ^ padding);
+    }
+    if black_box(invalid) != 0 {
+        plaintext.zeroize();
+        return Err(PaddingError::InvalidPadding);
+    }
+    let start = plaintext.len() - padding as usize;
+    plaintext.truncate(start);
+    Ok(plaintext)
+}
+
+/// `0xFF` if `a < b`, `0x00` otherwise
+fn ct_lt(a: u8, b: u8) -> u8 {
+    // a - b only borrows into the high byte when a is smaller
+    ((black_box(a) as u16).wrapping_sub(b as u16) >> 8) as u8
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecryptionError {
+    /// The ciphertext given to [`AESManager::decrypt_message`] or [`AESManager::decrypt_cbc`] is
+    /// not correctly padded
+    Padding(PaddingError),
+    /// The decrypted bytes are not UTF-8, so the message was likely encrypted with another key
+    InvalidUtf8,
+    /// The key is for ChaCha20-Poly1305, see [`UnsupportedCipher`]
+    UnsupportedCipher
+}
+
+impl Display for DecryptionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for DecryptionError { }
+
+impl From<PaddingError> for DecryptionError {
+    fn from(e: PaddingError) -> Self {
+        match e {
+            PaddingError::UnsupportedCipher => DecryptionError::UnsupportedCipher,
+            e => DecryptionError::Padding(e)
+        }
+    }
+}
+
+impl From<UnsupportedCipher> for DecryptionError {
+    fn from(_: UnsupportedCipher) -> Self {
+        DecryptionError::UnsupportedCipher
+    }
+}
+
+/// The tag given to [`AESManager::decrypt_authenticated`] does not match the ciphertext, or the
+/// message given to [`AESManager::open`] fails authentication
+#[derive(Debug, PartialEq)]
+pub struct AuthenticationError;
+
+impl Display for AuthenticationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for AuthenticationError { }
+
+#[derive(Debug, PartialEq)]
+pub enum AESManagerParseError {
+    /// The string is not made of pairs of hex digits
+    InvalidHex,
+    /// The key is `got` bytes long instead of 16, 24 or 32
+    WrongKeyLength { got: usize }
+}
 
 impl Display for AESManagerParseError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -159,62 +908,159 @@ impl Error for AESManagerParseError { }
 
 impl From<ParseBigIntError> for AESManagerParseError {
     fn from(_: ParseBigIntError) -> Self {
-        AESManagerParseError
+        AESManagerParseError::InvalidHex
     }
 }
 
 impl FromStr for AESManager {
     type Err = AESManagerParseError;
 
+    /// Parses the key from [`parsable_string`](AESManager::parsable_string), two hex digits per
+    /// byte, so the key length comes from the length of the string and leading zero bytes are kept
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let big_uint: BigUint = BigUint::from_str_radix(s, 16)?;
-        let bytes = big_uint.to_bytes_be();
-        let key = match bytes.len() * 8 {
-            128 => {
-                Key::Aes128(Aes128::new_varkey(&* bytes).unwrap())
-            },
-            192 => {
-                Key::Aes192(Aes192::new_varkey(&* bytes).unwrap())
-            },
-            256 => {
-                Key::Aes256(Aes256::new_varkey(&* bytes).unwrap())
-            },
-            _ => {
-                return Err(AESManagerParseError)
-            }
-        };
-        Ok(Self {
-            key_value: bytes,
-            key
-        })
+        match s.strip_prefix(CHACHA20_POLY1305_PREFIX) {
+            Some(hex) => AESManager::from_cipher_bytes(CipherChoice::ChaCha20Poly1305, parse_hex(hex)?),
+            None => AESManager::from_key_value(parse_hex(s)?)
+        }
     }
 }
 
+fn parse_hex(s: &str) -> Result<Vec<u8>, AESManagerParseError> {
+    if !s.bytes().all(|b| b.is_ascii_hexdigit()) || !s.len().is_multiple_of(2) {
+        return Err(AESManagerParseError::InvalidHex);
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| AESManagerParseError::InvalidHex)
+}
 
 
+/// Selects the AES variant for a key of `N` bytes
+pub struct KeyBytes<const N: usize>;
 
-pub fn generate_key(key_size: KeySize) -> (Key, Vec<u8>) {
-    let bits = key_size as u16;
-    let mut bytes: Vec<u8> = Vec::with_capacity(bits as usize / 8);
+pub trait AesKeyBytes {
+    type Cipher: BlockCipher<BlockSize = U16> + NewBlockCipher;
+}
+
+impl AesKeyBytes for KeyBytes<16> {
+    type Cipher = Aes128;
+}
+
+impl AesKeyBytes for KeyBytes<24> {
+    type Cipher = Aes192;
+}
+
+impl AesKeyBytes for KeyBytes<32> {
+    type Cipher = Aes256;
+}
+
+/// An [`AESManager`] whose key size is fixed at compile time
+///
+/// The cipher is chosen by the type, so encrypting and decrypting blocks does not have to match
+/// on the key size. Produces the same blocks as an `AESManager` with the same key.
+pub struct FixedAESManager<const N: usize> where KeyBytes<N>: AesKeyBytes {
+    key_value: [u8; N],
+    cipher: <KeyBytes<N> as AesKeyBytes>::Cipher
+}
+
+impl<const N: usize> Drop for FixedAESManager<N> where KeyBytes<N>: AesKeyBytes {
+    fn drop(&mut self) {
+        self.key_value.zeroize();
+        // the old cipher is overwritten in place, since the ciphers have no destructor of their own
+        self.cipher = <KeyBytes<N> as AesKeyBytes>::Cipher::new_varkey(&[0u8; N]).expect("the key length matches the cipher");
+        black_box(&self.cipher);
+    }
+}
+
+pub type AESManager128 = FixedAESManager<16>;
+pub type AESManager192 = FixedAESManager<24>;
+pub type AESManager256 = FixedAESManager<32>;
 
-    for _ in 0..(bits / 8) {
+impl<const N: usize> FixedAESManager<N> where KeyBytes<N>: AesKeyBytes {
+
+    /// Creates a manager with a random key
+    pub fn new() -> Self {
+        let mut key_value = [0u8; N];
+        for byte in key_value.iter_mut() {
+            *byte = random();
+        }
+        Self::from_key_value(key_value)
+    }
+
+    pub fn from_key_value(key_value: [u8; N]) -> Self {
+        let cipher = <KeyBytes<N> as AesKeyBytes>::Cipher::new_varkey(&key_value)
+            .expect("the key length matches the cipher");
+        FixedAESManager { key_value, cipher }
+    }
+
+    pub fn key_value(&self) -> &[u8; N] {
+        &self.key_value
+    }
+
+    /// Encrypts the message one block at a time, padding the last block with zeros
+    pub fn encrypt_blocks<S : AsRef<[u8]>>(&self, message: S) -> Vec<[u8; 16]> {
+        message.as_ref()
+            .chunks(16)
+            .map(|chunk| {
+                let mut block = GenericArray::default();
+                block[..chunk.len()].copy_from_slice(chunk);
+                self.cipher.encrypt_block(&mut block);
+                block.into()
+            })
+            .collect()
+    }
+
+    pub fn decrypt_blocks<V : AsRef<[[u8; 16]]>>(&self, blocks: V) -> Vec<u8> {
+        let mut output = Vec::with_capacity(blocks.as_ref().len() * 16);
+        for block in blocks.as_ref() {
+            let mut block = GenericArray::clone_from_slice(block);
+            self.cipher.decrypt_block(&mut block);
+            output.extend_from_slice(&block);
+        }
+        output
+    }
+}
+
+impl<const N: usize> Default for FixedAESManager<N> where KeyBytes<N>: AesKeyBytes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> From<FixedAESManager<N>> for AESManager where KeyBytes<N>: AesKeyBytes {
+    fn from(manager: FixedAESManager<N>) -> Self {
+        AESManager::from_key_value(manager.key_value.to_vec()).expect("the key length is a valid AES key size")
+    }
+}
+
+fn random_bytes(count: usize) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(count);
+
+    for _ in 0..count {
         let mut byte = 0u8;
         for _ in 0..8 {
-            byte = byte << 1;
+            byte <<= 1;
             let bit: bool = random();
             byte |= if bit { 1 } else { 0 };
         }
         bytes.push(byte)
     }
+    bytes
+}
+
+pub fn generate_key(key_size: KeySize) -> (Key, Vec<u8>) {
+    let bytes = random_bytes(key_size.bits() as usize / 8);
     (match key_size {
         KeySize::K128 => {
-            Key::Aes128(Aes128::new_varkey(&* bytes).unwrap())
+            Key::Aes128(Aes128::new_varkey(&bytes).unwrap())
         }
         KeySize::K192 => {
-            Key::Aes192(Aes192::new_varkey(&* bytes).unwrap())
+            Key::Aes192(Aes192::new_varkey(&bytes).unwrap())
         }
         KeySize::K256 => {
-            Key::Aes256(Aes256::new_varkey(&* bytes).unwrap())
+            Key::Aes256(Aes256::new_varkey(&bytes).unwrap())
         }
     },
      bytes)
@@ -237,7 +1083,7 @@ mod tests {
 
         let phrase = b"Hello, World!";
         let mut slice = [0u8; 16];
-        for (a, b) in phrase.into_iter().zip(&mut slice) {
+        for (a, b) in phrase.iter().zip(&mut slice) {
             *b = *a;
         }
         let mut block: Block<Aes192> = GenericArray::clone_from_slice(&slice);
@@ -256,7 +1102,7 @@ mod tests {
         let key2 = AESManager::from_str(string.as_str()).unwrap();
         let phrase = b"Hello, World!";
         let mut slice = [0u8; 16];
-        for (a, b) in phrase.into_iter().zip(&mut slice) {
+        for (a, b) in phrase.iter().zip(&mut slice) {
             *b = *a;
         }
         let mut block: Block<Aes192> = GenericArray::clone_from_slice(&slice);
@@ -264,4 +1110,406 @@ mod tests {
         key2.key.aes192().unwrap().decrypt_block(&mut block);
         assert_eq!(block.as_slice(), slice);
     }
-}
\ No newline at end of file
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_matches_sequential() {
+        let key = AESManager::new(KeySize::K256);
+        for size in [0, 1, 16, 1024 * 16, 1024 * 16 + 1, 3 * 1024 * 16 + 7] {
+            let message: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            assert_eq!(key.encrypt_parallel(&message).unwrap(), key.encrypt_blocks(&message).unwrap(), "{} byte message", size);
+        }
+    }
+
+    #[test]
+    fn parsable_string_round_trip() {
+        for key_size in [KeySize::K128, KeySize::K192, KeySize::K256] {
+            for _ in 0..1000 {
+                let key = AESManager::new(key_size);
+                let parsed = AESManager::from_str(&key.parsable_string()).unwrap();
+                assert_eq!(parsed.key_value, key.key_value);
+            }
+        }
+    }
+
+    proptest::proptest! {
+        #![proptest_config(proptest::prelude::ProptestConfig::with_cases(1000))]
+
+        /// Keys with up to a quarter of their bytes zero at the start, which are the ones a
+        /// number based encoding gets wrong
+        #[test]
+        fn parsable_string_identity(key_value in key_with_leading_zeros()) {
+            let key = AESManager::from_key_value(key_value.clone()).unwrap();
+            let string = key.parsable_string();
+            proptest::prop_assert_eq!(string.len(), key_value.len() * 2);
+            let parsed = AESManager::from_str(&string).unwrap();
+            proptest::prop_assert_eq!(parsed.parsable_string(), string);
+            proptest::prop_assert_eq!(&parsed.key_value, &key_value);
+        }
+    }
+
+    fn key_with_leading_zeros() -> impl proptest::strategy::Strategy<Value = Vec<u8>> {
+        use proptest::prelude::*;
+
+        (prop::sample::select(vec![16usize, 24, 32]), 0usize..=8)
+            .prop_flat_map(|(length, zeros)| {
+                prop::collection::vec(any::<u8>(), length).prop_map(move |mut key_value| {
+                    key_value[..zeros].fill(0);
+                    key_value
+                })
+            })
+    }
+
+    #[test]
+    fn key_bytes_and_size_survive_parsing() {
+        for (key_size, length) in [(KeySize::K128, 16), (KeySize::K192, 24), (KeySize::K256, 32)] {
+            let manager = AESManager::new(key_size);
+            assert_eq!(manager.key_size(), key_size);
+            assert_eq!(manager.key_bytes().len(), length);
+
+            let parsed = AESManager::from_str(&manager.parsable_string()).unwrap();
+            assert_eq!(parsed.key_bytes(), manager.key_bytes());
+            assert_eq!(parsed.key_size(), key_size);
+        }
+    }
+
+    #[test]
+    fn pkcs7_round_trip() {
+        let key = AESManager::new(KeySize::K256);
+        for length in 0..=32 {
+            let message: Vec<u8> = (0..length as u8).collect();
+            let encrypted = key.encrypt_pkcs7(&message).unwrap();
+            assert_eq!(encrypted.len(), (length / 16 + 1) * 16, "length {}", length);
+
+            let padded = key.decrypt_blocks(encrypted.chunks_exact(16).map(|c| c.try_into().unwrap()).collect::<Vec<[u8; 16]>>()).unwrap();
+            let padding = 16 - length % 16;
+            assert!(padded[length..].iter().all(|&b| b as usize == padding), "length {}", length);
+            assert_eq!(key.decrypt_pkcs7(&encrypted).unwrap(), message, "length {}", length);
+        }
+        // trailing zeros are kept, unlike with the zero padding of encrypt_blocks
+        assert_eq!(key.decrypt_pkcs7(&key.encrypt_pkcs7(&[7, 0, 0]).unwrap()).unwrap(), [7, 0, 0]);
+    }
+
+    #[test]
+    fn pkcs7_invalid_padding() {
+        let key = AESManager::new(KeySize::K128);
+        let encrypt = |block: [u8; 16]| key.encrypt_blocks(block).unwrap().concat();
+        let mut block = [0u8; 16];
+        assert_eq!(key.decrypt_pkcs7(&encrypt(block)), Err(PaddingError::InvalidPadding));
+        block[15] = 17;
+        assert_eq!(key.decrypt_pkcs7(&encrypt(block)), Err(PaddingError::InvalidPadding));
+        block[13..].copy_from_slice(&[2, 3, 3]);
+        assert_eq!(key.decrypt_pkcs7(&encrypt(block)), Err(PaddingError::InvalidPadding));
+        block[12] = 3;
+        block[13] = 3;
+        assert_eq!(key.decrypt_pkcs7(&encrypt(block)).unwrap(), &block[..13]);
+        block = [16; 16];
+        assert_eq!(key.decrypt_pkcs7(&encrypt(block)).unwrap(), []);
+        block[0] = 15;
+        assert_eq!(key.decrypt_pkcs7(&encrypt(block)), Err(PaddingError::InvalidPadding));
+
+        assert_eq!(ct_lt(3, 4), 0xFF);
+        assert_eq!(ct_lt(4, 4), 0x00);
+        assert_eq!(ct_lt(255, 0), 0x00);
+
+        assert_eq!(key.decrypt_pkcs7(&[]), Err(PaddingError::InvalidLength));
+        assert_eq!(key.decrypt_pkcs7(&key.encrypt_pkcs7(b"Hello").unwrap()[..15]), Err(PaddingError::InvalidLength));
+    }
+
+    #[test]
+    fn string_messages() {
+        let key = AESManager::new(KeySize::K192);
+        for message in ["", "a", "fifteen chars!!", "sixteen chars!!!", "seventeen chars!!"] {
+            let encrypted = key.encrypt_message(message).unwrap();
+            assert_eq!(encrypted.len(), (message.len() / 16 + 1) * 16, "{:?}", message);
+            assert_eq!(key.decrypt_message(&encrypted).unwrap(), message);
+        }
+
+        let invalid = key.encrypt_pkcs7(&[0xFF, 0xFE]).unwrap();
+        assert_eq!(key.decrypt_message(&invalid), Err(DecryptionError::InvalidUtf8));
+        assert_eq!(key.decrypt_message(&invalid[..8]), Err(DecryptionError::Padding(PaddingError::InvalidLength)));
+    }
+
+    #[test]
+    fn cbc_hides_repeated_blocks() {
+        let key = AESManager::new(KeySize::K128);
+        let iv = [0x24u8; 16];
+        let plaintext = [b'A'; 32];
+        let ecb = key.encrypt_pkcs7(&plaintext).unwrap();
+        assert_eq!(ecb[..16], ecb[16..32]);
+        let cbc = key.encrypt_cbc(&iv, &plaintext).unwrap();
+        assert_ne!(cbc[..16], cbc[16..32]);
+        assert_eq!(key.decrypt_cbc(&iv, &cbc).unwrap(), plaintext);
+
+        for length in 0..=33 {
+            let message: Vec<u8> = (0..length as u8).collect();
+            let encrypted = key.encrypt_cbc(&iv, &message).unwrap();
+            assert_eq!(encrypted.len(), (length / 16 + 1) * 16, "length {}", length);
+            assert_eq!(key.decrypt_cbc(&iv, &encrypted).unwrap(), message, "length {}", length);
+        }
+        // a different IV gives different ciphertext for the same message
+        assert_ne!(key.encrypt_cbc(&[0u8; 16], &plaintext).unwrap(), cbc);
+        assert_eq!(key.decrypt_cbc(&iv, &cbc[..20]), Err(DecryptionError::Padding(PaddingError::InvalidLength)));
+    }
+
+    /// SP 800-38A appendix F.5.1, CTR-AES128.Encrypt
+    #[test]
+    fn ctr_nist_vector() {
+        let key = AESManager::from_key_bytes(KeySize::K128, &[
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f, 0x3c
+        ]).unwrap();
+        let counter: [u8; 16] = (0xf0..=0xff).collect::<Vec<u8>>().try_into().unwrap();
+        let plaintext = [
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17, 0x2a,
+            0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac, 0x45, 0xaf, 0x8e, 0x51,
+            0x30, 0xc8, 0x1c, 0x46, 0xa3, 0x5c, 0xe4, 0x11, 0xe5, 0xfb, 0xc1, 0x19, 0x1a, 0x0a, 0x52, 0xef,
+            0xf6, 0x9f, 0x24, 0x45, 0xdf, 0x4f, 0x9b, 0x17, 0xad, 0x2b, 0x41, 0x7b, 0xe6, 0x6c, 0x37, 0x10
+        ];
+        let ciphertext = [
+            0x87, 0x4d, 0x61, 0x91, 0xb6, 0x20, 0xe3, 0x26, 0x1b, 0xef, 0x68, 0x64, 0x99, 0x0d, 0xb6, 0xce,
+            0x98, 0x06, 0xf6, 0x6b, 0x79, 0x70, 0xfd, 0xff, 0x86, 0x17, 0x18, 0x7b, 0xb9, 0xff, 0xfd, 0xff,
+            0x5a, 0xe4, 0xdf, 0x3e, 0xdb, 0xd5, 0xd3, 0x5e, 0x5b, 0x4f, 0x09, 0x02, 0x0d, 0xb0, 0x3e, 0xab,
+            0x1e, 0x03, 0x1d, 0xda, 0x2f, 0xbe, 0x03, 0xd1, 0x79, 0x21, 0x70, 0xa0, 0xf3, 0x00, 0x9c, 0xee
+        ];
+        assert_eq!(key.encrypt_ctr(&counter, &plaintext).unwrap(), ciphertext);
+        assert_eq!(key.decrypt_ctr(&counter, &ciphertext).unwrap(), plaintext);
+        // no padding, so a partial block gives a partial block
+        assert_eq!(key.encrypt_ctr(&counter, &plaintext[..21]).unwrap(), ciphertext[..21]);
+        assert!(AESManager::new(CipherChoice::ChaCha20Poly1305).encrypt_ctr(&counter, b"Hello").is_err());
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let choices = [
+            CipherChoice::Aes(KeySize::K128),
+            CipherChoice::Aes(KeySize::K192),
+            CipherChoice::Aes(KeySize::K256),
+            CipherChoice::ChaCha20Poly1305
+        ];
+        for choice in choices {
+            let key = AESManager::new(choice);
+            assert_eq!(key.cipher_choice(), choice);
+            let sealed = key.seal(b"Hello, World!");
+            assert_eq!(sealed.len(), SEAL_NONCE_SIZE + 13 + 16);
+            assert_ne!(sealed, key.seal(b"Hello, World!"), "the nonce should be random");
+            assert_eq!(key.open(&sealed).unwrap(), b"Hello, World!");
+
+            for index in [0, SEAL_NONCE_SIZE, sealed.len() - 1] {
+                let mut tampered = sealed.clone();
+                tampered[index] ^= 1;
+                assert_eq!(key.open(&tampered), Err(AuthenticationError), "{:?} byte {}", choice, index);
+            }
+            assert_eq!(key.open(&sealed[..SEAL_NONCE_SIZE - 1]), Err(AuthenticationError));
+            assert_eq!(AESManager::new(choice).open(&sealed), Err(AuthenticationError));
+        }
+    }
+
+    #[test]
+    fn chacha20_poly1305_parsable_string() {
+        let key = AESManager::new(CipherChoice::ChaCha20Poly1305);
+        assert_eq!(key.key_size(), KeySize::K256);
+        let string = key.parsable_string();
+        assert!(string.starts_with("chacha20poly1305:"), "{}", string);
+        let parsed = AESManager::from_str(&string).unwrap();
+        assert_eq!(parsed, key);
+        assert_eq!(parsed.cipher_choice(), CipherChoice::ChaCha20Poly1305);
+        assert_eq!(parsed.open(&key.seal(b"Hello, World!")).unwrap(), b"Hello, World!");
+
+        // the same bytes as an AES key are a different key
+        let aes = AESManager::from_str(&string["chacha20poly1305:".len()..]).unwrap();
+        assert_eq!(aes.cipher_choice(), CipherChoice::Aes(KeySize::K256));
+        assert_ne!(aes, key);
+        assert_ne!(aes.key, key.key);
+
+        assert_eq!(AESManager::from_str("chacha20poly1305:00").err(), Some(AESManagerParseError::WrongKeyLength { got: 1 }));
+        assert_eq!(AESManager::from_str("chacha20poly1305:zz").err(), Some(AESManagerParseError::InvalidHex));
+    }
+
+    #[test]
+    fn chacha20_poly1305_has_no_blocks() {
+        let key = AESManager::new(CipherChoice::ChaCha20Poly1305);
+        let aes = AESManager::new(KeySize::K256);
+        let iv = [0u8; 16];
+        let blocks = aes.encrypt_blocks(b"Hello, World!").unwrap();
+        let ciphertext = blocks.concat();
+
+        assert_eq!(key.encrypt_blocks(b"Hello, World!"), Err(UnsupportedCipher));
+        assert_eq!(key.decrypt_blocks(&blocks), Err(UnsupportedCipher));
+        assert_eq!(key.encrypt(b"Hello, World!"), Err(UnsupportedCipher));
+        assert_eq!(key.decrypt(&ciphertext), Err(CiphertextError::UnsupportedCipher));
+        assert_eq!(key.encrypt_pkcs7(b"Hello, World!"), Err(UnsupportedCipher));
+        assert_eq!(key.decrypt_pkcs7(&ciphertext), Err(PaddingError::UnsupportedCipher));
+        assert_eq!(key.encrypt_cbc(&iv, b"Hello, World!"), Err(UnsupportedCipher));
+        assert_eq!(key.decrypt_cbc(&iv, &ciphertext), Err(DecryptionError::UnsupportedCipher));
+        assert_eq!(key.encrypt_message("Hello, World!"), Err(UnsupportedCipher));
+        assert_eq!(key.decrypt_message(&ciphertext), Err(DecryptionError::UnsupportedCipher));
+        assert_eq!(key.encrypt_authenticated(b"Hello, World!"), Err(UnsupportedCipher));
+        let tag = key.ciphertext_mac(&blocks).finalize().into_bytes().into();
+        assert_eq!(key.decrypt_authenticated(&blocks, &tag), Err(AuthenticationError));
+        #[cfg(feature = "rayon")]
+        assert_eq!(key.encrypt_parallel(b"Hello, World!"), Err(UnsupportedCipher));
+
+        let metered = MeteredAesManager::new(key);
+        assert_eq!(metered.encrypt_blocks(b"Hello, World!"), Err(UnsupportedCipher));
+        assert_eq!(metered.decrypt_blocks(&blocks), Err(UnsupportedCipher));
+        assert_eq!(metered.encrypt(b"Hello, World!"), Err(UnsupportedCipher));
+        assert_eq!(metered.decrypt(&ciphertext), Err(CiphertextError::UnsupportedCipher));
+        assert_eq!(metered.stats(), (0, 0));
+    }
+
+    #[test]
+    fn key_size_bits() {
+        for key_size in [KeySize::K128, KeySize::K192, KeySize::K256] {
+            assert_eq!(KeySize::from_bits(key_size.bits()), Some(key_size));
+            assert_eq!(KeySize::try_from(key_size.bits()), Ok(key_size));
+        }
+        assert_eq!(KeySize::K192.bits(), 192);
+        for bits in [0, 1, 16, 32, 64, 127, 129, 255, 512, u16::MAX] {
+            assert_eq!(KeySize::from_bits(bits), None);
+            assert_eq!(KeySize::try_from(bits), Err(UnsupportedKeySize { bits }));
+        }
+    }
+
+    #[test]
+    fn from_key_bytes() {
+        for key_size in [KeySize::K128, KeySize::K192, KeySize::K256] {
+            let manager = AESManager::new(key_size);
+            let copy = AESManager::from_key_bytes(key_size, manager.key_bytes()).unwrap();
+            assert_eq!(copy, manager);
+            assert_eq!(copy.key_size(), key_size);
+            assert_eq!(copy.decrypt(&manager.encrypt(b"Hello, World!").unwrap()).unwrap(), b"Hello, World!");
+        }
+    }
+
+    #[test]
+    fn from_key_bytes_wrong_length() {
+        assert_eq!(AESManager::from_key_bytes(KeySize::K256, &[0; 16]).err(), Some(AESManagerParseError::WrongKeyLength { got: 16 }));
+        assert_eq!(AESManager::from_key_bytes(KeySize::K128, &[0; 17]).err(), Some(AESManagerParseError::WrongKeyLength { got: 17 }));
+        assert_eq!(AESManager::from_key_bytes(KeySize::K192, &[]).err(), Some(AESManagerParseError::WrongKeyLength { got: 0 }));
+    }
+
+    #[test]
+    fn leading_zero_key() {
+        let mut key_value = vec![0u8; 16];
+        key_value[15] = 1;
+        let key = AESManager::from_key_value(key_value.clone()).unwrap();
+        assert_eq!(key.parsable_string(), "00000000000000000000000000000001");
+        assert_eq!(AESManager::from_str(&key.parsable_string()).unwrap().key_value, key_value);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(AESManager::from_str("0001").err(), Some(AESManagerParseError::WrongKeyLength { got: 2 }));
+        assert_eq!(AESManager::from_str(&"00".repeat(20)).err(), Some(AESManagerParseError::WrongKeyLength { got: 20 }));
+        assert_eq!(AESManager::from_str(&"0".repeat(31)).err(), Some(AESManagerParseError::InvalidHex));
+        assert_eq!(AESManager::from_str(&"zz".repeat(16)).err(), Some(AESManagerParseError::InvalidHex));
+        assert_eq!(AESManager::from_str(&"+1".repeat(16)).err(), Some(AESManagerParseError::InvalidHex));
+
+        for length in [16, 24, 32] {
+            let mut key_value = vec![0u8; length];
+            key_value[length - 1] = 0xab;
+            let key = AESManager::from_key_value(key_value.clone()).unwrap();
+            assert_eq!(AESManager::from_str(&key.parsable_string()).unwrap().key_value, key_value);
+        }
+    }
+
+    #[test]
+    fn fixed_size_managers_match() {
+        let fixed = AESManager128::new();
+        let message = b"Hello, World! This spans two blocks";
+        let encrypted = fixed.encrypt_blocks(message);
+        assert_eq!(encrypted.len(), 3);
+        assert_eq!(&fixed.decrypt_blocks(&encrypted)[..message.len()], &message[..]);
+
+        let dynamic = AESManager::from(AESManager128::from_key_value(*fixed.key_value()));
+        assert_eq!(dynamic.encrypt_blocks(message).unwrap(), encrypted);
+
+        let fixed = AESManager256::new();
+        let dynamic = AESManager::from_key_value(fixed.key_value().to_vec()).unwrap();
+        assert_eq!(dynamic.encrypt_blocks(message).unwrap(), fixed.encrypt_blocks(message));
+    }
+
+    #[test]
+    fn length_prefixed_round_trip() {
+        let key = AESManager::new(KeySize::K128);
+        for message in [&b""[..], b"\0", b"Hello, World!\0\0", b"exactly twelve", &[0u8; 40]] {
+            let encrypted = key.encrypt(message).unwrap();
+            assert_eq!(encrypted.len(), (message.len() + 4).div_ceil(16) * 16);
+            assert_eq!(key.decrypt(&encrypted).unwrap(), message);
+        }
+
+        let encrypted = key.encrypt(b"Hello, World! This spans two blocks").unwrap();
+        assert_eq!(key.decrypt(&encrypted[..encrypted.len() - 1]), Err(CiphertextError::InvalidLength));
+        assert_eq!(key.decrypt(&encrypted[..16]), Err(CiphertextError::InvalidLength));
+        assert_eq!(key.decrypt(&[]), Err(CiphertextError::InvalidLength));
+        // an extra block leaves more than a block of padding
+        let mut extended = encrypted.clone();
+        extended.extend_from_slice(&encrypted[..16]);
+        assert_eq!(key.decrypt(&extended), Err(CiphertextError::InvalidLength));
+    }
+
+    #[test]
+    fn authenticated_round_trip() {
+        let key = AESManager::new(KeySize::K256);
+        let message = b"Transfer 100 to account 42";
+        let (mut blocks, tag) = key.encrypt_authenticated(message).unwrap();
+        assert_eq!(&key.decrypt_authenticated(&blocks, &tag).unwrap()[..message.len()], &message[..]);
+
+        blocks[1][3] ^= 1;
+        assert_eq!(key.decrypt_authenticated(&blocks, &tag), Err(AuthenticationError));
+
+        let (blocks, mut tag) = key.encrypt_authenticated(message).unwrap();
+        tag[0] ^= 1;
+        assert_eq!(key.decrypt_authenticated(&blocks, &tag), Err(AuthenticationError));
+        let other = AESManager::new(KeySize::K256);
+        let (blocks, tag) = key.encrypt_authenticated(message).unwrap();
+        assert_eq!(other.decrypt_authenticated(&blocks, &tag), Err(AuthenticationError));
+    }
+
+    #[test]
+    fn manager_equality() {
+        let key = AESManager::new(KeySize::K256);
+        let first = AESManager::from_str(&key.parsable_string()).unwrap();
+        let second = AESManager::from_str(&key.parsable_string()).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.key, second.key);
+        assert_ne!(AESManager::new(KeySize::K256), AESManager::new(KeySize::K256));
+        assert_ne!(AESManager::new(KeySize::K128).key, AESManager::new(KeySize::K128).key);
+        assert_ne!(AESManager::new(KeySize::K128).key, key.key);
+    }
+
+    #[test]
+    fn zeroize_clears_key() {
+        let mut manager = std::mem::ManuallyDrop::new(AESManager::new(KeySize::K256));
+        let key_bytes = manager.key_value.as_ptr();
+        let zero_key = AESManager::from_key_value(vec![0u8; 32]).unwrap();
+        assert_ne!(manager.key, zero_key.key);
+
+        manager.zeroize();
+        // the vector keeps its allocation, so the old key bytes can still be inspected
+        let old_key = unsafe { std::slice::from_raw_parts(key_bytes, 32) };
+        assert_eq!(old_key, &[0u8; 32]);
+        assert!(manager.key_value.is_empty());
+        assert_eq!(manager.key, zero_key.key);
+        unsafe { std::mem::ManuallyDrop::drop(&mut manager) };
+    }
+
+    #[test]
+    fn metered_messages() {
+        let metered = MeteredAesManager::from(AESManager::new(KeySize::K256));
+        let encrypted = metered.encrypt(&[3u8; 100]).unwrap();
+        assert_eq!(metered.stats(), (100, 0));
+        assert_eq!(metered.decrypt(&encrypted).unwrap(), [3u8; 100]);
+        // only the message is counted, not its length header or padding
+        assert_eq!(metered.stats(), (100, 100));
+        assert!(metered.decrypt(&encrypted[..16]).is_err());
+        assert_eq!(metered.stats(), (100, 100));
+
+        // blocks aren't counted, whether through the trait or not
+        let blocks = metered.encrypt_blocks([3u8; 20]).unwrap();
+        assert_eq!(AESBlockCipher::encrypt_blocks(&metered, &[3u8; 20]).unwrap().len(), 2);
+        assert_eq!(&metered.decrypt_blocks(&blocks).unwrap()[..20], &[3u8; 20]);
+        assert_eq!(&AESBlockCipher::decrypt_blocks(&metered, &blocks).unwrap()[..20], &[3u8; 20]);
+        assert_eq!(metered.stats(), (100, 100));
+    }
+}
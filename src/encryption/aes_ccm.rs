@@ -0,0 +1,305 @@
+//! AES-CCM (RFC 3610, NIST SP 800-38C): counter mode encryption with a CBC-MAC tag
+//!
+//! The 15 bytes of a counter block after its flags hold the nonce and the message length. Nonces
+//! are 7 bytes unless [`AesCcmManager::encrypt_with_nonce`] is used, which leaves 8 bytes for the
+//! length. A nonce must never be used twice with the same key.
+use crate::encryption::aes::{AESManager, UnsupportedCipher};
+use crate::encryption::aes::aes_hmac_stream::AuthError;
+
+/// The number of bytes in a nonce
+pub const NONCE_SIZE: usize = 7;
+/// The longest nonce, which leaves 2 bytes for the message length and so limits messages to
+/// 65535 bytes
+pub const MAX_NONCE_SIZE: usize = 13;
+
+pub struct AesCcmManager {
+    key: AESManager
+}
+
+impl AesCcmManager {
+
+    /// Fails if the key is for ChaCha20-Poly1305, which has no blocks
+    pub fn new(key: AESManager) -> Result<Self, UnsupportedCipher> {
+        key.check_block_cipher()?;
+        Ok(AesCcmManager { key })
+    }
+
+    /// Encrypts `plaintext` and authenticates it along with `aad`, returning `(ciphertext, tag)`
+    ///
+    /// # Panics
+    /// Panics if `tag_len` is not one of 4, 6, 8, 10, 12, 14, or 16.
+    pub fn encrypt(&self, nonce: &[u8; NONCE_SIZE], plaintext: &[u8], aad: &[u8], tag_len: usize) -> (Vec<u8>, Vec<u8>) {
+        self.encrypt_with_nonce(nonce, plaintext, aad, tag_len)
+    }
+
+    /// Decrypts `ciphertext`, returning the plaintext only if `tag` authenticates it and `aad`
+    pub fn decrypt(&self, nonce: &[u8; NONCE_SIZE], ciphertext: &[u8], aad: &[u8], tag: &[u8]) -> Result<Vec<u8>, AuthError> {
+        self.decrypt_with_nonce(nonce, ciphertext, aad, tag)
+    }
+
+    /// Encrypts like [`encrypt`](Self::encrypt) with a nonce of [`NONCE_SIZE`] to
+    /// [`MAX_NONCE_SIZE`] bytes, for protocols that fix a longer one
+    ///
+    /// Every byte of nonce over [`NONCE_SIZE`] takes one from the message length, so the
+    /// plaintext must be shorter than `2^(8 * (15 - nonce.len()))` bytes.
+    ///
+    /// # Panics
+    /// Panics if `tag_len` is not one of 4, 6, 8, 10, 12, 14, or 16, or if the nonce or the
+    /// plaintext has a length CCM can't encode.
+    pub fn encrypt_with_nonce(&self, nonce: &[u8], plaintext: &[u8], aad: &[u8], tag_len: usize) -> (Vec<u8>, Vec<u8>) {
+        assert!(valid_tag_len(tag_len), "CCM tags must be an even number of bytes from 4 to 16, not {}", tag_len);
+        assert!(valid_nonce_len(nonce.len()), "CCM nonces must be from 7 to 13 bytes, not {}", nonce.len());
+        assert!(fits_length(nonce, plaintext.len()), "a {} byte nonce can't encrypt {} bytes", nonce.len(), plaintext.len());
+        let mac = self.cbc_mac(nonce, plaintext, aad, tag_len);
+        let ciphertext = self.ctr(nonce, plaintext);
+        (ciphertext, self.mask_tag(nonce, &mac[..tag_len]))
+    }
+
+    /// Decrypts a message from [`encrypt_with_nonce`](Self::encrypt_with_nonce), failing like
+    /// [`decrypt`](Self::decrypt)
+    pub fn decrypt_with_nonce(&self, nonce: &[u8], ciphertext: &[u8], aad: &[u8], tag: &[u8]) -> Result<Vec<u8>, AuthError> {
+        if !valid_tag_len(tag.len()) || !valid_nonce_len(nonce.len()) || !fits_length(nonce, ciphertext.len()) {
+            return Err(AuthError::AuthenticationFailed);
+        }
+        let plaintext = self.ctr(nonce, ciphertext);
+        let mac = self.cbc_mac(nonce, &plaintext, aad, tag.len());
+        let expected = self.mask_tag(nonce, &mac[..tag.len()]);
+        let difference = expected.iter().zip(tag).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if difference == 0 {
+            Ok(plaintext)
+        } else {
+            Err(AuthError::AuthenticationFailed)
+        }
+    }
+
+    fn encrypt_block(&self, block: [u8; 16]) -> [u8; 16] {
+        self.key.encrypt_blocks(block).expect("the key was checked to be an AES key by new")[0]
+    }
+
+    /// Computes the CBC-MAC over the formatted blocks `B0 || encoded aad || plaintext`
+    fn cbc_mac(&self, nonce: &[u8], plaintext: &[u8], aad: &[u8], tag_len: usize) -> [u8; 16] {
+        let adata = if aad.is_empty() { 0 } else { 0x40 };
+        let flags = adata | ((((tag_len - 2) / 2) as u8) << 3);
+        let mut y = self.encrypt_block(Self::format_block(flags, nonce, plaintext.len() as u64));
+        if !aad.is_empty() {
+            let mut encoded = encode_aad_length(aad.len());
+            encoded.extend_from_slice(aad);
+            y = self.chain(y, &encoded);
+        }
+        self.chain(y, plaintext)
+    }
+
+    /// Continues the CBC-MAC over `data`, zero padded to a whole number of blocks
+    fn chain(&self, mut y: [u8; 16], data: &[u8]) -> [u8; 16] {
+        for chunk in data.chunks(16) {
+            for (y, b) in y.iter_mut().zip(chunk) {
+                *y ^= b;
+            }
+            y = self.encrypt_block(y);
+        }
+        y
+    }
+
+    /// The block `flags || nonce || value`, with the size of the length field in the low bits of
+    /// the flags and `value` filling the bytes after the nonce
+    fn format_block(flags: u8, nonce: &[u8], value: u64) -> [u8; 16] {
+        let length_size = 15 - nonce.len();
+        let mut block = [0u8; 16];
+        block[0] = flags | (length_size as u8 - 1);
+        block[1..=nonce.len()].copy_from_slice(nonce);
+        block[16 - length_size..].copy_from_slice(&value.to_be_bytes()[8 - length_size..]);
+        block
+    }
+
+    /// XORs `data` with the key stream that starts at counter 1
+    fn ctr(&self, nonce: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(data.len());
+        for (i, chunk) in data.chunks(16).enumerate() {
+            let stream = self.encrypt_block(Self::format_block(0, nonce, i as u64 + 1));
+            output.extend(chunk.iter().zip(&stream).map(|(a, b)| a ^ b));
+        }
+        output
+    }
+
+    /// XORs the tag with the key stream block for counter 0
+    fn mask_tag(&self, nonce: &[u8], tag: &[u8]) -> Vec<u8> {
+        let stream = self.encrypt_block(Self::format_block(0, nonce, 0));
+        tag.iter().zip(&stream).map(|(a, b)| a ^ b).collect()
+    }
+}
+
+fn valid_nonce_len(nonce_len: usize) -> bool {
+    (NONCE_SIZE..=MAX_NONCE_SIZE).contains(&nonce_len)
+}
+
+/// Whether the length field left by `nonce` can hold `length`, and the counter can reach the
+/// last block
+fn fits_length(nonce: &[u8], length: usize) -> bool {
+    let length_size = 15 - nonce.len();
+    length_size >= 8 || (length as u64) < 1u64 << (8 * length_size)
+}
+
+fn valid_tag_len(tag_len: usize) -> bool {
+    (4..=16).contains(&tag_len) && tag_len.is_multiple_of(2)
+}
+
+/// The length prefix of the associated data, SP 800-38C appendix A.2.2
+fn encode_aad_length(length: usize) -> Vec<u8> {
+    if length < 0xFF00 {
+        (length as u16).to_be_bytes().to_vec()
+    } else if (length as u64) <= u32::MAX as u64 {
+        let mut encoded = vec![0xFF, 0xFE];
+        encoded.extend_from_slice(&(length as u32).to_be_bytes());
+        encoded
+    } else {
+        let mut encoded = vec![0xFF, 0xFF];
+        encoded.extend_from_slice(&(length as u64).to_be_bytes());
+        encoded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nist_key() -> AesCcmManager {
+        AesCcmManager::new(AESManager::from_key_value((0x40..0x50).collect()).unwrap()).unwrap()
+    }
+
+    const NONCE: [u8; NONCE_SIZE] = [0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16];
+
+    /// SP 800-38C appendix C, example 1
+    #[test]
+    fn nist_example_1() {
+        let key = nist_key();
+        let aad: Vec<u8> = (0x00..0x08).collect();
+        let plaintext = [0x20, 0x21, 0x22, 0x23];
+        let (ciphertext, tag) = key.encrypt(&NONCE, &plaintext, &aad, 4);
+        assert_eq!(ciphertext, [0x71, 0x62, 0x01, 0x5b]);
+        assert_eq!(tag, [0x4d, 0xac, 0x25, 0x5d]);
+        assert_eq!(key.decrypt(&NONCE, &ciphertext, &aad, &tag).unwrap(), plaintext);
+    }
+
+    /// SP 800-38C appendix C, example 2, with an 8 byte nonce
+    #[test]
+    fn nist_example_2() {
+        let key = nist_key();
+        let nonce: Vec<u8> = (0x10..0x18).collect();
+        let aad: Vec<u8> = (0x00..0x10).collect();
+        let plaintext: Vec<u8> = (0x20..0x30).collect();
+        let (ciphertext, tag) = key.encrypt_with_nonce(&nonce, &plaintext, &aad, 6);
+        assert_eq!(ciphertext, [
+            0xd2, 0xa1, 0xf0, 0xe0, 0x51, 0xea, 0x5f, 0x62, 0x08, 0x1a, 0x77, 0x92, 0x07, 0x3d, 0x59, 0x3d
+        ]);
+        assert_eq!(tag, [0x1f, 0xc6, 0x4f, 0xbf, 0xac, 0xcd]);
+        assert_eq!(key.decrypt_with_nonce(&nonce, &ciphertext, &aad, &tag).unwrap(), plaintext);
+    }
+
+    /// SP 800-38C appendix C, example 3, with a 12 byte nonce
+    #[test]
+    fn nist_example_3() {
+        let key = nist_key();
+        let nonce: Vec<u8> = (0x10..0x1c).collect();
+        let aad: Vec<u8> = (0x00..0x14).collect();
+        let plaintext: Vec<u8> = (0x20..0x38).collect();
+        let (ciphertext, tag) = key.encrypt_with_nonce(&nonce, &plaintext, &aad, 8);
+        assert_eq!(ciphertext, [
+            0xe3, 0xb2, 0x01, 0xa9, 0xf5, 0xb7, 0x1a, 0x7a, 0x9b, 0x1c, 0xea, 0xec, 0xcd, 0x97, 0xe7, 0x0b,
+            0x61, 0x76, 0xaa, 0xd9, 0xa4, 0x42, 0x8a, 0xa5
+        ]);
+        assert_eq!(tag, [0x48, 0x43, 0x92, 0xfb, 0xc1, 0xb0, 0x99, 0x51]);
+        assert_eq!(key.decrypt_with_nonce(&nonce, &ciphertext, &aad, &tag).unwrap(), plaintext);
+    }
+
+    /// SP 800-38C appendix C, example 4, with a 13 byte nonce and 65536 bytes of associated data,
+    /// which need the longer encoding of their length
+    #[test]
+    fn nist_example_4() {
+        let key = nist_key();
+        let nonce: Vec<u8> = (0x10..0x1d).collect();
+        let aad: Vec<u8> = (0..65536).map(|i| i as u8).collect();
+        let plaintext: Vec<u8> = (0x20..0x40).collect();
+        let (ciphertext, tag) = key.encrypt_with_nonce(&nonce, &plaintext, &aad, 14);
+        assert_eq!(ciphertext, [
+            0x69, 0x91, 0x5d, 0xad, 0x1e, 0x84, 0xc6, 0x37, 0x6a, 0x68, 0xc2, 0x96, 0x7e, 0x4d, 0xab, 0x61,
+            0x5a, 0xe0, 0xfd, 0x1f, 0xae, 0xc4, 0x4c, 0xc4, 0x84, 0x82, 0x85, 0x29, 0x46, 0x3c, 0xcf, 0x72
+        ]);
+        assert_eq!(tag, [0xb4, 0xac, 0x6b, 0xec, 0x93, 0xe8, 0x59, 0x8e, 0x7f, 0x0d, 0xad, 0xbc, 0xea, 0x5b]);
+        assert_eq!(key.decrypt_with_nonce(&nonce, &ciphertext, &aad, &tag).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn nonce_lengths() {
+        let key = nist_key();
+        let nonce = [0x42u8; 14];
+        for length in NONCE_SIZE..=MAX_NONCE_SIZE {
+            let (ciphertext, tag) = key.encrypt_with_nonce(&nonce[..length], b"Hello", b"", 8);
+            assert_eq!(key.decrypt_with_nonce(&nonce[..length], &ciphertext, b"", &tag).unwrap(), b"Hello");
+        }
+        let (ciphertext, tag) = key.encrypt_with_nonce(&nonce[..MAX_NONCE_SIZE], b"Hello", b"", 8);
+        assert!(key.decrypt_with_nonce(&nonce[..MAX_NONCE_SIZE - 1], &ciphertext, b"", &tag).is_err());
+        assert!(key.decrypt_with_nonce(&nonce, &ciphertext, b"", &tag).is_err());
+        assert!(key.decrypt_with_nonce(&nonce[..6], &ciphertext, b"", &tag).is_err());
+        // a 13 byte nonce leaves 2 bytes for the length
+        assert!(key.decrypt_with_nonce(&nonce[..MAX_NONCE_SIZE], &[0u8; 65536], b"", &tag).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn long_nonce_panics() {
+        nist_key().encrypt_with_nonce(&[0u8; 14], b"Hello", b"", 8);
+    }
+
+    #[test]
+    fn tampered_tag_rejected() {
+        let key = nist_key();
+        let (ciphertext, tag) = key.encrypt(&NONCE, b"Hello, World!", b"header", 16);
+        for index in 0..tag.len() {
+            let mut tampered = tag.clone();
+            tampered[index] ^= 0x80;
+            assert!(matches!(key.decrypt(&NONCE, &ciphertext, b"header", &tampered), Err(AuthError::AuthenticationFailed)), "byte {}", index);
+        }
+    }
+
+    #[test]
+    fn round_trip_all_tag_lengths() {
+        let key = nist_key();
+        let plaintext = b"A message that is longer than one block";
+        for tag_len in (4..=16).step_by(2) {
+            let (ciphertext, tag) = key.encrypt(&NONCE, plaintext, b"header", tag_len);
+            assert_eq!(tag.len(), tag_len);
+            assert_eq!(key.decrypt(&NONCE, &ciphertext, b"header", &tag).unwrap(), plaintext);
+        }
+        let (ciphertext, tag) = key.encrypt(&NONCE, b"", b"", 16);
+        assert!(ciphertext.is_empty());
+        assert!(key.decrypt(&NONCE, &ciphertext, b"", &tag).unwrap().is_empty());
+    }
+
+    #[test]
+    fn modifications_rejected() {
+        let key = nist_key();
+        let (ciphertext, tag) = key.encrypt(&NONCE, b"Hello, World!", b"header", 8);
+
+        let mut modified = ciphertext.clone();
+        modified[3] ^= 1;
+        assert!(key.decrypt(&NONCE, &modified, b"header", &tag).is_err());
+        assert!(key.decrypt(&NONCE, &ciphertext, b"headers", &tag).is_err());
+        assert!(key.decrypt(&NONCE, &ciphertext, b"header", &tag[..6]).is_err());
+        assert!(key.decrypt(&NONCE, &ciphertext, b"header", &tag[..3]).is_err());
+        let mut other_nonce = NONCE;
+        other_nonce[0] ^= 1;
+        assert!(key.decrypt(&other_nonce, &ciphertext, b"header", &tag).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn odd_tag_length_panics() {
+        nist_key().encrypt(&NONCE, b"Hello", b"", 5);
+    }
+
+    #[test]
+    fn chacha20_poly1305_key_is_rejected() {
+        let key = AESManager::new(crate::encryption::aes::CipherChoice::ChaCha20Poly1305);
+        assert_eq!(AesCcmManager::new(key).err(), Some(UnsupportedCipher));
+    }
+}
@@ -0,0 +1,198 @@
+//! AES streams authenticated by a single HMAC tag written after the last block
+//!
+//! The plaintext is padded PKCS#7 style when the writer is finalized, so the reader does not need
+//! to be told the message length in advance. The tag covers the ciphertext, so
+//! [`HmacFinalizedAesReader::verify`] checks it before decrypting anything or looking at the
+//! padding.
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::io::{Read, Write};
+
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::encryption::aes::AESManager;
+
+const BLOCK_SIZE: usize = 16;
+const TAG_SIZE: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The HMAC key is derived from the AES key so the same key is never used for both
+fn mac(key_manager: &AESManager) -> HmacSha256 {
+    let hkdf = Hkdf::<Sha256>::new(None, key_manager.key_bytes());
+    let mut key = [0u8; 32];
+    hkdf.expand(b"aes-stream-hmac", &mut key).expect("32 bytes is a valid HKDF output length");
+    HmacSha256::new_from_slice(&key).expect("HMAC accepts keys of any length")
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Io(std::io::Error),
+    /// The stream was modified, truncated, or encrypted with a different key
+    AuthenticationFailed
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for AuthError { }
+
+impl From<std::io::Error> for AuthError {
+    fn from(e: std::io::Error) -> Self {
+        AuthError::Io(e)
+    }
+}
+
+pub struct HmacFinalizedAesWriter<'a, W : Write> {
+    key_manager: &'a AESManager,
+    inner: W,
+    mac: HmacSha256,
+    /// Plaintext that does not yet fill a whole block
+    pending: Vec<u8>
+}
+
+impl<'a, W: Write> HmacFinalizedAesWriter<'a, W> {
+    pub fn new(key_manager: &'a AESManager, inner: W) -> Self {
+        HmacFinalizedAesWriter { key_manager, inner, mac: mac(key_manager), pending: Vec::with_capacity(BLOCK_SIZE) }
+    }
+
+    /// Pads and writes the last block followed by the HMAC tag, returning the inner writer and the tag
+    pub fn finalize(mut self) -> std::io::Result<(W, [u8; 32])> {
+        let padding = BLOCK_SIZE - self.pending.len();
+        self.pending.resize(BLOCK_SIZE, padding as u8);
+        for block in self.key_manager.encrypt_blocks(&self.pending)? {
+            self.mac.update(&block);
+            self.inner.write_all(&block)?;
+        }
+        let tag: [u8; TAG_SIZE] = self.mac.finalize().into_bytes().into();
+        self.inner.write_all(&tag)?;
+        self.inner.flush()?;
+        Ok((self.inner, tag))
+    }
+}
+
+impl<W : Write> Write for HmacFinalizedAesWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let whole = self.pending.len() - self.pending.len() % BLOCK_SIZE;
+        for block in self.key_manager.encrypt_blocks(&self.pending[..whole])? {
+            self.mac.update(&block);
+            self.inner.write_all(&block)?;
+        }
+        self.pending.drain(..whole);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct HmacFinalizedAesReader<'a, R : Read> {
+    key_manager: &'a AESManager,
+    inner: R
+}
+
+impl<'a, R: Read> HmacFinalizedAesReader<'a, R> {
+    pub fn new(key_manager: &'a AESManager, inner: R) -> Self {
+        HmacFinalizedAesReader { key_manager, inner }
+    }
+
+    /// Reads the rest of the stream, and returns the plaintext only if the tag matches it
+    ///
+    /// Every way the stream can be wrong gives [`AuthError::AuthenticationFailed`], and nothing is
+    /// decrypted until the tag has been checked.
+    pub fn verify(mut self) -> Result<Vec<u8>, AuthError> {
+        let mut data = Vec::new();
+        self.inner.read_to_end(&mut data)?;
+        let split = data.len().checked_sub(TAG_SIZE).ok_or(AuthError::AuthenticationFailed)?;
+        let (ciphertext, tag) = data.split_at(split);
+        let mut mac = mac(self.key_manager);
+        mac.update(ciphertext);
+        mac.verify_slice(tag).map_err(|_| AuthError::AuthenticationFailed)?;
+        // only a writer with the key could have made these, so they always hold
+        if ciphertext.is_empty() || !ciphertext.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(AuthError::AuthenticationFailed);
+        }
+
+        let blocks: Vec<[u8; 16]> = ciphertext.chunks(BLOCK_SIZE)
+            .map(|chunk| {
+                let mut block = [0u8; BLOCK_SIZE];
+                block.copy_from_slice(chunk);
+                block
+            })
+            .collect();
+        let mut plaintext = self.key_manager.decrypt_blocks(blocks).map_err(std::io::Error::from)?;
+
+        let padding = *plaintext.last().unwrap() as usize;
+        let padding_valid = (1..=BLOCK_SIZE).contains(&padding)
+            && plaintext[plaintext.len() - padding..].iter().all(|&b| b as usize == padding);
+        if !padding_valid {
+            return Err(AuthError::AuthenticationFailed);
+        }
+        plaintext.truncate(plaintext.len() - padding);
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encryption::aes::KeySize;
+
+    use super::*;
+
+    fn payload() -> Vec<u8> {
+        (0..10 * 1024).map(|i| (i % 256) as u8).collect()
+    }
+
+    fn write(key: &AESManager, message: &[u8]) -> Vec<u8> {
+        let mut writer = HmacFinalizedAesWriter::new(key, Vec::new());
+        // uneven writes, so blocks are split across calls
+        for part in message.chunks(1000) {
+            writer.write_all(part).unwrap();
+        }
+        writer.finalize().unwrap().0
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = AESManager::new(KeySize::K256);
+        let message = payload();
+        let stream = write(&key, &message);
+        assert_eq!(HmacFinalizedAesReader::new(&key, stream.as_slice()).verify().unwrap(), message);
+
+        let stream = write(&key, b"");
+        assert_eq!(HmacFinalizedAesReader::new(&key, stream.as_slice()).verify().unwrap(), b"");
+    }
+
+    #[test]
+    fn tampering_detected() {
+        let key = AESManager::new(KeySize::K256);
+        let stream = write(&key, &payload());
+        for index in [0, 5000, stream.len() - 40, stream.len() - 1] {
+            let mut tampered = stream.clone();
+            tampered[index] ^= 1;
+            assert!(matches!(
+                HmacFinalizedAesReader::new(&key, tampered.as_slice()).verify(),
+                Err(AuthError::AuthenticationFailed)
+            ));
+        }
+        let other_key = AESManager::new(KeySize::K256);
+        let short: &[u8] = &[0u8; TAG_SIZE - 1];
+        for rejected in [&stream[BLOCK_SIZE..], &stream[..stream.len() - 1], short] {
+            assert!(matches!(
+                HmacFinalizedAesReader::new(&key, rejected).verify(),
+                Err(AuthError::AuthenticationFailed)
+            ));
+        }
+        assert!(matches!(
+            HmacFinalizedAesReader::new(&other_key, stream.as_slice()).verify(),
+            Err(AuthError::AuthenticationFailed)
+        ));
+    }
+}
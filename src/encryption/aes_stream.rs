@@ -1,77 +1,1309 @@
-use std::io::{Read, Write};
-use crate::encryption::aes::AESManager;
+use std::convert::TryInto;
+use std::io::{BufRead, Read, Write};
+use crate::encryption::aes::{AESBlockCipher, AESManager, CipherChoice};
 use std::collections::VecDeque;
+use aes_gcm::{Aes128Gcm, Aes256Gcm, AesGcm, KeyInit};
+use aes_gcm::aead::Aead;
+use aes_gcm::aead::Payload;
+use aes_gcm::aead::consts::U12;
+use aes_gcm::aead::generic_array::GenericArray;
+use rand::RngCore;
+use rand::rngs::OsRng;
 
-pub struct AESReader<'a, R : Read> {
-    key_manager: &'a AESManager,
+/// Every write is sent as a frame: the plaintext length as a little endian `u32`, followed by the
+/// encrypted blocks. The length tells the reader where the zero padding of the last block starts,
+/// so the plaintext itself may contain zero bytes.
+const LENGTH_HEADER_SIZE: usize = 4;
+
+/// Bounds the memory used before a corrupt length is noticed
+const MAX_READ: usize = 8192;
+
+/// The most plaintext bytes one frame or message may hold
+///
+/// The length in front of a frame can't be authenticated until the whole frame has arrived, so a
+/// reader fails with `InvalidData` on a longer length instead of buffering that many bytes. A
+/// write of more than this sends only the first `MAX_FRAME_SIZE` bytes, and a longer message is
+/// rejected with `InvalidInput`.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Fails with `InvalidData` if a received frame or message claims more than [`MAX_FRAME_SIZE`]
+/// plaintext bytes
+fn check_length(length: usize) -> std::io::Result<()> {
+    if length > MAX_FRAME_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("a frame of {} bytes is over the {} byte limit", length, MAX_FRAME_SIZE)
+        ));
+    }
+    Ok(())
+}
+
+/// Fails with `InvalidInput` if `length` bytes can't be sent as one frame or message
+fn check_send_length(length: usize) -> std::io::Result<u32> {
+    if length > MAX_FRAME_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("a message is limited to {} bytes", MAX_FRAME_SIZE)
+        ));
+    }
+    Ok(length as u32)
+}
+
+/// Encrypted after a zero length header by [`AESWriter::finish`], to mark the end of the stream.
+/// Writes of nothing send no frame, so a real frame never has a length of zero.
+const CLOSE_SENTINEL: [u8; 16] = [0xFF; 16];
+
+pub struct AESReader<'a, R : Read, M : AESBlockCipher = AESManager> {
+    key_manager: &'a M,
     inner: R,
-    internal_buffer: VecDeque<u8>
+    /// Encrypted bytes that do not form a whole frame yet
+    raw_buffer: Vec<u8>,
+    internal_buffer: VecDeque<u8>,
+    closed: bool
 }
 
-impl<'a, R: Read> AESReader<'a, R> {
-    pub fn new(key_manager: &'a AESManager, inner: R) -> Self {
-        AESReader { key_manager, inner, internal_buffer: VecDeque::new() }
+impl<'a, R: Read, M: AESBlockCipher> AESReader<'a, R, M> {
+    pub fn new(key_manager: &'a M, inner: R) -> Self {
+        AESReader { key_manager, inner, raw_buffer: Vec::new(), internal_buffer: VecDeque::new(), closed: false }
+    }
+
+    /// Whether the writer ended the stream with [`AESWriter::finish`]
+    ///
+    /// Reads return nothing once the stream is closed. A stream that ends without being closed may
+    /// have been cut off.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns the inner reader. Decrypted bytes that have not been read yet are lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Reads one message sent with [`AESWriter::write_message`]
+    ///
+    /// Messages and the byte stream of [`Read`] use different framing, so a stream should only be
+    /// read the way it was written.
+    pub fn read_message(&mut self) -> std::io::Result<Vec<u8>> {
+        read_message(self.key_manager, &mut self.inner)
     }
 }
 
-impl<R : Read> Read for AESReader<'_, R> {
+impl<R : Read, M : AESBlockCipher> Read for AESReader<'_, R, M> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut internal_buffer = [0u8; 16];
-        if self.inner.read(&mut internal_buffer)? == 0 {
-            return Ok(0)
+        let available = self.fill_buf()?;
+        let count = buf.len().min(available.len());
+        buf[..count].copy_from_slice(&available[..count]);
+        self.consume(count);
+        Ok(count)
+    }
+}
+
+/// The decrypted bytes are already buffered, so lines can be read without wrapping the reader in
+/// a `BufReader`
+impl<R : Read, M : AESBlockCipher> BufRead for AESReader<'_, R, M> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        while self.internal_buffer.is_empty() && !self.closed {
+            match read_frame(self.key_manager, &mut self.inner, &mut self.raw_buffer, &mut self.internal_buffer)? {
+                Frame::Data => {}
+                Frame::Closed => self.closed = true,
+                Frame::Incomplete => break
+            }
         }
-        let bytes = self.key_manager.decrypt([internal_buffer]);
-        for byte in bytes {
-            self.internal_buffer.push_back(byte);
+        // the front slice only ends early if the buffer wraps around, and the rest is returned
+        // by the next call
+        Ok(self.internal_buffer.as_slices().0)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.internal_buffer.drain(..amt);
+    }
+}
+
+/// Fills `buffer` from `inner`, even if the data arrives in multiple parts
+///
+/// Returns `false` if `inner` ended cleanly before any data, and an error of kind `UnexpectedEof`
+/// if it ended part way through.
+fn fill<R: Read>(inner: &mut R, buffer: &mut [u8]) -> std::io::Result<bool> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match inner.read(&mut buffer[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("stream ended after {} of {} bytes", filled, buffer.len())
+                ))
+            }
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(true)
+}
+
+/// The size of the frame at the start of `raw`, or of its length header if that is incomplete
+fn pending_frame_size(raw: &[u8]) -> usize {
+    if raw.len() < LENGTH_HEADER_SIZE {
+        return LENGTH_HEADER_SIZE;
+    }
+    let length = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as usize;
+    // the closing frame has no length but holds the sentinel block
+    LENGTH_HEADER_SIZE + length.div_ceil(16).max(1) * 16
+}
+
+/// The frame at the start of a stream
+#[derive(Debug, PartialEq)]
+enum Frame {
+    /// Not all of the frame has arrived, or from [`read_frame`], the stream ended before it started
+    Incomplete,
+    /// The frame's bytes were added to the output
+    Data,
+    /// The frame from [`AESWriter::finish`] that ends the stream
+    Closed
+}
+
+/// Decrypts the first frame of `raw` into `output` if all of it has arrived
+///
+/// A zero length frame must hold the [`CLOSE_SENTINEL`], otherwise it was not encrypted with the
+/// same key and an error of kind `InvalidData` is returned.
+fn decode_frame<M: AESBlockCipher>(key_manager: &M, raw: &mut Vec<u8>, output: &mut VecDeque<u8>) -> std::io::Result<Frame> {
+    if raw.len() >= LENGTH_HEADER_SIZE {
+        check_length(u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as usize)?;
+    }
+    let frame_size = pending_frame_size(raw);
+    if raw.len() < frame_size {
+        return Ok(Frame::Incomplete);
+    }
+    let length = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as usize;
+    let blocks: Vec<[u8; 16]> = raw[LENGTH_HEADER_SIZE..frame_size]
+        .chunks_exact(16)
+        .map(|chunk| {
+            let mut block = [0u8; 16];
+            block.copy_from_slice(chunk);
+            block
+        })
+        .collect();
+    let bytes = key_manager.decrypt_blocks(&blocks)?;
+    raw.drain(..frame_size);
+    if length == 0 {
+        return if bytes == CLOSE_SENTINEL {
+            Ok(Frame::Closed)
+        } else {
+            Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "empty frame without the closing sentinel"))
+        };
+    }
+    output.extend(&bytes[..length]);
+    key_manager.record_decrypted(length);
+    Ok(Frame::Data)
+}
+
+/// Reads one frame written by a single call to `write`, adding only its real bytes to `output`
+///
+/// The encrypted bytes are kept in `raw` until the whole frame has arrived, so if `inner` fails
+/// part way through, for example with `WouldBlock`, calling this again carries on from the same
+/// place. Returns [`Frame::Incomplete`] if `inner` ended cleanly before the frame started.
+fn read_frame<R: Read, M: AESBlockCipher>(key_manager: &M, inner: &mut R, raw: &mut Vec<u8>, output: &mut VecDeque<u8>)
+    -> std::io::Result<Frame> {
+    read_frame_with(inner, raw, pending_frame_size, |raw| decode_frame(key_manager, raw, output))
+}
+
+/// Reads into `raw` until `decode` finds a whole frame, where `frame_size` gives the size of the
+/// frame at the start of `raw`, or of its length header if that is incomplete
+fn read_frame_with<R: Read, D>(inner: &mut R, raw: &mut Vec<u8>, frame_size: fn(&[u8]) -> usize, mut decode: D)
+    -> std::io::Result<Frame> where D: FnMut(&mut Vec<u8>) -> std::io::Result<Frame> {
+    loop {
+        let frame = decode(raw)?;
+        if frame != Frame::Incomplete {
+            return Ok(frame);
         }
-        let mut index = 0;
-        while index < buf.len() && !self.internal_buffer.is_empty() {
-            let byte = self.internal_buffer.pop_front().unwrap();
-            if byte == 0 {
-                break;
+        let start = raw.len();
+        let wanted = (frame_size(raw) - start).min(MAX_READ);
+        raw.resize(start + wanted, 0);
+        let result = inner.read(&mut raw[start..]);
+        raw.truncate(start + *result.as_ref().unwrap_or(&0));
+        match result {
+            Ok(0) if start == 0 => return Ok(Frame::Incomplete),
+            Ok(0) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("stream ended {} bytes into a {} byte frame", start, frame_size(raw))
+                ))
             }
-            buf[index] = byte;
-            index += 1;
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+/// Copies decrypted bytes into `buf`, reading another frame first if none are buffered
+fn read_buffered<R: Read, M: AESBlockCipher>(key_manager: &M, inner: &mut R, raw: &mut Vec<u8>, buffer: &mut VecDeque<u8>, buf: &mut [u8])
+    -> std::io::Result<usize> {
+    while buffer.is_empty() {
+        if read_frame(key_manager, inner, raw, buffer)? != Frame::Data {
+            return Ok(0);
+        }
+    }
+    Ok(copy_buffered(buffer, buf))
+}
+
+fn copy_buffered(buffer: &mut VecDeque<u8>, buf: &mut [u8]) -> usize {
+    let mut index = 0;
+    while index < buf.len() && !buffer.is_empty() {
+        buf[index] = buffer.pop_front().unwrap();
+        index += 1;
+    }
+    index
+}
+
+/// Encrypts the message's length as a big endian `u32` followed by the message itself, so unlike
+/// a frame the length is hidden along with the data
+fn encode_message<M: AESBlockCipher>(key_manager: &M, data: &[u8]) -> std::io::Result<Vec<[u8; 16]>> {
+    let length = check_send_length(data.len())?;
+    let mut plaintext = Vec::with_capacity(LENGTH_HEADER_SIZE + data.len());
+    plaintext.extend_from_slice(&length.to_be_bytes());
+    plaintext.extend_from_slice(data);
+    let encrypted = key_manager.encrypt_blocks(&plaintext)?;
+    key_manager.record_encrypted(data.len());
+    Ok(encrypted)
+}
+
+/// The number of plaintext bytes in a message, including its length, from its first block
+fn message_size(first_block: &[u8]) -> usize {
+    let length = u32::from_be_bytes([first_block[0], first_block[1], first_block[2], first_block[3]]) as usize;
+    LENGTH_HEADER_SIZE + length
+}
+
+fn write_message<W: Write, M: AESBlockCipher>(key_manager: &M, inner: &mut W, data: &[u8]) -> std::io::Result<()> {
+    for block in encode_message(key_manager, data)? {
+        inner.write_all(&block)?;
+    }
+    Ok(())
+}
+
+fn read_message<R: Read, M: AESBlockCipher>(key_manager: &M, inner: &mut R) -> std::io::Result<Vec<u8>> {
+    let read_block = |inner: &mut R| -> std::io::Result<[u8; 16]> {
+        let mut block = [0u8; 16];
+        if !fill(inner, &mut block)? {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "stream ended before the message"));
         }
-        Ok(index)
+        Ok(block)
+    };
+
+    let mut plaintext = key_manager.decrypt_blocks(&[read_block(inner)?])?;
+    let total = message_size(&plaintext);
+    check_length(total - LENGTH_HEADER_SIZE)?;
+    // the blocks are read one at a time, so a corrupt length can't cause a huge allocation up front
+    while plaintext.len() < total {
+        let block = read_block(inner)?;
+        plaintext.extend(key_manager.decrypt_blocks(&[block])?);
     }
+    plaintext.truncate(total);
+    key_manager.record_decrypted(total - LENGTH_HEADER_SIZE);
+    Ok(plaintext.split_off(LENGTH_HEADER_SIZE))
 }
 
-pub struct AESWriter<'a, W : Write> {
-    key_manager: &'a AESManager,
-    inner: W,
+/// Encrypts `buf` into a single frame
+fn encode_frame<M: AESBlockCipher>(key_manager: &M, buf: &[u8]) -> std::io::Result<Vec<u8>> {
+    let length = check_send_length(buf.len())?;
+    let encrypted = key_manager.encrypt_blocks(buf)?;
+    key_manager.record_encrypted(buf.len());
+    let mut frame = Vec::with_capacity(LENGTH_HEADER_SIZE + encrypted.len() * 16);
+    frame.extend_from_slice(&length.to_le_bytes());
+    for block in &encrypted {
+        frame.extend_from_slice(block);
+    }
+    Ok(frame)
 }
 
-impl<'a, W: Write> AESWriter<'a, W> {
-    pub fn new(key_manager: &'a AESManager, inner: W) -> Self {
-        AESWriter { key_manager, inner }
+/// Writes as much of `buf` as fits in a single frame
+fn write_frame<W: Write, M: AESBlockCipher>(key_manager: &M, inner: &mut W, buf: &[u8]) -> std::io::Result<usize> {
+    if buf.is_empty() {
+        return Ok(0);
     }
+    let buf = &buf[..buf.len().min(MAX_FRAME_SIZE)];
+    inner.write_all(&encode_frame(key_manager, buf)?)?;
+    Ok(buf.len())
+}
+
+/// The frame written by [`AESWriter::finish`]: a zero length header and the encrypted sentinel
+fn close_frame<M: AESBlockCipher>(key_manager: &M) -> std::io::Result<Vec<u8>> {
+    let sentinel = key_manager.encrypt_blocks(&CLOSE_SENTINEL)?;
+    let mut frame = Vec::with_capacity(LENGTH_HEADER_SIZE + 16);
+    frame.extend_from_slice(&0u32.to_le_bytes());
+    frame.extend_from_slice(&sentinel[0]);
+    Ok(frame)
 }
 
-impl <W : Write> Write for AESWriter<'_, W> {
+/// Encrypts everything written to `inner`
+///
+/// The stream is ended with [`finish`](Self::finish), or when the writer is dropped, which lets
+/// [`AESReader::is_closed`] tell a finished stream from one that was cut off. Like `BufWriter`,
+/// errors while closing on drop are ignored. A writer that has sent a message with
+/// [`write_message`](Self::write_message) is not closed on drop, as the closing frame is not a
+/// message, and neither is one that has written nothing.
+pub struct AESWriter<'a, W : Write, M : AESBlockCipher = AESManager> {
+    key_manager: &'a M,
+    /// Only `None` once [`finish`](Self::finish) or [`into_inner`](Self::into_inner) took it
+    inner: Option<W>,
+    wrote_frames: bool,
+    wrote_messages: bool
+}
+
+impl<'a, W: Write, M: AESBlockCipher> AESWriter<'a, W, M> {
+    pub fn new(key_manager: &'a M, inner: W) -> Self {
+        AESWriter { key_manager, inner: Some(inner), wrote_frames: false, wrote_messages: false }
+    }
+
+    /// Ends the stream with a closing frame and flushes it
+    ///
+    /// Only streams read as frames should be finished, as the closing frame is not a message for
+    /// [`AESReader::read_message`].
+    pub fn finish(mut self) -> std::io::Result<()> {
+        let mut inner = self.inner.take().expect("the inner writer is only taken by consuming the writer");
+        inner.write_all(&close_frame(self.key_manager)?)?;
+        inner.flush()
+    }
+
+    pub fn inner(&self) -> &W {
+        self.inner.as_ref().expect("the inner writer is only taken by consuming the writer")
+    }
+
+    pub fn inner_mut(&mut self) -> &mut W {
+        self.inner.as_mut().expect("the inner writer is only taken by consuming the writer")
+    }
+
+    /// Returns the inner writer without ending the stream, so more can be written to it
+    pub fn into_inner(mut self) -> W {
+        self.inner.take().expect("the inner writer is only taken by consuming the writer")
+    }
+
+    /// Sends `data` as one message, which [`AESReader::read_message`] returns in one piece
+    pub fn write_message(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.wrote_messages = true;
+        write_message(self.key_manager, self.inner_mut(), data)
+    }
+}
+
+impl <W : Write, M : AESBlockCipher> Write for AESWriter<'_, W, M> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let encrypted = self.key_manager.encrypt(buf);
-        for block in &encrypted {
-            self.inner.write_all(block)?;
+        let written = write_frame(self.key_manager, self.inner_mut(), buf)?;
+        self.wrote_frames |= written > 0;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner_mut().flush()
+    }
+}
+
+impl<W : Write, M : AESBlockCipher> Drop for AESWriter<'_, W, M> {
+    fn drop(&mut self) {
+        if !self.wrote_frames || self.wrote_messages {
+            return;
+        }
+        if let Some(inner) = self.inner.as_mut() {
+            let _ = close_frame(self.key_manager).and_then(|frame| inner.write_all(&frame)).and_then(|_| inner.flush());
+        }
+    }
+}
+
+/// The size of the random counter block at the start of every CTR frame
+const CTR_COUNTER_SIZE: usize = 16;
+
+/// How an [`AESStream`] encrypts each frame and message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameCipher {
+    /// Split into blocks that are each encrypted on their own
+    Blocks,
+    /// Sealed with [`AESManager::seal`]
+    Sealed,
+    /// Encrypted with [`AESManager::encrypt_ctr`] from a random counter block
+    Counter
+}
+
+impl FrameCipher {
+    /// The bytes a frame holds on top of its plaintext, not counting the length in front of it
+    fn overhead(self) -> usize {
+        match self {
+            FrameCipher::Counter => CTR_COUNTER_SIZE,
+            _ => GCM_NONCE_SIZE + GCM_TAG_SIZE
+        }
+    }
+
+    fn encrypt(self, manager: &AESManager, plaintext: &[u8]) -> std::io::Result<Vec<u8>> {
+        if self != FrameCipher::Counter {
+            return Ok(manager.seal(plaintext));
+        }
+        let mut counter = [0u8; CTR_COUNTER_SIZE];
+        OsRng.fill_bytes(&mut counter);
+        let mut output = counter.to_vec();
+        output.extend(manager.encrypt_ctr(&counter, plaintext)?);
+        Ok(output)
+    }
+
+    fn decrypt(self, manager: &AESManager, encrypted: &[u8]) -> std::io::Result<Vec<u8>> {
+        if self != FrameCipher::Counter {
+            return manager.open(encrypted).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e));
         }
+        if encrypted.len() < CTR_COUNTER_SIZE {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "a CTR frame is shorter than its counter"));
+        }
+        let (counter, ciphertext) = encrypted.split_at(CTR_COUNTER_SIZE);
+        Ok(manager.decrypt_ctr(counter.try_into().expect("the counter is 16 bytes"), ciphertext)?)
+    }
+}
+
+/// ChaCha20-Poly1305 has no blocks, so an [`AESStream`] with such a key sends every write and
+/// message as a sealed frame instead: the length of the output of [`AESManager::seal`] as a little
+/// endian `u32`, followed by that output. CTR frames have the same layout, with the output of
+/// [`FrameCipher::encrypt`] instead.
+fn encode_sealed_frame(cipher: FrameCipher, manager: &AESManager, buf: &[u8]) -> std::io::Result<Vec<u8>> {
+    check_send_length(buf.len())?;
+    let sealed = cipher.encrypt(manager, buf)?;
+    let mut frame = Vec::with_capacity(LENGTH_HEADER_SIZE + sealed.len());
+    frame.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&sealed);
+    Ok(frame)
+}
+
+fn pending_sealed_frame_size(raw: &[u8]) -> usize {
+    if raw.len() < LENGTH_HEADER_SIZE {
+        return LENGTH_HEADER_SIZE;
+    }
+    LENGTH_HEADER_SIZE + u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as usize
+}
+
+/// Fails with `InvalidData` if the sealed frame at the start of `raw` claims too many bytes
+fn check_sealed_frame_size(cipher: FrameCipher, raw: &[u8]) -> std::io::Result<()> {
+    check_length(pending_sealed_frame_size(raw).saturating_sub(LENGTH_HEADER_SIZE + cipher.overhead()))
+}
+
+/// Opens the first sealed frame of `raw` into `output` if all of it has arrived, failing with
+/// `InvalidData` if it was changed or sealed with another key
+fn decode_sealed_frame(cipher: FrameCipher, manager: &AESManager, raw: &mut Vec<u8>, output: &mut VecDeque<u8>) -> std::io::Result<Frame> {
+    check_sealed_frame_size(cipher, raw)?;
+    let frame_size = pending_sealed_frame_size(raw);
+    if raw.len() < frame_size {
+        return Ok(Frame::Incomplete);
+    }
+    let opened = cipher.decrypt(manager, &raw[LENGTH_HEADER_SIZE..frame_size])?;
+    raw.drain(..frame_size);
+    output.extend(&opened);
+    Ok(Frame::Data)
+}
+
+/// Encrypts everything written to `inner` and decrypts everything read from it, for streams such
+/// as sockets that are used in both directions
+///
+/// Unlike [`AESReader`] and [`AESWriter`] the stream owns its manager, so it can be stored without
+/// also keeping the manager alive. It also works with a ChaCha20-Poly1305 manager, whose frames
+/// and messages are sealed rather than split into blocks, and [`new_gcm`](Self::new_gcm) seals
+/// them with AES-GCM for an AES manager, while [`new_ctr`](Self::new_ctr) encrypts them in CTR mode.
+pub struct AESStream<S> {
+    manager: AESManager,
+    inner: S,
+    /// How frames are encrypted if the manager is for AES
+    mode: FrameCipher,
+    /// Encrypted bytes that do not form a whole frame yet
+    raw_read_buffer: Vec<u8>,
+    read_buffer: VecDeque<u8>
+}
+
+impl<S> AESStream<S> {
+    pub fn new(manager: AESManager, inner: S) -> Self {
+        AESStream { manager, inner, mode: FrameCipher::Blocks, raw_read_buffer: Vec::new(), read_buffer: VecDeque::new() }
+    }
+
+    /// Creates a stream that seals every frame and message with [`AESManager::seal`], which is
+    /// AES-GCM for an AES manager, instead of splitting them into unauthenticated blocks
+    ///
+    /// Both ends must be made the same way.
+    pub fn new_gcm(manager: AESManager, inner: S) -> Self {
+        AESStream { mode: FrameCipher::Sealed, ..AESStream::new(manager, inner) }
+    }
+
+    /// Creates a stream that encrypts every frame and message with AES-CTR from a random counter
+    /// block, see [`AESManager::encrypt_ctr`], so nothing is padded to a whole block
+    ///
+    /// Like the blocks of [`new`](Self::new), CTR frames are not authenticated. Both ends must be
+    /// made the same way, and a ChaCha20-Poly1305 manager still seals its frames.
+    pub fn new_ctr(manager: AESManager, inner: S) -> Self {
+        AESStream { mode: FrameCipher::Counter, ..AESStream::new(manager, inner) }
+    }
+
+    pub fn manager(&self) -> &AESManager {
+        &self.manager
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Encrypts everything after this with `manager`, returning the previous one
+    ///
+    /// Only the bytes of a frame that has not completely arrived yet are decrypted with the new key.
+    pub fn replace_manager(&mut self, manager: AESManager) -> AESManager {
+        std::mem::replace(&mut self.manager, manager)
+    }
+
+    /// Whether frames are sealed, because the stream was made with [`new_gcm`](Self::new_gcm) or
+    /// the manager is for ChaCha20-Poly1305
+    pub fn is_sealed(&self) -> bool {
+        self.frame_cipher() == FrameCipher::Sealed
+    }
+
+    /// Whether frames are encrypted in CTR mode, because the stream was made with
+    /// [`new_ctr`](Self::new_ctr) and the manager is for AES
+    pub fn is_counter_mode(&self) -> bool {
+        self.frame_cipher() == FrameCipher::Counter
+    }
+
+    fn frame_cipher(&self) -> FrameCipher {
+        if self.manager.cipher_choice() == CipherChoice::ChaCha20Poly1305 {
+            return FrameCipher::Sealed;
+        }
+        self.mode
+    }
+
+    /// Returns the inner stream. Decrypted bytes that have not been read yet are lost.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Returns the manager and the inner stream. Decrypted bytes that have not been read yet are lost.
+    pub fn into_parts(self) -> (AESManager, S) {
+        (self.manager, self.inner)
+    }
+
+    /// Splits the stream into one that only reads and one that only writes, both with the same key
+    ///
+    /// `split` divides the inner stream. The bytes that have been read but not returned yet stay
+    /// with the reading half.
+    pub fn split<R, W, F>(self, split: F) -> std::io::Result<(AESStream<R>, AESStream<W>)>
+        where F: FnOnce(S) -> std::io::Result<(R, W)> {
+        let writer_manager = self.manager.clone();
+        let (reader, writer) = split(self.inner)?;
+        let writer = AESStream::new(writer_manager, writer).with_mode(self.mode);
+        Ok((
+            AESStream { manager: self.manager, inner: reader, mode: self.mode, raw_read_buffer: self.raw_read_buffer, read_buffer: self.read_buffer },
+            writer
+        ))
+    }
+
+    /// A stream over `inner` with a copy of this stream's key, which encrypts the same way
+    pub fn with_copied_key<T>(&self, inner: T) -> AESStream<T> {
+        AESStream::new(self.manager.clone(), inner).with_mode(self.mode)
+    }
+
+    /// Starts the stream with `raw`, encrypted bytes that were already read from the inner stream
+    pub(crate) fn with_buffered(mut self, raw: Vec<u8>) -> Self {
+        self.raw_read_buffer = raw;
+        self
+    }
+
+    fn with_mode(mut self, mode: FrameCipher) -> Self {
+        self.mode = mode;
+        self
+    }
+}
+
+impl<S : Write> AESStream<S> {
+
+    /// Sends `data` as one message. See [`AESWriter::write_message`].
+    pub fn write_message(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let cipher = self.frame_cipher();
+        if cipher != FrameCipher::Blocks {
+            return self.inner.write_all(&encode_sealed_frame(cipher, &self.manager, data)?);
+        }
+        write_message(&self.manager, &mut self.inner, data)
+    }
+}
+
+impl<S : Read> AESStream<S> {
+
+    /// Reads one message. See [`AESReader::read_message`].
+    ///
+    /// The encrypted bytes are kept until the whole message has arrived, so if `inner` fails part
+    /// way through, for example with `WouldBlock`, calling this again carries on from the same
+    /// place.
+    pub fn read_message(&mut self) -> std::io::Result<Vec<u8>> {
+        loop {
+            if let Some(message) = self.decode_message()? {
+                return Ok(message);
+            }
+            let start = self.raw_read_buffer.len();
+            let wanted = (self.pending_message_size()? - start).min(MAX_READ);
+            self.raw_read_buffer.resize(start + wanted, 0);
+            let result = self.inner.read(&mut self.raw_read_buffer[start..]);
+            self.raw_read_buffer.truncate(start + *result.as_ref().unwrap_or(&0));
+            match result {
+                Ok(0) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!("stream ended {} bytes into a {} byte message", start, self.pending_message_size()?)
+                    ))
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    /// Reads one message like [`read_message`](Self::read_message), but returns `None` instead of
+    /// failing with `WouldBlock` if a non-blocking `inner` has no whole message yet
+    pub fn try_read_message(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        match self.read_message() {
+            Ok(message) => Ok(Some(message)),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// The size of the encrypted message at the start of the raw buffer, or of the part that
+    /// holds its length if that is incomplete
+    fn pending_message_size(&self) -> std::io::Result<usize> {
+        let raw = &self.raw_read_buffer;
+        let cipher = self.frame_cipher();
+        if cipher != FrameCipher::Blocks {
+            check_sealed_frame_size(cipher, raw)?;
+            return Ok(pending_sealed_frame_size(raw));
+        }
+        if raw.len() < 16 {
+            return Ok(16);
+        }
+        let mut first_block = [0u8; 16];
+        first_block.copy_from_slice(&raw[..16]);
+        let size = message_size(&self.manager.decrypt_blocks([first_block])?);
+        check_length(size - LENGTH_HEADER_SIZE)?;
+        Ok(size.div_ceil(16) * 16)
+    }
+
+    /// Decrypts the message at the start of the raw buffer if all of it has arrived
+    fn decode_message(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        let size = self.pending_message_size()?;
+        if self.raw_read_buffer.len() < size {
+            return Ok(None);
+        }
+        let cipher = self.frame_cipher();
+        if cipher != FrameCipher::Blocks {
+            let mut message = VecDeque::new();
+            decode_sealed_frame(cipher, &self.manager, &mut self.raw_read_buffer, &mut message)?;
+            return Ok(Some(message.into()));
+        }
+        let blocks: Vec<[u8; 16]> = self.raw_read_buffer.drain(..size)
+            .collect::<Vec<u8>>()
+            .chunks_exact(16)
+            .map(|chunk| {
+                let mut block = [0u8; 16];
+                block.copy_from_slice(chunk);
+                block
+            })
+            .collect();
+        let mut plaintext = self.manager.decrypt_blocks(&blocks)?;
+        plaintext.truncate(message_size(&plaintext));
+        Ok(Some(plaintext.split_off(LENGTH_HEADER_SIZE)))
+    }
+}
+
+impl<S : Read> Read for AESStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let cipher = self.frame_cipher();
+        if cipher == FrameCipher::Blocks {
+            return read_buffered(&self.manager, &mut self.inner, &mut self.raw_read_buffer, &mut self.read_buffer, buf);
+        }
+        while self.read_buffer.is_empty() {
+            let (manager, buffer) = (&self.manager, &mut self.read_buffer);
+            let frame = read_frame_with(&mut self.inner, &mut self.raw_read_buffer, pending_sealed_frame_size, |raw| {
+                decode_sealed_frame(cipher, manager, raw, buffer)
+            })?;
+            if frame != Frame::Data {
+                return Ok(0);
+            }
+        }
+        Ok(copy_buffered(&mut self.read_buffer, buf))
+    }
+}
+
+impl<S : Write> Write for AESStream<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let cipher = self.frame_cipher();
+        if cipher != FrameCipher::Blocks && !buf.is_empty() {
+            let buf = &buf[..buf.len().min(MAX_FRAME_SIZE)];
+            self.inner.write_all(&encode_sealed_frame(cipher, &self.manager, buf)?)?;
+            return Ok(buf.len());
+        }
+        write_frame(&self.manager, &mut self.inner, buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The size of the random nonce at the start of every GCM frame
+const GCM_NONCE_SIZE: usize = 12;
+/// The size of the authentication tag at the end of every GCM frame
+const GCM_TAG_SIZE: usize = 16;
+
+type Aes192Gcm = AesGcm<aes_gcm::aes::Aes192, U12>;
+
+/// AES in Galois/Counter Mode, with the key size of the manager it was made from
+pub(super) enum GcmCipher {
+    Aes128(Aes128Gcm),
+    Aes192(Aes192Gcm),
+    Aes256(Aes256Gcm)
+}
+
+impl GcmCipher {
+    pub(super) fn new(manager: &AESManager) -> Self {
+        let key = manager.key_bytes();
+        match key.len() {
+            16 => GcmCipher::Aes128(Aes128Gcm::new_from_slice(key).expect("the key is 16 bytes")),
+            24 => GcmCipher::Aes192(Aes192Gcm::new_from_slice(key).expect("the key is 24 bytes")),
+            _ => GcmCipher::Aes256(Aes256Gcm::new_from_slice(key).expect("a manager's key is 16, 24 or 32 bytes"))
+        }
+    }
+
+    pub(super) fn encrypt(&self, nonce: &[u8; GCM_NONCE_SIZE], payload: Payload) -> Vec<u8> {
+        let nonce = GenericArray::from_slice(nonce);
+        match self {
+            GcmCipher::Aes128(cipher) => cipher.encrypt(nonce, payload),
+            GcmCipher::Aes192(cipher) => cipher.encrypt(nonce, payload),
+            GcmCipher::Aes256(cipher) => cipher.encrypt(nonce, payload)
+        }.expect("GCM can encrypt any frame that fits the u32 length")
+    }
+
+    pub(super) fn decrypt(&self, nonce: &[u8], payload: Payload) -> Option<Vec<u8>> {
+        let nonce = GenericArray::from_slice(nonce);
+        match self {
+            GcmCipher::Aes128(cipher) => cipher.decrypt(nonce, payload),
+            GcmCipher::Aes192(cipher) => cipher.decrypt(nonce, payload),
+            GcmCipher::Aes256(cipher) => cipher.decrypt(nonce, payload)
+        }.ok()
+    }
+}
+
+/// Encrypts and authenticates every write with AES-GCM, using the manager's key
+///
+/// Each write is sent as a frame of the plaintext length as a little endian `u32`, a fresh random
+/// 12 byte nonce, the ciphertext and the 16 byte tag. The length is authenticated along with the
+/// ciphertext, so [`AESGCMReader`] notices if any part of the frame was changed. The manager's key
+/// size is used, so a 256 bit manager gives AES-256-GCM.
+pub struct AESGCMWriter<W : Write> {
+    cipher: GcmCipher,
+    inner: W
+}
+
+impl<W : Write> AESGCMWriter<W> {
+    pub fn new(manager: &AESManager, inner: W) -> Self {
+        AESGCMWriter { cipher: GcmCipher::new(manager), inner }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W : Write> Write for AESGCMWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let buf = &buf[..buf.len().min(MAX_FRAME_SIZE)];
+        let header = (buf.len() as u32).to_le_bytes();
+        let mut nonce = [0u8; GCM_NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let sealed = self.cipher.encrypt(&nonce, Payload { msg: buf, aad: &header });
+
+        let mut frame = Vec::with_capacity(LENGTH_HEADER_SIZE + GCM_NONCE_SIZE + sealed.len());
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(&nonce);
+        frame.extend_from_slice(&sealed);
+        self.inner.write_all(&frame)?;
         Ok(buf.len())
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
         self.inner.flush()
     }
+}
+
+/// Decrypts a stream written by [`AESGCMWriter`], failing with `InvalidData` if a frame was
+/// changed or was not encrypted with the same key
+pub struct AESGCMReader<R : Read> {
+    cipher: GcmCipher,
+    inner: R,
+    /// Encrypted bytes that do not form a whole frame yet
+    raw_buffer: Vec<u8>,
+    internal_buffer: VecDeque<u8>
+}
+
+impl<R : Read> AESGCMReader<R> {
+    pub fn new(manager: &AESManager, inner: R) -> Self {
+        AESGCMReader { cipher: GcmCipher::new(manager), inner, raw_buffer: Vec::new(), internal_buffer: VecDeque::new() }
+    }
 
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
 
+    /// Reads and checks one frame, returning `false` if `inner` ended cleanly before it started
+    ///
+    /// The encrypted bytes are kept until the whole frame has arrived, so if `inner` fails part
+    /// way through, for example with `WouldBlock`, calling this again carries on from the same
+    /// place.
+    fn read_frame(&mut self) -> std::io::Result<bool> {
+        let (cipher, buffer) = (&self.cipher, &mut self.internal_buffer);
+        let frame = read_frame_with(&mut self.inner, &mut self.raw_buffer, pending_gcm_frame_size, |raw| {
+            decode_gcm_frame(cipher, raw, buffer)
+        })?;
+        Ok(frame == Frame::Data)
+    }
+}
+
+/// The size of the [`AESGCMWriter`] frame at the start of `raw`, or of its length header if that
+/// is incomplete
+fn pending_gcm_frame_size(raw: &[u8]) -> usize {
+    if raw.len() < LENGTH_HEADER_SIZE {
+        return LENGTH_HEADER_SIZE;
+    }
+    LENGTH_HEADER_SIZE + GCM_NONCE_SIZE + u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as usize + GCM_TAG_SIZE
+}
+
+/// Checks and decrypts the first [`AESGCMWriter`] frame of `raw` into `output` if all of it has
+/// arrived
+fn decode_gcm_frame(cipher: &GcmCipher, raw: &mut Vec<u8>, output: &mut VecDeque<u8>) -> std::io::Result<Frame> {
+    if raw.len() < LENGTH_HEADER_SIZE {
+        return Ok(Frame::Incomplete);
+    }
+    check_length(u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as usize)?;
+    let frame_size = pending_gcm_frame_size(raw);
+    if raw.len() < frame_size {
+        return Ok(Frame::Incomplete);
+    }
+    let (header, frame) = raw[..frame_size].split_at(LENGTH_HEADER_SIZE);
+    let (nonce, sealed) = frame.split_at(GCM_NONCE_SIZE);
+    let plaintext = cipher.decrypt(nonce, Payload { msg: sealed, aad: header })
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "GCM frame failed authentication"))?;
+    raw.drain(..frame_size);
+    output.extend(plaintext);
+    Ok(Frame::Data)
+}
+
+impl<R : Read> Read for AESGCMReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.internal_buffer.is_empty() {
+            if !self.read_frame()? {
+                return Ok(0);
+            }
+        }
+        let count = buf.len().min(self.internal_buffer.len());
+        for (slot, byte) in buf.iter_mut().zip(self.internal_buffer.drain(..count)) {
+            *slot = byte;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(feature = "async")]
+pub use self::asynchronous::{AsyncAESReader, AsyncAESWriter};
+
+#[cfg(feature = "async")]
+mod asynchronous {
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+    use std::task::{ready, Context, Poll};
+
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+    use crate::encryption::aes::{AESBlockCipher, AESManager};
+
+    use super::{check_length, close_frame, decode_frame, encode_frame, encode_message, encode_sealed_frame, message_size, AESStream, Frame,
+                FrameCipher, LENGTH_HEADER_SIZE, MAX_FRAME_SIZE};
+
+    impl<S : AsyncRead + AsyncWrite + Unpin> AESStream<S> {
+
+        /// Sends `data` as one message, in the same format as [`AESStream::write_message`]
+        pub async fn write_message_async(&mut self, data: &[u8]) -> std::io::Result<()> {
+            let cipher = self.frame_cipher();
+            if cipher != FrameCipher::Blocks {
+                let frame = encode_sealed_frame(cipher, &self.manager, data)?;
+                return self.inner.write_all(&frame).await;
+            }
+            let encrypted: Vec<u8> = encode_message(&self.manager, data)?.concat();
+            self.inner.write_all(&encrypted).await
+        }
+
+        /// Reads one message, in the same format as [`AESStream::read_message`]
+        pub async fn read_message_async(&mut self) -> std::io::Result<Vec<u8>> {
+            let cipher = self.frame_cipher();
+            if cipher != FrameCipher::Blocks {
+                let mut header = [0u8; LENGTH_HEADER_SIZE];
+                self.inner.read_exact(&mut header).await?;
+                let length = u32::from_le_bytes(header) as usize;
+                check_length(length.saturating_sub(cipher.overhead()))?;
+                // read as it arrives, so a corrupt length can't cause a huge allocation up front
+                let mut sealed = Vec::new();
+                (&mut self.inner).take(length as u64).read_to_end(&mut sealed).await?;
+                if sealed.len() < length {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                return cipher.decrypt(&self.manager, &sealed);
+            }
+            let mut block = [0u8; 16];
+            self.inner.read_exact(&mut block).await?;
+            let mut plaintext = self.manager.decrypt_blocks([block])?;
+            let total = message_size(&plaintext);
+            check_length(total - LENGTH_HEADER_SIZE)?;
+            while plaintext.len() < total {
+                self.inner.read_exact(&mut block).await?;
+                plaintext.extend(self.manager.decrypt_blocks([block])?);
+            }
+            plaintext.truncate(total);
+            Ok(plaintext.split_off(LENGTH_HEADER_SIZE))
+        }
+
+        /// Flushes the inner stream
+        pub async fn flush_async(&mut self) -> std::io::Result<()> {
+            self.inner.flush().await
+        }
+    }
+
+    /// Decrypts a stream written by [`AsyncAESWriter`] or [`AESWriter`](super::AESWriter)
+    pub struct AsyncAESReader<'a, R : AsyncRead + Unpin, M : AESBlockCipher = AESManager> {
+        key_manager: &'a M,
+        inner: R,
+        /// Encrypted bytes that do not form a whole frame yet
+        raw_buffer: Vec<u8>,
+        internal_buffer: VecDeque<u8>,
+        closed: bool
+    }
+
+    impl<'a, R: AsyncRead + Unpin, M: AESBlockCipher> AsyncAESReader<'a, R, M> {
+        pub fn new(key_manager: &'a M, inner: R) -> Self {
+            AsyncAESReader { key_manager, inner, raw_buffer: Vec::new(), internal_buffer: VecDeque::new(), closed: false }
+        }
+
+        /// Whether the writer ended the stream, see [`AESReader::is_closed`](super::AESReader::is_closed)
+        pub fn is_closed(&self) -> bool {
+            self.closed
+        }
+    }
+
+    impl<R : AsyncRead + Unpin, M : AESBlockCipher> AsyncRead for AsyncAESReader<'_, R, M> {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            let this = self.get_mut();
+            loop {
+                if !this.internal_buffer.is_empty() || buf.remaining() == 0 {
+                    let count = buf.remaining().min(this.internal_buffer.len());
+                    let bytes: Vec<u8> = this.internal_buffer.drain(..count).collect();
+                    buf.put_slice(&bytes);
+                    return Poll::Ready(Ok(()));
+                }
+                if this.closed {
+                    return Poll::Ready(Ok(()));
+                }
+                match decode_frame(this.key_manager, &mut this.raw_buffer, &mut this.internal_buffer)? {
+                    Frame::Data => continue,
+                    Frame::Closed => {
+                        this.closed = true;
+                        return Poll::Ready(Ok(()));
+                    }
+                    Frame::Incomplete => {}
+                }
+
+                let mut chunk = [0u8; 4096];
+                let mut chunk_buf = ReadBuf::new(&mut chunk);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+                let read = chunk_buf.filled();
+                if read.is_empty() {
+                    return if this.raw_buffer.is_empty() {
+                        Poll::Ready(Ok(()))
+                    } else {
+                        Poll::Ready(Err(std::io::Error::new(
+                            std::io::ErrorKind::UnexpectedEof,
+                            format!("stream ended {} bytes into a frame", this.raw_buffer.len())
+                        )))
+                    };
+                }
+                this.raw_buffer.extend_from_slice(read);
+            }
+        }
+    }
+
+    /// Encrypts everything written to it, in the same frames as [`AESWriter`](super::AESWriter)
+    ///
+    /// A write is accepted once it is encrypted, and is sent to `inner` by the next write or by a
+    /// flush, so the writer must be flushed or shut down before it is dropped.
+    ///
+    /// ```no_run
+    /// use secure_communication::encryption::aes::{AESManager, KeySize};
+    /// use secure_communication::encryption::aes::aes_stream::AsyncAESWriter;
+    /// use tokio::io::AsyncWriteExt;
+    /// use tokio::net::TcpStream;
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let manager = AESManager::new(KeySize::K256);
+    /// let stream = TcpStream::connect("127.0.0.1:8080").await?;
+    /// let mut writer = AsyncAESWriter::new(&manager, stream);
+    /// writer.write_all(b"Hello, World!").await?;
+    /// writer.flush().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub struct AsyncAESWriter<'a, W : AsyncWrite + Unpin, M : AESBlockCipher = AESManager> {
+        key_manager: &'a M,
+        inner: W,
+        /// Encrypted frames that have not been written to `inner` yet
+        pending: Vec<u8>
+    }
+
+    impl<'a, W: AsyncWrite + Unpin, M: AESBlockCipher> AsyncAESWriter<'a, W, M> {
+        pub fn new(key_manager: &'a M, inner: W) -> Self {
+            AsyncAESWriter { key_manager, inner, pending: Vec::new() }
+        }
+
+        /// Ends the stream with the same closing frame as [`AESWriter::finish`](super::AESWriter::finish)
+        pub async fn finish(mut self) -> std::io::Result<()> {
+            self.flush().await?;
+            self.inner.write_all(&close_frame(self.key_manager)?).await?;
+            self.inner.flush().await
+        }
+
+        fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            while !self.pending.is_empty() {
+                let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+                if written == 0 {
+                    return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+                }
+                self.pending.drain(..written);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl<W : AsyncWrite + Unpin, M : AESBlockCipher> AsyncWrite for AsyncAESWriter<'_, W, M> {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            let this = self.get_mut();
+            ready!(this.poll_write_pending(cx))?;
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            let buf = &buf[..buf.len().min(MAX_FRAME_SIZE)];
+            this.pending = encode_frame(this.key_manager, buf)?;
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            let this = self.get_mut();
+            ready!(this.poll_write_pending(cx))?;
+            Pin::new(&mut this.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            let this = self.get_mut();
+            ready!(this.poll_write_pending(cx))?;
+            Pin::new(&mut this.inner).poll_shutdown(cx)
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::encryption::aes::{AESManager, KeySize};
-    use std::string::FromUtf8Error;
+    use crate::encryption::aes::{AESManager, KeySize, MeteredAesManager};
     use super::*;
 
     const TEST_MESSAGE: &str = "Hello World";
 
+    /// Simulates a stream that delivers data in very small segments
+    struct ReadOneByteAtATime<R: Read>(R);
+
+    impl<R: Read> Read for ReadOneByteAtATime<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let end = buf.len().min(1);
+            self.0.read(&mut buf[..end])
+        }
+    }
+
+    #[test]
+    fn partial_reads() {
+        let key = AESManager::new(KeySize::K256);
+        let longer = TEST_MESSAGE.repeat(5);
+        let mut array: Vec<u8> = Vec::new();
+        {
+            let mut writer = AESWriter::new(&key, &mut array);
+            write!(writer, "{}", longer).unwrap();
+        }
+        let mut reader = AESReader::new(&key, ReadOneByteAtATime(std::io::Cursor::new(array)));
+        let mut string = String::new();
+        reader.read_to_string(&mut string).unwrap();
+        assert_eq!(string, longer);
+    }
+
+    /// Delivers a few bytes at a time, failing with `WouldBlock` between them like a non-blocking socket
+    struct NonBlocking<R: Read> {
+        inner: R,
+        ready: bool
+    }
+
+    impl<R: Read> Read for NonBlocking<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.ready = !self.ready;
+            if !self.ready {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            let end = buf.len().min(5);
+            self.inner.read(&mut buf[..end])
+        }
+    }
+
+    #[test]
+    fn would_block_part_way_through_a_block() {
+        let key = AESManager::new(KeySize::K256);
+        let longer = TEST_MESSAGE.repeat(5);
+        let mut array: Vec<u8> = Vec::new();
+        {
+            let mut writer = AESWriter::new(&key, &mut array);
+            write!(writer, "{}", longer).unwrap();
+            write!(writer, "{}", TEST_MESSAGE).unwrap();
+        }
+        let mut reader = AESReader::new(&key, NonBlocking { inner: std::io::Cursor::new(array), ready: false });
+        let mut output = Vec::new();
+        let mut buffer = [0u8; 8];
+        let mut would_block = 0;
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => output.extend_from_slice(&buffer[..read]),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => would_block += 1,
+                Err(e) => panic!("{}", e)
+            }
+        }
+        assert!(would_block > 16, "only blocked {} times", would_block);
+        assert_eq!(String::from_utf8(output).unwrap(), longer + TEST_MESSAGE);
+    }
+
+    #[test]
+    fn gcm_would_block_part_way_through_a_frame() {
+        let manager = AESManager::new(KeySize::K128);
+        let longer = TEST_MESSAGE.repeat(5);
+        let mut encrypted = Vec::new();
+        {
+            let mut writer = AESGCMWriter::new(&manager, &mut encrypted);
+            write!(writer, "{}", longer).unwrap();
+            write!(writer, "{}", TEST_MESSAGE).unwrap();
+        }
+        let mut reader = AESGCMReader::new(&manager, NonBlocking { inner: std::io::Cursor::new(encrypted), ready: false });
+        let mut output = Vec::new();
+        let mut buffer = [0u8; 8];
+        let mut would_block = 0;
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => output.extend_from_slice(&buffer[..read]),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => would_block += 1,
+                Err(e) => panic!("{}", e)
+            }
+        }
+        assert!(would_block > 16, "only blocked {} times", would_block);
+        assert_eq!(String::from_utf8(output).unwrap(), longer + TEST_MESSAGE);
+    }
+
+    #[test]
+    fn truncated_block() {
+        let key = AESManager::new(KeySize::K128);
+        let mut array: Vec<u8> = Vec::new();
+        {
+            let mut writer = AESWriter::new(&key, &mut array);
+            write!(writer, "{}", TEST_MESSAGE).unwrap();
+        }
+        array.truncate(LENGTH_HEADER_SIZE + 10);
+        let mut reader = AESReader::new(&key, &*array);
+        let mut buffer = [0u8; 16];
+        let error = reader.read(&mut buffer).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn dropping_a_writer_closes_the_stream() {
+        let key = AESManager::new(KeySize::K128);
+        let mut dropped: Vec<u8> = Vec::new();
+        {
+            let mut writer = AESWriter::new(&key, &mut dropped);
+            write!(writer, "{}", TEST_MESSAGE).unwrap();
+        }
+        let mut reader = AESReader::new(&key, &*dropped);
+        let mut string = String::new();
+        reader.read_to_string(&mut string).unwrap();
+        assert_eq!(string, TEST_MESSAGE);
+        assert!(reader.is_closed());
+
+        // a writer of messages, or one taken apart with into_inner, is left open
+        let mut messages: Vec<u8> = Vec::new();
+        for message in [&b"first"[..], b"second"] {
+            let mut writer = AESWriter::new(&key, &mut messages);
+            writer.write_message(message).unwrap();
+        }
+        let mut reader = AESReader::new(&key, &*messages);
+        assert_eq!(reader.read_message().unwrap(), b"first");
+        assert_eq!(reader.read_message().unwrap(), b"second");
+
+        let mut writer = AESWriter::new(&key, Vec::new());
+        write!(writer, "{}", TEST_MESSAGE).unwrap();
+        let written = writer.inner().len();
+        assert_eq!(writer.into_inner().len(), written);
+    }
+
+    #[test]
+    fn closing_sentinel() {
+        let key = AESManager::new(KeySize::K128);
+        let mut writer = AESWriter::new(&key, Vec::new());
+        write!(writer, "{}", TEST_MESSAGE).unwrap();
+        let mut open = writer.inner().clone();
+        writer.finish().unwrap();
+
+        let mut finished: Vec<u8> = Vec::new();
+        let mut writer = AESWriter::new(&key, &mut finished);
+        write!(writer, "{}", TEST_MESSAGE).unwrap();
+        writer.finish().unwrap();
+        // anything after the sentinel is not read
+        finished.extend_from_slice(&open);
+
+        let mut reader = AESReader::new(&key, &*finished);
+        let mut string = String::new();
+        reader.read_to_string(&mut string).unwrap();
+        assert_eq!(string, TEST_MESSAGE);
+        assert!(reader.is_closed());
+        assert_eq!(reader.read(&mut [0u8; 16]).unwrap(), 0);
+        assert_eq!(reader.into_inner().len(), open.len());
+
+        let mut reader = AESReader::new(&key, &*open);
+        string.clear();
+        reader.read_to_string(&mut string).unwrap();
+        assert_eq!(string, TEST_MESSAGE);
+        assert!(!reader.is_closed());
+
+        // an empty frame that is not the sentinel
+        open.extend_from_slice(&0u32.to_le_bytes());
+        open.extend_from_slice(&[0u8; 16]);
+        let mut reader = AESReader::new(&key, &*open);
+        let error = reader.read_to_string(&mut String::new()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
     #[test]
     fn read_and_write_128() {
         let key = AESManager::new(KeySize::K128);
@@ -80,11 +1312,8 @@ mod tests {
             let mut writer = AESWriter::new(&key, &mut array);
             write!(writer, "{}", TEST_MESSAGE).unwrap();
         }
-        match String::from_utf8(array.clone()) {
-            Ok(o) => {
-                assert_ne!(o, TEST_MESSAGE)
-            }
-            Err(_) => {}
+        if let Ok(o) = String::from_utf8(array.clone()) {
+            assert_ne!(o, TEST_MESSAGE)
         }
         let mut reader = AESReader::new(&key, &*array);
         let mut string = [0u8; 32];
@@ -109,16 +1338,377 @@ mod tests {
             let mut writer = AESWriter::new(&key, &mut array);
             write!(writer, "{}", longer).unwrap();
         }
-        match String::from_utf8(array.clone()) {
-            Ok(o) => {
-                assert_ne!(o, longer)
-            }
-            Err(_) => {}
+        if let Ok(o) = String::from_utf8(array.clone()) {
+            assert_ne!(o, longer)
         }
         let mut reader = AESReader::new(&key, &*array);
         let mut string = String::new();
-        let length = reader.read_to_string(&mut string).unwrap();
+        reader.read_to_string(&mut string).unwrap();
         assert_eq!(string, longer);
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn embedded_null_bytes() {
+        let key = AESManager::new(KeySize::K256);
+        let message = b"\0leading\0\0middle\0trailing\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+        let mut array: Vec<u8> = Vec::new();
+        {
+            let mut writer = AESWriter::new(&key, &mut array);
+            writer.write_all(message).unwrap();
+            writer.write_all(&[0u8; 3]).unwrap();
+        }
+        let mut output = Vec::new();
+        AESReader::new(&key, &*array).read_to_end(&mut output).unwrap();
+        let mut expected = message.to_vec();
+        expected.extend_from_slice(&[0u8; 3]);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let key = AESManager::new(KeySize::K128);
+        // only the header has arrived, so nothing past it may be buffered
+        let header = ((MAX_FRAME_SIZE + 1) as u32).to_le_bytes();
+        let error = AESReader::new(&key, &header[..]).read(&mut [0u8; 16]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        let error = AESGCMReader::new(&key, &header[..]).read(&mut [0u8; 16]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        let sealed_header = u32::MAX.to_le_bytes();
+        let chacha = AESManager::new(CipherChoice::ChaCha20Poly1305);
+        let error = AESStream::new(chacha, std::io::Cursor::new(sealed_header.to_vec())).read_message().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        let first_block = key.encrypt_blocks(((MAX_FRAME_SIZE + 1) as u32).to_be_bytes()).unwrap().concat();
+        let error = AESReader::new(&key, &first_block[..]).read_message().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        let mut stream = AESStream::new(key, std::io::Cursor::new(Vec::new()));
+        let error = stream.write_message(&vec![0u8; MAX_FRAME_SIZE + 1]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(stream.into_inner().into_inner().is_empty());
+    }
+
+    #[test]
+    fn metered_writer() {
+        let key = MeteredAesManager::from(AESManager::new(KeySize::K128));
+        let mut array: Vec<u8> = Vec::new();
+        {
+            let mut writer: AESWriter<'_, _, MeteredAesManager> = AESWriter::new(&key, &mut array);
+            writer.write_all(&[7u8; 1000]).unwrap();
+        }
+        assert_eq!(key.stats(), (1000, 0));
+
+        let mut output = Vec::new();
+        AESReader::new(&key, &*array).read_to_end(&mut output).unwrap();
+        assert_eq!(output, vec![7u8; 1000]);
+        assert_eq!(key.stats(), (1000, 1000));
+    }
+
+    #[test]
+    fn stream_over_cursor() {
+        let manager = AESManager::new(KeySize::K256);
+        let key = manager.clone();
+        let mut stream = AESStream::new(manager, std::io::Cursor::new(Vec::new()));
+        stream.write_all(b"first\0message").unwrap();
+        stream.write_all(b"second").unwrap();
+
+        let mut cursor = stream.into_inner();
+        assert!(!cursor.get_ref().windows(5).any(|w| w == b"first"));
+        cursor.set_position(0);
+        let mut stream = AESStream::new(key, cursor);
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).unwrap();
+        assert_eq!(output, b"first\0messagesecond");
+    }
+
+    #[test]
+    fn gcm_stream() {
+        let manager = AESManager::new(KeySize::K128);
+        let key = manager.clone();
+        let mut stream = AESStream::new_gcm(manager, std::io::Cursor::new(Vec::new()));
+        assert!(stream.is_sealed());
+        stream.write_all(b"bytes").unwrap();
+        stream.write_message(b"a message").unwrap();
+
+        let sealed = stream.into_inner().into_inner();
+        // a 4 byte length, a 12 byte nonce, 5 bytes and a 16 byte tag
+        assert_eq!(sealed.len(), 37 + 4 + 12 + 9 + 16);
+        let mut stream = AESStream::new_gcm(key.clone(), std::io::Cursor::new(sealed.clone()));
+        let mut bytes = [0u8; 5];
+        stream.read_exact(&mut bytes).unwrap();
+        assert_eq!(&bytes, b"bytes");
+        assert_eq!(stream.read_message().unwrap(), b"a message");
+
+        let mut tampered = sealed;
+        tampered[20] ^= 1;
+        let mut stream = AESStream::new_gcm(key, std::io::Cursor::new(tampered));
+        assert_eq!(stream.read(&mut bytes).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn ctr_stream() {
+        let manager = AESManager::new(KeySize::K256);
+        let key = manager.clone();
+        let mut stream = AESStream::new_ctr(manager, std::io::Cursor::new(Vec::new()));
+        assert!(stream.is_counter_mode() && !stream.is_sealed());
+        stream.write_all(b"bytes").unwrap();
+        stream.write_message(b"a message").unwrap();
+        stream.write_message(b"").unwrap();
+
+        let encrypted = stream.into_inner().into_inner();
+        // a 4 byte length and a 16 byte counter block in front of each, with no padding
+        assert_eq!(encrypted.len(), 3 * 20 + 5 + 9);
+        let mut stream = AESStream::new_ctr(key.clone(), std::io::Cursor::new(encrypted.clone()));
+        let mut bytes = [0u8; 5];
+        stream.read_exact(&mut bytes).unwrap();
+        assert_eq!(&bytes, b"bytes");
+        assert_eq!(stream.read_message().unwrap(), b"a message");
+        assert_eq!(stream.read_message().unwrap(), b"");
+
+        // CTR is not authenticated, so a truncated frame is the only thing it notices
+        let truncated = [4u8, 0, 0, 0, 1, 2, 3, 4];
+        let mut stream = AESStream::new_ctr(key, &truncated[..]);
+        assert_eq!(stream.read_message().unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_lines_directly() {
+        let manager = AESManager::new(KeySize::K128);
+        let mut inner = Vec::new();
+        {
+            let mut writer = AESWriter::new(&manager, &mut inner);
+            writer.write_all(b"first line\nsecond ").unwrap();
+            writer.write_all(b"line, split across frames\n").unwrap();
+            writer.write_all(b"\nno newline at the end").unwrap();
+        }
+
+        let mut reader = AESReader::new(&manager, inner.as_slice());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "first line\n");
+        let rest: Vec<String> = reader.lines().map(Result::unwrap).collect();
+        assert_eq!(rest, ["second line, split across frames", "", "no newline at the end"]);
+
+        let mut reader = AESReader::new(&manager, &[][..]);
+        assert!(reader.fill_buf().unwrap().is_empty());
+    }
+
+    #[test]
+    fn into_inner() {
+        let manager = AESManager::new(KeySize::K128);
+        let mut writer = AESWriter::new(&manager, Vec::new());
+        writer.write_all(b"Hello, World!").unwrap();
+        assert_eq!(writer.inner().len(), LENGTH_HEADER_SIZE + 16);
+        let encrypted = writer.into_inner();
+
+        let mut reader = AESReader::new(&manager, encrypted.as_slice());
+        let mut buffer = [0u8; 5];
+        reader.read_exact(&mut buffer).unwrap();
+        // the whole frame was read, leaving the rest of the message buffered in the reader
+        assert!(reader.inner_mut().is_empty());
+        assert!(reader.into_inner().is_empty());
+    }
+
+    #[test]
+    fn stream_full_duplex() {
+        use crate::testing::ChannelDuplex;
+
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let manager = AESManager::new(KeySize::K128);
+        let server_manager = manager.clone();
+
+        let server = std::thread::spawn(move || {
+            let mut stream = AESStream::new(server_manager, &server_end);
+            let mut request = [0u8; 4];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(&request, b"ping");
+            stream.write_all(b"pong").unwrap();
+        });
+
+        let mut stream = AESStream::new(manager, &client_end);
+        stream.write_all(b"ping").unwrap();
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"pong");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn chacha20_poly1305_stream() {
+        use crate::encryption::aes::CipherChoice;
+
+        let manager = AESManager::new(CipherChoice::ChaCha20Poly1305);
+        let mut stream = AESStream::new(manager.clone(), std::io::Cursor::new(Vec::new()));
+        stream.write_all(b"Hello, ").unwrap();
+        stream.write_all(b"World!").unwrap();
+        stream.write_message(&[0u8; 40]).unwrap();
+        let encrypted = stream.into_inner().into_inner();
+
+        let mut stream = AESStream::new(manager, std::io::Cursor::new(encrypted.clone()));
+        let mut greeting = [0u8; 13];
+        stream.read_exact(&mut greeting).unwrap();
+        assert_eq!(&greeting, b"Hello, World!");
+        assert_eq!(stream.read_message().unwrap(), vec![0u8; 40]);
+        assert_eq!(stream.read(&mut greeting).unwrap(), 0);
+
+        let mut tampered = encrypted;
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        let (manager, _) = stream.into_parts();
+        let mut stream = AESStream::new(manager, std::io::Cursor::new(tampered));
+        stream.read_exact(&mut greeting).unwrap();
+        assert_eq!(stream.read_message().unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn chacha20_poly1305_block_streams_fail() {
+        use crate::encryption::aes::CipherChoice;
+
+        let key = AESManager::new(CipherChoice::ChaCha20Poly1305);
+        let mut output = Vec::new();
+        let mut writer = AESWriter::new(&key, &mut output);
+        assert_eq!(writer.write(b"Hello").unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(writer.write_message(b"Hello").unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(writer.finish().unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        assert!(output.is_empty());
+
+        let aes = AESManager::new(KeySize::K256);
+        let mut encrypted = Vec::new();
+        AESWriter::new(&aes, &mut encrypted).write_all(b"Hello").unwrap();
+        let mut buffer = [0u8; 5];
+        let error = AESReader::new(&key, encrypted.as_slice()).read(&mut buffer).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        let error = AESReader::new(&key, [0u8; 16].as_slice()).read_message().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn messages() {
+        let key = AESManager::new(KeySize::K192);
+        let long: Vec<u8> = (0..100u8).collect();
+        let messages: [&[u8]; 5] = [b"", b"x", &[0u8; 12], &[1u8; 13], &long];
+        let mut array: Vec<u8> = Vec::new();
+        {
+            let mut writer = AESWriter::new(&key, &mut array);
+            for message in &messages {
+                writer.write_message(message).unwrap();
+            }
+        }
+        // 4 bytes of length, then padding to a whole block
+        assert_eq!(array.len(), 16 * (1 + 1 + 1 + 2 + 7));
+
+        let mut reader = AESReader::new(&key, &*array);
+        for message in &messages {
+            assert_eq!(&reader.read_message().unwrap(), message);
+        }
+        assert_eq!(reader.read_message().unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_round_trip() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let manager = AESManager::new(KeySize::K128);
+        // a small duplex buffer makes frames arrive in several parts
+        let (client, server) = tokio::io::duplex(7);
+        let message: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+
+        let writing = async {
+            let mut writer = AsyncAESWriter::new(&manager, client);
+            writer.write_all(b"Hello, World!\0").await.unwrap();
+            writer.write_all(&message).await.unwrap();
+            writer.finish().await.unwrap();
+        };
+        let reading = async {
+            let mut reader = AsyncAESReader::new(&manager, server);
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).await.unwrap();
+            output
+        };
+        let ((), output) = tokio::join!(writing, reading);
+        assert_eq!(&output[..14], b"Hello, World!\0");
+        assert_eq!(&output[14..], message.as_slice());
+
+        // the sync reader understands the same frames
+        let mut encrypted = Vec::new();
+        let mut writer = AsyncAESWriter::new(&manager, &mut encrypted);
+        writer.write_all(b"sync").await.unwrap();
+        writer.finish().await.unwrap();
+        let mut output = String::new();
+        let mut reader = AESReader::new(&manager, encrypted.as_slice());
+        reader.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "sync");
+        assert!(reader.is_closed());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_reads_after_close() {
+        use tokio::io::AsyncReadExt;
+
+        let manager = AESManager::new(KeySize::K128);
+        let mut encrypted = Vec::new();
+        let mut writer = AESWriter::new(&manager, &mut encrypted);
+        write!(writer, "{}", TEST_MESSAGE).unwrap();
+        writer.finish().unwrap();
+        // anything after the sentinel is not read
+        AESWriter::new(&manager, &mut encrypted).write_all(b"after").unwrap();
+
+        let mut reader = AsyncAESReader::new(&manager, encrypted.as_slice());
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).await.unwrap();
+        assert_eq!(output, TEST_MESSAGE.as_bytes());
+        assert!(reader.is_closed());
+        assert_eq!(reader.read(&mut [0u8; 16]).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn gcm_round_trip() {
+        for key_size in [KeySize::K128, KeySize::K192, KeySize::K256] {
+            let manager = AESManager::new(key_size);
+            let mut encrypted = Vec::new();
+            let mut writer = AESGCMWriter::new(&manager, &mut encrypted);
+            writer.write_all(b"Hello, World!\0").unwrap();
+            writer.write_all(&[0u8; 100]).unwrap();
+            assert_eq!(encrypted.len(), 2 * (LENGTH_HEADER_SIZE + GCM_NONCE_SIZE + GCM_TAG_SIZE) + 14 + 100);
+
+            let mut output = Vec::new();
+            AESGCMReader::new(&manager, encrypted.as_slice()).read_to_end(&mut output).unwrap();
+            assert_eq!(&output[..14], b"Hello, World!\0");
+            assert_eq!(&output[14..], &[0u8; 100][..]);
+        }
+    }
+
+    #[test]
+    fn gcm_tampering_detected() {
+        let manager = AESManager::new(KeySize::K256);
+        let mut encrypted = Vec::new();
+        AESGCMWriter::new(&manager, &mut encrypted).write_all(b"Transfer 100 to account 42").unwrap();
+        let nonce_start = LENGTH_HEADER_SIZE;
+        let tag_start = encrypted.len() - GCM_TAG_SIZE;
+
+        let read = |bytes: &[u8]| {
+            let mut output = Vec::new();
+            AESGCMReader::new(&manager, bytes).read_to_end(&mut output).map(|_| output)
+        };
+        assert_eq!(read(&encrypted).unwrap(), b"Transfer 100 to account 42");
+        for index in [nonce_start, nonce_start + GCM_NONCE_SIZE, tag_start, encrypted.len() - 1] {
+            let mut tampered = encrypted.clone();
+            tampered[index] ^= 1;
+            assert_eq!(read(&tampered).unwrap_err().kind(), std::io::ErrorKind::InvalidData, "byte {}", index);
+        }
+
+        // a shorter length header leaves the frame without its tag
+        let mut tampered = encrypted.clone();
+        tampered[0] -= 1;
+        assert_eq!(read(&tampered).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(read(&encrypted[..tag_start]).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+
+        let other = AESManager::new(KeySize::K256);
+        let mut output = Vec::new();
+        let error = AESGCMReader::new(&other, encrypted.as_slice()).read_to_end(&mut output).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,133 @@
+//! AES-XTS (IEEE 1619) for encrypting fixed size disk sectors
+use crate::encryption::aes::{AESManager, UnsupportedCipher};
+
+/// The number of bytes in a sector
+pub const SECTOR_SIZE: usize = 512;
+
+const AES_KEYS: &str = "the keys were checked to be AES keys by new";
+
+/// Encrypts sectors using two AES keys: one for the data and one for the tweak
+pub struct AesXtsManager {
+    key1: AESManager,
+    key2: AESManager
+}
+
+impl AesXtsManager {
+
+    /// `key1` encrypts the data, `key2` encrypts the sector number to create the tweak
+    ///
+    /// Fails if either key is for ChaCha20-Poly1305, which has no blocks.
+    pub fn new(key1: AESManager, key2: AESManager) -> Result<Self, UnsupportedCipher> {
+        key1.check_block_cipher()?;
+        key2.check_block_cipher()?;
+        Ok(AesXtsManager { key1, key2 })
+    }
+
+    pub fn encrypt_sector(&self, sector_number: u64, data: &mut [u8; SECTOR_SIZE]) {
+        let mut tweak = self.initial_tweak(sector_number);
+        for chunk in data.chunks_mut(16) {
+            let mut block = xor(chunk, &tweak);
+            block = self.key1.encrypt_blocks(block).expect(AES_KEYS)[0];
+            chunk.copy_from_slice(&xor(&block, &tweak));
+            multiply_by_alpha(&mut tweak);
+        }
+    }
+
+    pub fn decrypt_sector(&self, sector_number: u64, data: &mut [u8; SECTOR_SIZE]) {
+        let mut tweak = self.initial_tweak(sector_number);
+        for chunk in data.chunks_mut(16) {
+            let block = xor(chunk, &tweak);
+            let decrypted = self.key1.decrypt_blocks([block]).expect(AES_KEYS);
+            chunk.copy_from_slice(&xor(&decrypted, &tweak));
+            multiply_by_alpha(&mut tweak);
+        }
+    }
+
+    /// The sector number is encoded as a 128 bit little endian integer and encrypted with `key2`
+    fn initial_tweak(&self, sector_number: u64) -> [u8; 16] {
+        let mut block = [0u8; 16];
+        block[..8].copy_from_slice(&sector_number.to_le_bytes());
+        self.key2.encrypt_blocks(block).expect(AES_KEYS)[0]
+    }
+}
+
+fn xor(block: &[u8], tweak: &[u8; 16]) -> [u8; 16] {
+    let mut output = [0u8; 16];
+    for ((out, a), b) in output.iter_mut().zip(block).zip(tweak) {
+        *out = a ^ b;
+    }
+    output
+}
+
+/// Multiplies the tweak by the primitive element of GF(2^128), using the little endian convention
+/// of the standard
+fn multiply_by_alpha(tweak: &mut [u8; 16]) {
+    let mut carry = 0u8;
+    for byte in tweak.iter_mut() {
+        let next_carry = *byte >> 7;
+        *byte = (*byte << 1) | carry;
+        carry = next_carry;
+    }
+    if carry == 1 {
+        tweak[0] ^= 0x87;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::encryption::aes::{CipherChoice, KeySize};
+
+    use super::*;
+
+    fn sample_sector() -> [u8; SECTOR_SIZE] {
+        let mut sector = [0u8; SECTOR_SIZE];
+        for (i, byte) in sector.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        sector
+    }
+
+    #[test]
+    fn ieee_1619_vector() {
+        let manager = AesXtsManager::new(
+            AESManager::from_str("27182818284590452353602874713526").unwrap(),
+            AESManager::from_str("31415926535897932384626433832795").unwrap()
+        ).unwrap();
+        let mut sector = sample_sector();
+        manager.encrypt_sector(0, &mut sector);
+        assert_eq!(
+            sector[..16],
+            [0x27, 0xa7, 0x47, 0x9b, 0xef, 0xa1, 0xd4, 0x76, 0x48, 0x9f, 0x30, 0x8c, 0xd4, 0xcf, 0xa6, 0xe2]
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let manager = AesXtsManager::new(AESManager::new(KeySize::K256), AESManager::new(KeySize::K256)).unwrap();
+        let mut sector = sample_sector();
+        manager.encrypt_sector(7, &mut sector);
+        assert_ne!(sector[..], sample_sector()[..]);
+        manager.decrypt_sector(7, &mut sector);
+        assert_eq!(sector[..], sample_sector()[..]);
+    }
+
+    #[test]
+    fn sector_number_changes_ciphertext() {
+        let manager = AesXtsManager::new(AESManager::new(KeySize::K128), AESManager::new(KeySize::K128)).unwrap();
+        let mut first = sample_sector();
+        let mut second = sample_sector();
+        manager.encrypt_sector(1, &mut first);
+        manager.encrypt_sector(2, &mut second);
+        assert_ne!(first[..], second[..]);
+    }
+
+    #[test]
+    fn chacha20_poly1305_keys_are_rejected() {
+        let chacha = || AESManager::new(CipherChoice::ChaCha20Poly1305);
+        let aes = || AESManager::new(KeySize::K128);
+        assert_eq!(AesXtsManager::new(chacha(), aes()).err(), Some(UnsupportedCipher));
+        assert_eq!(AesXtsManager::new(aes(), chacha()).err(), Some(UnsupportedCipher));
+    }
+}
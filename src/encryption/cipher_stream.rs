@@ -0,0 +1,169 @@
+//! Encrypted streams whose cipher is chosen at run time, behind one trait object
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::io::{Read, Write};
+
+use crate::encryption::aes::{AESManager, CipherChoice};
+use crate::encryption::aes::aes_stream::AESStream;
+
+/// How a [`CipherStream`] encrypts its frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherSuite {
+    /// AES blocks without authentication, as written by [`AESStream::new`]
+    Aes,
+    /// AES-GCM, which also notices frames that were changed
+    AesGcm,
+    /// AES-CTR, as written by [`AESStream::new_ctr`], which pads nothing but is not authenticated
+    AesCtr,
+    ChaCha20Poly1305
+}
+
+/// An encrypted stream that is read and written, whatever its cipher
+pub trait CipherStream: Read + Write + Send {
+    fn cipher_suite(&self) -> CipherSuite;
+}
+
+pub type CipherStreamBox = Box<dyn CipherStream>;
+
+/// The reading half from [`CipherStreamFactory::halves`]
+pub type CipherReaderBox = Box<dyn Read + Send>;
+
+/// The writing half from [`CipherStreamFactory::halves`]
+pub type CipherWriterBox = Box<dyn Write + Send>;
+
+impl<S : Read + Write + Send> CipherStream for AESStream<S> {
+    fn cipher_suite(&self) -> CipherSuite {
+        suite_of(self)
+    }
+}
+
+/// The suite `stream` encrypts with
+pub(crate) fn suite_of<S>(stream: &AESStream<S>) -> CipherSuite {
+    match (stream.manager().cipher_choice(), stream.is_sealed(), stream.is_counter_mode()) {
+        (CipherChoice::ChaCha20Poly1305, _, _) => CipherSuite::ChaCha20Poly1305,
+        (_, true, _) => CipherSuite::AesGcm,
+        (_, _, true) => CipherSuite::AesCtr,
+        _ => CipherSuite::Aes
+    }
+}
+
+/// The manager's key is for another cipher than the suite asks for
+#[derive(Debug, PartialEq)]
+pub struct CipherSuiteMismatch;
+
+impl Display for CipherSuiteMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for CipherSuiteMismatch { }
+
+/// Makes the streams of one cipher suite, so that the suite can come from configuration
+#[derive(Debug, Clone, Copy)]
+pub struct CipherStreamFactory {
+    suite: CipherSuite
+}
+
+impl CipherStreamFactory {
+    pub fn new(suite: CipherSuite) -> Self {
+        CipherStreamFactory { suite }
+    }
+
+    pub fn suite(&self) -> CipherSuite {
+        self.suite
+    }
+
+    /// Wraps `inner` in a stream that encrypts with the factory's suite, failing if `manager`'s key
+    /// is for another cipher
+    pub fn stream<S : Read + Write + Send + 'static>(&self, manager: AESManager, inner: S) -> Result<CipherStreamBox, CipherSuiteMismatch> {
+        Ok(Box::new(aes_stream(self.suite, manager, inner)?))
+    }
+
+    /// Wraps the two directions of a connection, such as a socket and its `try_clone`, in a reader
+    /// and a writer that encrypt with the factory's suite and both use `manager`'s key
+    pub fn halves<R, W>(&self, manager: AESManager, reader: R, writer: W) -> Result<(CipherReaderBox, CipherWriterBox), CipherSuiteMismatch>
+        where R : Read + Send + 'static, W : Write + Send + 'static {
+        let reader = aes_stream(self.suite, manager, reader)?;
+        let writer = reader.with_copied_key(writer);
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+}
+
+/// The [`AESStream`] that encrypts with `suite`
+pub(crate) fn aes_stream<S>(suite: CipherSuite, manager: AESManager, inner: S) -> Result<AESStream<S>, CipherSuiteMismatch> {
+    let chacha = manager.cipher_choice() == CipherChoice::ChaCha20Poly1305;
+    match suite {
+        CipherSuite::Aes if !chacha => Ok(AESStream::new(manager, inner)),
+        CipherSuite::AesGcm if !chacha => Ok(AESStream::new_gcm(manager, inner)),
+        CipherSuite::AesCtr if !chacha => Ok(AESStream::new_ctr(manager, inner)),
+        CipherSuite::ChaCha20Poly1305 if chacha => Ok(AESStream::new(manager, inner)),
+        _ => Err(CipherSuiteMismatch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::channel::SplitStream;
+    use crate::encryption::aes::KeySize;
+    use crate::testing::ChannelDuplex;
+
+    use super::*;
+
+    #[test]
+    fn every_suite_round_trips() {
+        for (suite, cipher) in [
+            (CipherSuite::Aes, CipherChoice::Aes(KeySize::K128)),
+            (CipherSuite::AesGcm, CipherChoice::Aes(KeySize::K256)),
+            (CipherSuite::AesCtr, CipherChoice::Aes(KeySize::K192)),
+            (CipherSuite::ChaCha20Poly1305, CipherChoice::ChaCha20Poly1305)
+        ] {
+            let factory = CipherStreamFactory::new(suite);
+            let (client_end, server_end) = ChannelDuplex::pair();
+            let manager = AESManager::new(cipher);
+            let server_manager = manager.clone();
+
+            let mut client = factory.stream(manager, client_end).unwrap();
+            let mut server = factory.stream(server_manager, server_end).unwrap();
+            assert_eq!((client.cipher_suite(), server.cipher_suite()), (suite, suite));
+            client.write_all(b"over a boxed stream").unwrap();
+            let mut received = [0u8; 19];
+            server.read_exact(&mut received).unwrap();
+            assert_eq!(&received, b"over a boxed stream");
+        }
+    }
+
+    #[test]
+    fn halves_round_trip() {
+        for suite in [CipherSuite::Aes, CipherSuite::AesGcm, CipherSuite::AesCtr] {
+            let factory = CipherStreamFactory::new(suite);
+            let (client_end, server_end) = ChannelDuplex::pair();
+            let (client_read, client_write) = client_end.split().unwrap();
+            let manager = AESManager::new(KeySize::K128);
+            let server_manager = manager.clone();
+
+            let (mut reader, mut writer) = factory.halves(manager, client_read, client_write).unwrap();
+            let mut server = factory.stream(server_manager, server_end).unwrap();
+            writer.write_all(b"from the writing half").unwrap();
+            let mut received = [0u8; 21];
+            server.read_exact(&mut received).unwrap();
+            assert_eq!(&received, b"from the writing half");
+
+            server.write_all(b"to the reading half").unwrap();
+            let mut received = [0u8; 19];
+            reader.read_exact(&mut received).unwrap();
+            assert_eq!(&received, b"to the reading half");
+        }
+    }
+
+    #[test]
+    fn key_must_match_suite() {
+        let factory = CipherStreamFactory::new(CipherSuite::AesGcm);
+        let chacha = AESManager::new(CipherChoice::ChaCha20Poly1305);
+        assert!(matches!(factory.stream(chacha, std::io::Cursor::new(Vec::new())), Err(CipherSuiteMismatch)));
+
+        let factory = CipherStreamFactory::new(CipherSuite::ChaCha20Poly1305);
+        let aes = AESManager::new(KeySize::K256);
+        assert!(matches!(factory.stream(aes, std::io::Cursor::new(Vec::new())), Err(CipherSuiteMismatch)));
+    }
+}
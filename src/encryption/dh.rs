@@ -0,0 +1,180 @@
+//! Finite field Diffie-Hellman (RFC 3526) key agreement
+//!
+//! An alternative to the RSA key exchange: both sides send `g^x mod p` for a random private `x`,
+//! and arrive at the same shared secret without it being sent. On its own this does not
+//! authenticate either side.
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+
+use num::Num;
+use num_bigint::{BigUint, RandBigInt};
+use num_traits::One;
+
+/// The 2048-bit MODP group, RFC 3526 section 3
+static MODP_2048_P: &str = concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05",
+    "98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB",
+    "9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718",
+    "3995497CEA956AE515D2261898FA051015728E5A8AACAA68FFFFFFFFFFFFFFFF"
+);
+/// The 4096-bit MODP group, RFC 3526 section 5
+static MODP_4096_P: &str = concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05",
+    "98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB",
+    "9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718",
+    "3995497CEA956AE515D2261898FA051015728E5A8AAAC42DAD33170D04507A33",
+    "A85521ABDF1CBA64ECFB850458DBEF0A8AEA71575D060C7DB3970F85A6E1E4C7",
+    "ABF5AE8CDB0933D71E8C94E04A25619DCEE3D2261AD2EE6BF12FFA06D98A0864",
+    "D87602733EC86A64521F2B18177B200CBBE117577A615D6C770988C0BAD946E2",
+    "08E24FA074E5AB3143DB5BFCE0FD108E4B82D120A92108011A723C12A787E6D7",
+    "88719A10BDBA5B2699C327186AF4E23C1A946834B6150BDA2583E9CA2AD44CE8",
+    "DBBBC2DB04DE8EF92E8EFC141FBECAA6287C59474E6BC05D99B2964FA090C3A2",
+    "233BA186515BE7ED1F612970CEE2D7AFB81BDD762170481CD0069127D5B05AA9",
+    "93B4EA988D8FDDC186FFB7DC90A6C08F4DF435C934063199FFFFFFFFFFFFFFFF"
+);
+/// Both groups use 2 as the generator
+const GENERATOR: u32 = 2;
+
+lazy_static! {
+    static ref P_2048: BigUint = BigUint::from_str_radix(MODP_2048_P, 16).unwrap();
+    static ref P_4096: BigUint = BigUint::from_str_radix(MODP_4096_P, 16).unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DhGroup {
+    Modp2048,
+    Modp4096
+}
+
+impl DhGroup {
+    pub fn prime(&self) -> &'static BigUint {
+        match self {
+            DhGroup::Modp2048 => &P_2048,
+            DhGroup::Modp4096 => &P_4096
+        }
+    }
+
+    pub fn generator(&self) -> BigUint {
+        BigUint::from(GENERATOR)
+    }
+
+    /// The size of the private exponent, twice the group's estimated security strength
+    fn exponent_bits(&self) -> u64 {
+        match self {
+            DhGroup::Modp2048 => 256,
+            DhGroup::Modp4096 => 512
+        }
+    }
+}
+
+impl Display for DhGroup {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DhGroup::Modp2048 => write!(f, "modp2048"),
+            DhGroup::Modp4096 => write!(f, "modp4096")
+        }
+    }
+}
+
+impl FromStr for DhGroup {
+    type Err = DhError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "modp2048" => Ok(DhGroup::Modp2048),
+            "modp4096" => Ok(DhGroup::Modp4096),
+            _ => Err(DhError::UnknownGroup)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DhError {
+    /// The group name was not recognised
+    UnknownGroup,
+    /// The peer's public value is outside `2..=p-2`, which would force a predictable secret
+    InvalidPublicValue
+}
+
+impl Display for DhError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for DhError { }
+
+pub struct DhKeypair {
+    group: DhGroup,
+    private: BigUint,
+    public: BigUint
+}
+
+impl DhKeypair {
+
+    pub fn generate(group: DhGroup) -> Self {
+        let private = rand::thread_rng().gen_biguint(group.exponent_bits());
+        let public = group.generator().modpow(&private, group.prime());
+        DhKeypair { group, private, public }
+    }
+
+    pub fn group(&self) -> DhGroup {
+        self.group
+    }
+
+    /// The value `g^x mod p` sent to the other side
+    pub fn public_value(&self) -> &BigUint {
+        &self.public
+    }
+
+    /// Computes the shared secret `peer_public^x mod p`
+    pub fn diffie_hellman(&self, peer_public: &BigUint) -> Result<BigUint, DhError> {
+        let p = self.group.prime();
+        if *peer_public <= BigUint::one() || *peer_public >= p - 1u32 {
+            return Err(DhError::InvalidPublicValue);
+        }
+        Ok(peer_public.modpow(&self.private, p))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_secret_matches() {
+        for group in [DhGroup::Modp2048, DhGroup::Modp4096] {
+            let alice = DhKeypair::generate(group);
+            let bob = DhKeypair::generate(group);
+            assert_eq!(
+                alice.diffie_hellman(bob.public_value()).unwrap(),
+                bob.diffie_hellman(alice.public_value()).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn group_primes() {
+        assert_eq!(DhGroup::Modp2048.prime().bits(), 2048);
+        assert_eq!(DhGroup::Modp4096.prime().bits(), 4096);
+        assert_eq!(DhGroup::from_str(&DhGroup::Modp4096.to_string()), Ok(DhGroup::Modp4096));
+    }
+
+    #[test]
+    fn degenerate_public_values_rejected() {
+        let keys = DhKeypair::generate(DhGroup::Modp2048);
+        let p = DhGroup::Modp2048.prime();
+        for value in [BigUint::from(0u32), BigUint::one(), p - 1u32, p.clone()] {
+            assert_eq!(keys.diffie_hellman(&value), Err(DhError::InvalidPublicValue));
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Distributes one AES key to several servers, each of which can only read its own copy
+//!
+//! The bundle starts with the number of recipients as a big endian `u16`. Each recipient then has
+//! the 32 byte fingerprint of its public key, the length of its entry as a big endian `u32`, and
+//! the AES key encrypted with its public key.
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use crate::encryption::aes::AESManager;
+use crate::encryption::rsa::{PrivateKey, PublicKey, RSAReader, RSAStreamEncoding, RSAWriter};
+
+const FINGERPRINT_LENGTH: usize = 32;
+
+#[derive(Debug, PartialEq)]
+pub enum KeyExtractionError {
+    /// The bundle ended early or its header is inconsistent
+    Malformed,
+    /// None of the entries were encrypted for the given key
+    NoMatchingRecipient,
+    /// The entry for the given key could not be decrypted into an AES key
+    InvalidKey
+}
+
+impl Display for KeyExtractionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for KeyExtractionError { }
+
+/// The encoded bundle, ready to be sent to every recipient
+pub struct MultiRecipientKeyBundle {
+    bytes: Vec<u8>
+}
+
+impl MultiRecipientKeyBundle {
+
+    /// Encrypts the AES key once for each recipient
+    ///
+    /// # Panics
+    /// Panics if there are more than `u16::MAX` recipients.
+    pub fn new(aes_manager: &AESManager, recipient_keys: &[PublicKey]) -> Self {
+        let count = u16::try_from(recipient_keys.len()).expect("too many recipients for one bundle");
+        let mut bundle = count.to_be_bytes().to_vec();
+        for key in recipient_keys {
+            let mut entry = Vec::new();
+            RSAWriter::with_encoding(key.clone(), &mut entry, RSAStreamEncoding::Base64)
+                .write_all(aes_manager.parsable_string().as_bytes())
+                .expect("writing to a vector can not fail");
+            bundle.extend_from_slice(&key.fingerprint());
+            bundle.extend_from_slice(&(entry.len() as u32).to_be_bytes());
+            bundle.extend(entry);
+        }
+        MultiRecipientKeyBundle { bytes: bundle }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Finds the entry encrypted for `private_key` and decrypts the AES key from it
+    pub fn extract_key(bundle: &[u8], private_key: PrivateKey) -> Result<AESManager, KeyExtractionError> {
+        let fingerprint = private_key.public_key().fingerprint();
+        let (count, mut rest) = split(bundle, 2)?;
+        let count = u16::from_be_bytes([count[0], count[1]]);
+        for _ in 0..count {
+            let (entry_fingerprint, after) = split(rest, FINGERPRINT_LENGTH)?;
+            let (length, after) = split(after, 4)?;
+            let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+            let (entry, after) = split(after, length)?;
+            rest = after;
+
+            if entry_fingerprint == fingerprint {
+                let mut key_string = String::new();
+                RSAReader::with_encoding(private_key, entry, RSAStreamEncoding::Base64)
+                    .read_to_string(&mut key_string)
+                    .map_err(|_| KeyExtractionError::InvalidKey)?;
+                return AESManager::from_str(&key_string).map_err(|_| KeyExtractionError::InvalidKey);
+            }
+        }
+        Err(KeyExtractionError::NoMatchingRecipient)
+    }
+}
+
+fn split(bytes: &[u8], at: usize) -> Result<(&[u8], &[u8]), KeyExtractionError> {
+    if bytes.len() < at {
+        Err(KeyExtractionError::Malformed)
+    } else {
+        Ok(bytes.split_at(at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encryption::aes::KeySize;
+    use crate::encryption::rsa::RSAKeysGenerator;
+
+    use super::*;
+
+    #[test]
+    fn each_recipient_extracts_key() {
+        let aes_manager = AESManager::new(KeySize::K256);
+        let recipients: Vec<_> = (0..3).map(|_| RSAKeysGenerator::new(256).generate_keys()).collect();
+        let public_keys: Vec<_> = recipients.iter().map(|keys| keys.public_key()).collect();
+        let bundle = MultiRecipientKeyBundle::new(&aes_manager, &public_keys).into_bytes();
+
+        for keys in &recipients {
+            assert_eq!(MultiRecipientKeyBundle::extract_key(&bundle, keys.private_key()).unwrap(), aes_manager);
+        }
+
+        let outsider = RSAKeysGenerator::new(256).generate_keys();
+        assert_eq!(
+            MultiRecipientKeyBundle::extract_key(&bundle, outsider.private_key()).err(),
+            Some(KeyExtractionError::NoMatchingRecipient)
+        );
+        assert_eq!(
+            MultiRecipientKeyBundle::extract_key(&bundle[..bundle.len() - 1], recipients[2].private_key()).err(),
+            Some(KeyExtractionError::Malformed)
+        );
+    }
+}
@@ -1,81 +1,116 @@
 //! This module handles the encryption aspect of the communications between clients
 //! Connections will be established using asymmetric encryption, then continued using using
 //! symmetric encryption
-use std::error::Error;
-use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::str::FromStr;
 
-use rand::random;
-
-use crate::encryption::aes::aes_stream::{AESReader, AESWriter};
 use crate::encryption::aes::AESManager;
-use crate::encryption::rsa::{RSAReader, RSAWriter};
+use crate::encryption::nonce::{Nonce, NonceRegistry};
+use crate::error::SecureComError;
+use crate::protocol::{ProtocolMessage, ProtocolParseError};
 pub mod rsa;
 
 pub mod aes;
 
+pub mod cipher_stream;
 
-/// Creates a nonce with `nonce_size` amount of bytes to create a number
-pub fn generate_nonce(nonce_size: usize) -> String {
-    let mut ret = String::new();
-    for _ in 0..nonce_size {
-        let byte: u8 = random();
-        ret = format!("{}{}", ret, byte);
-    }
-    ret
+pub mod aes_xts;
+
+pub mod aes_ccm;
+
+pub mod key_bundle;
+
+pub mod dh;
+
+pub mod nonce;
+
+/// Reads one line and parses it, so that a malformed message is told apart from a failed read
+fn read_message<R : Read>(reader: &mut R) -> std::io::Result<Result<ProtocolMessage, ProtocolParseError>> {
+    let mut buf_reader = BufReader::new(reader);
+    let mut line = String::new();
+    buf_reader.read_line(&mut line)?;
+    Ok(line.parse())
 }
 
 pub mod unsecure {
     use super::*;
+    use crate::cert::Certificate;
     use crate::encryption::rsa::PublicKey;
 
-    static HANDSHAKE_START_PHRASE: &str = "COM_BEGIN";
+    use crate::protocol::HANDSHAKE_START_PHRASE;
+
+    /// Client sends [`PROTOCOL_VERSION`](crate::PROTOCOL_VERSION), before anything else
+    pub fn send_protocol_version<W : Write>(writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&[crate::PROTOCOL_VERSION])
+    }
+
+    /// Server checks the client uses the same [`PROTOCOL_VERSION`](crate::PROTOCOL_VERSION)
+    pub fn receive_protocol_version<R : Read>(reader: &mut R) -> Result<(), SecureComError> {
+        check_protocol_version(reader, crate::PROTOCOL_VERSION)
+    }
+
+    pub(crate) fn check_protocol_version<R : Read>(reader: &mut R, expected: u8) -> Result<(), SecureComError> {
+        let mut version = [0u8];
+        reader.read_exact(&mut version)?;
+        if version[0] != expected {
+            return Err(SecureComError::ProtocolVersionMismatch { got: version[0], expected });
+        }
+        Ok(())
+    }
 
 
     /// Client begins a handshake
-    pub fn handshake_start<W : Write>(start_nonce: &String, writer: &mut W) -> std::io::Result<()> {
-        writeln!(writer, "{} {}", HANDSHAKE_START_PHRASE, start_nonce)
+    pub fn handshake_start<W : Write>(start_nonce: &Nonce, writer: &mut W) -> std::io::Result<()> {
+        writeln!(writer, "{}", ProtocolMessage::HandshakeBegin { nonce: *start_nonce })
     }
 
     /// Server acknowledges handshake and responds with nonce
+    ///
+    /// Fails with `InvalidData`, without writing anything, if the line is not the start phrase
+    /// followed by a nonce.
     pub fn server_ack<W: Write, R: Read>(writer: &mut W, reader: &mut R) -> std::io::Result<()> {
-        let mut buf_reader = BufReader::new(reader);
-        let mut line = String::new();
-        buf_reader.read_line(&mut line)?;
-        let split: Vec<&str> = line.split_whitespace().collect();
-        if split[0] == HANDSHAKE_START_PHRASE {
-            writeln!(writer, "{}", split[1])
-        } else {
-            Ok(())
+        match read_message(reader)? {
+            Ok(ProtocolMessage::HandshakeBegin { nonce }) => writeln!(writer, "{}", ProtocolMessage::NonceEcho { nonce }),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("expected {}", HANDSHAKE_START_PHRASE)))
         }
     }
 
     /// Client confirms server responded with nonce
-    pub fn receive_ack<R : Read>(start_nonce: &String, reader: &mut R) -> std::io::Result<bool> {
-        let mut buf_reader = BufReader::new(reader);
-        let mut line = String::new();
-        buf_reader.read_line(&mut line)?;
-        println!("Received {}, looking for {}", line.trim(), start_nonce);
-        Ok(line.trim() == start_nonce)
+    pub fn receive_ack<R : Read>(start_nonce: &Nonce, reader: &mut R) -> std::io::Result<bool> {
+        Ok(matches!(read_message(reader)?, Ok(ProtocolMessage::NonceEcho { nonce }) if nonce == *start_nonce))
     }
 
     /// Sends public key
     pub fn send_public_key<W : Write>(key: PublicKey, writer: &mut W) -> std::io::Result<()> {
-        writeln!(writer, "RSA:{}", key)
+        writeln!(writer, "{}", ProtocolMessage::PublicKey { key })
     }
 
     /// Receive public key
-    pub fn receive_public_key<R : Read>(reader: &mut R) -> Result<PublicKey, Box<dyn Error>> {
-        let mut buf_reader = BufReader::new(reader);
-        let mut line = String::new();
-        buf_reader.read_line(&mut line)?;
+    pub fn receive_public_key<R : Read>(reader: &mut R) -> Result<PublicKey, SecureComError> {
+        match read_message(reader)? {
+            Ok(ProtocolMessage::PublicKey { key }) => Ok(key),
+            Err(ProtocolParseError::InvalidPublicKey(e)) => Err(e.into()),
+            _ => Err(SecureComError::HandshakePhaseError("Incorrect public key format".to_string()))
+        }
+    }
 
-        let split: Vec<&str> = line.trim().split(":").collect();
-        if split[0] != "RSA" {
-            Err("Incorrect AES key format from client")?;
+    /// Sends a certificate in place of a bare public key, with the PEM on a single line
+    pub fn send_certificate<W : Write>(certificate: &Certificate, writer: &mut W) -> std::io::Result<()> {
+        let pem = certificate.to_pem().trim().replace('\n', " ");
+        writeln!(writer, "{}", ProtocolMessage::Certificate { pem })
+    }
+
+    /// Receives a certificate, failing unless it is correctly self signed
+    pub fn receive_certificate<R : Read>(reader: &mut R) -> Result<Certificate, SecureComError> {
+        let pem = match read_message(reader)? {
+            Ok(ProtocolMessage::Certificate { pem }) => pem,
+            _ => return Err(SecureComError::HandshakePhaseError("Incorrect certificate format".to_string()))
+        };
+        let certificate = Certificate::from_pem(&pem)?;
+        if !certificate.verify_self_signed() {
+            return Err(SecureComError::InvalidCertificate(None));
         }
-        let public_key_string = split[1];
-        PublicKey::from_str(public_key_string).map_err(|e| Box::new(e) as Box<dyn Error>)
+        Ok(certificate)
     }
 }
 
@@ -83,81 +118,336 @@ pub mod unsecure {
 pub mod secure {
     use super::*;
 
-    static SECRET_HANDSHAKE_START_PHRASE: &str = "SECOP_BEGIN";
+    use crate::protocol::SECURE_HANDSHAKE_PHRASE;
+
+    /// Reads a message from a decrypting reader, which reports a message it can't decrypt as
+    /// `InvalidData`
+    fn read_decrypted_message<R: Read>(reader: &mut R) -> Result<Result<ProtocolMessage, ProtocolParseError>, SecureComError> {
+        let mut buf_reader = BufReader::new(reader);
+        let mut line = String::new();
+        match buf_reader.read_line(&mut line) {
+            Ok(_) => Ok(line.parse()),
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => Err(SecureComError::RsaDecryptionError),
+            Err(e) => Err(e.into())
+        }
+    }
 
     /// Client
-    pub fn handshake_start<W: Write>(my_nonce: &String, writer: &mut RSAWriter<W>) -> std::io::Result<()> {
-        writeln!(writer, "{} {}", SECRET_HANDSHAKE_START_PHRASE, my_nonce)
+    pub fn handshake_start<W: Write>(my_nonce: &Nonce, writer: &mut W) -> std::io::Result<()> {
+        writeln!(writer, "{}", ProtocolMessage::SecureHandshakeBegin { nonce: *my_nonce })
     }
 
     /// Server
-    pub fn server_ack<W: Write, R: Read>(server_nonce: &String, writer: &mut RSAWriter<W>, reader: &mut RSAReader<R>) -> std::io::Result<bool> {
-        let mut buf_reader = BufReader::new(reader);
-        let mut line = String::new();
-        buf_reader.read_line(&mut line)?;
-        let split: Vec<&str> = line.split_whitespace().collect();
-        if split[0] != SECRET_HANDSHAKE_START_PHRASE {
-            return Ok(false);
+    pub fn server_ack<W: Write, R: Read>(server_nonce: &Nonce, writer: &mut W, reader: &mut R) -> Result<(), SecureComError> {
+        server_ack_with_registry(server_nonce, None, writer, reader).map(|_| ())
+    }
+
+    /// Server, failing with [`SecureComError::NonceReused`] if the client's nonce is already in
+    /// the registry
+    pub fn server_ack_checked<W: Write, R: Read>(server_nonce: &Nonce, registry: &mut NonceRegistry, writer: &mut W, reader: &mut R)
+                                                 -> Result<(), SecureComError> {
+        server_ack_with_registry(server_nonce, Some(registry), writer, reader).map(|_| ())
+    }
+
+    /// Returns the client's nonce
+    pub(crate) fn server_ack_with_registry<W: Write, R: Read>(server_nonce: &Nonce, registry: Option<&mut NonceRegistry>, writer: &mut W, reader: &mut R)
+                                                              -> Result<Nonce, SecureComError> {
+        let client_nonce = match read_decrypted_message(reader)? {
+            Ok(ProtocolMessage::SecureHandshakeBegin { nonce }) => nonce,
+            Err(ProtocolParseError::InvalidNonce) => {
+                return Err(SecureComError::HandshakePhaseError("Client sent an invalid nonce".to_string()))
+            }
+            _ => return Err(SecureComError::HandshakePhaseError(
+                format!("Expected {} from the client", SECURE_HANDSHAKE_PHRASE)
+            ))
+        };
+        if let Some(registry) = registry {
+            if !registry.insert_and_check(&client_nonce) {
+                return Err(SecureComError::NonceReused);
+            }
         }
-        let client_nonce = split[1];
-        writeln!(writer, "{} {}", client_nonce, server_nonce).map(|_| true)
+        writeln!(writer, "{}", ProtocolMessage::HandshakeAck { client_nonce, server_nonce: *server_nonce })?;
+        Ok(client_nonce)
     }
 
-    /// Client
-    pub fn receive_and_repeat<W: Write, R: Read>(my_nonce: &String, writer: &mut RSAWriter<W>, reader: &mut RSAReader<R>) -> Result<(), Box<dyn Error>> {
-        let server_nonce = {
-            let mut buf_reader = BufReader::new(reader);
-            let mut line = String::new();
-            buf_reader.read_line(&mut line)?;
-            let mut split = line.split_whitespace();
-            let my_nonce_recv = split.next().ok_or("Did not receive proper response")?;
-            if my_nonce != my_nonce_recv {
-                Err("Received nonce from server incorrect")?
+    /// Client, returning the server's nonce
+    pub fn receive_and_repeat<W: Write, R: Read>(my_nonce: &Nonce, writer: &mut W, reader: &mut R) -> Result<Nonce, SecureComError> {
+        let server_nonce = match read_decrypted_message(reader)? {
+            Ok(ProtocolMessage::HandshakeAck { client_nonce, server_nonce }) if client_nonce == *my_nonce => server_nonce,
+            Ok(ProtocolMessage::HandshakeAck { .. }) | Err(ProtocolParseError::InvalidNonce) => {
+                return Err(SecureComError::NonceMismatch)
+            }
+            Ok(ProtocolMessage::NonceEcho { nonce }) if nonce == *my_nonce => {
+                return Err(SecureComError::HandshakePhaseError("Did not receive the server's nonce".to_string()))
             }
-            split.next().ok_or("Did not receive the server's nonce")?.to_string()
+            _ => return Err(SecureComError::HandshakePhaseError("Did not receive proper response".to_string()))
         };
-        writeln!(writer, "{}", server_nonce)?;
-        Ok(())
+        writeln!(writer, "{}", ProtocolMessage::NonceEcho { nonce: server_nonce })?;
+        Ok(server_nonce)
     }
 
     /// Server
-    pub fn client_repeat_correct<W: Write, R: Read>(server_nonce: &String, writer: &mut RSAWriter<W>, reader: &mut RSAReader<R>) -> std::io::Result<bool> {
-        let mut buf_reader = BufReader::new(reader);
-        let mut line = String::new();
-        buf_reader.read_line(&mut line)?;
-        let server_nonce_recv = line.trim();
-        Ok(server_nonce == server_nonce_recv)
+    pub fn client_repeat_correct<W: Write, R: Read>(server_nonce: &Nonce, _writer: &mut W, reader: &mut R) -> std::io::Result<bool> {
+        Ok(matches!(read_message(reader)?, Ok(ProtocolMessage::NonceEcho { nonce }) if nonce == *server_nonce))
     }
 
 
     /// Client
-    pub fn encryption_successful<R: Read>(reader: &mut RSAReader<R>) -> std::io::Result<bool> {
-        let mut buf_reader = BufReader::new(reader);
-        let mut line = String::new();
-        buf_reader.read_line(&mut line)?;
-        match &*line.trim() {
-            "SUCCESS" => Ok(true),
-            _ => Ok(false)
+    pub fn encryption_successful<R: Read>(reader: &mut R) -> std::io::Result<bool> {
+        Ok(matches!(read_message(reader)?, Ok(ProtocolMessage::Success)))
+    }
+
+    /// Client, like [`encryption_successful`] but passing on the reason of a server that sent
+    /// [`ProtocolMessage::Failure`]
+    pub fn receive_success<R: Read>(reader: &mut R) -> Result<(), SecureComError> {
+        match read_message(reader)? {
+            Ok(ProtocolMessage::Success) => Ok(()),
+            Ok(ProtocolMessage::Failure { reason }) => {
+                Err(SecureComError::HandshakePhaseError(format!("Server rejected the handshake: {}", reason)))
+            }
+            _ => Err(SecureComError::HandshakePhaseError("Encrypted connection was not established".to_string()))
         }
     }
 
     /// Client
-    pub fn begin_aes_encryption_client<W : Write>(manager: &AESManager, rsa_writer: &mut RSAWriter<W>)
+    pub fn begin_aes_encryption_client<W : Write>(manager: &AESManager, rsa_writer: &mut W)
                                                                           -> std::io::Result<()> {
-        write!(rsa_writer, "AES_KEY:{}", manager.parsable_string())
+        writeln!(rsa_writer, "{}", ProtocolMessage::AesKeyExchange { key_hex: manager.parsable_string() })
     }
 
     /// Server
-    pub fn get_aes_key<R: Read>(rsa_reader: &mut RSAReader<R>)
-                                                     -> Result<AESManager, Box<dyn Error>> {
-        let mut buf_reader = BufReader::new(rsa_reader);
-        let mut line = String::new();
-        buf_reader.read_line(&mut line)?;
-        let split: Vec<&str> = line.trim().split(":").collect();
-        if split[0] != "AES_KEY" {
-            Err("Incorrect AES key format from client")?;
+    pub fn get_aes_key<R: Read>(rsa_reader: &mut R)
+                                                     -> Result<AESManager, SecureComError> {
+        match read_decrypted_message(rsa_reader)? {
+            Ok(ProtocolMessage::AesKeyExchange { key_hex }) if key_hex.is_empty() => {
+                Err(SecureComError::HandshakePhaseError("AES key missing from message".to_string()))
+            }
+            Ok(ProtocolMessage::AesKeyExchange { key_hex }) => Ok(AESManager::from_str(&key_hex)?),
+            _ => Err(SecureComError::HandshakePhaseError("Incorrect AES key format from client".to_string()))
         }
-        AESManager::from_str(split[1].as_ref()).map_err(|e| Box::new(e) as Box<dyn Error>)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::cert::{CertError, Certificate};
+    use crate::encryption::aes::{CipherChoice, KeySize};
+    use crate::encryption::rsa::{RSAKeys, RSAKeysGenerator, RSAReader, RSAWriter};
+    use crate::testing::ChannelDuplex;
+    use std::error::Error;
+    use std::time::Duration;
+
+    use super::*;
+
+    type Transport<'a> = Box<dyn Fn(Vec<u8>) -> std::io::Result<Vec<u8>> + 'a>;
+
+    /// Runs the secure part of the handshake one message at a time over plain buffers, passing
+    /// each message through `to_server` or `to_client` on its way
+    fn secure_exchange(to_server: Transport, to_client: Transport) -> Result<AESManager, Box<dyn Error>> {
+        let client_nonce = Nonce::generate();
+        let server_nonce = Nonce::generate();
+        let aes_manager = AESManager::new(KeySize::K128);
+
+        let mut message = Vec::new();
+        secure::handshake_start(&client_nonce, &mut message)?;
+        let received = to_server(message)?;
+
+        let mut message = Vec::new();
+        secure::server_ack(&server_nonce, &mut message, &mut received.as_slice())?;
+        let received = to_client(message)?;
+
+        let mut message = Vec::new();
+        secure::receive_and_repeat(&client_nonce, &mut message, &mut received.as_slice())?;
+        let received = to_server(message)?;
+
+        assert!(secure::client_repeat_correct(&server_nonce, &mut Vec::new(), &mut received.as_slice())?);
+        let received = to_client(b"SUCCESS\n".to_vec())?;
+        assert!(secure::encryption_successful(&mut received.as_slice())?);
+
+        let mut message = Vec::new();
+        secure::begin_aes_encryption_client(&aes_manager, &mut message)?;
+        let received = to_server(message)?;
+        let server_manager = secure::get_aes_key(&mut received.as_slice())?;
+        assert_eq!(server_manager, aes_manager);
+        Ok(server_manager)
+    }
+
+    #[test]
+    fn secure_exchange_plaintext() {
+        secure_exchange(Box::new(Ok), Box::new(Ok)).unwrap();
+    }
+
+    /// Encrypts `message` for `to` and decrypts it again with `keys`
+    fn through_rsa(to: &RSAKeys, message: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        let mut encrypted = Vec::new();
+        RSAWriter::new(to.public_key(), &mut encrypted).write_all(&message)?;
+        let mut decrypted = Vec::new();
+        RSAReader::new(to.private_key(), encrypted.as_slice()).read_to_end(&mut decrypted)?;
+        Ok(decrypted)
+    }
+
+    #[test]
+    fn secure_exchange_rsa() {
+        let client_keys = RSAKeysGenerator::new(128).generate_keys();
+        let server_keys = RSAKeysGenerator::new(128).generate_keys();
+        secure_exchange(
+            Box::new(|message| through_rsa(&server_keys, message)),
+            Box::new(|message| through_rsa(&client_keys, message))
+        ).unwrap();
+    }
+
+    #[test]
+    fn full_double_handshake() {
+        let (client, server) = ChannelDuplex::pair();
+
+        // unencrypted half
+        let start_nonce = Nonce::generate();
+        unsecure::handshake_start(&start_nonce, &mut &client).unwrap();
+        unsecure::server_ack(&mut &server, &mut &server).unwrap();
+        assert!(unsecure::receive_ack(&start_nonce, &mut &client).unwrap());
+
+        let client_keys = RSAKeysGenerator::new(256).generate_keys();
+        let server_keys = RSAKeysGenerator::new(256).generate_keys();
+        unsecure::send_public_key(client_keys.public_key(), &mut &client).unwrap();
+        let client_public = unsecure::receive_public_key(&mut &server).unwrap();
+        assert_eq!(client_public.to_string(), client_keys.public_key().to_string());
+        unsecure::send_public_key(server_keys.public_key(), &mut &server).unwrap();
+        let server_public = unsecure::receive_public_key(&mut &client).unwrap();
+        assert_eq!(server_public.to_string(), server_keys.public_key().to_string());
+
+        // encrypted half
+        let mut client_writer = RSAWriter::new(server_public, &client);
+        let mut server_writer = RSAWriter::new(client_public, &server);
+        let client_nonce = Nonce::generate();
+        let server_nonce = Nonce::generate();
+
+        let mut client_reader = RSAReader::new(client_keys.private_key(), &client);
+        let mut server_reader = RSAReader::new(server_keys.private_key(), &server);
+
+        secure::handshake_start(&client_nonce, &mut client_writer).unwrap();
+        secure::server_ack(&server_nonce, &mut server_writer, &mut server_reader).unwrap();
+        secure::receive_and_repeat(&client_nonce, &mut client_writer, &mut client_reader).unwrap();
+        assert!(secure::client_repeat_correct(&server_nonce, &mut server_writer, &mut server_reader).unwrap());
+        writeln!(server_writer, "SUCCESS").unwrap();
+        assert!(secure::encryption_successful(&mut client_reader).unwrap());
+
+        let aes_manager = AESManager::new(KeySize::K256);
+        secure::begin_aes_encryption_client(&aes_manager, &mut client_writer).unwrap();
+        assert_eq!(secure::get_aes_key(&mut server_reader).unwrap(), aes_manager);
+    }
+
+    #[test]
+    fn replayed_nonce_rejected() {
+        let mut registry = NonceRegistry::new(Duration::from_secs(60));
+        let client_nonce = Nonce::generate();
+        let mut start = Vec::new();
+        secure::handshake_start(&client_nonce, &mut start).unwrap();
+
+        // the first connection is answered, a second one with the same nonce is not
+        let mut reply = Vec::new();
+        secure::server_ack_checked(&Nonce::generate(), &mut registry, &mut reply, &mut start.as_slice()).unwrap();
+        assert!(!reply.is_empty());
+        let mut reply = Vec::new();
+        let error = secure::server_ack_checked(&Nonce::generate(), &mut registry, &mut reply, &mut start.as_slice()).unwrap_err();
+        assert!(matches!(error, SecureComError::NonceReused));
+        assert!(reply.is_empty());
+
+        // a new connection from the client uses a new nonce
+        let mut start = Vec::new();
+        secure::handshake_start(&Nonce::generate(), &mut start).unwrap();
+        secure::server_ack_checked(&Nonce::generate(), &mut registry, &mut Vec::new(), &mut start.as_slice()).unwrap();
+    }
+
+    /// The string form of a ChaCha20-Poly1305 key contains a colon of its own
+    #[test]
+    fn chacha20_poly1305_key_sent() {
+        let keys = RSAKeys::from_test_vector();
+        let manager = AESManager::new(CipherChoice::ChaCha20Poly1305);
+        let mut inner = Vec::new();
+        secure::begin_aes_encryption_client(&manager, &mut RSAWriter::new(keys.public_key(), &mut inner)).unwrap();
+        let received = secure::get_aes_key(&mut RSAReader::new(keys.private_key(), &*inner)).unwrap();
+        assert_eq!(received, manager);
+        assert_eq!(received.cipher_choice(), CipherChoice::ChaCha20Poly1305);
+    }
+
+    #[test]
+    fn malformed_unsecure_messages() {
+        let mut output = Vec::new();
+        for start in [&b"\n"[..], b"COM_BEGIN\n", b"GET / HTTP/1.1\r\n", b"SECOP_BEGIN 00\n"] {
+            let error = unsecure::server_ack(&mut output, &mut &start[..]).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        }
+        assert!(output.is_empty());
+
+        let nonce = Nonce::generate();
+        let mut start = Vec::new();
+        unsecure::handshake_start(&nonce, &mut start).unwrap();
+        unsecure::server_ack(&mut output, &mut start.as_slice()).unwrap();
+        assert_eq!(output, format!("{}\n", nonce).into_bytes());
+
+        assert!(matches!(unsecure::receive_public_key(&mut &b"\n"[..]), Err(SecureComError::HandshakePhaseError(_))));
+        assert!(matches!(unsecure::receive_public_key(&mut &b"RSA\n"[..]), Err(SecureComError::InvalidPublicKey)));
+        assert!(matches!(unsecure::receive_public_key(&mut &b"RSA:(12,\n"[..]), Err(SecureComError::InvalidPublicKey)));
+        assert!(matches!(unsecure::receive_public_key(&mut &b"RSA:(35,5):(35,7)\n"[..]), Err(SecureComError::InvalidPublicKey)));
+        assert!(matches!(unsecure::receive_public_key(&mut &b"DSA:(35,5)\n"[..]), Err(SecureComError::HandshakePhaseError(_))));
+        assert_eq!(unsecure::receive_public_key(&mut &b"RSA:(35,5)\n"[..]).unwrap().key(), &5u32.into());
+        assert!(matches!(unsecure::receive_public_key(&mut &[0xffu8, b'\n'][..]), Err(SecureComError::IoError(_))));
+    }
+
+    #[test]
+    fn certificate_exchange() {
+        let keys = RSAKeysGenerator::new(512).generate_keys();
+        let certificate = Certificate::self_signed("server.example.com", &keys);
+        let mut output = Vec::new();
+        unsecure::send_certificate(&certificate, &mut output).unwrap();
+        assert_eq!(output.iter().filter(|&&b| b == b'\n').count(), 1);
+
+        let received = unsecure::receive_certificate(&mut output.as_slice()).unwrap();
+        assert_eq!(received.identity(), "server.example.com");
+        assert_eq!(received.public_key().to_string(), keys.public_key().to_string());
+
+        assert!(matches!(unsecure::receive_certificate(&mut &b"RSA:(35,5)\n"[..]), Err(SecureComError::HandshakePhaseError(_))));
+        assert!(matches!(unsecure::receive_certificate(&mut &b"CERT:hello\n"[..]),
+                         Err(SecureComError::InvalidCertificate(Some(CertError::MissingPemMarkers)))));
+    }
+
+    #[test]
+    fn malformed_secure_messages() {
+        let keys = RSAKeysGenerator::new(128).generate_keys();
+        let lines = |text: &str| {
+            let mut inner = Vec::new();
+            write!(RSAWriter::new(keys.public_key(), &mut inner), "{}", text).unwrap();
+            inner
+        };
+
+        let mut output = Vec::new();
+        // the client's nonce has to be hex of the right length
+        for text in &["\n", "SECOP_BEGIN\n", "SECOP_BEGIN 1234\n"] {
+            let inner = lines(text);
+            let mut writer = RSAWriter::new(keys.public_key(), &mut output);
+            let mut reader = RSAReader::new(keys.private_key(), &*inner);
+            let error = secure::server_ack(&Nonce::generate(), &mut writer, &mut reader).unwrap_err();
+            assert!(matches!(error, SecureComError::HandshakePhaseError(_)), "{:?}", error);
+        }
+
+        for text in &["\n", "AES_KEY\n"] {
+            let inner = lines(text);
+            let mut reader = RSAReader::new(keys.private_key(), &*inner);
+            assert!(matches!(secure::get_aes_key(&mut reader), Err(SecureComError::HandshakePhaseError(_))));
+        }
+
+        let inner = lines("AES_KEY:zz\n");
+        let mut reader = RSAReader::new(keys.private_key(), &*inner);
+        assert!(matches!(secure::get_aes_key(&mut reader), Err(SecureComError::AesKeyParseError(_))));
+
+        // encrypted for a different key, so the chunk header is wrong after decrypting
+        let other = RSAKeysGenerator::new(128).generate_keys();
+        let mut inner = Vec::new();
+        writeln!(RSAWriter::new(other.public_key(), &mut inner), "AES_KEY:00").unwrap();
+        let mut reader = RSAReader::new(keys.private_key(), &*inner);
+        assert!(matches!(secure::get_aes_key(&mut reader), Err(SecureComError::RsaDecryptionError)));
+        let mut reader = RSAReader::new(keys.private_key(), &*inner);
+        assert!(matches!(secure::receive_and_repeat(&Nonce::generate(), &mut Vec::new(), &mut reader),
+                         Err(SecureComError::RsaDecryptionError)));
+    }
+}
@@ -0,0 +1,180 @@
+//! The random values each side of a handshake sends to prove the other side is live
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::time::{Duration, Instant};
+
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+/// The number of random bytes in a nonce
+pub const NONCE_BYTES: usize = 16;
+
+/// 16 random bytes, written as 32 lowercase hex digits on the wire
+///
+/// Equality takes the same time wherever the nonces differ, so a peer can't learn how much of a
+/// nonce it guessed correctly from how quickly a wrong one is rejected.
+#[derive(Debug, Clone, Copy)]
+pub struct Nonce([u8; NONCE_BYTES]);
+
+#[derive(Debug, PartialEq)]
+pub enum NonceParseError {
+    /// The string is not made of pairs of hex digits
+    InvalidHex,
+    /// The string is hex, but not of 16 bytes
+    WrongLength { got: usize }
+}
+
+impl Display for NonceParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for NonceParseError { }
+
+impl Nonce {
+
+    /// Fills a nonce from the operating system's secure random number generator
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; NONCE_BYTES];
+        OsRng.fill_bytes(&mut bytes);
+        Nonce(bytes)
+    }
+
+    pub fn from_hex(s: &str) -> Result<Self, NonceParseError> {
+        if !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(NonceParseError::InvalidHex);
+        }
+        if s.len() != NONCE_BYTES * 2 {
+            return Err(NonceParseError::WrongLength { got: s.len() / 2 });
+        }
+        let mut bytes = [0u8; NONCE_BYTES];
+        for (byte, pair) in bytes.iter_mut().zip(s.as_bytes().chunks(2)) {
+            let pair = std::str::from_utf8(pair).expect("hex digits are ascii");
+            *byte = u8::from_str_radix(pair, 16).expect("the digits were checked");
+        }
+        Ok(Nonce(bytes))
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    pub fn as_bytes(&self) -> &[u8; NONCE_BYTES] {
+        &self.0
+    }
+}
+
+impl From<[u8; NONCE_BYTES]> for Nonce {
+    fn from(bytes: [u8; NONCE_BYTES]) -> Self {
+        Nonce(bytes)
+    }
+}
+
+/// Compares every byte, instead of stopping at the first difference
+impl PartialEq for Nonce {
+    fn eq(&self, other: &Self) -> bool {
+        let difference = self.0.iter()
+            .zip(other.0.iter())
+            .fold(0u8, |difference, (a, b)| difference | (a ^ b));
+        std::hint::black_box(difference) == 0
+    }
+}
+
+impl Eq for Nonce { }
+
+/// Writes the nonce in hex, the form it is sent in
+impl Display for Nonce {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+/// Remembers the nonces a server has accepted, so a recorded handshake can't be replayed
+///
+/// Nonces are forgotten once they are older than the registry's lifetime, which keeps the
+/// registry from growing forever. The lifetime should be at least as long as a session.
+#[derive(Debug)]
+pub struct NonceRegistry {
+    lifetime: Duration,
+    seen: HashMap<[u8; NONCE_BYTES], Instant>
+}
+
+impl NonceRegistry {
+    pub fn new(lifetime: Duration) -> Self {
+        NonceRegistry { lifetime, seen: HashMap::new() }
+    }
+
+    /// Records the nonce, returning `false` if it was already recorded within the lifetime
+    pub fn insert_and_check(&mut self, nonce: &Nonce) -> bool {
+        let lifetime = self.lifetime;
+        self.seen.retain(|_, seen| seen.elapsed() < lifetime);
+        self.seen.insert(nonce.0, Instant::now()).is_none()
+    }
+
+    /// The number of nonces that have been recorded and not yet forgotten
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_round_trip() {
+        let nonce = Nonce::generate();
+        let hex = nonce.to_hex();
+        assert_eq!(hex.len(), 32);
+        assert!(hex.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)), "{}", hex);
+        assert_eq!(Nonce::from_hex(&hex).unwrap(), nonce);
+        assert_eq!(Nonce::from_hex(&hex.to_uppercase()).unwrap(), nonce);
+        assert_ne!(Nonce::generate(), Nonce::generate());
+
+        assert_eq!(Nonce::from([0; 16]).to_hex(), "0".repeat(32));
+        assert_eq!(Nonce::from([0xab; 16]).to_string(), "ab".repeat(16));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Nonce::from_hex("").err(), Some(NonceParseError::WrongLength { got: 0 }));
+        assert_eq!(Nonce::from_hex(&"00".repeat(17)).err(), Some(NonceParseError::WrongLength { got: 17 }));
+        assert_eq!(Nonce::from_hex(&"0".repeat(31)).err(), Some(NonceParseError::InvalidHex));
+        assert_eq!(Nonce::from_hex(&"zz".repeat(16)).err(), Some(NonceParseError::InvalidHex));
+        assert_eq!(Nonce::from_hex(&"+1".repeat(16)).err(), Some(NonceParseError::InvalidHex));
+    }
+
+    #[test]
+    fn equality() {
+        let mut bytes = [7u8; 16];
+        let nonce = Nonce::from(bytes);
+        assert_eq!(nonce, Nonce::from(bytes));
+        for index in [0, 8, 15] {
+            bytes[index] ^= 1;
+            assert_ne!(nonce, Nonce::from(bytes));
+            bytes[index] ^= 1;
+        }
+    }
+
+    #[test]
+    fn registry_rejects_reuse() {
+        let mut registry = NonceRegistry::new(Duration::from_secs(60));
+        let first = Nonce::generate();
+        let second = Nonce::generate();
+        assert!(registry.insert_and_check(&first));
+        assert!(registry.insert_and_check(&second));
+        assert!(!registry.insert_and_check(&first));
+        assert_eq!(registry.len(), 2);
+
+        // with no lifetime every nonce is forgotten before the next one is checked
+        let mut registry = NonceRegistry::new(Duration::ZERO);
+        assert!(registry.insert_and_check(&first));
+        assert!(registry.insert_and_check(&first));
+    }
+}
@@ -0,0 +1,196 @@
+//! Decoding of PKCS#1 v1.5 encryption padding without a timing side channel
+//!
+//! If a server takes a different amount of time to reject bad padding than it does to reject a bad
+//! message, an attacker can use it as a padding oracle (Bleichenbacher's attack) to decrypt
+//! captured ciphertexts. The decoder here inspects every byte of the block, and keeps its
+//! intermediate results in masks instead of branching on them.
+//!
+//! The signature padding (EMSA-PKCS1-v1_5 with SHA-256) lives here too, since it shares the block
+//! layout.
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::hint::black_box;
+
+use sha2::{Digest, Sha256};
+
+/// The smallest encoded block: `00 02`, eight bytes of padding, and the `00` separator
+const MINIMUM_BLOCK_LENGTH: usize = 11;
+
+/// Decryption failed. Deliberately says nothing about why.
+#[derive(Debug, PartialEq)]
+pub enum RsaError {
+    DecryptionFailed
+}
+
+impl Display for RsaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for RsaError { }
+
+/// Removes the padding from a decrypted block `em` of `k` bytes, the length of the modulus
+///
+/// The block must be `00 02 PS 00 M`, where `PS` is at least eight non-zero bytes. All `k` bytes
+/// are read whether or not the padding is valid, and every failure returns the same error.
+pub fn rsa_pkcs1v15_decode_constant_time(em: &[u8], k: usize) -> Result<Vec<u8>, RsaError> {
+    // the lengths are public, so checking them can not leak anything about the plaintext
+    if k < MINIMUM_BLOCK_LENGTH || em.len() != k {
+        return Err(RsaError::DecryptionFailed);
+    }
+
+    let mut valid = ct_eq(em[0], 0x00) & ct_eq(em[1], 0x02);
+    let mut searching = 0xFFu8;
+    let mut separator = 0usize;
+    for (index, &byte) in em.iter().enumerate().skip(2) {
+        let is_zero = ct_eq(byte, 0x00);
+        separator = ct_select(searching & is_zero, index, separator);
+        searching &= !is_zero;
+    }
+    // a separator must exist, and come after at least eight bytes of padding
+    valid &= !searching;
+    valid &= ct_ge(separator, MINIMUM_BLOCK_LENGTH - 1);
+
+    if black_box(valid) == 0xFF {
+        Ok(em[separator + 1..].to_vec())
+    } else {
+        Err(RsaError::DecryptionFailed)
+    }
+}
+
+/// The DER encoded `DigestInfo` prefix that identifies a SHA-256 hash, from RFC 8017 section 9.2
+const SHA256_DIGEST_INFO: [u8; 19] = [
+    0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05,
+    0x00, 0x04, 0x20
+];
+
+/// Encodes the SHA-256 hash of `message` into a signature block of `k` bytes
+///
+/// The block is `00 01 FF.. 00 DigestInfo H`, with at least eight `FF` bytes. Returns `None` if
+/// `k` is too short to hold that.
+pub(crate) fn emsa_pkcs1v15_encode_sha256(message: &[u8], k: usize) -> Option<Vec<u8>> {
+    let hash = Sha256::digest(message);
+    let t_length = SHA256_DIGEST_INFO.len() + hash.len();
+    if k < t_length + MINIMUM_BLOCK_LENGTH {
+        return None;
+    }
+
+    let mut em = vec![0x00, 0x01];
+    em.resize(k - t_length - 1, 0xFF);
+    em.push(0x00);
+    em.extend_from_slice(&SHA256_DIGEST_INFO);
+    em.extend_from_slice(&hash);
+    Some(em)
+}
+
+/// `0xFF` if the bytes are equal, `0x00` otherwise
+fn ct_eq(a: u8, b: u8) -> u8 {
+    let difference = black_box(a ^ b) as u16;
+    // difference - 1 only borrows into the high byte when difference is zero
+    (difference.wrapping_sub(1) >> 8) as u8
+}
+
+/// `0xFF` if `a >= b`, `0x00` otherwise
+fn ct_ge(a: usize, b: usize) -> u8 {
+    let (_, borrow) = black_box(a).overflowing_sub(b);
+    (borrow as u8).wrapping_sub(1)
+}
+
+/// `a` if `mask` is `0xFF`, `b` if it is `0x00`
+fn ct_select(mask: u8, a: usize, b: usize) -> usize {
+    let mask = (black_box(mask) as usize).wrapping_neg() >> (usize::BITS - 1);
+    let mask = mask.wrapping_neg();
+    (a & mask) | (b & !mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    const K: usize = 64;
+
+    fn encode(message: &[u8]) -> Vec<u8> {
+        let mut em = vec![0x00, 0x02];
+        em.extend(vec![0xAB; K - 3 - message.len()]);
+        em.push(0x00);
+        em.extend_from_slice(message);
+        em
+    }
+
+    #[test]
+    fn signature_encoding() {
+        let em = emsa_pkcs1v15_encode_sha256(b"message", K).unwrap();
+        assert_eq!(em.len(), K);
+        assert_eq!(&em[..2], &[0x00, 0x01]);
+        assert!(em[2..K - 52].iter().all(|&byte| byte == 0xFF));
+        assert_eq!(em[K - 52], 0x00);
+        assert_eq!(&em[K - 51..K - 32], &SHA256_DIGEST_INFO);
+        assert_eq!(&em[K - 32..], Sha256::digest(b"message").as_slice());
+
+        assert!(emsa_pkcs1v15_encode_sha256(b"message", 62).is_some());
+        assert!(emsa_pkcs1v15_encode_sha256(b"message", 61).is_none());
+    }
+
+    #[test]
+    fn helpers() {
+        assert_eq!(ct_eq(7, 7), 0xFF);
+        assert_eq!(ct_eq(7, 6), 0x00);
+        assert_eq!(ct_ge(10, 10), 0xFF);
+        assert_eq!(ct_ge(9, 10), 0x00);
+        assert_eq!(ct_select(0xFF, 1, 2), 1);
+        assert_eq!(ct_select(0x00, 1, 2), 2);
+    }
+
+    #[test]
+    fn valid_padding() {
+        assert_eq!(rsa_pkcs1v15_decode_constant_time(&encode(b"secret"), K).unwrap(), b"secret");
+        assert_eq!(rsa_pkcs1v15_decode_constant_time(&encode(b""), K).unwrap(), b"");
+    }
+
+    #[test]
+    fn invalid_padding() {
+        let mut wrong_first = encode(b"secret");
+        wrong_first[0] = 0x01;
+        let mut wrong_type = encode(b"secret");
+        wrong_type[1] = 0x01;
+        let mut short_padding = encode(b"secret");
+        short_padding[5] = 0x00;
+        let no_separator = {
+            let mut em = vec![0x00, 0x02];
+            em.extend(vec![0xAB; K - 2]);
+            em
+        };
+        for em in [wrong_first, wrong_type, short_padding, no_separator] {
+            assert_eq!(rsa_pkcs1v15_decode_constant_time(&em, K), Err(RsaError::DecryptionFailed));
+        }
+        assert_eq!(rsa_pkcs1v15_decode_constant_time(&encode(b"secret")[1..], K), Err(RsaError::DecryptionFailed));
+    }
+
+    /// A coarse check that rejecting bad padding takes about as long as accepting good padding.
+    /// The bound is loose, but timing still depends on the machine, so it only runs when asked
+    /// for with `cargo test -- --ignored`.
+    #[test]
+    #[ignore = "depends on the timing of the machine"]
+    fn similar_timing() {
+        let valid = encode(b"secret");
+        let mut invalid = valid.clone();
+        invalid[0] = 0x01;
+
+        let time = |em: &[u8]| {
+            let start = Instant::now();
+            for _ in 0..20_000 {
+                let _ = black_box(rsa_pkcs1v15_decode_constant_time(black_box(em), K));
+            }
+            start.elapsed().as_secs_f64()
+        };
+        // warm up, then take the best of several runs of each
+        time(&valid);
+        let valid_time = (0..5).map(|_| time(&valid)).fold(f64::MAX, f64::min);
+        let invalid_time = (0..5).map(|_| time(&invalid)).fold(f64::MAX, f64::min);
+        let ratio = valid_time.max(invalid_time) / valid_time.min(invalid_time);
+        assert!(ratio < 3.0, "valid took {}s, invalid took {}s", valid_time, invalid_time);
+    }
+}
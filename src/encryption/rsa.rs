@@ -1,13 +1,20 @@
 use std::error::Error;
+use std::convert::{TryFrom, TryInto};
 use std::fmt::{Debug, Display, Formatter};
 use std::str::FromStr;
 use std::string::FromUtf8Error;
 
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use num_bigint::BigUint;
+use num_traits::One;
+use sha2::{Digest, Sha256};
+use std::hint::black_box;
+use zeroize::Zeroize;
 
-use regex::Regex;
-
+pub use der::*;
 pub use generator::*;
+pub use pkcs1::*;
 pub use rsa_stream::*;
 
 use crate::encryption::rsa::RSAMessage::Encrypted;
@@ -18,6 +25,12 @@ mod generator;
 #[path="rsa_stream.rs"]
 mod rsa_stream;
 
+#[path="pkcs1.rs"]
+mod pkcs1;
+
+#[path="der.rs"]
+mod der;
+
 
 #[derive(Debug, Clone)]
 pub struct RSAKeys {
@@ -29,6 +42,21 @@ pub struct RSAKeys {
 #[derive(Debug)]
 pub struct InvalidRSAKey;
 
+/// The smallest key size, in bits, accepted by [`RSAKeys::generate_checked`]
+pub const MINIMUM_KEY_BITS: u16 = 1024;
+
+/// The requested key size is smaller than [`MINIMUM_KEY_BITS`]
+#[derive(Debug, PartialEq)]
+pub struct WeakKeyError;
+
+impl Display for WeakKeyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for WeakKeyError { }
+
 impl RSAKeys {
 
     pub fn new<E : Into<BigUint>, D : Into<BigUint>, N : Into<BigUint>>(public: E, private: D, n_value: N) -> Result<Self, InvalidRSAKey> {
@@ -44,6 +72,11 @@ impl RSAKeys {
         }
     }
 
+    /// Creates the key pair without checking that it is valid
+    ///
+    /// # Safety
+    /// The caller must guarantee that the keys actually form a valid RSA key pair, otherwise
+    /// messages encrypted with it can not be decrypted.
     pub unsafe fn new_unchecked<E : Into<BigUint>, D : Into<BigUint>, N : Into<BigUint>>(public: E, private: D, n_value: N) -> Self {
         Self {
             public_key: public.into(),
@@ -52,6 +85,24 @@ impl RSAKeys {
         }
     }
 
+    /// Generates a new key pair of `key_bits` bits
+    pub fn generate(key_bits: u16) -> Self {
+        RSAKeysGenerator::new(key_bits).generate_keys()
+    }
+
+    /// Generates a new key pair, refusing sizes below [`MINIMUM_KEY_BITS`]
+    pub fn generate_checked(key_bits: u16) -> Result<Self, WeakKeyError> {
+        if key_bits < MINIMUM_KEY_BITS {
+            return Err(WeakKeyError);
+        }
+        Ok(Self::generate(key_bits))
+    }
+
+    /// Generates a new 2048 bit key pair
+    pub fn generate_default() -> Self {
+        Self::generate(2048)
+    }
+
     pub fn from_strings(e: String, d: String, n_value: String) -> Result<Self, Box<dyn Error>> {
         let public_key: BigUint = e.parse()?;
         let private_key: BigUint = d.parse()?;
@@ -70,36 +121,114 @@ impl RSAKeys {
         }
     }
 
-    pub fn private_key(&self) -> PrivateKey {
-        PrivateKey {
-            parent: self,
+    /// An owned copy of the private key, which can outlive these keys
+    pub fn private_key_owned(&self) -> OwnedPrivateKey {
+        OwnedPrivateKey {
             key: self.private_key.clone(),
             n_value: self.n_value.clone()
         }
     }
 
+    /// Consumes the keys, keeping only the private key
+    pub fn into_private_key(mut self) -> OwnedPrivateKey {
+        OwnedPrivateKey {
+            key: std::mem::take(&mut self.private_key),
+            n_value: std::mem::take(&mut self.n_value)
+        }
+    }
+
+    /// Consumes the keys, returning both halves so that they can be moved to different owners
+    pub fn split(mut self) -> (PublicKey, OwnedPrivateKey) {
+        let public_key = PublicKey {
+            key: std::mem::take(&mut self.public_key),
+            n_value: self.n_value.clone()
+        };
+        let private_key = OwnedPrivateKey {
+            key: std::mem::take(&mut self.private_key),
+            n_value: std::mem::take(&mut self.n_value)
+        };
+        (public_key, private_key)
+    }
+
+    pub fn private_key(&self) -> PrivateKey<'_> {
+        PrivateKey { parent: self }
+    }
+
+    /// Signs `message` with PKCS#1 v1.5 padding over its SHA-256 hash (RSASSA-PKCS1-v1_5)
+    ///
+    /// # Panics
+    ///
+    /// Panics if the n value is shorter than 62 bytes, too short to hold the padded hash
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        sign(message, &self.private_key, &self.n_value)
+    }
+
     pub fn valid(&self) -> bool {
         let test_message = BigUint::from(2usize);
 
         let message = RSAMessage::Decrypted(test_message.clone());
-        let encrypted = message.encrypt(self.public_key());
-        let decrypt = encrypted.decrypt(self.private_key());
-        if let RSAMessage::Decrypted(de) = decrypt {
-            de == test_message
-        } else {
-            false
-        }
+        let decrypted = message.try_encrypt(self.public_key())
+            .and_then(|encrypted| encrypted.try_decrypt(self.private_key()));
+        decrypted == Ok(RSAMessage::Decrypted(test_message))
     }
 
     /// Maximum message size in bytes
     ///
-    /// This is computed as the bits of the n value - 11 bytes
+    /// This is computed as the bytes of the n value - 11
     pub fn max_message_size(&self) -> usize {
-        let bits = self.n_value.bits();
-        ((bits - 11 * 8) / 8) as usize
+        max_message_size(&self.n_value)
     }
 }
-#[derive(Debug, Clone)]
+/// The private exponent of the fixed 2048 bit test key, with a public exponent of 17
+#[cfg(any(test, feature = "test-utils"))]
+static TEST_VECTOR_D: &str = concat!(
+    "230076846291486467569065256438032609294259885683777129587196776732239115638269591186913055155459",
+    "474743521939711427056535691478748902715127186984348609427884472564969478734419220153720399149967",
+    "338785114289869142331474194208969051491580582809861365524549498516585519250205894525508567720976",
+    "214671378062044890849927649574084638606290196906515894580696719384348877337477370916080954707050",
+    "592586302755937713324761917278279226163541418745778677372127303361260039427421751817871441467759",
+    "943059441165648938402725871562383661406384073559077577239042254924470273211402443972557081539616",
+    "93763533951058901408298379634438092165577"
+);
+/// The n value of the fixed test key
+#[cfg(any(test, feature = "test-utils"))]
+static TEST_VECTOR_N: &str = concat!(
+    "279379027639662139191007811389039597000172718330300800213024657460576068989327360726965852688772",
+    "219331419498221018568650482509909381868368727052423311448145430971748652748937624472374770396388",
+    "911381924494841101402504378682319562525490707697688800994095819627282416232392871923831832232613",
+    "974958101932483081749686187141388407235731094583966947281940680309861580664052574582423664330336",
+    "016004886998942072350165502742407501173714975445172211557700364650872268788520849510292849298391",
+    "244102952538046224597569505190309778736783847587843632130108500777304415825623316969477753691042",
+    "49607475967178272053291116626854396933361"
+);
+
+#[cfg(any(test, feature = "test-utils"))]
+lazy_static! {
+    /// A fixed 2048 bit key pair, so tests don't have to spend time generating keys
+    pub static ref TEST_RSA_KEYS: RSAKeys = RSAKeys::new(
+        17u32,
+        BigUint::from_str(TEST_VECTOR_D).unwrap(),
+        BigUint::from_str(TEST_VECTOR_N).unwrap()
+    ).unwrap();
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl RSAKeys {
+    /// A copy of [`TEST_RSA_KEYS`]. Never use it outside of tests, the private key is public.
+    pub fn from_test_vector() -> Self {
+        TEST_RSA_KEYS.clone()
+    }
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl PublicKey {
+    /// The public half of [`TEST_RSA_KEYS`]
+    pub fn test_vector() -> PublicKey {
+        TEST_RSA_KEYS.public_key()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PublicKey {
     key: BigUint,
     n_value: BigUint
@@ -114,21 +243,119 @@ impl PublicKey {
     }
     /// Maximum message size in bytes
     ///
-    /// This is computed as the bits of the n value - 11 bytes
+    /// This is computed as the bytes of the n value - 11
     pub fn max_message_size(&self) -> usize {
-        (self.n_value.bits() - 1) as usize / 8
+        max_message_size(&self.n_value)
+    }
+
+    /// SHA-256 of the key's string form, used to identify a key without sending all of it
+    pub fn fingerprint(&self) -> [u8; 32] {
+        Sha256::digest(self.to_string().as_bytes()).into()
     }
+
+    /// Encodes the n value and then the exponent, each as a big endian `u32` length followed by
+    /// the big endian bytes of the integer
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for integer in [&self.n_value, &self.key] {
+            let integer = integer.to_bytes_be();
+            let length = u32::try_from(integer.len()).expect("key integers are shorter than 4 GiB");
+            bytes.extend_from_slice(&length.to_be_bytes());
+            bytes.extend_from_slice(&integer);
+        }
+        bytes
+    }
+
+    /// Reads a key written by [`PublicKey::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PublicKeyParseError> {
+        let mut rest = bytes;
+        let mut next_integer = || -> Result<BigUint, PublicKeyParseError> {
+            if rest.len() < 4 {
+                return Err(PublicKeyParseError::InvalidLength);
+            }
+            let (length, after) = rest.split_at(4);
+            let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+            if after.len() < length {
+                return Err(PublicKeyParseError::InvalidLength);
+            }
+            let (integer, after) = after.split_at(length);
+            rest = after;
+            Ok(BigUint::from_bytes_be(integer))
+        };
+        let n_value = next_integer()?;
+        let key = next_integer()?;
+        if !rest.is_empty() {
+            return Err(PublicKeyParseError::InvalidLength);
+        }
+        if key >= n_value {
+            return Err(PublicKeyParseError::WrongFieldOrder);
+        }
+        Ok(Self { key, n_value })
+    }
+
+    /// Checks that `signature` was made by [`RSAKeys::sign`] for `message` with the matching private key
+    pub fn verify_signature(&self, message: &[u8], signature: &Signature) -> bool {
+        let k = modulus_length(&self.n_value);
+        if signature.0.len() != k {
+            return false;
+        }
+        let expected = match pkcs1::emsa_pkcs1v15_encode_sha256(message, k) {
+            Some(expected) => expected,
+            None => return false
+        };
+        let signature = BigUint::from_bytes_be(&signature.0);
+        signature < self.n_value
+            && left_pad(signature.modpow(&self.key, &self.n_value).to_bytes_be(), k) == expected
+    }
+}
+
+/// The length of the n value in bytes
+fn modulus_length(n_value: &BigUint) -> usize {
+    (n_value.bits() as usize).div_ceil(8)
+}
+
+/// Pads big endian `bytes` with leading zeros to `length` bytes
+fn left_pad(bytes: Vec<u8>, length: usize) -> Vec<u8> {
+    let mut padded = vec![0u8; length.saturating_sub(bytes.len())];
+    padded.extend(bytes);
+    padded
+}
+
+fn sign(message: &[u8], key: &BigUint, n_value: &BigUint) -> Signature {
+    let k = modulus_length(n_value);
+    let encoded = pkcs1::emsa_pkcs1v15_encode_sha256(message, k)
+        .expect("the n value is too short to sign with");
+    let signature = BigUint::from_bytes_be(&encoded).modpow(key, n_value);
+    Signature(left_pad(signature.to_bytes_be(), k))
 }
 
-impl <S : AsRef<str>> From<S> for PublicKey {
-    /// Format is <n> <e>
-    fn from(str: S) -> Self {
-        PublicKey::from_str(str.as_ref()).unwrap()
+/// An RSASSA-PKCS1-v1_5 signature: `EM^d mod n` as big endian bytes, as long as the n value
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature(Vec<u8>);
+
+impl Signature {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
     }
 }
 
-#[derive(Debug)]
-pub struct PublicKeyParseError;
+impl From<Vec<u8>> for Signature {
+    fn from(bytes: Vec<u8>) -> Self {
+        Signature(bytes)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PublicKeyParseError {
+    /// The text is not of the form `(n,e)`
+    MissingField,
+    /// A field is not a non-negative integer
+    InvalidInteger,
+    /// The exponent is not smaller than the n value, so the fields were most likely swapped
+    WrongFieldOrder,
+    /// The bytes end inside a field, or continue after the exponent
+    InvalidLength
+}
 
 impl Display for PublicKeyParseError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -142,28 +369,28 @@ impl Error for PublicKeyParseError { }
 impl FromStr for PublicKey {
     type Err = PublicKeyParseError;
 
+    /// Format is `(n,e)`
     fn from_str(str: &str) -> Result<Self, Self::Err> {
-        lazy_static! {
-            static ref RE: Regex = Regex::new("\\((\\d+),(\\d+)\\)").unwrap();
-        }
-
-        let captures = RE.captures(str);
-        if let Some(captures) = captures {
-            if let (Some(n), Some(e)) = (captures.get(1), captures.get(2)) {
-                let n: BigUint = captures.get(1).unwrap().as_str().parse().expect("n not an integer");
-                let e: BigUint = captures.get(2).unwrap().as_str().parse().expect("e not an integer");
-
-                Ok(Self {
-                    key: e,
-                    n_value: n
-                })
-            } else{
-                Err(PublicKeyParseError)
+        let fields = str.trim()
+            .strip_prefix('(')
+            .and_then(|rest| rest.strip_suffix(')'))
+            .ok_or(PublicKeyParseError::MissingField)?;
+        let (n, e) = match fields.split(',').map(str::trim).collect::<Vec<_>>().as_slice() {
+            [n, e] if !n.is_empty() && !e.is_empty() => (*n, *e),
+            _ => return Err(PublicKeyParseError::MissingField)
+        };
+        let parse = |field: &str| -> Result<BigUint, PublicKeyParseError> {
+            if !field.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(PublicKeyParseError::InvalidInteger);
             }
-
-        } else {
-            Err(PublicKeyParseError)
+            field.parse().map_err(|_| PublicKeyParseError::InvalidInteger)
+        };
+        let n_value = parse(n)?;
+        let key = parse(e)?;
+        if key >= n_value {
+            return Err(PublicKeyParseError::WrongFieldOrder);
         }
+        Ok(Self { key, n_value })
     }
 }
 
@@ -174,14 +401,53 @@ impl Display for PublicKey {
 }
 
 /// Should only exist while parent structure exist to ensure no information is lost
+///
+/// The key borrows its values from the parent, so it leaves no copy of them behind to zeroize.
 #[derive(Debug, Clone)]
 pub struct PrivateKey<'a> {
-    parent: &'a RSAKeys,
+    parent: &'a RSAKeys
+}
+
+impl<'a> PrivateKey<'a> {
+    /// The public half of the pair this key belongs to
+    pub fn public_key(&self) -> PublicKey {
+        self.parent.public_key()
+    }
+    pub fn key(&self) -> &'a BigUint {
+        &self.parent.private_key
+    }
+    pub fn n_value(&self) -> &'a BigUint {
+        &self.parent.n_value
+    }
+    /// Signs `message` the same way as [`RSAKeys::sign`]
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        sign(message, self.key(), self.n_value())
+    }
+    /// Maximum message size in bytes
+    ///
+    /// This is computed as the bytes of the n value - 11
+    pub fn max_message_size(&self) -> usize {
+        max_message_size(self.n_value())
+    }
+}
+
+
+/// The bytes of `n_value` less the 11 bytes of headroom PKCS#1 v1.5 padding needs, used by every
+/// key type so that a message split for one key fits the others
+fn max_message_size(n_value: &BigUint) -> usize {
+    ((n_value.bits() / 8) as usize).saturating_sub(11)
+}
+
+/// A private key that owns its values instead of borrowing the [`RSAKeys`] it came from
+///
+/// Use this to store a key, or an [`RSAReader`] built from one, without keeping the key pair alive.
+#[derive(Debug, Clone)]
+pub struct OwnedPrivateKey {
     key: BigUint,
     n_value: BigUint
 }
 
-impl<'a> PrivateKey<'a> {
+impl OwnedPrivateKey {
     pub fn key(&self) -> &BigUint {
         &self.key
     }
@@ -190,20 +456,112 @@ impl<'a> PrivateKey<'a> {
     }
     /// Maximum message size in bytes
     ///
-    /// This is computed as the bits of the n value - 11 bytes
+    /// This is computed as the bytes of the n value - 11
     pub fn max_message_size(&self) -> usize {
-        let bits = self.n_value.bits();
-        ((bits - 11 * 8) / 8) as usize
+        max_message_size(&self.n_value)
     }
 }
 
+impl From<PrivateKey<'_>> for OwnedPrivateKey {
+    fn from(private_key: PrivateKey<'_>) -> Self {
+        private_key.parent.private_key_owned()
+    }
+}
+
+impl Zeroize for RSAKeys {
+    fn zeroize(&mut self) {
+        zeroize_biguint(&mut self.private_key);
+    }
+}
+
+impl Drop for RSAKeys {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl Zeroize for OwnedPrivateKey {
+    fn zeroize(&mut self) {
+        zeroize_biguint(&mut self.key);
+    }
+}
+
+impl Drop for OwnedPrivateKey {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+/// Overwrites the digits of `value` in place, leaving it zero
+///
+/// `BigUint` has no mutable access to its digits, so they are set to all ones with an in place OR
+/// and then cleared with an in place XOR against the same mask. Copies left behind by earlier
+/// arithmetic, such as the old buffer of a number that grew, can't be reached this way.
+pub(crate) fn zeroize_biguint(value: &mut BigUint) {
+    let bits = value.bits();
+    if bits == 0 {
+        return;
+    }
+    let mask = (BigUint::one() << bits) - 1u32;
+    *value |= &mask;
+    *value ^= &mask;
+    // keeps the writes from being removed as dead stores before the digits are freed
+    black_box(&*value);
+}
+
+/// Errors that occur while turning received data back into an [`RSAMessage`]
+#[derive(Debug)]
+pub enum RSADecryptError {
+    /// The received text was not a valid encrypted number
+    InvalidCiphertext(String)
+}
+
+impl Display for RSADecryptError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for RSADecryptError { }
+
+/// The received text was not valid base64
+#[derive(Debug)]
+pub struct Base64Error;
+
+impl Display for Base64Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for Base64Error { }
 
 #[derive(PartialEq)]
 pub enum RSAMessage { Decrypted(BigUint), Encrypted(BigUint) }
 
+/// [`RSAMessage::try_encrypt`] or [`RSAMessage::try_decrypt`] was called on a message that is
+/// already in the state it would produce
+#[derive(Debug, PartialEq)]
+pub enum RSAStateError {
+    AlreadyEncrypted,
+    AlreadyDecrypted
+}
+
+impl Display for RSAStateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
 
+impl Error for RSAStateError { }
 
 
+
+
+/// Put in front of the bytes of [`RSAMessage::from_bytes`], so that leading zero bytes are not
+/// lost when the message is stored as a number
+pub const BYTES_MARKER: u8 = 1;
+
 impl RSAMessage {
 
     pub fn from_message<S : AsRef<str>>(message: S) -> Self {
@@ -213,10 +571,35 @@ impl RSAMessage {
         Self::Decrypted(big_int)
     }
 
-    pub fn from_encrypted<S : AsRef<str>>(message: S) -> Self {
+    /// The binary counterpart of [`from_message`](Self::from_message)
+    ///
+    /// The message is stored as a number, so a [`BYTES_MARKER`] is put in front of the bytes to
+    /// keep any leading zeros. The marker takes up one byte of the key's maximum message size.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut marked = Vec::with_capacity(1 + bytes.len());
+        marked.push(BYTES_MARKER);
+        marked.extend_from_slice(bytes);
+        let message = Self::Decrypted(BigUint::from_bytes_be(&marked));
+        marked.zeroize();
+        message
+    }
+
+    pub fn from_encrypted<S : AsRef<str>>(message: S) -> Result<Self, RSADecryptError> {
         let string = message.as_ref();
-        let big_int = BigUint::from_str(string).unwrap();
-        Self::Encrypted(big_int)
+        let big_int = BigUint::from_str(string)
+            .map_err(|_| RSADecryptError::InvalidCiphertext(string.to_string()))?;
+        Ok(Self::Encrypted(big_int))
+    }
+
+    /// Parses an encrypted message from the base64 encoding of its big endian bytes
+    pub fn from_base64(s: &str) -> Result<Self, Base64Error> {
+        let bytes = BASE64.decode(s).map_err(|_| Base64Error)?;
+        Ok(Self::Encrypted(BigUint::from_bytes_be(&bytes)))
+    }
+
+    /// Base64 encoding of the big endian bytes, which is much shorter than the decimal form
+    pub fn to_base64(&self) -> String {
+        BASE64.encode(self.bytes_be())
     }
 
 
@@ -244,23 +627,57 @@ impl RSAMessage {
         }
     }
 
+    /// The bytes given to [`from_bytes`](Self::from_bytes), for messages that are not text
+    ///
+    /// Returns `None` if the message is still encrypted, or does not start with the
+    /// [`BYTES_MARKER`] because it was not made by `from_bytes`.
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        match self {
+            RSAMessage::Decrypted(msg) => {
+                let mut bytes = msg.to_bytes_be();
+                if bytes.first() != Some(&BYTES_MARKER) {
+                    bytes.zeroize();
+                    return None;
+                }
+                bytes.remove(0);
+                Some(bytes)
+            }
+            RSAMessage::Encrypted(_) => None
+        }
+    }
+
+    /// Returns an encrypted message unchanged
+    #[deprecated(note = "use try_encrypt, which fails if the message is already encrypted")]
     pub fn encrypt(self, public_key: PublicKey) -> Self {
-        if let Self::Decrypted(message) = self {
-            let n = public_key.n_value();
-            let public = public_key.key();
-            let encrypted = message.modpow(public, n);
-            Self::Encrypted(encrypted)
-        } else {
-            self
+        match self {
+            Self::Decrypted(_) => self.try_encrypt(public_key).expect("the message is decrypted"),
+            Self::Encrypted(_) => self
         }
     }
 
-    pub fn decrypt(self, private_key: PrivateKey<'_>) -> Self {
-        if let Self::Encrypted(message) = self {
-            let decrypted = message.modpow(private_key.key(), private_key.n_value());
-            Self::Decrypted(decrypted)
-        } else {
-            self
+    /// Returns a decrypted message unchanged
+    #[deprecated(note = "use try_decrypt, which fails if the message is already decrypted")]
+    pub fn decrypt<K : Into<OwnedPrivateKey>>(self, private_key: K) -> Self {
+        match self {
+            Self::Encrypted(_) => self.try_decrypt(private_key).expect("the message is encrypted"),
+            Self::Decrypted(_) => self
+        }
+    }
+
+    pub fn try_encrypt(self, public_key: PublicKey) -> Result<Self, RSAStateError> {
+        match self {
+            Self::Decrypted(message) => Ok(Self::Encrypted(message.modpow(public_key.key(), public_key.n_value()))),
+            Self::Encrypted(_) => Err(RSAStateError::AlreadyEncrypted)
+        }
+    }
+
+    pub fn try_decrypt<K : Into<OwnedPrivateKey>>(self, private_key: K) -> Result<Self, RSAStateError> {
+        match self {
+            Self::Encrypted(message) => {
+                let private_key = private_key.into();
+                Ok(Self::Decrypted(message.modpow(private_key.key(), private_key.n_value())))
+            }
+            Self::Decrypted(_) => Err(RSAStateError::AlreadyDecrypted)
         }
     }
 
@@ -280,20 +697,23 @@ impl RSAMessage {
 
     pub fn backing(&self) -> &BigUint {
         match self {
-            RSAMessage::Decrypted(d) => { &d }
-            RSAMessage::Encrypted(d) => { &d }
+            RSAMessage::Decrypted(d) => { d }
+            RSAMessage::Encrypted(d) => { d }
         }
     }
 
 }
 
+/// A decrypted message is shown as text, or in base64 if it is not valid UTF-8
 impl Display for RSAMessage {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             RSAMessage::Decrypted(msg) => {
                 let bytes = BigUint::to_bytes_be(msg);
-                let str = String::from_utf8(bytes).ok().unwrap();
-                write!(f, "{}", str)
+                match String::from_utf8(bytes) {
+                    Ok(str) => write!(f, "{}", str),
+                    Err(e) => write!(f, "{}", BASE64.encode(e.as_bytes()))
+                }
             }
             Encrypted(e) => {
                 write!(f, "{}", e)
@@ -327,21 +747,19 @@ impl Debug for RSAMessage {
 mod tests {
     use std::str::FromStr;
 
+    use std::io::{Read, Write};
+
     use crate::encryption::rsa::RSAMessage::Decrypted;
 
     use super::*;
 
     #[test]
     fn encrypt_decrypt_string() {
-        let keys = RSAKeys::new(
-            BigUint::from_str("17").unwrap(),
-            BigUint::from_str("23007684629148646756906525643803260929425988568377712958719677673223911563826959118691305515545947474352193971142705653569147874890271512718698434860942788447256496947873441922015372039914996733878511428986914233147419420896905149158058280986136552454949851658551925020589452550856772097621467137806204489084992764957408463860629019690651589458069671938434887733747737091608095470705059258630275593771332476191727827922616354141874577867737212730336126003942742175181787144146775994305944116564893840272587156238366140638407355907757723904225492447027321140244397255708153961693763533951058901408298379634438092165577").unwrap(),
-            BigUint::from_str("27937902763966213919100781138903959700017271833030080021302465746057606898932736072696585268877221933141949822101856865048250990938186836872705242331144814543097174865274893762447237477039638891138192449484110140250437868231956252549070769768880099409581962728241623239287192383183223261397495810193248308174968618714138840723573109458396694728194068030986158066405257458242366433033601600488699894207235016550274240750117371497544517221155770036465087226878852084951029284929839124410295253804622459756950519030977873678384758784363213010850077730441582562331696947775369104249607475967178272053291116626854396933361").unwrap()
-        ).unwrap();
+        let keys = RSAKeys::from_test_vector();
         let string = "RSA ENCRYPTION TEST";
-        let rsa_message = RSAMessage::from_message(string.clone());
-        let encrypted = rsa_message.encrypt(keys.public_key());
-        let decrypted = encrypted.decrypt(keys.private_key());
+        let rsa_message = RSAMessage::from_message(string);
+        let encrypted = rsa_message.try_encrypt(keys.public_key()).unwrap();
+        let decrypted = encrypted.try_decrypt(keys.private_key()).unwrap();
         if let Some(Ok(message)) = decrypted.into_message() {
             assert_eq!(message, string);
         } else {
@@ -349,22 +767,46 @@ mod tests {
         }
     }
 
+    #[test]
+    fn encrypt_decrypt_bytes() {
+        let bytes: Vec<u8> = (0..=255u8).collect();
+        assert_eq!(RSAMessage::from_bytes(&bytes).into_bytes(), Some(bytes));
+
+        // the marker keeps the leading zeros through encryption as well
+        let keys = RSAKeys::from_test_vector();
+        let bytes = vec![0, 0, 0xFF, 0xFE, 0];
+        let decrypted = RSAMessage::from_bytes(&bytes).try_encrypt(keys.public_key())
+            .and_then(|encrypted| encrypted.try_decrypt(keys.private_key()))
+            .unwrap();
+        assert_eq!(decrypted.into_bytes(), Some(bytes));
+
+        let unmarked = RSAMessage::Decrypted(BigUint::from_bytes_be(&[0xFF, 0xFE]));
+        assert_eq!(unmarked.to_string(), BASE64.encode([0xFF, 0xFE]), "non UTF-8 messages are shown in base64");
+        assert_eq!(unmarked.into_bytes(), None);
+        assert_eq!(RSAMessage::Encrypted(BigUint::from(12u64)).into_bytes(), None);
+    }
+
     #[test]
     fn encrypt_decrypt_number() {
-        let keys = RSAKeys::new(
-            BigUint::from_str("17").unwrap(),
-            BigUint::from_str("23007684629148646756906525643803260929425988568377712958719677673223911563826959118691305515545947474352193971142705653569147874890271512718698434860942788447256496947873441922015372039914996733878511428986914233147419420896905149158058280986136552454949851658551925020589452550856772097621467137806204489084992764957408463860629019690651589458069671938434887733747737091608095470705059258630275593771332476191727827922616354141874577867737212730336126003942742175181787144146775994305944116564893840272587156238366140638407355907757723904225492447027321140244397255708153961693763533951058901408298379634438092165577").unwrap(),
-            BigUint::from_str("27937902763966213919100781138903959700017271833030080021302465746057606898932736072696585268877221933141949822101856865048250990938186836872705242331144814543097174865274893762447237477039638891138192449484110140250437868231956252549070769768880099409581962728241623239287192383183223261397495810193248308174968618714138840723573109458396694728194068030986158066405257458242366433033601600488699894207235016550274240750117371497544517221155770036465087226878852084951029284929839124410295253804622459756950519030977873678384758784363213010850077730441582562331696947775369104249607475967178272053291116626854396933361").unwrap()
-        ).unwrap();
+        let keys = RSAKeys::from_test_vector();
 
         let rsa_message = RSAMessage::Decrypted(BigUint::from(12u64));
-        let encrypted = rsa_message.encrypt(keys.public_key());
+        let encrypted = rsa_message.try_encrypt(keys.public_key()).unwrap();
         if let Decrypted(_) = &encrypted {
             panic!("Did not encrypt")
         }
-        let decrypted = encrypted.decrypt(keys.private_key());
+        let decrypted = encrypted.try_decrypt(keys.private_key()).unwrap();
         assert_eq!(decrypted, RSAMessage::Decrypted(BigUint::from(12u64)));
     }
+
+    #[test]
+    fn wrong_message_state() {
+        let keys = RSAKeys::from_test_vector();
+        let encrypted = RSAMessage::Decrypted(BigUint::from(12u64)).try_encrypt(keys.public_key()).unwrap();
+        assert_eq!(encrypted.try_encrypt(keys.public_key()), Err(RSAStateError::AlreadyEncrypted));
+        let decrypted = RSAMessage::Decrypted(BigUint::from(12u64));
+        assert_eq!(decrypted.try_decrypt(keys.private_key()), Err(RSAStateError::AlreadyDecrypted));
+    }
     #[test]
     fn lifetime() {
         let public_key: PublicKey;
@@ -378,16 +820,153 @@ mod tests {
         println!("Public: {:?}, Private: {:?}", public_key, private_key);
     }
 
+    #[test]
+    fn private_key_is_not_copied() {
+        let keys = RSAKeys::generate(512);
+        assert!(std::ptr::eq(keys.private_key().key(), &keys.private_key));
+
+        let mut owned = OwnedPrivateKey::from(keys.private_key());
+        owned.zeroize();
+        assert_eq!(owned.key(), &BigUint::from(0u32));
+        assert_ne!(keys.private_key().key(), &BigUint::from(0u32));
+    }
+
+    #[test]
+    fn generate_shortcuts() {
+        assert!(RSAKeys::generate(512).valid());
+        assert_eq!(RSAKeys::generate_checked(512).err(), Some(WeakKeyError));
+    }
+
+    #[test]
+    fn max_message_sizes_agree() {
+        for keys in [RSAKeys::from_test_vector(), RSAKeysGenerator::new(512).generate_keys(), RSAKeysGenerator::new(128).generate_keys()] {
+            let expected = (keys.n_value.bits() / 8) as usize - 11;
+            assert_eq!(keys.max_message_size(), expected);
+            assert_eq!(keys.max_message_size(), keys.public_key().max_message_size());
+            assert_eq!(keys.max_message_size(), keys.private_key().max_message_size());
+            assert_eq!(keys.max_message_size(), keys.private_key_owned().max_message_size());
+        }
+        assert_eq!(RSAKeys::new(5u32, 29u32, 35u32).unwrap().public_key().max_message_size(), 0);
+    }
+
+    #[test]
+    fn split_keys() {
+        let keys = RSAKeys::from_test_vector();
+        let expected_public = keys.public_key().to_string();
+        let expected_private = keys.private_key().key().clone();
+        let (public_key, private_key) = keys.split();
+        assert_eq!(public_key.to_string(), expected_public);
+        assert_eq!(private_key.key(), &expected_private);
+        assert_eq!(private_key.n_value(), public_key.n_value());
+
+        let mut encrypted = Vec::new();
+        RSAWriter::new(public_key, &mut encrypted).write_all(b"moved to another thread").unwrap();
+        let decrypted = std::thread::spawn(move || {
+            let mut output = String::new();
+            RSAReader::new(private_key, encrypted.as_slice()).read_to_string(&mut output).unwrap();
+            output
+        }).join().unwrap();
+        assert_eq!(decrypted, "moved to another thread");
+    }
+
+    #[test]
+    fn signatures() {
+        let keys = RSAKeysGenerator::new(512).generate_keys();
+        let message = b"The signed message";
+        let signature = keys.sign(message);
+        assert!(keys.public_key().verify_signature(message, &signature));
+
+        let mut flipped = signature.as_bytes().to_vec();
+        let last = flipped.len() - 1;
+        flipped[last] ^= 1;
+        assert!(!keys.public_key().verify_signature(message, &Signature::from(flipped)));
+
+        assert!(!keys.public_key().verify_signature(b"The signed messagf", &signature));
+
+        let other = RSAKeysGenerator::new(512).generate_keys();
+        assert!(!keys.public_key().verify_signature(message, &other.sign(message)));
+    }
+
+    #[test]
+    fn signature_matches_openssl() {
+        // openssl dgst -sha256 -sign key.pem, where key.pem is the test vector's to_pem_private_key
+        let expected = BASE64.decode(
+            "qo+LVYU8KRjDYd6Oph+2gKz7MdxzZGfZGFnZqJf5XElPTUF8J/EZrp9CeDh2JwN77eKUsqZxB9mS/+iAmaFRDmdE2tum6rXN\
+             M7fGEX4cwJmHeOpihK6TwE/jg1msl0AiVw2H3oop+J1VzgZWxjZ2jo9AQOIOcDANf/Klh+5ZInLWkhT425k/AVj/GZU0\
+             2mpidJmYPOBK+Y1gjAiMp9R6JP6ThGe/kPJ+s8asCN80xeVEfpdkk4aM+3aDArm+j5zkFtx8Gs2MGBZO49MqtISgqxR0\
+             7Fj3tEt2Ni3c0HhURby5dtnzxfJsHcBYhwTU3ddkBboJfMEmI7UrFZsCydnvjw=="
+        ).unwrap();
+        let keys = RSAKeys::from_test_vector();
+        let message = b"secure_com signature test";
+        assert_eq!(keys.sign(message).as_bytes(), expected.as_slice());
+        assert_eq!(keys.private_key().sign(message).as_bytes(), expected.as_slice());
+        assert!(keys.public_key().verify_signature(message, &Signature::from(expected)));
+    }
+
     #[test]
     fn public_key_parsing() {
-        let key1 = "(4,7)";
+        let key1 = "(7,4)";
         let result = PublicKey::from_str(key1);
         assert!(result.is_ok());
         let unwrap = result.unwrap();
-        assert_eq!(unwrap.n_value, BigUint::from(4usize));
-        assert_eq!(unwrap.key, BigUint::from(7usize));
+        assert_eq!(unwrap.n_value, BigUint::from(7usize));
+        assert_eq!(unwrap.key, BigUint::from(4usize));
         let key2 = "(asds)[[]";
-        assert!(PublicKey::from_str(key2).is_err());
+        assert_eq!(PublicKey::from_str(key2).err(), Some(PublicKeyParseError::MissingField));
+        assert_eq!(PublicKey::from_str("(7)").err(), Some(PublicKeyParseError::MissingField));
+        assert_eq!(PublicKey::from_str("(7,)").err(), Some(PublicKeyParseError::MissingField));
+        assert_eq!(PublicKey::from_str("(7,4,1)").err(), Some(PublicKeyParseError::MissingField));
+        assert_eq!(PublicKey::from_str("(7,x)").err(), Some(PublicKeyParseError::InvalidInteger));
+        assert_eq!(PublicKey::from_str("(-7,4)").err(), Some(PublicKeyParseError::InvalidInteger));
+        assert_eq!(PublicKey::from_str("(4,7)").err(), Some(PublicKeyParseError::WrongFieldOrder));
+
+        let keys = RSAKeys::from_test_vector();
+        let parsed = PublicKey::from_str(&keys.public_key().to_string()).unwrap();
+        assert_eq!(parsed.to_string(), keys.public_key().to_string());
+
+        let key = RSAKeysGenerator::new(512).generate_keys().public_key();
+        let parsed = key.to_string().parse::<PublicKey>().unwrap();
+        assert_eq!(parsed.n_value(), key.n_value());
+        assert_eq!(parsed.key(), key.key());
     }
-}
 
+    #[test]
+    fn public_key_bytes() {
+        let key = PublicKey::test_vector();
+        let bytes = key.to_bytes();
+        let parsed = PublicKey::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.n_value, key.n_value);
+        assert_eq!(parsed.key, key.key);
+
+        assert_eq!(PublicKey::from_bytes(&[]).err(), Some(PublicKeyParseError::InvalidLength));
+        assert_eq!(PublicKey::from_bytes(&bytes[..bytes.len() - 1]).err(), Some(PublicKeyParseError::InvalidLength));
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(PublicKey::from_bytes(&longer).err(), Some(PublicKeyParseError::InvalidLength));
+        let swapped = PublicKey { key: key.n_value.clone(), n_value: key.key.clone() };
+        assert_eq!(PublicKey::from_bytes(&swapped.to_bytes()).err(), Some(PublicKeyParseError::WrongFieldOrder));
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn public_key_bytes_round_trip(key in public_key()) {
+            let parsed = PublicKey::from_bytes(&key.to_bytes()).unwrap();
+            proptest::prop_assert_eq!(&parsed.n_value, &key.n_value);
+            proptest::prop_assert_eq!(&parsed.key, &key.key);
+        }
+    }
+
+    /// Keys with n values from one byte up to 4096 bits, and any exponent smaller than n
+    fn public_key() -> impl proptest::strategy::Strategy<Value = PublicKey> {
+        use proptest::prelude::*;
+
+        (prop::collection::vec(any::<u8>(), 1..=512), prop::collection::vec(any::<u8>(), 0..=512))
+            .prop_map(|(mut n_bytes, e_bytes)| {
+                // a non-zero top byte keeps n at the generated size and above zero
+                n_bytes[0] |= 1;
+                let n_value = BigUint::from_bytes_be(&n_bytes);
+                let key = BigUint::from_bytes_be(&e_bytes) % &n_value;
+                PublicKey { key, n_value }
+            })
+    }
+}
@@ -1,50 +1,153 @@
-use crate::encryption::rsa::{PrivateKey, RSAMessage, PublicKey};
+use crate::encryption::rsa::{OwnedPrivateKey, RSAMessage, PublicKey};
 use std::io::{Read, BufReader, BufRead, Write};
 use std::collections::VecDeque;
 use num_bigint::BigUint;
-use std::cell::RefCell;
+use zeroize::Zeroize;
 
-pub struct RSAReader<'a, R>
+/// Prepended to every plaintext chunk before encryption, so that leading zero bytes of the chunk
+/// survive the conversion to and from a `BigUint`
+const CHUNK_HEADER: u8 = 1;
+
+/// How each encrypted message is written as a line of text
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum RSAStreamEncoding {
+    /// The encrypted number in base 10
+    #[default]
+    Decimal,
+    /// The big endian bytes of the encrypted number in base64
+    Base64
+}
+
+impl RSAStreamEncoding {
+    fn encode(&self, message: &RSAMessage) -> String {
+        match self {
+            RSAStreamEncoding::Decimal => message.backing().to_string(),
+            RSAStreamEncoding::Base64 => message.to_base64()
+        }
+    }
+
+    fn decode(&self, line: &str) -> std::io::Result<RSAMessage> {
+        match self {
+            RSAStreamEncoding::Decimal => {
+                RSAMessage::from_encrypted(line).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+            }
+            RSAStreamEncoding::Base64 => {
+                RSAMessage::from_base64(line).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+            }
+        }
+    }
+}
+
+pub struct RSAReader<R>
     where R : Read
 {
-    private_key: PrivateKey<'a>,
-    reader: RefCell<R>,
-    buffer: VecDeque<u8>
+    private_key: OwnedPrivateKey,
+    reader: BufReader<R>,
+    buffer: DecryptedBuffer,
+    encoding: RSAStreamEncoding
 }
 
-impl<'a, R> RSAReader<'a, R> where R : Read {
-    pub fn new(private_key: PrivateKey<'a>, reader: R) -> Self {
-        RSAReader { private_key, reader: RefCell::new(reader), buffer: VecDeque::new() }
+impl<R> RSAReader<R> where R : Read {
+    /// Accepts either a borrowed [`PrivateKey`](crate::encryption::rsa::PrivateKey) or an [`OwnedPrivateKey`]. The reader keeps its own
+    /// copy, so it does not borrow the key pair.
+    pub fn new<K : Into<OwnedPrivateKey>>(private_key: K, reader: R) -> Self {
+        Self::with_encoding(private_key, reader, RSAStreamEncoding::default())
+    }
+
+    pub fn with_encoding<K : Into<OwnedPrivateKey>>(private_key: K, reader: R, encoding: RSAStreamEncoding) -> Self {
+        RSAReader { private_key: private_key.into(), reader: BufReader::new(reader), buffer: DecryptedBuffer(VecDeque::new()), encoding }
+    }
+
+    pub fn inner(&self) -> &R {
+        self.reader.get_ref()
+    }
+
+    /// Reading from the inner reader directly may skip lines that were already buffered
+    pub fn inner_mut(&mut self) -> &mut R {
+        self.reader.get_mut()
+    }
+
+    /// Returns the inner reader. Decrypted bytes that have not been read yet are lost, as are
+    /// lines that were buffered but not decrypted.
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner()
+    }
+
+    /// Returns the inner reader along with the bytes that were read from it past the last line,
+    /// such as the start of whatever the other side sent after its last message. Decrypted bytes
+    /// that have not been read yet are lost.
+    pub fn into_parts(self) -> (R, Vec<u8>) {
+        let leftover = self.reader.buffer().to_vec();
+        (self.reader.into_inner(), leftover)
     }
 }
 
-impl<'a, R> Read for RSAReader<'a, R> where R : Read {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut ref_mut = self.reader.borrow_mut();
-        let mut buffered_reader = BufReader::new(ref_mut.by_ref());
+/// Decrypted bytes waiting to be read
+struct DecryptedBuffer(VecDeque<u8>);
+
+/// Overwrites the decrypted bytes that are still buffered, and those already read out of the buffer
+impl Drop for DecryptedBuffer {
+    fn drop(&mut self) {
+        self.0.resize(self.0.capacity(), 0);
+        let (front, back) = self.0.as_mut_slices();
+        front.zeroize();
+        back.zeroize();
+    }
+}
 
+impl<R> RSAReader<R> where R : Read {
+    /// Reads lines until one holds a message and decrypts it into the buffer, returning `false`
+    /// if the inner reader ended first
+    fn read_message(&mut self) -> std::io::Result<bool> {
         let mut line = String::new();
-        while buffered_reader.read_line(&mut line)? != 0 {
-            let rsa_message = RSAMessage::from_encrypted(line.trim());
-            let decrypted = rsa_message.decrypt(self.private_key.clone());
-            if let RSAMessage::Decrypted(big) = decrypted {
-                let bytes = big.to_bytes_be();
-                for byte in bytes {
-                    self.buffer.push_back(byte)
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(false);
+            }
+            if !line.trim().is_empty() {
+                break;
+            }
+        }
+        let rsa_message = self.encoding.decode(line.trim())?;
+        let decrypted = rsa_message.try_decrypt(self.private_key.clone())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        if let RSAMessage::Decrypted(big) = decrypted {
+            let bytes = big.to_bytes_be();
+            match bytes.split_first() {
+                Some((&CHUNK_HEADER, chunk)) => self.buffer.0.extend(chunk),
+                _ => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                                                   "RSA message is missing its chunk header"))
                 }
-            } else {
-                unreachable!()
             }
-            line.clear();
+        } else {
+            unreachable!()
         }
+        Ok(true)
+    }
+}
 
-        let mut index = 0;
-        while index < buf.len() && self.buffer.len() > 0 {
-            buf[index] = self.buffer.pop_front().unwrap();
-            index += 1;
+impl<R> Read for RSAReader<R> where R : Read {
+    /// Returns buffered bytes if there are any, and otherwise decrypts the next line
+    ///
+    /// At most one line is read per call, so on a live connection data is returned as soon as
+    /// its line arrives instead of when the connection closes.
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.buffer.0.is_empty() {
+            if !self.read_message()? {
+                return Ok(0);
+            }
         }
 
-        return Ok(index);
+        let count = buf.len().min(self.buffer.0.len());
+        for (slot, byte) in buf.iter_mut().zip(self.buffer.0.drain(..count)) {
+            *slot = byte;
+        }
+        Ok(count)
     }
 }
 
@@ -52,48 +155,269 @@ pub struct RSAWriter<W>
 where W : Write
 {
     public_key: PublicKey,
-    writer: W
+    writer: W,
+    encoding: RSAStreamEncoding
 }
 
 impl<W> RSAWriter<W>
     where W : Write {
     pub fn new(public_key: PublicKey, writer: W) -> Self {
-        RSAWriter { public_key, writer }
+        Self::with_encoding(public_key, writer, RSAStreamEncoding::default())
+    }
+
+    pub fn with_encoding(public_key: PublicKey, writer: W, encoding: RSAStreamEncoding) -> Self {
+        RSAWriter { public_key, writer, encoding }
+    }
+
+    pub fn inner(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn inner_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
     }
 }
 
 impl <W> Write for RSAWriter<W> where W : Write {
-    /// Writes the a message, split into multiple parts if required
+    /// Writes the whole message, split into multiple parts if required
+    ///
+    /// Each part is encrypted separately and written as its own line, so a single call to `write`
+    /// may produce multiple lines of ciphertext. The entire buffer is consumed unless an error occurs,
+    /// including `WriteZero` if the key is too small to hold a single byte of data.
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let max_bytes = self.public_key.max_message_size();
-        let mut index = 0;
-        let mut bytes = Vec::new();
-        while index < buf.len() && index < max_bytes {
-            let byte = buf[index];
-            index += 1;
-            bytes.push(byte);
+        if buf.is_empty() {
+            return Ok(0);
         }
-        let big_uint = BigUint::from_bytes_be(bytes.as_ref());
-        let encrypted = RSAMessage::Decrypted(big_uint).encrypt(self.public_key.clone());
-        let big_uint = encrypted.backing();
-        writeln!(self.writer, "{}", big_uint)?;
-        Ok(index)
+        // one byte of every message is taken up by the chunk header
+        let max_bytes = self.public_key.max_message_size().saturating_sub(1);
+        if max_bytes == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::WriteZero, "RSA modulus too small to encrypt any data"));
+        }
+        for chunk in buf.chunks(max_bytes) {
+            let mut bytes = Vec::with_capacity(chunk.len() + 1);
+            bytes.push(CHUNK_HEADER);
+            bytes.extend_from_slice(chunk);
+            let big_uint = BigUint::from_bytes_be(bytes.as_ref());
+            bytes.zeroize();
+            let encrypted = RSAMessage::Decrypted(big_uint).try_encrypt(self.public_key.clone())
+                .expect("a new message is not encrypted yet");
+            writeln!(self.writer, "{}", self.encoding.encode(&encrypted))?;
+        }
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        (&mut self.writer).flush()
+        self.writer.flush()
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use crate::encryption::rsa::{RSAKeysGenerator, RSAWriter, RSAReader};
+    use crate::encryption::rsa::{RSAKeys, RSAKeysGenerator, RSAWriter, RSAReader, RSAStreamEncoding};
     use std::io::{Write, BufReader, Read};
 
+    fn round_trip(key_size: u16, message: &[u8]) -> Vec<u8> {
+        let keys = RSAKeysGenerator::new(key_size).generate_keys();
+        let mut inner: Vec<u8> = Vec::new();
+        {
+            let mut writer = RSAWriter::new(keys.public_key(), &mut inner);
+            assert_eq!(writer.write(message).unwrap(), message.len());
+        }
+        let mut reader = RSAReader::new(keys.private_key(), &*inner);
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).unwrap();
+        all
+    }
+
+    #[test]
+    fn single_write_spans_multiple_lines() {
+        let message = "Hello, World! ".repeat(10);
+        let keys = RSAKeysGenerator::new(128).generate_keys();
+        let mut inner: Vec<u8> = Vec::new();
+        {
+            let mut writer = RSAWriter::new(keys.public_key(), &mut inner);
+            assert_eq!(writer.write(message.as_bytes()).unwrap(), message.len());
+        }
+        let lines = String::from_utf8(inner.clone()).unwrap().lines().count();
+        assert!(lines > 1, "message should have been split, found {} line(s)", lines);
+
+        let mut reader = RSAReader::new(keys.private_key(), &*inner);
+        let mut string = String::new();
+        reader.read_to_string(&mut string).unwrap();
+        assert_eq!(string, message);
+    }
+
+    fn encoded_round_trip(encoding: RSAStreamEncoding) -> usize {
+        let keys = RSAKeys::from_test_vector();
+        let message = "Hello, World! ".repeat(20);
+        let mut inner: Vec<u8> = Vec::new();
+        {
+            let mut writer = RSAWriter::with_encoding(keys.public_key(), &mut inner, encoding);
+            write!(writer, "{}", message).unwrap();
+        }
+        let mut reader = RSAReader::with_encoding(keys.private_key(), &*inner, encoding);
+        let mut string = String::new();
+        reader.read_to_string(&mut string).unwrap();
+        assert_eq!(string, message);
+        inner.len()
+    }
+
+    #[test]
+    fn encodings_round_trip() {
+        let decimal = encoded_round_trip(RSAStreamEncoding::Decimal);
+        let base64 = encoded_round_trip(RSAStreamEncoding::Base64);
+        assert!(base64 < decimal, "base64 used {} bytes, decimal used {}", base64, decimal);
+    }
+
+    #[test]
+    fn invalid_base64_is_invalid_data() {
+        let keys = RSAKeys::from_test_vector();
+        let mut reader = RSAReader::with_encoding(keys.private_key(), &b"not*base64\n"[..], RSAStreamEncoding::Base64);
+        let mut buffer = [0u8; 16];
+        assert_eq!(reader.read(&mut buffer).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn non_numeric_line_is_invalid_data() {
+        let keys = RSAKeys::from_test_vector();
+        let mut reader = RSAReader::new(keys.private_key(), &b"HTTP/1.1 400 Bad Request\r\n"[..]);
+        let mut buffer = [0u8; 16];
+        let error = reader.read(&mut buffer).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn garbage_bytes_are_invalid_data() {
+        let keys = RSAKeys::from_test_vector();
+        let garbage = std::io::Cursor::new(vec![0xff, 0xfe, b'1', 0x00, 0x80, b'\n']);
+        let mut reader = RSAReader::new(keys.private_key(), garbage);
+        let mut buffer = [0u8; 16];
+        assert_eq!(reader.read(&mut buffer).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+        // a line cut short still parses as a number, but doesn't decrypt to a chunk
+        let mut inner = Vec::new();
+        RSAWriter::new(keys.public_key(), &mut inner).write_all(b"Hello, World!").unwrap();
+        let truncated = format!("{}\n", &String::from_utf8(inner).unwrap()[..40]);
+        let mut reader = RSAReader::new(keys.private_key(), std::io::Cursor::new(truncated));
+        assert_eq!(reader.read(&mut buffer).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_returns_one_line_at_a_time() {
+        let keys = RSAKeys::from_test_vector();
+        let mut inner = Vec::new();
+        {
+            let mut writer = RSAWriter::new(keys.public_key(), &mut inner);
+            writer.write_all(b"first").unwrap();
+            writer.write_all(b"second").unwrap();
+        }
+        let mut reader = RSAReader::new(keys.private_key(), std::io::Cursor::new(inner));
+        let mut buffer = [0u8; 64];
+        let count = reader.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[..count], b"first");
+        let count = reader.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[..count], b"second");
+        assert_eq!(reader.read(&mut buffer).unwrap(), 0);
+    }
+
+    /// The first line must be readable while the other end is still connected
+    #[test]
+    fn read_before_stream_ends() {
+        let keys = RSAKeys::from_test_vector();
+        let (sender, receiver) = std::sync::mpsc::channel::<Vec<u8>>();
+        struct ChannelReader(std::sync::mpsc::Receiver<Vec<u8>>);
+        impl Read for ChannelReader {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                // panics instead of blocking if the reader asks for more than was sent
+                let bytes = self.0.try_recv().expect("read past the data that was sent");
+                buf[..bytes.len()].copy_from_slice(&bytes);
+                Ok(bytes.len())
+            }
+        }
+
+        let mut line = Vec::new();
+        RSAWriter::new(keys.public_key(), &mut line).write_all(b"Hello").unwrap();
+        sender.send(line).unwrap();
+        let mut reader = RSAReader::new(keys.private_key(), ChannelReader(receiver));
+        let mut buffer = [0u8; 64];
+        let count = reader.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[..count], b"Hello");
+    }
+
+    #[test]
+    fn empty_lines_skipped() {
+        let keys = RSAKeys::from_test_vector();
+        let mut inner: Vec<u8> = b"\n\n".to_vec();
+        {
+            let mut writer = RSAWriter::new(keys.public_key(), &mut inner);
+            write!(writer, "Hello").unwrap();
+        }
+        inner.extend_from_slice(b"\n");
+        let mut reader = RSAReader::new(keys.private_key(), &*inner);
+        let mut string = String::new();
+        reader.read_to_string(&mut string).unwrap();
+        assert_eq!(string, "Hello");
+    }
+
+    /// `write_all` must see the whole buffer consumed, including when the input splits into
+    /// chunks with no remainder
+    #[test]
+    fn write_consumes_multiples_of_chunk_size() {
+        let keys = RSAKeysGenerator::new(128).generate_keys();
+        let chunk_size = keys.public_key().max_message_size() - 1;
+        for message in [vec![b'a'; chunk_size], vec![b'b'; chunk_size * 3], vec![b'c'; chunk_size * 3 + 1]] {
+            let mut inner: Vec<u8> = Vec::new();
+            {
+                let mut writer = RSAWriter::new(keys.public_key(), &mut inner);
+                assert_eq!(writer.write(&message).unwrap(), message.len());
+            }
+            let lines = String::from_utf8(inner.clone()).unwrap().lines().count();
+            assert_eq!(lines, message.len().div_ceil(chunk_size));
+
+            let mut all = Vec::new();
+            RSAReader::new(keys.private_key(), &*inner).read_to_end(&mut all).unwrap();
+            assert_eq!(all, message);
+        }
+    }
+
+    #[test]
+    fn modulus_too_small() {
+        let keys = RSAKeys::new(5u32, 29u32, 35u32).unwrap();
+        let mut inner: Vec<u8> = Vec::new();
+        let mut writer = RSAWriter::new(keys.public_key(), &mut inner);
+        let error = writer.write_all(b"Hello").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::WriteZero);
+        assert_eq!(error.to_string(), "RSA modulus too small to encrypt any data");
+        assert_eq!(writer.write(b"").unwrap(), 0);
+        assert!(inner.is_empty());
+    }
+
+    #[test]
+    fn write_all_larger_than_modulus() {
+        let keys = RSAKeysGenerator::new(128).generate_keys();
+        let message = "The quick brown fox jumps over the lazy dog. ".repeat(8);
+        let mut inner: Vec<u8> = Vec::new();
+        RSAWriter::new(keys.public_key(), &mut inner).write_all(message.as_bytes()).unwrap();
+
+        let mut all = Vec::new();
+        RSAReader::new(keys.private_key(), &*inner).read_to_end(&mut all).unwrap();
+        assert_eq!(all, message.as_bytes());
+    }
+
+    #[test]
+    fn leading_zero_bytes_preserved() {
+        let message = [0u8, 0, 0, 7, 0, 0, 9, 0];
+        assert_eq!(round_trip(128, &message), message);
+    }
+
     #[test]
     fn read_and_write_small() {
-        let keys = RSAKeysGenerator::new(32).generate_keys();
+        let keys = RSAKeysGenerator::new(128).generate_keys();
         let mut inner: Vec<u8> = Vec::new();
         {
             let mut writer = RSAWriter::new(keys.public_key(), &mut inner);
@@ -132,7 +456,7 @@ mod tests {
 
     #[test]
     fn read_and_write_big() {
-        let keys = RSAKeysGenerator::new(2048).generate_keys();
+        let keys = RSAKeys::from_test_vector();
         let mut inner: Vec<u8> = Vec::new();
         {
             let mut writer = RSAWriter::new(keys.public_key(), &mut inner);
@@ -149,7 +473,54 @@ mod tests {
 
     }
 
-}
+    /// The streams can be moved to another thread, for example by `spawn_blocking`
+    #[test]
+    fn streams_are_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<RSAReader<std::io::Cursor<Vec<u8>>>>();
+        assert_send::<RSAWriter<Vec<u8>>>();
+    }
+
+    /// A reader built from owned keys can be stored after the key pair is gone
+    #[test]
+    fn reader_outlives_keys() {
+        struct Receiver {
+            reader: RSAReader<std::io::Cursor<Vec<u8>>>
+        }
+
+        let keys = RSAKeysGenerator::new(128).generate_keys();
+        let mut inner: Vec<u8> = Vec::new();
+        RSAWriter::new(keys.public_key(), &mut inner).write_all(b"owned").unwrap();
+        let mut receiver = Receiver {
+            reader: RSAReader::new(keys.into_private_key(), std::io::Cursor::new(inner))
+        };
+
+        let mut all = Vec::new();
+        receiver.reader.read_to_end(&mut all).unwrap();
+        assert_eq!(all, b"owned");
+    }
 
 
+    #[test]
+    fn into_inner_returns_the_same_buffers() {
+        let keys = RSAKeys::from_test_vector();
+        let inner = Vec::with_capacity(4096);
+        let pointer = inner.as_ptr();
+        let mut writer = RSAWriter::new(keys.public_key(), inner);
+        writer.write_all(b"Hello, World!").unwrap();
+        assert!(!writer.inner().is_empty());
+        let lines = writer.inner().len();
+        writer.inner_mut().extend_from_slice(b"\n");
+        let inner = writer.into_inner();
+        assert_eq!(inner.as_ptr(), pointer);
+        assert_eq!(inner.len(), lines + 1);
 
+        let mut reader = RSAReader::new(keys.private_key(), inner.as_slice());
+        let mut string = String::new();
+        reader.read_to_string(&mut string).unwrap();
+        assert_eq!(string, "Hello, World!");
+        assert!(reader.inner().is_empty());
+        assert!(reader.inner_mut().is_empty());
+        assert!(reader.into_inner().is_empty());
+    }
+}
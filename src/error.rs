@@ -0,0 +1,91 @@
+//! The error returned by the handshake and by the messages it is built from
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+
+use crate::cert::CertError;
+use crate::encryption::aes::AESManagerParseError;
+use crate::encryption::dh::DhError;
+use crate::encryption::rsa::PublicKeyParseError;
+
+#[derive(Debug)]
+pub enum SecureComError {
+    /// The other side sent a message that does not belong at this point of the handshake
+    HandshakePhaseError(String),
+    /// The other side did not repeat the nonce it was sent
+    NonceMismatch,
+    /// The AES key sent by the client could not be parsed
+    AesKeyParseError(AESManagerParseError),
+    /// A message could not be decrypted with our private key
+    RsaDecryptionError,
+    IoError(std::io::Error),
+    /// The public key sent by the other side could not be parsed
+    InvalidPublicKey,
+    /// The certificate sent by the other side could not be parsed or is not correctly signed
+    InvalidCertificate(Option<CertError>),
+    /// The Diffie-Hellman group or public value sent by the other side can not be used
+    KeyExchangeError(DhError),
+    /// The other side sent a nonce that was already used, so the handshake may be a replay
+    NonceReused,
+    /// The other side speaks a different version of the protocol
+    ProtocolVersionMismatch { got: u8, expected: u8 },
+    /// The other side's key is not one of [`HandshakeConfig::trusted_keys`](crate::handshake::HandshakeConfig::trusted_keys)
+    UntrustedKey,
+    /// A stream shared between threads can't be used because another thread panicked while holding
+    /// its lock
+    PoisonedLock
+}
+
+/// The error inside the `BrokenPipe` [`std::io::Error`] returned by a stream shared between
+/// threads once its lock is poisoned, which the handshake turns into
+/// [`SecureComError::PoisonedLock`]
+#[derive(Debug)]
+pub struct LockPoisoned;
+
+impl Display for LockPoisoned {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "stream lock was poisoned by a panic in another thread")
+    }
+}
+
+impl Error for LockPoisoned { }
+
+impl Display for SecureComError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for SecureComError { }
+
+impl From<std::io::Error> for SecureComError {
+    fn from(e: std::io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<LockPoisoned>()) {
+            return SecureComError::PoisonedLock;
+        }
+        SecureComError::IoError(e)
+    }
+}
+
+impl From<AESManagerParseError> for SecureComError {
+    fn from(e: AESManagerParseError) -> Self {
+        SecureComError::AesKeyParseError(e)
+    }
+}
+
+impl From<PublicKeyParseError> for SecureComError {
+    fn from(_: PublicKeyParseError) -> Self {
+        SecureComError::InvalidPublicKey
+    }
+}
+
+impl From<CertError> for SecureComError {
+    fn from(e: CertError) -> Self {
+        SecureComError::InvalidCertificate(Some(e))
+    }
+}
+
+impl From<DhError> for SecureComError {
+    fn from(e: DhError) -> Self {
+        SecureComError::KeyExchangeError(e)
+    }
+}
@@ -1,91 +1,464 @@
-use crate::encryption::aes::{AESManager, KeySize};
-use std::io::{Write, Read};
-use crate::encryption::aes::aes_stream::{AESWriter, AESReader};
-use crate::encryption::generate_nonce;
+use crate::encryption::aes::{AESManager, CipherChoice, KeySize};
+use std::io::{Write, Read, BufRead, BufReader};
+use std::str::FromStr;
+use hkdf::Hkdf;
+use num::Num;
+use num_bigint::BigUint;
+use sha2::Sha256;
+use crate::encryption::dh::{DhError, DhGroup, DhKeypair};
+use crate::encryption::nonce::{Nonce, NonceRegistry};
 
+use crate::cert::Certificate;
 use crate::encryption::{unsecure, secure};
-use std::error::Error;
-use crate::encryption::rsa::{RSAWriter, RSAKeys, RSAKeysGenerator, RSAReader};
-use crate::encryption::unsecure::{send_public_key, receive_public_key, handshake_start};
-use crate::encryption::secure::{receive_and_repeat, encryption_successful, begin_aes_encryption_client, client_repeat_correct, get_aes_key};
-
-pub fn client_handshake<W: Write, R: Read>(mut writer: W, mut reader: R)
-                                           -> Result<AESManager, Box<dyn Error>> {
-    let first_nonce = generate_nonce(4);
+use crate::channel::SecureChannel;
+use crate::error::SecureComError;
+use crate::protocol::{ProtocolMessage, DH_REPLY_PHRASE, DH_START_PHRASE};
+use std::cell::RefCell;
+use crate::encryption::rsa::{PublicKey, RSAWriter, RSAKeys, RSAReader};
+use crate::encryption::unsecure::{send_public_key, receive_public_key, send_certificate, receive_certificate};
+use crate::encryption::secure::{receive_and_repeat, receive_success, begin_aes_encryption_client, client_repeat_correct, get_aes_key};
+
+/// The key sizes used by a handshake
+///
+/// Both sides must use the same AES key size, since the server rejects a key of any other size.
+#[derive(Debug, Clone)]
+pub struct HandshakeConfig {
+    /// The size of the RSA keys generated to exchange the AES key
+    pub rsa_key_bits: u16,
+    /// The size of the AES key the client generates
+    pub aes_key_size: KeySize,
+    /// When set, this side sends a self signed certificate naming this identity in place of its
+    /// bare RSA public key, and expects a correctly signed certificate from the other side
+    ///
+    /// Both sides must either set it or leave it unset. Signing the certificate needs RSA keys of
+    /// at least 496 bits.
+    pub certificate_identity: Option<String>,
+    /// The keys the other side may use, such as a server's key pinned by its clients
+    ///
+    /// When it is not empty, the handshake fails with [`SecureComError::UntrustedKey`] if the key
+    /// the other side sends, bare or in its certificate, is not one of them. A self signed
+    /// certificate only shows the other side holds the key it names, so without this anyone can
+    /// present a certificate for any identity.
+    pub trusted_keys: Vec<PublicKey>,
+    /// Whether the channels made by [`client_handshake_channel_with_config`] and
+    /// [`server_handshake_channel_with_config`] count the bytes they send and receive, see
+    /// [`SecureChannel::with_metrics`]
+    pub enable_metrics: bool
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        HandshakeConfig {
+            rsa_key_bits: 2048,
+            aes_key_size: KeySize::K256,
+            certificate_identity: None,
+            trusted_keys: Vec::new(),
+            enable_metrics: false
+        }
+    }
+}
+
+impl HandshakeConfig {
+    pub fn builder() -> HandshakeConfigBuilder {
+        HandshakeConfigBuilder::default()
+    }
+}
+
+/// Builds a [`HandshakeConfig`], starting from the default values
+#[derive(Debug, Clone, Default)]
+pub struct HandshakeConfigBuilder {
+    config: HandshakeConfig
+}
+
+impl HandshakeConfigBuilder {
+    pub fn rsa_key_bits(mut self, bits: u16) -> Self {
+        self.config.rsa_key_bits = bits;
+        self
+    }
+
+    pub fn aes_key_size(mut self, key_size: KeySize) -> Self {
+        self.config.aes_key_size = key_size;
+        self
+    }
+
+    pub fn certificate_identity(mut self, identity: &str) -> Self {
+        self.config.certificate_identity = Some(identity.to_string());
+        self
+    }
+
+    /// Adds `key` to [`HandshakeConfig::trusted_keys`]
+    pub fn trust_key(mut self, key: PublicKey) -> Self {
+        self.config.trusted_keys.push(key);
+        self
+    }
+
+    pub fn enable_metrics(mut self, enable: bool) -> Self {
+        self.config.enable_metrics = enable;
+        self
+    }
+
+    pub fn build(self) -> HandshakeConfig {
+        self.config
+    }
+}
+
+/// What both sides know after a handshake, beyond the AES key
+#[derive(Debug)]
+pub struct HandshakeResult {
+    pub aes_manager: AESManager,
+    /// The RSA key the other side generated for this handshake, for logging or pinning
+    pub remote_public_key: PublicKey,
+    /// The XOR of the client's and the server's nonce from the encrypted half of the handshake,
+    /// which is the same on both sides
+    pub session_id: [u8; 16],
+    /// The size of the RSA keys this side generated, from its [`HandshakeConfig`]
+    pub negotiated_rsa_bits: u16,
+    /// The identity named in the other side's certificate, if the handshake exchanged certificates
+    pub remote_identity: Option<String>
+}
+
+fn session_id(client_nonce: &Nonce, server_nonce: &Nonce) -> [u8; 16] {
+    let mut id = *client_nonce.as_bytes();
+    for (byte, other) in id.iter_mut().zip(server_nonce.as_bytes()) {
+        *byte ^= other;
+    }
+    id
+}
+
+/// Sends the public half of `keys`, in a certificate if the config asks for one
+fn send_key<W: Write>(keys: &RSAKeys, config: &HandshakeConfig, writer: &mut W) -> std::io::Result<()> {
+    match &config.certificate_identity {
+        Some(identity) => send_certificate(&Certificate::self_signed(identity, keys), writer),
+        None => send_public_key(keys.public_key(), writer)
+    }
+}
+
+/// Receives the other side's public key, and the identity in its certificate if the config asks
+/// for one, failing if the config trusts only other keys
+fn receive_key<R: Read>(config: &HandshakeConfig, reader: &mut R) -> Result<(PublicKey, Option<String>), SecureComError> {
+    let (key, identity) = if config.certificate_identity.is_some() {
+        let certificate = receive_certificate(reader)?;
+        (certificate.public_key().clone(), Some(certificate.identity().to_string()))
+    } else {
+        (receive_public_key(reader)?, None)
+    };
+    if !config.trusted_keys.is_empty() && !config.trusted_keys.contains(&key) {
+        return Err(SecureComError::UntrustedKey);
+    }
+    Ok((key, identity))
+}
+
+pub fn client_handshake<W: Write, R: Read>(writer: W, reader: R)
+                                           -> Result<HandshakeResult, SecureComError> {
+    client_handshake_with_config(writer, reader, &HandshakeConfig::default())
+}
+
+pub fn client_handshake_with_config<W: Write, R: Read>(writer: W, reader: R, config: &HandshakeConfig)
+                                                       -> Result<HandshakeResult, SecureComError> {
+    client_handshake_with_manager(writer, reader, config, AESManager::new(config.aes_key_size))
+}
+
+/// Runs the client's side of the handshake, sending `aes_manager` as the key
+fn client_handshake_with_manager<W: Write, R: Read>(mut writer: W, mut reader: R, config: &HandshakeConfig, aes_manager: AESManager)
+                                                    -> Result<HandshakeResult, SecureComError> {
+    let first_nonce = Nonce::generate();
+    unsecure::send_protocol_version(&mut writer)?;
     unsecure::handshake_start(&first_nonce, &mut writer)?;
     if !unsecure::receive_ack(&first_nonce, &mut reader)? {
-        Err("Did not receive correct acknowlegement from server")?
+        return Err(SecureComError::NonceMismatch);
     }
 
 
-    let aes_manager = AESManager::new(KeySize::K256);
-    { // RSA segment
-        let key = RSAKeysGenerator::new(512).generate_keys();
-        send_public_key(key.public_key(), &mut writer)?;
-        let server_public_key = receive_public_key(&mut reader)?;
+    let key = RSAKeys::generate(config.rsa_key_bits);
+    send_key(&key, config, &mut writer)?;
+    let (server_public_key, remote_identity) = receive_key(config, &mut reader)?;
 
-        let mut rsa_writer = RSAWriter::new(server_public_key, &mut writer);
-        let mut rsa_reader = RSAReader::new(key.private_key(), &mut reader);
+    let mut rsa_writer = RSAWriter::new(server_public_key.clone(), &mut writer);
+    let mut rsa_reader = RSAReader::new(key.private_key(), &mut reader);
 
-        let second_nonce = generate_nonce(16);
-        secure::handshake_start(&second_nonce, &mut rsa_writer)?;
-        receive_and_repeat(&second_nonce, &mut rsa_writer, &mut rsa_reader)?;
+    let second_nonce = Nonce::generate();
+    secure::handshake_start(&second_nonce, &mut rsa_writer)?;
+    let server_nonce = receive_and_repeat(&second_nonce, &mut rsa_writer, &mut rsa_reader)?;
 
-        if !encryption_successful(&mut rsa_reader)? {
-            Err("Encrypted connection was not established")?
-        }
+    receive_success(&mut rsa_reader)?;
 
-        begin_aes_encryption_client(&aes_manager, &mut rsa_writer)?;
-    };
+    begin_aes_encryption_client(&aes_manager, &mut rsa_writer)?;
+
+    Ok(HandshakeResult {
+        aes_manager,
+        remote_public_key: server_public_key,
+        session_id: session_id(&second_nonce, &server_nonce),
+        negotiated_rsa_bits: config.rsa_key_bits,
+        remote_identity
+    })
+}
 
-    Ok(aes_manager)
+pub fn server_handshake<W: Write, R: Read>(writer: W, reader: R)
+                                               -> Result<HandshakeResult, SecureComError> {
+    server_handshake_with_config(writer, reader, &HandshakeConfig::default())
 }
 
-pub fn server_handshake<'a, W: Write, R: Read>(mut writer: W, mut reader: R)
-                                               -> Result<AESManager, Box<dyn Error>> {
-    //let first_nonce = generate_nonce(4);
+pub fn server_handshake_with_config<W: Write, R: Read>(writer: W, reader: R, config: &HandshakeConfig)
+                                                       -> Result<HandshakeResult, SecureComError> {
+    server_handshake_with_registry(writer, reader, config, None)
+}
+
+/// Runs [`server_handshake_with_config`], failing with [`SecureComError::NonceReused`] if the
+/// client's nonce is already in `registry`
+///
+/// A server should share one registry between all of its connections, so that a recorded
+/// handshake can't be replayed on a new connection.
+pub fn server_handshake_checked<W: Write, R: Read>(writer: W, reader: R, config: &HandshakeConfig, registry: &mut NonceRegistry)
+                                                   -> Result<HandshakeResult, SecureComError> {
+    server_handshake_with_registry(writer, reader, config, Some(registry))
+}
+
+fn server_handshake_with_registry<W: Write, R: Read>(writer: W, reader: R, config: &HandshakeConfig, registry: Option<&mut NonceRegistry>)
+                                                     -> Result<HandshakeResult, SecureComError> {
+    server_handshake_with_keys(writer, reader, config, &RSAKeys::generate(config.rsa_key_bits), registry)
+        .map(|(result, _)| result)
+}
+
+/// Runs the server's side of the handshake with `key` in place of a newly generated key pair, which
+/// must have `config.rsa_key_bits` bits
+///
+/// Also returns the bytes read from `reader` past the client's last message, which the client may
+/// have sent with the new key straight after it.
+pub(crate) fn server_handshake_with_keys<W: Write, R: Read>(mut writer: W, mut reader: R, config: &HandshakeConfig, key: &RSAKeys, registry: Option<&mut NonceRegistry>)
+                                                            -> Result<(HandshakeResult, Vec<u8>), SecureComError> {
+    //let first_nonce = Nonce::generate();
+    unsecure::receive_protocol_version(&mut reader)?;
     unsecure::server_ack(&mut writer, &mut reader)?;
 
-    let key = RSAKeysGenerator::new(512).generate_keys();
-    let client_key = receive_public_key(&mut reader)?;
-    send_public_key(key.public_key(), &mut writer)?;
+    let (client_key, remote_identity) = receive_key(config, &mut reader)?;
+    send_key(key, config, &mut writer)?;
 
-    let mut rsa_writer = RSAWriter::new(client_key, &mut writer);
+    let mut rsa_writer = RSAWriter::new(client_key.clone(), &mut writer);
     let mut rsa_reader = RSAReader::new(key.private_key(), &mut reader);
 
-    let nonce = generate_nonce(16);
-    secure::server_ack(&nonce, &mut rsa_writer, &mut rsa_reader)?;
+    let nonce = Nonce::generate();
+    let client_nonce = secure::server_ack_with_registry(&nonce, registry, &mut rsa_writer, &mut rsa_reader)?;
     if !client_repeat_correct(&nonce, &mut rsa_writer, &mut rsa_reader)? {
-        Err("Client did not repeat correct nonce")?;
+        writeln!(rsa_writer, "{}", ProtocolMessage::Failure { reason: "nonce mismatch".to_string() })?;
+        return Err(SecureComError::NonceMismatch);
     }
-    writeln!(rsa_writer, "SUCCESS")?;
-    get_aes_key(&mut rsa_reader)
+    writeln!(rsa_writer, "{}", ProtocolMessage::Success)?;
+    let aes_manager = get_aes_key(&mut rsa_reader)?;
+    // a ChaCha20-Poly1305 key has the size of an AES-256 key, so the cipher is checked as well
+    if aes_manager.cipher_choice() != CipherChoice::Aes(config.aes_key_size) {
+        return Err(SecureComError::HandshakePhaseError(
+            format!("Client sent a {:?} key instead of {:?}", aes_manager.cipher_choice(), config.aes_key_size)
+        ));
+    }
+    let (_, leftover) = rsa_reader.into_parts();
+    let result = HandshakeResult {
+        aes_manager,
+        remote_public_key: client_key,
+        session_id: session_id(&client_nonce, &nonce),
+        negotiated_rsa_bits: config.rsa_key_bits,
+        remote_identity
+    };
+    Ok((result, leftover))
+}
+
+/// Runs [`client_handshake`] over a single stream, returning a channel for the encrypted messages
+/// that follow
+pub fn client_handshake_channel<S: Read + Write>(stream: S) -> Result<SecureChannel<S>, SecureComError> {
+    client_handshake_channel_with_config(stream, &HandshakeConfig::default())
+}
+
+/// Runs [`client_handshake_with_config`] over a single stream, returning a channel for the
+/// encrypted messages that follow
+pub fn client_handshake_channel_with_config<S: Read + Write>(stream: S, config: &HandshakeConfig) -> Result<SecureChannel<S>, SecureComError> {
+    let stream = RefCell::new(stream);
+    let result = client_handshake_with_config(SharedStream(&stream), SharedStream(&stream), config)?;
+    Ok(channel(result.aes_manager, stream.into_inner(), config, Vec::new()))
+}
+
+/// Runs [`server_handshake`] over a single stream, returning a channel for the encrypted messages
+/// that follow
+pub fn server_handshake_channel<S: Read + Write>(stream: S) -> Result<SecureChannel<S>, SecureComError> {
+    server_handshake_channel_with_config(stream, &HandshakeConfig::default())
+}
+
+/// Runs [`server_handshake_with_config`] over a single stream, returning a channel for the
+/// encrypted messages that follow
+pub fn server_handshake_channel_with_config<S: Read + Write>(stream: S, config: &HandshakeConfig) -> Result<SecureChannel<S>, SecureComError> {
+    let stream = RefCell::new(stream);
+    let keys = RSAKeys::generate(config.rsa_key_bits);
+    let (result, leftover) = server_handshake_with_keys(SharedStream(&stream), SharedStream(&stream), config, &keys, None)?;
+    Ok(channel(result.aes_manager, stream.into_inner(), config, leftover))
+}
+
+/// The channel that follows a handshake over `stream`, starting with the `leftover` bytes the
+/// handshake read past its last message
+pub(crate) fn channel<S>(manager: AESManager, stream: S, config: &HandshakeConfig, leftover: Vec<u8>) -> SecureChannel<S> {
+    let channel = if config.enable_metrics {
+        SecureChannel::with_metrics(manager, stream)
+    } else {
+        SecureChannel::new(manager, stream)
+    };
+    channel.with_buffered(leftover)
+}
+
+/// Lets one stream be passed to a handshake as both its writer and its reader
+pub(crate) struct SharedStream<'a, S>(pub(crate) &'a RefCell<S>);
+
+impl<S: Read> Read for SharedStream<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+impl<S: Write> Write for SharedStream<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// Runs [`client_handshake`] over an async stream
+///
+/// The handshake is the same as the sync one and runs on tokio's blocking thread pool, so neither
+/// the RSA key generation nor the waits for the server stall other tasks. Has to be called from
+/// within a tokio runtime.
+#[cfg(feature = "async")]
+pub async fn client_handshake_async<S>(stream: S) -> Result<SecureChannel<S>, SecureComError>
+    where S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static {
+    handshake_on_blocking_thread(stream, client_handshake_channel).await
+}
+
+/// Runs [`server_handshake`] over an async stream. See [`client_handshake_async`].
+#[cfg(feature = "async")]
+pub async fn server_handshake_async<S>(stream: S) -> Result<SecureChannel<S>, SecureComError>
+    where S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static {
+    handshake_on_blocking_thread(stream, server_handshake_channel).await
+}
+
+#[cfg(feature = "async")]
+async fn handshake_on_blocking_thread<S, H>(stream: S, handshake: H) -> Result<SecureChannel<S>, SecureComError>
+    where S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+          H: FnOnce(BlockingStream<S>) -> Result<SecureChannel<BlockingStream<S>>, SecureComError> + Send + 'static {
+    let runtime = tokio::runtime::Handle::current();
+    let channel = tokio::task::spawn_blocking(move || handshake(BlockingStream { runtime, inner: stream }))
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))?;
+    let (manager, stream) = channel.into_parts();
+    Ok(SecureChannel::new(manager, stream.inner))
+}
+
+/// Lets the sync handshake use an async stream by blocking on each read and write, which is only
+/// allowed outside of the runtime's worker threads
+#[cfg(feature = "async")]
+struct BlockingStream<S> {
+    runtime: tokio::runtime::Handle,
+    inner: S
+}
+
+#[cfg(feature = "async")]
+impl<S: tokio::io::AsyncRead + Unpin> Read for BlockingStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        use tokio::io::AsyncReadExt;
+        self.runtime.block_on(self.inner.read(buf))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<S: tokio::io::AsyncWrite + Unpin> Write for BlockingStream<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        use tokio::io::AsyncWriteExt;
+        self.runtime.block_on(self.inner.write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+        self.runtime.block_on(self.inner.flush())
+    }
+}
+
+/// Agrees on an AES key with Diffie-Hellman in `group` instead of exchanging RSA keys
+///
+/// The public values are sent unencrypted and unsigned, so this is only safe from passive
+/// eavesdroppers. Returns the same key as [`server_dh_handshake`] on the other end.
+pub fn client_dh_handshake<W: Write, R: Read>(mut writer: W, mut reader: R, group: DhGroup)
+                                              -> Result<AESManager, SecureComError> {
+    let keys = DhKeypair::generate(group);
+    let client_nonce = Nonce::generate();
+    writeln!(writer, "{} {} {} {:x}", DH_START_PHRASE, group, client_nonce, keys.public_value())?;
+
+    let line = read_line(&mut reader)?;
+    let (server_nonce, server_public) = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [phrase, nonce, public] if *phrase == DH_REPLY_PHRASE => (parse_nonce(nonce)?, parse_dh_public(public)?),
+        _ => return Err(SecureComError::HandshakePhaseError("Did not receive the server's Diffie-Hellman value".to_string()))
+    };
+    let secret = keys.diffie_hellman(&server_public)?;
+    Ok(dh_aes_manager(&secret, &client_nonce, &server_nonce))
+}
+
+/// The server side of [`client_dh_handshake`], using the group the client chose
+pub fn server_dh_handshake<W: Write, R: Read>(mut writer: W, mut reader: R)
+                                              -> Result<AESManager, SecureComError> {
+    let line = read_line(&mut reader)?;
+    let (group, client_nonce, client_public) = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [phrase, group, nonce, public] if *phrase == DH_START_PHRASE => {
+            (DhGroup::from_str(group)?, parse_nonce(nonce)?, parse_dh_public(public)?)
+        }
+        _ => return Err(SecureComError::HandshakePhaseError("Did not receive the client's Diffie-Hellman value".to_string()))
+    };
+
+    let keys = DhKeypair::generate(group);
+    let secret = keys.diffie_hellman(&client_public)?;
+    let server_nonce = Nonce::generate();
+    writeln!(writer, "{} {} {:x}", DH_REPLY_PHRASE, server_nonce, keys.public_value())?;
+    Ok(dh_aes_manager(&secret, &client_nonce, &server_nonce))
+}
+
+fn parse_dh_public(hex: &str) -> Result<BigUint, SecureComError> {
+    BigUint::from_str_radix(hex, 16).map_err(|_| SecureComError::KeyExchangeError(DhError::InvalidPublicValue))
+}
+
+fn parse_nonce(hex: &str) -> Result<Nonce, SecureComError> {
+    Nonce::from_hex(hex).map_err(|e| SecureComError::HandshakePhaseError(format!("Invalid nonce: {}", e)))
+}
+
+fn read_line<R: Read>(reader: &mut R) -> std::io::Result<String> {
+    let mut buf_reader = BufReader::new(reader);
+    let mut line = String::new();
+    buf_reader.read_line(&mut line)?;
+    Ok(line)
+}
+
+/// `HKDF(shared_secret, client_nonce || server_nonce, "dh-aes-key")`
+fn dh_aes_manager(secret: &BigUint, client_nonce: &Nonce, server_nonce: &Nonce) -> AESManager {
+    let salt = format!("{}{}", client_nonce, server_nonce);
+    let hkdf = Hkdf::<Sha256>::new(Some(salt.as_bytes()), &secret.to_bytes_be());
+    let mut key = [0u8; 32];
+    hkdf.expand(b"dh-aes-key", &mut key).expect("32 bytes is a valid HKDF output length");
+    AESManager::from_key_value(key.to_vec()).expect("32 bytes is a valid AES key")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::File;
-    use crate::multi_file_stream::MultiFileReadWrite;
-    use std::net::{TcpStream, TcpListener, SocketAddr};
+    use crate::testing::ChannelDuplex;
+    use crate::context::SecureComContext;
 
 
     #[test]
     fn handshake_succeeds() {
+        let (client_end, server_end) = ChannelDuplex::pair();
 
-        let client_thread = std::thread::spawn(||
+        let client_thread = std::thread::spawn(move ||
             {
-                let tcp_stream = TcpStream::connect("127.0.0.1:8000").unwrap();
-                let multi = MultiFileReadWrite::new(tcp_stream);
-                client_handshake(multi.clone(), multi).unwrap()
+                client_handshake(&client_end, &client_end).unwrap().aes_manager
             }
         );
-        let server_thread = std::thread::spawn( ||
+        let server_thread = std::thread::spawn(move ||
             {
-                let tcp_listener = TcpListener::bind("127.0.0.1:8000").unwrap();
-                let tcp_stream = tcp_listener.accept().unwrap().0;
-                let multi = MultiFileReadWrite::new(tcp_stream);
-                server_handshake(multi.clone(), multi).unwrap()
+                server_handshake(&server_end, &server_end).unwrap().aes_manager
             }
         );
 
@@ -96,4 +469,408 @@ mod tests {
         assert_eq!(client_key, server_key, "Handshake failed to create matching AES keys");
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn handshake_with_matching_config() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let config = HandshakeConfig::builder()
+            .rsa_key_bits(1024)
+            .aes_key_size(KeySize::K128)
+            .build();
+        let server_config = config.clone();
+
+        let server_thread = std::thread::spawn(move || {
+            server_handshake_with_config(&server_end, &server_end, &server_config).unwrap()
+        });
+        let client_key = client_handshake_with_config(&client_end, &client_end, &config).unwrap().aes_manager;
+        let server_key = server_thread.join().unwrap().aes_manager;
+
+        assert_eq!(client_key, server_key, "Handshake failed to create matching AES keys");
+        assert_eq!(client_key.key_size(), KeySize::K128);
+    }
+
+    #[test]
+    fn server_rejects_other_cipher() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let config = HandshakeConfig::builder().rsa_key_bits(1024).build();
+        let server_config = config.clone();
+
+        let server_thread = std::thread::spawn(move || {
+            server_handshake_with_config(&server_end, &server_end, &server_config)
+        });
+        let chacha = AESManager::new(CipherChoice::ChaCha20Poly1305);
+        client_handshake_with_manager(&client_end, &client_end, &config, chacha).unwrap();
+        let result = server_thread.join().unwrap();
+        assert!(matches!(result, Err(SecureComError::HandshakePhaseError(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn handshake_result_fields() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let config = HandshakeConfig::builder().rsa_key_bits(1024).build();
+        let server_config = config.clone();
+
+        let server_thread = std::thread::spawn(move || {
+            server_handshake_with_config(&server_end, &server_end, &server_config).unwrap()
+        });
+        let HandshakeResult {
+            aes_manager: client_key,
+            remote_public_key: server_public_key,
+            session_id: client_session,
+            negotiated_rsa_bits: client_bits,
+            remote_identity: client_remote_identity
+        } = client_handshake_with_config(&client_end, &client_end, &config).unwrap();
+        let HandshakeResult {
+            aes_manager: server_key,
+            remote_public_key: client_public_key,
+            session_id: server_session,
+            negotiated_rsa_bits: server_bits,
+            remote_identity: server_remote_identity
+        } = server_thread.join().unwrap();
+
+        assert_eq!(client_key, server_key, "Handshake failed to create matching AES keys");
+        assert_eq!(client_session, server_session);
+        assert_ne!(client_session, [0; 16]);
+        assert_eq!((client_bits, server_bits), (1024, 1024));
+        assert_eq!((client_remote_identity, server_remote_identity), (None, None));
+        assert_ne!(client_public_key.fingerprint(), server_public_key.fingerprint());
+        for key in [client_public_key, server_public_key] {
+            assert!(key.n_value().bits() > 1000, "{} bit key", key.n_value().bits());
+        }
+    }
+
+    #[test]
+    fn handshake_with_certificates() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let client_config = HandshakeConfig::builder()
+            .rsa_key_bits(1024)
+            .certificate_identity("client.example.com")
+            .build();
+        let server_config = HandshakeConfig::builder()
+            .rsa_key_bits(1024)
+            .certificate_identity("server.example.com")
+            .build();
+
+        let server_thread = std::thread::spawn(move || {
+            server_handshake_with_config(&server_end, &server_end, &server_config).unwrap()
+        });
+        let client = client_handshake_with_config(&client_end, &client_end, &client_config).unwrap();
+        let server = server_thread.join().unwrap();
+
+        assert_eq!(client.aes_manager, server.aes_manager, "Handshake failed to create matching AES keys");
+        assert_eq!(client.remote_identity.as_deref(), Some("server.example.com"));
+        assert_eq!(server.remote_identity.as_deref(), Some("client.example.com"));
+    }
+
+    #[test]
+    fn certificate_for_an_untrusted_key_is_rejected() {
+        let server_config = HandshakeConfig::builder()
+            .rsa_key_bits(1024)
+            .certificate_identity("server.example.com")
+            .build();
+        let server_keys = RSAKeys::generate(1024);
+        let other_keys = RSAKeys::generate(1024);
+
+        for (trusted, accepted) in [(server_keys.public_key(), true), (other_keys.public_key(), false)] {
+            let (client_end, server_end) = ChannelDuplex::pair();
+            let client_config = HandshakeConfig::builder()
+                .rsa_key_bits(1024)
+                .certificate_identity("client.example.com")
+                .trust_key(trusted)
+                .build();
+            let context = SecureComContext::with_keys(server_config.clone(), server_keys.clone());
+            let server_thread = std::thread::spawn(move || context.accept(&server_end, &server_end));
+
+            let client = client_handshake_with_config(&client_end, &client_end, &client_config);
+            if accepted {
+                assert_eq!(client.unwrap().remote_public_key, server_keys.public_key());
+                server_thread.join().unwrap().unwrap();
+            } else {
+                assert!(matches!(client, Err(SecureComError::UntrustedKey)), "{:?}", client);
+                drop(client_end);
+                assert!(server_thread.join().unwrap().is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn server_expecting_certificate_rejects_bare_key() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let client_config = HandshakeConfig::builder().rsa_key_bits(1024).build();
+        let server_config = HandshakeConfig::builder()
+            .rsa_key_bits(1024)
+            .certificate_identity("server.example.com")
+            .build();
+
+        let server_thread = std::thread::spawn(move || {
+            server_handshake_with_config(&server_end, &server_end, &server_config)
+        });
+        let _ = client_handshake_with_config(&client_end, &client_end, &client_config);
+        let result = server_thread.join().unwrap();
+        assert!(matches!(result, Err(SecureComError::HandshakePhaseError(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn config_builder() {
+        let default = HandshakeConfig::builder().build();
+        assert_eq!(default.rsa_key_bits, 2048);
+        assert_eq!(default.aes_key_size, KeySize::K256);
+        assert_eq!(default.certificate_identity, None);
+        assert!(default.trusted_keys.is_empty());
+        assert!(!default.enable_metrics);
+
+        let config = HandshakeConfig::builder().rsa_key_bits(1024).build();
+        assert_eq!(config.rsa_key_bits, 1024);
+        assert_eq!(config.aes_key_size, KeySize::K256);
+    }
+
+    #[test]
+    fn handshake_channel_exchanges_messages() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+
+        let server_thread = std::thread::spawn(move || {
+            let mut channel = server_handshake_channel(&server_end).unwrap();
+            for _ in 0..3 {
+                let message = channel.recv().unwrap();
+                channel.send(&message).unwrap();
+            }
+        });
+
+        let mut channel = client_handshake_channel(&client_end).unwrap();
+        for message in [&b"first"[..], b"second", b"a third message, longer than one block"] {
+            channel.send(message).unwrap();
+            assert_eq!(channel.recv().unwrap(), message);
+        }
+        server_thread.join().unwrap();
+    }
+
+    /// A socket whose reads wait a little first, so that by the time the server reads the AES key
+    /// the client's first message has arrived behind it
+    #[cfg(unix)]
+    struct DelayedReads(std::os::unix::net::UnixStream);
+
+    #[cfg(unix)]
+    impl Read for DelayedReads {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            self.0.read(buf)
+        }
+    }
+
+    #[cfg(unix)]
+    impl Write for DelayedReads {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn handshake_channel_keeps_bytes_sent_straight_after() {
+        let (client_end, server_end) = std::os::unix::net::UnixStream::pair().unwrap();
+        // fails rather than waiting forever for a message that was lost
+        server_end.set_read_timeout(Some(std::time::Duration::from_secs(10))).unwrap();
+        let config = HandshakeConfig::builder().rsa_key_bits(1024).build();
+        let server_config = config.clone();
+
+        let server_thread = std::thread::spawn(move || {
+            let mut channel = server_handshake_channel_with_config(DelayedReads(server_end), &server_config).unwrap();
+            (channel.recv().unwrap(), channel.recv().unwrap())
+        });
+
+        let mut channel = client_handshake_channel_with_config(client_end, &config).unwrap();
+        channel.send(b"sent with the key").unwrap();
+        channel.send(b"and after it").unwrap();
+        let (first, second) = server_thread.join().unwrap();
+        assert_eq!(first, b"sent with the key");
+        assert_eq!(second, b"and after it");
+    }
+
+    #[test]
+    fn handshake_channel_with_metrics() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let config = HandshakeConfig::builder().rsa_key_bits(1024).enable_metrics(true).build();
+        let server_config = HandshakeConfig::builder().rsa_key_bits(1024).build();
+
+        let server_thread = std::thread::spawn(move || {
+            let mut channel = server_handshake_channel_with_config(&server_end, &server_config).unwrap();
+            channel.recv().unwrap();
+            channel.stats()
+        });
+
+        let mut channel = client_handshake_channel_with_config(&client_end, &config).unwrap();
+        channel.send(&[7; 1000]).unwrap();
+        assert_eq!(channel.stats(), Some((1000, 0)));
+        assert_eq!(server_thread.join().unwrap(), None);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_handshake_keys_match() {
+        let (client_end, server_end) = tokio::io::duplex(4096);
+
+        let client = tokio::spawn(client_handshake_async(client_end));
+        let server = tokio::spawn(server_handshake_async(server_end));
+        let mut client_channel = client.await.unwrap().unwrap();
+        let mut server_channel = server.await.unwrap().unwrap();
+        assert_eq!(client_channel.manager(), server_channel.manager(), "Handshake failed to create matching AES keys");
+
+        client_channel.send_async(b"Hello, World!").await.unwrap();
+        assert_eq!(server_channel.recv_async().await.unwrap(), b"Hello, World!");
+    }
+
+    #[test]
+    fn dh_handshake_succeeds() {
+        let (client_end, server_end) = ChannelDuplex::pair();
+
+        let client_thread = std::thread::spawn(move || {
+            client_dh_handshake(&client_end, &client_end, DhGroup::Modp2048).unwrap()
+        });
+        let server_key = server_dh_handshake(&server_end, &server_end).unwrap();
+        let client_key = client_thread.join().unwrap();
+
+        assert_eq!(client_key, server_key, "Diffie-Hellman handshake failed to create matching AES keys");
+    }
+
+    #[test]
+    fn dh_handshake_rejects_unknown_group() {
+        let mut output = Vec::new();
+        let result = server_dh_handshake(&mut output, &b"DH_BEGIN modp1 12 ff\n"[..]);
+        assert!(matches!(result, Err(SecureComError::KeyExchangeError(DhError::UnknownGroup))));
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn dh_handshake_errors() {
+        let result = server_dh_handshake(&mut Vec::new(), &b"HELLO\n"[..]);
+        assert!(matches!(result, Err(SecureComError::HandshakePhaseError(_))));
+        let nonce = Nonce::generate();
+        let result = server_dh_handshake(&mut Vec::new(), format!("DH_BEGIN modp2048 {} zz\n", nonce).as_bytes());
+        assert!(matches!(result, Err(SecureComError::KeyExchangeError(DhError::InvalidPublicValue))));
+        let result = server_dh_handshake(&mut Vec::new(), format!("DH_BEGIN modp2048 {} 1\n", nonce).as_bytes());
+        assert!(matches!(result, Err(SecureComError::KeyExchangeError(DhError::InvalidPublicValue))));
+        let result = server_dh_handshake(&mut Vec::new(), &b"DH_BEGIN modp2048 12 ff\n"[..]);
+        assert!(matches!(result, Err(SecureComError::HandshakePhaseError(_))));
+    }
+
+    /// Panics on every write, which poisons the lock of a `MultiFileReadWrite` around it
+    struct PanicOnWrite;
+
+    impl Read for PanicOnWrite {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for PanicOnWrite {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            panic!("panic while holding the lock")
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn poisoned_stream_lock_is_reported() {
+        use crate::multi_file_stream::MultiFileReadWrite;
+
+        let stream = MultiFileReadWrite::new(PanicOnWrite);
+        let mut other = stream.clone();
+        assert!(std::thread::spawn(move || other.write(b"poison")).join().is_err());
+
+        let result = server_handshake(stream.clone(), stream);
+        assert!(matches!(result, Err(SecureComError::PoisonedLock)), "expected PoisonedLock, got {:?}", result.err());
+    }
+
+    #[test]
+    fn protocol_version_mismatch() {
+        let mut client_output = Vec::new();
+        assert!(client_handshake(&mut client_output, &b""[..]).is_err());
+        assert_eq!(client_output[0], crate::PROTOCOL_VERSION);
+        // a server from a later version of the library
+        let result = unsecure::check_protocol_version(&mut client_output.as_slice(), 2);
+        assert!(matches!(result, Err(SecureComError::ProtocolVersionMismatch { got: 1, expected: 2 })));
+
+        let mut server_output = Vec::new();
+        let result = server_handshake(&mut server_output, &b"\x02COM_BEGIN 1234\n"[..]);
+        assert!(matches!(result, Err(SecureComError::ProtocolVersionMismatch { got: 2, expected: 1 })));
+        assert!(server_output.is_empty());
+    }
+
+    #[test]
+    fn server_rejects_wrong_start() {
+        let mut server_output = Vec::new();
+        let result = server_handshake(&mut server_output, &b"\x01GET / HTTP/1.1\r\n"[..]);
+        match result {
+            Err(SecureComError::IoError(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+            other => panic!("expected InvalidData, got {:?}", other)
+        }
+        assert!(server_output.is_empty());
+    }
+
+    #[test]
+    fn handshake_nonce_verification_enforced() {
+        // a client that echoes a nonce other than the server's
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let config = HandshakeConfig::builder().rsa_key_bits(1024).build();
+        let server_thread = std::thread::spawn(move || {
+            server_handshake_with_config(&server_end, &server_end, &config)
+        });
+        let start_nonce = Nonce::generate();
+        unsecure::send_protocol_version(&mut &client_end).unwrap();
+        unsecure::handshake_start(&start_nonce, &mut &client_end).unwrap();
+        assert!(unsecure::receive_ack(&start_nonce, &mut &client_end).unwrap());
+        let key = RSAKeys::generate(1024);
+        send_public_key(key.public_key(), &mut &client_end).unwrap();
+        let server_public_key = receive_public_key(&mut &client_end).unwrap();
+        let mut rsa_writer = RSAWriter::new(server_public_key, &client_end);
+        let mut rsa_reader = RSAReader::new(key.private_key(), &client_end);
+        let client_nonce = Nonce::generate();
+        secure::handshake_start(&client_nonce, &mut rsa_writer).unwrap();
+        let server_nonce = receive_and_repeat(&client_nonce, &mut std::io::sink(), &mut rsa_reader).unwrap();
+        let mut wrong_nonce = Nonce::generate();
+        while wrong_nonce == server_nonce {
+            wrong_nonce = Nonce::generate();
+        }
+        writeln!(rsa_writer, "{}", ProtocolMessage::NonceEcho { nonce: wrong_nonce }).unwrap();
+        let result = server_thread.join().unwrap();
+        assert!(matches!(result, Err(SecureComError::NonceMismatch)), "{:?}", result);
+        // the server says why instead of going quiet
+        match secure::receive_success(&mut rsa_reader) {
+            Err(SecureComError::HandshakePhaseError(reason)) => assert!(reason.ends_with("nonce mismatch"), "{}", reason),
+            other => panic!("expected the server's failure, got {:?}", other)
+        }
+
+        // a server that acknowledges a nonce other than the client's
+        let (client_end, server_end) = ChannelDuplex::pair();
+        let config = HandshakeConfig::builder().rsa_key_bits(1024).build();
+        let client_thread = std::thread::spawn(move || {
+            client_handshake_with_config(&client_end, &client_end, &config)
+        });
+        unsecure::receive_protocol_version(&mut &server_end).unwrap();
+        unsecure::server_ack(&mut &server_end, &mut &server_end).unwrap();
+        let key = RSAKeys::generate(1024);
+        let client_public_key = receive_public_key(&mut &server_end).unwrap();
+        send_public_key(key.public_key(), &mut &server_end).unwrap();
+        let mut rsa_writer = RSAWriter::new(client_public_key, &server_end);
+        let mut rsa_reader = RSAReader::new(key.private_key(), &server_end);
+        let server_nonce = Nonce::generate();
+        secure::server_ack(&server_nonce, &mut std::io::sink(), &mut rsa_reader).unwrap();
+        let acknowledgement = ProtocolMessage::HandshakeAck { client_nonce: Nonce::generate(), server_nonce };
+        writeln!(rsa_writer, "{}", acknowledgement).unwrap();
+        let result = client_thread.join().unwrap();
+        assert!(matches!(result, Err(SecureComError::NonceMismatch)), "{:?}", result);
+    }
+
+    #[test]
+    fn client_rejects_wrong_acknowledgement() {
+        let result = client_handshake(Vec::new(), &b"1234\n"[..]);
+        assert!(matches!(result, Err(SecureComError::NonceMismatch)));
+    }
+}
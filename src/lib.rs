@@ -1,12 +1,23 @@
+pub mod cert;
+pub mod channel;
+pub mod context;
 pub mod encryption;
+pub mod error;
 pub mod handshake;
+pub mod pake;
+pub mod protocol;
+#[cfg(feature = "totp")]
+pub mod auth;
+#[cfg(feature = "key-cache")]
+pub mod cached_keygen;
 #[cfg(test)]
 pub mod multi_file_stream;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testing;
 
 #[macro_use]
 extern crate lazy_static;
 
-
-
-
-
+/// Sent as the first byte of a handshake, and changed whenever the wire format changes, so that
+/// peers running incompatible versions fail instead of misreading each other
+pub const PROTOCOL_VERSION: u8 = 1;
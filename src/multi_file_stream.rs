@@ -1,16 +1,59 @@
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Write};
 use std::path::Path;
-use std::sync::{Arc, RwLock};
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex, MutexGuard};
 
+use crate::error::LockPoisoned;
+
+/// Locks the shared stream, failing with `BrokenPipe` if another user panicked while holding it
+///
+/// The error holds a [`LockPoisoned`], so that the handshake can report it as
+/// [`SecureComError::PoisonedLock`](crate::error::SecureComError::PoisonedLock).
+///
+/// Reading from most streams changes their state, so readers need the same exclusive access as
+/// writers and a `Mutex` is used rather than a `RwLock`.
+fn lock<F>(inner: &Mutex<F>) -> std::io::Result<MutexGuard<'_, F>> {
+    inner.lock().map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, LockPoisoned))
+}
+
+/// The reading side of a shared stream, from [`MultiFileReadWrite::split`]
+pub struct MultiFileReadHalf<F : Read + Write> {
+    inner: Arc<Mutex<F>>
+}
+
+/// The writing side of a shared stream, from [`MultiFileReadWrite::split`]
+pub struct MultiFileWriteHalf<F : Read + Write> {
+    inner: Arc<Mutex<F>>
+}
+
+impl<F : Read + Write> Clone for MultiFileReadHalf<F> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone()
+        }
+    }
+}
+
+impl<F : Read + Write> Clone for MultiFileWriteHalf<F> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone()
+        }
+    }
+}
+
+/// A stream shared between threads, made of a read half and a write half of the same stream
 pub struct MultiFileReadWrite<F : Read + Write> {
-    rwlock: Arc<RwLock<F>>
+    read_half: MultiFileReadHalf<F>,
+    write_half: MultiFileWriteHalf<F>
 }
 
 impl<F : Read + Write> Clone for MultiFileReadWrite<F> {
     fn clone(&self) -> Self {
         Self {
-            rwlock: self.rwlock.clone()
+            read_half: self.read_half.clone(),
+            write_half: self.write_half.clone()
         }
     }
 }
@@ -18,10 +61,17 @@ impl<F : Read + Write> Clone for MultiFileReadWrite<F> {
 impl <F : Read + Write> MultiFileReadWrite<F> {
 
     pub fn new(inner: F) -> Self {
+        let inner = Arc::new(Mutex::new(inner));
         Self {
-            rwlock: Arc::new(RwLock::new(inner))
+            read_half: MultiFileReadHalf { inner: inner.clone() },
+            write_half: MultiFileWriteHalf { inner }
         }
     }
+
+    /// Separates the stream into halves that can be given to different threads
+    pub fn split(self) -> (MultiFileReadHalf<F>, MultiFileWriteHalf<F>) {
+        (self.read_half, self.write_half)
+    }
 }
 
 impl MultiFileReadWrite<File> {
@@ -30,6 +80,7 @@ impl MultiFileReadWrite<File> {
         let file = OpenOptions::new()
             .create(true)
             .append(false)
+            .truncate(false)
             .write(true)
             .read(true)
             .open(path)
@@ -38,22 +89,94 @@ impl MultiFileReadWrite<File> {
     }
 }
 
+impl <F : Read + Write> Read for MultiFileReadHalf<F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        lock(&self.inner)?.read(buf)
+    }
+}
+
+impl <F : Read + Write> Write for MultiFileWriteHalf<F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        lock(&self.inner)?.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        lock(&self.inner)?.flush()
+    }
+}
+
 impl <F : Read + Write> Read for MultiFileReadWrite<F> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut inner = self.rwlock.write().unwrap();
-        inner.read(buf)
+        self.read_half.read(buf)
     }
 }
 
 impl <F : Read + Write> Write for MultiFileReadWrite<F> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let mut inner = self.rwlock.write().unwrap();
-        inner.write(buf)
+        self.write_half.write(buf)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        let mut inner = self.rwlock.write().unwrap();
-        inner.flush()
+        self.write_half.flush()
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn poisoned_lock_is_an_error() {
+        let stream = MultiFileReadWrite::new(Cursor::new(Vec::new()));
+        let other = stream.clone();
+        let result = std::thread::spawn(move || {
+            let _guard = other.read_half.inner.lock().unwrap();
+            panic!("panic while holding the lock");
+        }).join();
+        assert!(result.is_err());
+
+        let mut stream = stream;
+        assert_eq!(stream.write(b"Hello").unwrap_err().kind(), ErrorKind::BrokenPipe);
+        assert_eq!(stream.read(&mut [0u8; 4]).unwrap_err().kind(), ErrorKind::BrokenPipe);
+        assert_eq!(stream.flush().unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+
+    /// Reads back whatever was written to it
+    struct Loopback(VecDeque<u8>);
+
+    impl Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.extend(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn halves_on_different_threads() {
+        let (mut read_half, mut write_half) = MultiFileReadWrite::new(Loopback(VecDeque::new())).split();
+        std::thread::spawn(move || {
+            write_half.write_all(b"Hello, World!").unwrap();
+        }).join().unwrap();
+
+        let reader = std::thread::spawn(move || {
+            let mut output = Vec::new();
+            read_half.read_to_end(&mut output).unwrap();
+            output
+        });
+        assert_eq!(reader.join().unwrap(), b"Hello, World!");
+    }
+}
@@ -0,0 +1,2 @@
+//! Password-authenticated key exchanges, which establish a shared key without any public keys
+pub mod srp;
@@ -0,0 +1,452 @@
+//! SRP-6a (RFC 5054) password-authenticated key exchange
+//!
+//! The server only stores a verifier derived from the password, never the password itself. Both
+//! sides end up with the same shared secret only if the client knew the password, and each side
+//! can prove this to the other before the derived key is used.
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use hkdf::Hkdf;
+use num::Num;
+use num_bigint::{BigUint, RandBigInt};
+use num_traits::Zero;
+use sha2::{Digest, Sha256};
+
+use crate::encryption::aes::AESManager;
+
+/// The 2048-bit group from RFC 5054, appendix A
+static GROUP_2048_N: &str = concat!(
+    "AC6BDB41324A9A9BF166DE5E1389582FAF72B6651987EE07FC3192943DB56050",
+    "A37329CBB4A099ED8193E0757767A13DD52312AB4B03310DCD7F48A9DA04FD50",
+    "E8083969EDB767B0CF6095179A163AB3661A05FBD5FAAAE82918A9962F0B93B8",
+    "55F97993EC975EEAA80D740ADBF4FF747359D041D5C33EA71D281E446B14773B",
+    "CA97B43A23FB801676BD207A436C6481F1D2B9078717461A5B9D32E688F87748",
+    "544523B524B0D57D5EA77A2775D2ECFA032CFBDBF52FB3786160279004E57AE6",
+    "AF874E7303CE53299CCC041C7BC308D82A5698F3A8D0C38271AE35F8E9DBFBB6",
+    "94B5C803D89F7AE435DE236D525F54759B65E372FCD68EF20FA7111F9E4AFF73"
+);
+const GROUP_2048_G: u32 = 2;
+
+/// The number of bytes in the group's modulus, which values are padded to before hashing
+const PAD_LENGTH: usize = 256;
+/// The number of bytes in a generated salt
+const SALT_LENGTH: usize = 16;
+/// The number of random bits in the private ephemeral values
+const EPHEMERAL_BITS: u64 = 256;
+
+lazy_static! {
+    static ref N: BigUint = BigUint::from_str_radix(GROUP_2048_N, 16).unwrap();
+    static ref G: BigUint = BigUint::from(GROUP_2048_G);
+    /// The multiplier parameter `k = H(N || PAD(g))`
+    static ref K: BigUint = BigUint::from_bytes_be(&hash(&[&N.to_bytes_be(), &pad(&G)]));
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SrpError {
+    /// The other side sent a public value that is `0 mod N`, which would make the shared secret predictable
+    InvalidPublicValue
+}
+
+impl Display for SrpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for SrpError { }
+
+/// What the server stores for each user in place of their password
+#[derive(Debug, Clone)]
+pub struct SrpVerifier {
+    username: String,
+    salt: Vec<u8>,
+    verifier: BigUint
+}
+
+impl SrpVerifier {
+
+    /// Computes the verifier `v = g^x mod N` where `x = H(salt || H(username || ":" || password))`
+    pub fn with_salt(username: &str, password: &str, salt: &[u8]) -> Self {
+        let x = private_key(username, password, salt);
+        SrpVerifier {
+            username: username.to_string(),
+            salt: salt.to_vec(),
+            verifier: G.modpow(&x, &N)
+        }
+    }
+
+    /// Computes the verifier with a new random 16 byte salt
+    pub fn from_password(username: &str, password: &str) -> Self {
+        let salt: [u8; SALT_LENGTH] = rand::random();
+        Self::with_salt(username, password, &salt)
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    /// The username and salt, each as a big endian `u16` length followed by its bytes, then the
+    /// verifier itself
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for field in [self.username.as_bytes(), &self.salt] {
+            bytes.extend_from_slice(&(field.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(field);
+        }
+        bytes.extend(self.verifier.to_bytes_be());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VerifierError> {
+        let (username, rest) = split_field(bytes)?;
+        let (salt, verifier) = split_field(rest)?;
+        if verifier.is_empty() {
+            return Err(VerifierError::Malformed);
+        }
+        Ok(SrpVerifier {
+            username: String::from_utf8(username.to_vec()).map_err(|_| VerifierError::Malformed)?,
+            salt: salt.to_vec(),
+            verifier: BigUint::from_bytes_be(verifier)
+        })
+    }
+}
+
+/// Splits a `u16` length prefixed field off the front of `bytes`
+fn split_field(bytes: &[u8]) -> Result<(&[u8], &[u8]), VerifierError> {
+    if bytes.len() < 2 {
+        return Err(VerifierError::Malformed);
+    }
+    let (length, rest) = bytes.split_at(2);
+    let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+    if rest.len() < length {
+        return Err(VerifierError::Malformed);
+    }
+    Ok(rest.split_at(length))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VerifierError {
+    /// The bytes do not have the layout written by [`SrpVerifier::to_bytes`]
+    Malformed
+}
+
+impl Display for VerifierError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for VerifierError { }
+
+/// The verifiers of every user a server accepts, looked up by username
+#[derive(Debug, Default, Clone)]
+pub struct SrpVerifierStore {
+    verifiers: HashMap<String, SrpVerifier>
+}
+
+impl SrpVerifierStore {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores the verifier under `username`, replacing any verifier already stored for it
+    pub fn add(&mut self, username: &str, verifier: SrpVerifier) {
+        self.verifiers.insert(username.to_string(), verifier);
+    }
+
+    pub fn lookup(&self, username: &str) -> Option<&SrpVerifier> {
+        self.verifiers.get(username)
+    }
+
+    pub fn remove(&mut self, username: &str) -> Option<SrpVerifier> {
+        self.verifiers.remove(username)
+    }
+
+    pub fn len(&self) -> usize {
+        self.verifiers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.verifiers.is_empty()
+    }
+
+    /// Writes every verifier as a big endian `u32` length followed by [`SrpVerifier::to_bytes`]
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let mut contents = Vec::new();
+        for verifier in self.verifiers.values() {
+            let bytes = verifier.to_bytes();
+            contents.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            contents.extend(bytes);
+        }
+        fs::write(path, contents)
+    }
+
+    /// Reads a store written by [`save_to_file`](Self::save_to_file). Verifiers are stored under
+    /// the username they were created with.
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let invalid = || std::io::Error::new(ErrorKind::InvalidData, "verifier store is corrupt");
+        let contents = fs::read(path)?;
+        let mut rest = contents.as_slice();
+        let mut store = Self::new();
+        while !rest.is_empty() {
+            if rest.len() < 4 {
+                return Err(invalid());
+            }
+            let (length, after) = rest.split_at(4);
+            let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+            if after.len() < length {
+                return Err(invalid());
+            }
+            let (entry, after) = after.split_at(length);
+            let verifier = SrpVerifier::from_bytes(entry).map_err(|_| invalid())?;
+            store.verifiers.insert(verifier.username.clone(), verifier);
+            rest = after;
+        }
+        Ok(store)
+    }
+}
+
+/// The client side of the exchange
+pub struct SrpClient {
+    username: String,
+    password: String,
+    a: BigUint,
+    a_public: BigUint
+}
+
+impl SrpClient {
+
+    pub fn new(username: &str, password: &str) -> Self {
+        let a = rand::thread_rng().gen_biguint(EPHEMERAL_BITS);
+        let a_public = G.modpow(&a, &N);
+        SrpClient {
+            username: username.to_string(),
+            password: password.to_string(),
+            a,
+            a_public
+        }
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// The value `A = g^a mod N` that is sent to the server
+    pub fn public_ephemeral(&self) -> &BigUint {
+        &self.a_public
+    }
+
+    /// Computes the session from the salt and the value `B` sent by the server
+    pub fn process_challenge(&self, salt: &[u8], b_public: &BigUint) -> Result<SrpSession, SrpError> {
+        if (b_public % &*N).is_zero() {
+            return Err(SrpError::InvalidPublicValue);
+        }
+        let u = scrambler(&self.a_public, b_public);
+        if u.is_zero() {
+            return Err(SrpError::InvalidPublicValue);
+        }
+        let x = private_key(&self.username, &self.password, salt);
+        // S = (B - k * g^x) ^ (a + u * x) mod N, kept positive by adding a multiple of N
+        let subtrahend = (&*K * G.modpow(&x, &N)) % &*N;
+        let base = (b_public + &*K * &*N - subtrahend) % &*N;
+        let secret = base.modpow(&(&self.a + &u * &x), &N);
+        Ok(SrpSession::new(&self.a_public, b_public, &secret))
+    }
+}
+
+/// The server side of the exchange
+pub struct SrpServer {
+    verifier: SrpVerifier,
+    b: BigUint,
+    b_public: BigUint
+}
+
+impl SrpServer {
+
+    pub fn new(verifier: SrpVerifier) -> Self {
+        let b = rand::thread_rng().gen_biguint(EPHEMERAL_BITS);
+        let b_public = (&*K * &verifier.verifier + G.modpow(&b, &N)) % &*N;
+        SrpServer { verifier, b, b_public }
+    }
+
+    /// The salt that is sent to the client along with `B`
+    pub fn salt(&self) -> &[u8] {
+        self.verifier.salt()
+    }
+
+    /// The value `B = k * v + g^b mod N` that is sent to the client
+    pub fn public_ephemeral(&self) -> &BigUint {
+        &self.b_public
+    }
+
+    /// Computes the session from the value `A` sent by the client
+    pub fn process_client(&self, a_public: &BigUint) -> Result<SrpSession, SrpError> {
+        if (a_public % &*N).is_zero() {
+            return Err(SrpError::InvalidPublicValue);
+        }
+        let u = scrambler(a_public, &self.b_public);
+        if u.is_zero() {
+            return Err(SrpError::InvalidPublicValue);
+        }
+        // S = (A * v^u) ^ b mod N
+        let secret = (a_public * self.verifier.verifier.modpow(&u, &N)).modpow(&self.b, &N);
+        Ok(SrpSession::new(a_public, &self.b_public, &secret))
+    }
+}
+
+/// The result of an exchange. Both sides only hold equal sessions if the client used the right password.
+pub struct SrpSession {
+    key: [u8; 32],
+    client_proof: [u8; 32],
+    server_proof: [u8; 32]
+}
+
+impl SrpSession {
+    fn new(a_public: &BigUint, b_public: &BigUint, secret: &BigUint) -> Self {
+        let secret = pad(secret);
+        let client_proof = hash(&[&pad(a_public), &pad(b_public), &secret]);
+        let server_proof = hash(&[&pad(a_public), &client_proof, &secret]);
+
+        let hkdf = Hkdf::<Sha256>::new(None, &secret);
+        let mut key = [0u8; 32];
+        hkdf.expand(b"srp-aes-key", &mut key).expect("32 bytes is a valid HKDF output length");
+        SrpSession { key, client_proof, server_proof }
+    }
+
+    /// The proof `M1` the client sends to show it computed the same secret
+    pub fn client_proof(&self) -> [u8; 32] {
+        self.client_proof
+    }
+
+    /// The proof `M2` the server sends back once it has checked `M1`
+    pub fn server_proof(&self) -> [u8; 32] {
+        self.server_proof
+    }
+
+    pub fn verify_client_proof(&self, proof: &[u8; 32]) -> bool {
+        constant_time_eq(&self.client_proof, proof)
+    }
+
+    pub fn verify_server_proof(&self, proof: &[u8; 32]) -> bool {
+        constant_time_eq(&self.server_proof, proof)
+    }
+
+    /// The AES-256 key derived from the shared secret
+    pub fn aes_manager(&self) -> AESManager {
+        AESManager::from_key_value(self.key.to_vec()).expect("32 bytes is a valid AES key")
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// Left pads the value with zeros to the length of `N`
+fn pad(value: &BigUint) -> Vec<u8> {
+    let bytes = value.to_bytes_be();
+    let mut padded = vec![0u8; PAD_LENGTH.saturating_sub(bytes.len())];
+    padded.extend(bytes);
+    padded
+}
+
+/// `x = H(salt || H(username || ":" || password))`
+fn private_key(username: &str, password: &str, salt: &[u8]) -> BigUint {
+    let identity = hash(&[username.as_bytes(), b":", password.as_bytes()]);
+    BigUint::from_bytes_be(&hash(&[salt, &identity]))
+}
+
+/// `u = H(PAD(A) || PAD(B))`
+fn scrambler(a_public: &BigUint, b_public: &BigUint) -> BigUint {
+    BigUint::from_bytes_be(&hash(&[&pad(a_public), &pad(b_public)]))
+}
+
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SALT: &[u8] = b"0123456789abcdef";
+
+    fn exchange(password: &str) -> (SrpSession, SrpSession) {
+        let verifier = SrpVerifier::with_salt("alice", "password123", SALT);
+        let client = SrpClient::new("alice", password);
+        let server = SrpServer::new(verifier);
+
+        let server_session = server.process_client(client.public_ephemeral()).unwrap();
+        let client_session = client.process_challenge(server.salt(), server.public_ephemeral()).unwrap();
+        (client_session, server_session)
+    }
+
+    #[test]
+    fn matching_password() {
+        let (client, server) = exchange("password123");
+        assert!(server.verify_client_proof(&client.client_proof()));
+        assert!(client.verify_server_proof(&server.server_proof()));
+        assert_eq!(client.aes_manager(), server.aes_manager());
+    }
+
+    #[test]
+    fn wrong_password() {
+        let (client, server) = exchange("password124");
+        assert!(!server.verify_client_proof(&client.client_proof()));
+        assert_ne!(client.aes_manager(), server.aes_manager());
+    }
+
+    #[test]
+    fn zero_public_value_rejected() {
+        let server = SrpServer::new(SrpVerifier::with_salt("alice", "password123", SALT));
+        assert_eq!(server.process_client(&N).err(), Some(SrpError::InvalidPublicValue));
+        let client = SrpClient::new("alice", "password123");
+        assert_eq!(client.process_challenge(SALT, &BigUint::zero()).err(), Some(SrpError::InvalidPublicValue));
+    }
+
+    #[test]
+    fn verifier_serialization() {
+        let verifier = SrpVerifier::from_password("alice", "password123");
+        assert_eq!(verifier.salt().len(), SALT_LENGTH);
+        let parsed = SrpVerifier::from_bytes(&verifier.to_bytes()).unwrap();
+        assert_eq!(parsed.username(), "alice");
+        assert_eq!(parsed.salt(), verifier.salt());
+        assert_eq!(parsed.verifier, verifier.verifier);
+
+        let bytes = verifier.to_bytes();
+        assert_eq!(SrpVerifier::from_bytes(&bytes[..5]).err(), Some(VerifierError::Malformed));
+    }
+
+    #[test]
+    fn verifier_store_file() {
+        let mut store = SrpVerifierStore::new();
+        store.add("alice", SrpVerifier::from_password("alice", "password123"));
+        store.add("bob", SrpVerifier::from_password("bob", "hunter2"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("verifiers");
+        store.save_to_file(&path).unwrap();
+        let loaded = SrpVerifierStore::load_from_file(&path).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert!(loaded.lookup("carol").is_none());
+
+        // a verifier loaded from the file still authenticates its user
+        let server = SrpServer::new(loaded.lookup("bob").unwrap().clone());
+        let client = SrpClient::new("bob", "hunter2");
+        let server_session = server.process_client(client.public_ephemeral()).unwrap();
+        let client_session = client.process_challenge(server.salt(), server.public_ephemeral()).unwrap();
+        assert!(server_session.verify_client_proof(&client_session.client_proof()));
+    }
+}
@@ -0,0 +1,203 @@
+//! The messages of the handshakes and the phrases that mark them, shared by the sending and
+//! receiving sides so the two can't drift apart
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+
+use crate::encryption::nonce::Nonce;
+use crate::encryption::rsa::{PublicKey, PublicKeyParseError};
+
+/// Starts the unencrypted half of the handshake
+pub const HANDSHAKE_START_PHRASE: &str = "COM_BEGIN";
+
+/// Starts the half of the handshake encrypted with the exchanged RSA keys
+pub const SECURE_HANDSHAKE_PHRASE: &str = "SECOP_BEGIN";
+
+/// Starts a Diffie-Hellman handshake
+pub const DH_START_PHRASE: &str = "DH_BEGIN";
+
+/// The server's reply to [`DH_START_PHRASE`]
+pub const DH_REPLY_PHRASE: &str = "DH";
+
+/// Sent over an established [`SecureChannel`](crate::channel::SecureChannel), followed by the new
+/// key, to replace the key the channel uses
+pub const REKEY_PHRASE: &str = "REKEY";
+
+/// The reply to [`REKEY_PHRASE`], and the last message sent with the old key
+pub const REKEY_ACK_PHRASE: &str = "REKEY_ACK";
+
+/// Sent over an established [`SecureChannel`](crate::channel::SecureChannel) to replace the key
+/// with one both ends derive from it, see
+/// [`SecureChannel::initiate_rekey`](crate::channel::SecureChannel::initiate_rekey)
+pub const REKEY_REQUEST_PHRASE: &str = "REKEY_REQUEST";
+
+/// The reply to [`REKEY_REQUEST_PHRASE`], and the last message sent with the old key
+pub const REKEY_CONFIRM_PHRASE: &str = "REKEY_CONFIRM";
+
+/// Sent over an established [`SecureChannel`](crate::channel::SecureChannel), followed by the
+/// sender's unix time, to keep an idle connection open. The receiver discards it.
+pub const KEEPALIVE_PHRASE: &str = "KEEPALIVE";
+
+/// Ends a stream sent with [`SecureChannel::send_stream`](crate::channel::SecureChannel::send_stream),
+/// followed by the number of bytes in it
+pub const EOF_FRAME_PHRASE: &str = "EOF";
+
+/// The server's reply once the client has repeated its nonce
+pub const SUCCESS_PHRASE: &str = "SUCCESS";
+
+const PUBLIC_KEY_LABEL: &str = "RSA";
+const CERTIFICATE_LABEL: &str = "CERT";
+const AES_KEY_LABEL: &str = "AES_KEY";
+const FAILURE_LABEL: &str = "FAILURE";
+
+/// One line of the RSA handshake
+///
+/// [`Display`] writes the line without its newline, and [`FromStr`] parses it, so that every
+/// message has its format in one place.
+#[derive(Debug, Clone)]
+pub enum ProtocolMessage {
+    /// `COM_BEGIN <nonce>`, which starts the unencrypted half
+    HandshakeBegin { nonce: Nonce },
+    /// `SECOP_BEGIN <nonce>`, which starts the encrypted half
+    SecureHandshakeBegin { nonce: Nonce },
+    /// `<client nonce> <server nonce>`, the server repeating the client's nonce with its own
+    HandshakeAck { client_nonce: Nonce, server_nonce: Nonce },
+    /// `<nonce>`, a nonce repeated back to the side that chose it
+    NonceEcho { nonce: Nonce },
+    /// `RSA:<key>`
+    PublicKey { key: PublicKey },
+    /// `CERT:<pem>`, with the lines of the PEM joined by spaces
+    Certificate { pem: String },
+    /// `AES_KEY:<key>`, the key in the form of [`AESManager::parsable_string`](crate::encryption::aes::AESManager::parsable_string)
+    AesKeyExchange { key_hex: String },
+    /// `SUCCESS`
+    Success,
+    /// `FAILURE:<reason>`, sent by the server in place of [`Success`](Self::Success) when it
+    /// rejects the client
+    Failure { reason: String }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProtocolParseError {
+    /// The line is not any of the messages
+    UnknownMessage,
+    /// A nonce is missing or is not hex of the right length
+    InvalidNonce,
+    /// The contents of a public key message could not be parsed
+    InvalidPublicKey(PublicKeyParseError)
+}
+
+impl Display for ProtocolParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for ProtocolParseError { }
+
+impl Display for ProtocolMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolMessage::HandshakeBegin { nonce } => write!(f, "{} {}", HANDSHAKE_START_PHRASE, nonce),
+            ProtocolMessage::SecureHandshakeBegin { nonce } => write!(f, "{} {}", SECURE_HANDSHAKE_PHRASE, nonce),
+            ProtocolMessage::HandshakeAck { client_nonce, server_nonce } => write!(f, "{} {}", client_nonce, server_nonce),
+            ProtocolMessage::NonceEcho { nonce } => write!(f, "{}", nonce),
+            ProtocolMessage::PublicKey { key } => write!(f, "{}:{}", PUBLIC_KEY_LABEL, key),
+            ProtocolMessage::Certificate { pem } => write!(f, "{}:{}", CERTIFICATE_LABEL, pem),
+            ProtocolMessage::AesKeyExchange { key_hex } => write!(f, "{}:{}", AES_KEY_LABEL, key_hex),
+            ProtocolMessage::Success => write!(f, "{}", SUCCESS_PHRASE),
+            ProtocolMessage::Failure { reason } => write!(f, "{}:{}", FAILURE_LABEL, reason)
+        }
+    }
+}
+
+impl FromStr for ProtocolMessage {
+    type Err = ProtocolParseError;
+
+    /// Parses a line, ignoring the whitespace around it
+    ///
+    /// Only the first colon separates a label from its contents, which may contain more. A label
+    /// without a colon has empty contents.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let line = s.trim();
+        if line == SUCCESS_PHRASE {
+            return Ok(ProtocolMessage::Success);
+        }
+        let (label, contents) = line.split_once(':').unwrap_or((line, ""));
+        match label {
+            PUBLIC_KEY_LABEL => {
+                let key = contents.parse().map_err(ProtocolParseError::InvalidPublicKey)?;
+                return Ok(ProtocolMessage::PublicKey { key });
+            }
+            CERTIFICATE_LABEL => return Ok(ProtocolMessage::Certificate { pem: contents.to_string() }),
+            AES_KEY_LABEL => return Ok(ProtocolMessage::AesKeyExchange { key_hex: contents.to_string() }),
+            FAILURE_LABEL => return Ok(ProtocolMessage::Failure { reason: contents.to_string() }),
+            _ => {}
+        }
+
+        let nonce = |word: Option<&&str>| {
+            word.and_then(|word| Nonce::from_hex(word).ok()).ok_or(ProtocolParseError::InvalidNonce)
+        };
+        // a line of words that aren't hex is some other message, not a nonce of the wrong length
+        let hex = |word: &&str| word.bytes().all(|b| b.is_ascii_hexdigit());
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [phrase, rest @ ..] if *phrase == HANDSHAKE_START_PHRASE => {
+                Ok(ProtocolMessage::HandshakeBegin { nonce: nonce(rest.first())? })
+            }
+            [phrase, rest @ ..] if *phrase == SECURE_HANDSHAKE_PHRASE => {
+                Ok(ProtocolMessage::SecureHandshakeBegin { nonce: nonce(rest.first())? })
+            }
+            [single] if hex(single) => Ok(ProtocolMessage::NonceEcho { nonce: nonce(Some(single))? }),
+            [client, server] if hex(client) && hex(server) => Ok(ProtocolMessage::HandshakeAck {
+                client_nonce: nonce(Some(client))?,
+                server_nonce: nonce(Some(server))?
+            }),
+            _ => Err(ProtocolParseError::UnknownMessage)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encryption::rsa::RSAKeys;
+
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        let nonce = Nonce::generate();
+        let other = Nonce::generate();
+        let key = RSAKeys::from_test_vector().public_key();
+        let messages = [
+            ProtocolMessage::HandshakeBegin { nonce },
+            ProtocolMessage::SecureHandshakeBegin { nonce },
+            ProtocolMessage::HandshakeAck { client_nonce: nonce, server_nonce: other },
+            ProtocolMessage::NonceEcho { nonce },
+            ProtocolMessage::PublicKey { key },
+            ProtocolMessage::Certificate { pem: "-----BEGIN A----- AAAA -----END A-----".to_string() },
+            ProtocolMessage::AesKeyExchange { key_hex: "chacha20poly1305:00ff".to_string() },
+            ProtocolMessage::Success,
+            ProtocolMessage::Failure { reason: "wrong key size: 128".to_string() }
+        ];
+        for message in messages {
+            let line = message.to_string();
+            let parsed: ProtocolMessage = format!("{}\n", line).parse().unwrap();
+            assert_eq!(parsed.to_string(), line);
+            assert_eq!(std::mem::discriminant(&parsed), std::mem::discriminant(&message));
+        }
+    }
+
+    #[test]
+    fn invalid_messages() {
+        let parse = |line: &str| line.parse::<ProtocolMessage>().err();
+        assert_eq!(parse(""), Some(ProtocolParseError::UnknownMessage));
+        assert_eq!(parse("GET / HTTP/1.1"), Some(ProtocolParseError::UnknownMessage));
+        assert_eq!(parse("COM_BEGIN"), Some(ProtocolParseError::InvalidNonce));
+        assert_eq!(parse("SECOP_BEGIN 1234"), Some(ProtocolParseError::InvalidNonce));
+        assert_eq!(parse("hello"), Some(ProtocolParseError::UnknownMessage));
+        assert_eq!(parse("hello world"), Some(ProtocolParseError::UnknownMessage));
+        assert_eq!(parse("1234"), Some(ProtocolParseError::InvalidNonce));
+        assert_eq!(parse("RSA"), Some(ProtocolParseError::InvalidPublicKey(PublicKeyParseError::MissingField)));
+        assert!(matches!("AES_KEY".parse(), Ok(ProtocolMessage::AesKeyExchange { key_hex }) if key_hex.is_empty()));
+    }
+}
@@ -0,0 +1,252 @@
+//! In-memory streams for exercising the protocol without binding sockets or touching the disk
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::time::Duration;
+
+use crate::channel::{CloneWriter, NonblockingStream, SplitStream};
+
+/// One end of an in-memory bidirectional stream
+///
+/// Bytes written to one end of a pair are read from the other. Reads block until the other end
+/// writes something, or fail with `WouldBlock` once [`set_nonblocking`](NonblockingStream::set_nonblocking)
+/// is on, and report end of stream once the other end has been dropped. Like
+/// `TcpStream`, both `ChannelDuplex` and `&ChannelDuplex` implement `Read` and `Write`, so a single
+/// end can be passed as both the reader and the writer of a handshake.
+pub struct ChannelDuplex {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+    pending: RefCell<VecDeque<u8>>,
+    nonblocking: Cell<bool>
+}
+
+impl ChannelDuplex {
+
+    /// Creates two connected ends of a stream
+    pub fn pair() -> (ChannelDuplex, ChannelDuplex) {
+        let (first_sender, first_receiver) = channel();
+        let (second_sender, second_receiver) = channel();
+        let first = ChannelDuplex {
+            sender: first_sender,
+            receiver: second_receiver,
+            pending: RefCell::new(VecDeque::new()),
+            nonblocking: Cell::new(false)
+        };
+        let second = ChannelDuplex {
+            sender: second_sender,
+            receiver: first_receiver,
+            pending: RefCell::new(VecDeque::new()),
+            nonblocking: Cell::new(false)
+        };
+        (first, second)
+    }
+
+    /// Waits up to `dur` for the other end to write, returning the received bytes
+    ///
+    /// An empty vector is returned if the other end has been dropped, and an error of kind
+    /// `TimedOut` if nothing was written in time.
+    pub fn blocking_read_timeout(&self, dur: Duration) -> std::io::Result<Vec<u8>> {
+        let mut pending = self.pending.borrow_mut();
+        if !pending.is_empty() {
+            return Ok(pending.drain(..).collect());
+        }
+        match self.receiver.recv_timeout(dur) {
+            Ok(bytes) => Ok(bytes),
+            Err(RecvTimeoutError::Timeout) => {
+                Err(std::io::Error::new(ErrorKind::TimedOut, "no data received before the timeout"))
+            }
+            Err(RecvTimeoutError::Disconnected) => Ok(Vec::new())
+        }
+    }
+
+    fn read_shared(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        receive(&self.receiver, &mut self.pending.borrow_mut(), self.nonblocking.get(), buf)
+    }
+
+    fn write_shared(&self, buf: &[u8]) -> std::io::Result<usize> {
+        send(&self.sender, buf)
+    }
+}
+
+fn receive(receiver: &Receiver<Vec<u8>>, pending: &mut VecDeque<u8>, nonblocking: bool, buf: &mut [u8]) -> std::io::Result<usize> {
+    if pending.is_empty() {
+        let received = if nonblocking {
+            receiver.try_recv().map_err(|e| e == TryRecvError::Empty)
+        } else {
+            receiver.recv().map_err(|_| false)
+        };
+        match received {
+            Ok(bytes) => pending.extend(bytes),
+            Err(true) => return Err(std::io::Error::new(ErrorKind::WouldBlock, "nothing has been written yet")),
+            Err(false) => return Ok(0)
+        }
+    }
+    let mut index = 0;
+    while index < buf.len() && !pending.is_empty() {
+        buf[index] = pending.pop_front().unwrap();
+        index += 1;
+    }
+    Ok(index)
+}
+
+fn send(sender: &Sender<Vec<u8>>, buf: &[u8]) -> std::io::Result<usize> {
+    // An empty message would be read as the end of the stream by the other side
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    sender.send(buf.to_vec())
+        .map(|_| buf.len())
+        .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "the other end of the channel was dropped"))
+}
+
+/// The reading half of a [`ChannelDuplex`], which can be moved to another thread than the writing half
+pub struct ChannelDuplexReader {
+    receiver: Receiver<Vec<u8>>,
+    pending: VecDeque<u8>,
+    nonblocking: bool
+}
+
+/// The writing half of a [`ChannelDuplex`]
+pub struct ChannelDuplexWriter {
+    sender: Sender<Vec<u8>>
+}
+
+impl SplitStream for ChannelDuplex {
+    type Reader = ChannelDuplexReader;
+    type Writer = ChannelDuplexWriter;
+
+    fn split(self) -> std::io::Result<(ChannelDuplexReader, ChannelDuplexWriter)> {
+        let reader = ChannelDuplexReader {
+            receiver: self.receiver,
+            pending: self.pending.into_inner(),
+            nonblocking: self.nonblocking.get()
+        };
+        Ok((reader, ChannelDuplexWriter { sender: self.sender }))
+    }
+}
+
+impl CloneWriter for ChannelDuplex {
+    type Writer = ChannelDuplexWriter;
+
+    fn clone_writer(&self) -> std::io::Result<ChannelDuplexWriter> {
+        Ok(ChannelDuplexWriter { sender: self.sender.clone() })
+    }
+}
+
+impl CloneWriter for &ChannelDuplex {
+    type Writer = ChannelDuplexWriter;
+
+    fn clone_writer(&self) -> std::io::Result<ChannelDuplexWriter> {
+        (*self).clone_writer()
+    }
+}
+
+impl Read for ChannelDuplexReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        receive(&self.receiver, &mut self.pending, self.nonblocking, buf)
+    }
+}
+
+impl Write for ChannelDuplexWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        send(&self.sender, buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl NonblockingStream for ChannelDuplex {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        self.nonblocking.set(nonblocking);
+        Ok(())
+    }
+}
+
+impl NonblockingStream for &ChannelDuplex {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        (*self).set_nonblocking(nonblocking)
+    }
+}
+
+impl Read for ChannelDuplex {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.read_shared(buf)
+    }
+}
+
+impl Read for &ChannelDuplex {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.read_shared(buf)
+    }
+}
+
+impl Write for ChannelDuplex {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write_shared(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for &ChannelDuplex {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write_shared(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pair_is_connected_both_ways() {
+        let (mut first, mut second) = ChannelDuplex::pair();
+        write!(first, "Hello").unwrap();
+        write!(second, "World").unwrap();
+        let mut buffer = [0u8; 5];
+        second.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"Hello");
+        first.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"World");
+    }
+
+    #[test]
+    fn dropped_end_reads_as_end_of_stream() {
+        let (mut first, second) = ChannelDuplex::pair();
+        drop(second);
+        let mut buffer = Vec::new();
+        assert_eq!(first.read_to_end(&mut buffer).unwrap(), 0);
+        assert_eq!(first.write(b"lost").unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn nonblocking_read() {
+        let (mut first, mut second) = ChannelDuplex::pair();
+        second.set_nonblocking(true).unwrap();
+        let mut buffer = [0u8; 5];
+        assert_eq!(second.read(&mut buffer).unwrap_err().kind(), ErrorKind::WouldBlock);
+        write!(first, "Hello").unwrap();
+        second.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"Hello");
+        drop(first);
+        assert_eq!(second.read(&mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_timeout() {
+        let (mut first, second) = ChannelDuplex::pair();
+        let error = second.blocking_read_timeout(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        write!(first, "Hello").unwrap();
+        assert_eq!(second.blocking_read_timeout(Duration::from_millis(10)).unwrap(), b"Hello");
+    }
+}